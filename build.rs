@@ -1,10 +1,13 @@
 fn main() {
     let mut config = prost_build::Config::new();
 
-    config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
-    config
+    config.bytes(["."]);
+    // enum 已经 derive 了 PartialOrd，只给需要排序的 message 加上
+    config.type_attribute(".abi.Kvpair", "#[derive(PartialOrd)]");
+    config.type_attribute(".abi.Value", "#[derive(PartialOrd)]");
+    // message 由 prost 生成，tonic 额外生成 KvService 的 client / server
+    tonic_build::configure()
         .out_dir("src/pb")
-        .compile_protos(&["abi.proto"], &["."])
+        .compile_with_config(config, &["abi.proto"], &["."])
         .unwrap();
 }
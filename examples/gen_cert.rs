@@ -25,7 +25,7 @@ async fn main() -> Result<()> {
 
 fn create_ca() -> Result<CertPem> {
     let (cert, key) = generate_ca(
-        &["acme.inc"],
+        ["acme.inc"],
         "CN",
         "Acme Inc",
         "Acme CA",
@@ -35,8 +35,8 @@ fn create_ca() -> Result<CertPem> {
 
     Ok(CertPem {
         cert_type: CertType::CA,
-        cert: cert,
-        key: key,
+        cert,
+        key,
     })
 }
 
@@ -50,9 +50,9 @@ fn create_cert(ca: &CA, domains: &[&str], cn: &str, is_client: bool) -> Result<C
     let (cert, key) = generate_cert(ca, domains, "CN", "Acme Inc", cn, None, is_client, days)?;
 
     Ok(CertPem {
-        cert_type: cert_type,
-        cert: cert,
-        key: key,
+        cert_type,
+        cert,
+        key,
     })
 }
 
@@ -8,7 +8,11 @@ use tracing::info;
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let service: Service<SledDb> = ServiceInner::new(SledDb::new("../tmp/kvserver"))
+    // 变更日志和数据保存在同一个 sled 数据库里
+    let db = SledDb::new("../tmp/kvserver");
+    let changes = db.change_store()?;
+    let service: Service<SledDb> = ServiceInner::new(db)
+        .with_change_log(changes)?
         .fn_before_send(|res| match res.message.as_ref() {
             "" => res.message = "altered. Original message is empty.".into(),
             s => res.message = format!("altered: {}", s),
@@ -0,0 +1,50 @@
+use std::{env, fs::File, io::BufReader};
+
+use anyhow::{anyhow, bail, Result};
+use kv::{backup_storage, BackupInfo, BackupReader, Compression, SledDb};
+
+const USAGE: &str = "Usage:
+    kvbackup dump <sled-dir> <file> [none|gzip|lz4|zstd]
+    kvbackup load <file> <sled-dir>
+    kvbackup info <file>";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    let info = match args.as_slice() {
+        ["dump", dir, file] => dump(dir, file, Compression::Zstd)?,
+        ["dump", dir, file, compression] => dump(dir, file, parse_compression(compression)?)?,
+        // 恢复到 sled 目录中，目录中已有的数据会保留，同名的 key 会被覆盖
+        ["load", file, dir] => BackupReader::new(open(file)?)?.restore_to(&SledDb::new(dir))?,
+        ["info", file] => BackupReader::new(open(file)?)?.read_all()?.0,
+        _ => bail!(USAGE),
+    };
+    println!(
+        "version: {}\ntimestamp: {}\ncompression: {:?}\ntables: {}\npairs: {}",
+        info.version,
+        info.timestamp,
+        info.compression(),
+        info.tables,
+        info.pairs
+    );
+    Ok(())
+}
+
+fn dump(dir: &str, file: &str, compression: Compression) -> Result<BackupInfo> {
+    let store = SledDb::new(dir);
+    Ok(backup_storage(&store, File::create(file)?, compression)?)
+}
+
+fn open(file: &str) -> Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(file)?))
+}
+
+fn parse_compression(s: &str) -> Result<Compression> {
+    match s {
+        "none" => Ok(Compression::None),
+        "gzip" => Ok(Compression::Gzip),
+        "lz4" => Ok(Compression::Lz4),
+        "zstd" => Ok(Compression::Zstd),
+        _ => Err(anyhow!("Unknown compression {}", s)),
+    }
+}
@@ -1,27 +1,43 @@
+use std::env;
+
 use anyhow::Result;
-use kv::{CommandRequest, ProstClientStream, TlsClientConnector};
-use tokio::net::TcpStream;
+use kv::{Compression, KvClient, KvConnector, TlsClientConnector};
+use tokio::net::UnixStream;
 use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let ca_cert = include_str!("../fixtures/ca.cert");
 
-    let addr = "127.0.0.1:9527";
-    let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
-    // 连接服务器
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
+    // 握手时优先使用 lz4，它的压缩速度更快
+    let compressions = [Compression::Lz4, Compression::Gzip];
 
-    let mut client = ProstClientStream::new(stream);
+    // 设置了 KV_UNIX_SOCKET 就走本机的 Unix socket，否则通过 TLS 连接服务器
+    let mut client = match env::var("KV_UNIX_SOCKET") {
+        Ok(path) => {
+            KvClient::new(UnixStream::connect(path).await?).with_compressions(&compressions)
+        }
+        Err(_) => {
+            let ca_cert = include_str!("../fixtures/ca.cert");
+            let addr = env::var("KV_ADDR").unwrap_or_else(|_| "127.0.0.1:9527".into());
+            let tls = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
+            KvConnector::new(addr)
+                .with_tls(tls)
+                .with_compressions(&compressions)
+                .connect()
+                .await?
+        }
+    };
 
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    let hello = client.hello().await?;
+    info!(
+        "Connected to server {} (protocol {})",
+        hello.version, hello.protocol_version
+    );
 
     // 发送 HSET 命令
-    let data = client.execute(cmd).await?;
-    info!("Got response {:?}", data);
+    let old = client.hset("table1", "hello", "world").await?;
+    info!("Got previous value {:?}", old);
 
     Ok(())
 }
@@ -1,3 +1,5 @@
+use std::convert::Infallible;
+
 use crate::Value;
 use thiserror::Error;
 
@@ -8,9 +10,9 @@ pub enum KvError {
 
     #[error("Cannot parse command: `{0}`")]
     InvalidCommand(String),
-    #[error("Cannot convert value {:0} to {1}")]
+    #[error("Cannot convert value {0:?} to {1}")]
     ConvertError(Value, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
+    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
 
     #[error("Failed to encode protobuf message")]
@@ -21,8 +23,38 @@ pub enum KvError {
     #[error("Failed to access sled db")]
     SledError(#[from] sled::Error),
 
-    #[error("Frame is larger than max size")]
-    FrameError,
+    #[error("Frame size {0} is larger than max size {1}")]
+    FrameTooLarge(usize, usize),
+    #[error("Frame checksum mismatch: expected {0:#x}, got {1:#x}")]
+    ChecksumMismatch(u64, u64),
+    #[error("Cannot decode frame: {0}")]
+    InvalidFrame(String),
+    #[error("Handshake failed: {0}")]
+    HandshakeError(String),
+    #[error("Protocol version {0} is not supported")]
+    UnsupportedVersion(u32),
+    #[error("Command {0} is not supported by the server")]
+    UnsupportedCommand(String),
+    #[error("Watch lagged behind, {0} events dropped")]
+    WatchLagged(u64),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Raft leader is not elected")]
+    NoLeader,
+    #[error("Slot {0} is moved to {1}")]
+    Moved(u32, String),
+    #[error("Slot {0} is migrating to {1}")]
+    Ask(u32, String),
+    #[error("Slot {0} is migrating, try again later")]
+    TryAgain(u32),
+    #[error("Change log offset {0} is out of range {1}..={2}")]
+    InvalidOffset(u64, u64, u64),
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+    #[error("Memory limit of {0} bytes is reached")]
+    OutOfMemory(u64),
+    #[error("Encryption error: {0}")]
+    Encryption(String),
 
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
@@ -30,9 +62,22 @@ pub enum KvError {
     #[error("Internal error: {0}")]
     Internal(String),
 
+    #[error("Yamux error")]
+    YamuxError(#[from] yamux::ConnectionError),
+
+    #[error("HTTP error")]
+    HttpError(#[from] hyper::Error),
+
     #[error("TLS error")]
     TlsError(#[from] tokio_rustls::rustls::TLSError),
 
     #[error("Certificate parse error: error to load {0} {0}")]
     CertifcateParseError(&'static str, &'static str),
 }
+
+// Value 到 Value 的转换不会失败，这样 KvClient 的泛型接口也可以直接返回 Value
+impl From<Infallible> for KvError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
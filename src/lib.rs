@@ -1,11 +1,13 @@
 mod error;
 mod network;
 mod pb;
+mod raft;
 mod service;
 mod storage;
 
 pub use error::KvError;
 pub use network::*;
 pub use pb::abi::*;
+pub use raft::*;
 pub use service::*;
 pub use storage::*;
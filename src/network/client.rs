@@ -0,0 +1,487 @@
+use std::{collections::HashMap, sync::Arc};
+
+use futures::Stream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::{
+    BackupInfo, ChangeEvent, Checksum, CommandRequest, CommandResponse, Compression, FrameConfig,
+    Hello, KvError, Kvpair, ProstClientStream, ReplicationEntry, TlsClientConnector, Value,
+    WatchEvent, YamuxCtrl,
+};
+
+/// KvClient 可以使用的底层连接
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// 类型擦除后的连接，TCP / TLS / yamux stream 都可以放进去
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// 类型化的客户端，封装了 CommandRequest 的构造和 CommandResponse 的解析
+///
+/// 服务器返回的错误状态会转换回对应的 KvError，比如 404 转换成 `KvError::NotFound`
+pub struct KvClient {
+    inner: ProstClientStream<BoxedStream>,
+}
+
+/// 建立连接的参数：TCP 地址、可选的 TLS，以及是否在一个连接上用 yamux 复用多个 KvClient
+#[derive(Clone)]
+pub struct KvConnector {
+    addr: String,
+    tls: Option<TlsClientConnector>,
+    // 使用 yamux 时，所有 connect 共享一个底层连接，连接断开后下次 connect 会重新建立
+    yamux: Option<Arc<Mutex<Option<YamuxCtrl<BoxedStream>>>>>,
+    frame_config: FrameConfig,
+    compressions: Option<Vec<Compression>>,
+    checksums: Vec<Checksum>,
+}
+
+impl KvConnector {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            tls: None,
+            yamux: None,
+            frame_config: FrameConfig::default(),
+            compressions: None,
+            checksums: vec![],
+        }
+    }
+
+    /// 使用 TLS 连接服务器
+    pub fn with_tls(mut self, tls: TlsClientConnector) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// 在一个连接上用 yamux 复用多个 KvClient，服务器需要同样使用 yamux
+    pub fn with_yamux(mut self) -> Self {
+        self.yamux = Some(Default::default());
+        self
+    }
+
+    /// 使用指定的 frame 参数，比如最大 frame 大小
+    pub fn with_frame_config(mut self, config: FrameConfig) -> Self {
+        self.frame_config = config;
+        self
+    }
+
+    /// 握手时希望使用的压缩算法，按优先级从高到低排列
+    pub fn with_compressions(mut self, compressions: &[Compression]) -> Self {
+        self.compressions = Some(compressions.to_vec());
+        self
+    }
+
+    /// 握手时希望使用的校验和算法，按优先级从高到低排列
+    pub fn with_checksums(mut self, checksums: &[Checksum]) -> Self {
+        self.checksums = checksums.to_vec();
+        self
+    }
+
+    /// 服务器地址
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// 建立连接并完成握手
+    pub async fn connect(&self) -> Result<KvClient, KvError> {
+        let stream = match &self.yamux {
+            Some(session) => self.open_yamux(session).await?,
+            None => self.connect_stream().await?,
+        };
+
+        let mut client = KvClient::new(stream)
+            .with_frame_config(self.frame_config)
+            .with_checksums(&self.checksums);
+        if let Some(compressions) = &self.compressions {
+            client = client.with_compressions(compressions);
+        }
+        client.inner.handshake().await?;
+        Ok(client)
+    }
+
+    async fn connect_stream(&self) -> Result<BoxedStream, KvError> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        match &self.tls {
+            Some(tls) => Ok(Box::new(tls.connect(stream).await?)),
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    async fn open_yamux(
+        &self,
+        session: &Mutex<Option<YamuxCtrl<BoxedStream>>>,
+    ) -> Result<BoxedStream, KvError> {
+        let mut session = session.lock().await;
+        if let Some(ctrl) = session.as_mut() {
+            match ctrl.open_stream().await {
+                Ok(stream) => return Ok(Box::new(stream)),
+                Err(e) => warn!("Yamux session to {} is broken: {:?}", self.addr, e),
+            }
+        }
+
+        let mut ctrl = YamuxCtrl::new_client(self.connect_stream().await?, None);
+        let stream = ctrl.open_stream().await?;
+        *session = Some(ctrl);
+        Ok(Box::new(stream))
+    }
+}
+
+impl KvClient {
+    /// 在已经建立的连接上创建客户端，第一次执行命令时自动握手
+    pub fn new(stream: impl AsyncStream + 'static) -> Self {
+        Self {
+            inner: ProstClientStream::new(Box::new(stream)),
+        }
+    }
+
+    /// 通过 TCP 连接服务器
+    pub async fn connect(addr: impl Into<String>) -> Result<Self, KvError> {
+        KvConnector::new(addr).connect().await
+    }
+
+    /// 使用指定的 frame 参数，比如最大 frame 大小
+    pub fn with_frame_config(mut self, config: FrameConfig) -> Self {
+        self.inner = self.inner.with_frame_config(config);
+        self
+    }
+
+    /// 握手时希望使用的压缩算法，按优先级从高到低排列
+    pub fn with_compressions(mut self, compressions: &[Compression]) -> Self {
+        self.inner = self.inner.with_compressions(compressions);
+        self
+    }
+
+    /// 握手时希望使用的校验和算法，按优先级从高到低排列
+    pub fn with_checksums(mut self, checksums: &[Checksum]) -> Self {
+        self.inner = self.inner.with_checksums(checksums);
+        self
+    }
+
+    /// 握手后服务器返回的 Hello，还没有握手时先握手
+    pub async fn hello(&mut self) -> Result<&Hello, KvError> {
+        if self.inner.server_hello().is_none() {
+            self.inner.handshake().await?;
+        }
+        Ok(self.inner.server_hello().unwrap())
+    }
+
+    /// 底层的 ProstClientStream
+    pub fn stream_mut(&mut self) -> &mut ProstClientStream<BoxedStream> {
+        &mut self.inner
+    }
+
+    /// 执行任意命令，非 200 的响应转换成 KvError
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.inner.execute(cmd).await?.into_result()
+    }
+
+    /// 检查连接是否可用
+    pub async fn ping(&mut self) -> Result<(), KvError> {
+        self.execute(CommandRequest::new_ping()).await?;
+        Ok(())
+    }
+
+    /// 读取 value，key 不存在时返回 `KvError::NotFound`
+    pub async fn hget<T>(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<T, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let res = self.execute(CommandRequest::new_hget(table, key)).await?;
+        let value = res.values.into_iter().next().unwrap_or_default();
+        Ok(value.try_into()?)
+    }
+
+    /// 读取多个 value，不存在的 key 对应 None
+    pub async fn hmget<T>(
+        &mut self,
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Vec<Option<T>>, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let keys = keys.into_iter().map(Into::into).collect();
+        let res = self.execute(CommandRequest::new_hmget(table, keys)).await?;
+        res.values.into_iter().map(convert_optional).collect()
+    }
+
+    /// 读取整个 table
+    pub async fn hgetall<T>(
+        &mut self,
+        table: impl Into<String>,
+    ) -> Result<HashMap<String, T>, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let res = self.execute(CommandRequest::new_hgetall(table)).await?;
+        res.pairs
+            .into_iter()
+            .map(|p| Ok((p.key, p.value.unwrap_or_default().try_into()?)))
+            .collect()
+    }
+
+    /// 写入 value，返回之前的 value
+    pub async fn hset(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset(table, key, value.into());
+        let res = self.execute(cmd).await?;
+        Ok(res.values.into_iter().next().and_then(non_empty))
+    }
+
+    /// 写入多个 value，返回之前的 value
+    pub async fn hmset<K, V>(
+        &mut self,
+        table: impl Into<String>,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Vec<Option<Value>>, KvError>
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(k, v)| Kvpair::new(k, v.into()))
+            .collect();
+        let res = self
+            .execute(CommandRequest::new_hmset(table, pairs))
+            .await?;
+        Ok(res.values.into_iter().map(non_empty).collect())
+    }
+
+    /// 删除 key，返回删除的 value
+    pub async fn hdel(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<Value>, KvError> {
+        let res = self.execute(CommandRequest::new_hdel(table, key)).await?;
+        Ok(res.values.into_iter().next().and_then(non_empty))
+    }
+
+    /// 删除多个 key，返回删除的 value
+    pub async fn hmdel(
+        &mut self,
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let keys = keys.into_iter().map(Into::into).collect();
+        let res = self.execute(CommandRequest::new_hmdel(table, keys)).await?;
+        Ok(res.values.into_iter().map(non_empty).collect())
+    }
+
+    /// key 是否存在
+    pub async fn hexist(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<bool, KvError> {
+        let res = self.execute(CommandRequest::new_hexist(table, key)).await?;
+        res.values.into_iter().next().unwrap_or_default().try_into()
+    }
+
+    /// 多个 key 是否存在
+    pub async fn hmexist(
+        &mut self,
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Vec<bool>, KvError> {
+        let keys = keys.into_iter().map(Into::into).collect();
+        let res = self
+            .execute(CommandRequest::new_hmexist(table, keys))
+            .await?;
+        res.values.into_iter().map(TryInto::try_into).collect()
+    }
+
+    /// 在服务器上备份所有数据，path 是服务器备份目录下的文件
+    pub async fn backup(
+        &mut self,
+        path: impl Into<String>,
+        compression: Compression,
+    ) -> Result<BackupInfo, KvError> {
+        let res = self
+            .execute(CommandRequest::new_backup(path, compression))
+            .await?;
+        res.backup
+            .ok_or_else(|| KvError::Internal("Missing backup info in response".into()))
+    }
+
+    /// 用服务器备份目录下的 path 文件替换服务器上所有的数据
+    pub async fn restore(&mut self, path: impl Into<String>) -> Result<BackupInfo, KvError> {
+        let res = self.execute(CommandRequest::new_restore(path)).await?;
+        res.backup
+            .ok_or_else(|| KvError::Internal("Missing backup info in response".into()))
+    }
+
+    /// 订阅 table 中 key 以 prefix 开头的数据变化，之后这个连接只用来接收通知
+    pub async fn watch(
+        self,
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+    ) -> Result<impl Stream<Item = Result<WatchEvent, KvError>>, KvError> {
+        self.inner.watch(table, prefix).await
+    }
+
+    /// 从主节点复制数据，会消耗掉 client
+    pub async fn replicate(
+        self,
+        id: impl Into<String>,
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<ReplicationEntry, KvError>>, KvError> {
+        self.inner.replicate(id, offset).await
+    }
+
+    /// 从 offset 开始订阅变更日志，会消耗掉 client；消费者重启后用最后收到的 seq + 1 继续
+    pub async fn subscribe(
+        self,
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<ChangeEvent, KvError>>, KvError> {
+        self.inner.subscribe(offset).await
+    }
+}
+
+// 服务器用没有内容的 Value 表示不存在
+fn non_empty(v: Value) -> Option<Value> {
+    v.value.is_some().then_some(v)
+}
+
+fn convert_optional<T>(v: Value) -> Result<Option<T>, KvError>
+where
+    T: TryFrom<Value>,
+    KvError: From<T::Error>,
+{
+    non_empty(v)
+        .map(T::try_from)
+        .transpose()
+        .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tls_utils::{tls_acceptor, tls_connector},
+        MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor,
+    };
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+    // tls 为 None 时使用 TCP，yamux 为 true 时每个连接上跑 yamux
+    async fn start_server(tls: Option<TlsServerAcceptor>, yamux: bool) -> Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream: BoxedStream = match &tls {
+                    Some(tls) => Box::new(tls.accept(stream).await.unwrap()),
+                    None => Box::new(stream),
+                };
+                let svc = service.clone();
+                if yamux {
+                    YamuxCtrl::new_server(stream, None, move |s| {
+                        let svc = svc.clone();
+                        async move {
+                            ProstServerStream::new(s.compat(), svc).process().await;
+                            Ok(())
+                        }
+                    });
+                } else {
+                    tokio::spawn(ProstServerStream::new(stream, svc).process());
+                }
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn check_client(client: &mut KvClient) -> Result<()> {
+        assert_eq!(client.hset("t1", "k1", "v1").await?, None);
+        assert_eq!(client.hset("t1", "k1", "v2").await?, Some("v1".into()));
+        let v: String = client.hget("t1", "k1").await?;
+        assert_eq!(v, "v2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn typed_commands_should_work() -> Result<()> {
+        let addr = start_server(None, false).await?;
+        let mut client = KvClient::connect(addr.to_string()).await?;
+
+        client.hmset("t1", [("a", 1), ("b", 2)]).await?;
+        client.hset("t1", "c", "hello").await?;
+        let n: i64 = client.hget("t1", "a").await?;
+        assert_eq!(n, 1);
+
+        let values: Vec<Option<i64>> = client.hmget("t1", ["a", "x", "b"]).await?;
+        assert_eq!(values, vec![Some(1), None, Some(2)]);
+
+        let all: HashMap<String, Value> = client.hgetall("t1").await?;
+        assert_eq!(all.len(), 3);
+        assert_eq!(all["c"], "hello".into());
+
+        assert!(client.hexist("t1", "a").await?);
+        assert_eq!(client.hmexist("t1", ["a", "x"]).await?, vec![true, false]);
+        assert_eq!(client.hdel("t1", "a").await?, Some(1.into()));
+        assert_eq!(
+            client.hmdel("t1", ["a", "b"]).await?,
+            vec![None, Some(2.into())]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_should_be_typed() -> Result<()> {
+        let addr = start_server(None, false).await?;
+        let mut client = KvClient::connect(addr.to_string()).await?;
+
+        let e = client.hget::<Value>("t1", "k1").await.unwrap_err();
+        assert!(matches!(e, KvError::NotFound(t, k) if t == "t1" && k == "k1"));
+
+        client.hset("t1", "k1", "v1").await?;
+        let e = client.hget::<i64>("t1", "k1").await.unwrap_err();
+        assert!(matches!(e, KvError::ConvertError(_, "Integer")));
+
+        let e = client.execute(CommandRequest::default()).await.unwrap_err();
+        assert!(matches!(e, KvError::InvalidCommand(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connector_should_support_tls_and_yamux() -> Result<()> {
+        let addr = start_server(Some(tls_acceptor(false)?), false).await?;
+        let connector = KvConnector::new(addr.to_string()).with_tls(tls_connector(false)?);
+        check_client(&mut connector.connect().await?).await?;
+
+        let addr = start_server(Some(tls_acceptor(false)?), true).await?;
+        let connector = KvConnector::new(addr.to_string())
+            .with_tls(tls_connector(false)?)
+            .with_yamux()
+            .with_compressions(&[Compression::Lz4]);
+        let mut c1 = connector.connect().await?;
+        let mut c2 = connector.connect().await?;
+        check_client(&mut c1).await?;
+        // 两个 client 复用同一个连接，能看到彼此写入的数据
+        let v: String = c2.hget("t1", "k1").await?;
+        assert_eq!(v, "v2");
+        Ok(())
+    }
+}
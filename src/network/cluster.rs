@@ -0,0 +1,629 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::future;
+use tokio::{sync::Mutex, time};
+use tracing::{debug, info};
+
+use crate::{
+    command_request::RequestData, execute_all, key_slot, merge_values, CommandRequest,
+    CommandResponse, Hgetall, Hmdel, Hmexist, Hmget, Hmset, KvConnector, KvError, KvPool, Kvpair,
+    PoolConfig, Service, SlotRange, SlotState, TlsClientConnector, SLOTS,
+};
+
+/// 迁移 slot 时每批搬运的 key 数量
+const MIGRATE_BATCH: usize = 100;
+/// 一个命令最多跟随的重定向次数
+const MAX_REDIRECTS: usize = 16;
+/// 收到 TRYAGAIN 之后等待的时间
+const TRY_AGAIN_DELAY: Duration = Duration::from_millis(10);
+
+/// 把本节点上一个 slot 的数据搬到另一个节点
+///
+/// slot 需要先在本节点标记为 MIGRATING、在目标节点标记为 IMPORTING。
+/// 每批 key 在搬运期间返回 TRYAGAIN，写到目标节点后从本地删除，
+/// 其它 key 在迁移过程中仍然可以正常读写
+#[derive(Clone)]
+pub struct SlotMigrator {
+    service: Service,
+    tls: Option<TlsClientConnector>,
+}
+
+impl SlotMigrator {
+    pub fn new(service: Service) -> Self {
+        Self { service, tls: None }
+    }
+
+    /// 使用 TLS 连接目标节点
+    pub fn with_tls(mut self, tls: TlsClientConnector) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// 搬运 slot 中所有的 key，返回搬运的 key 数量
+    pub async fn migrate(&self, slot: u32, addr: &str) -> Result<usize, KvError> {
+        let mut connector = KvConnector::new(addr);
+        if let Some(tls) = &self.tls {
+            connector = connector.with_tls(tls.clone());
+        }
+        let mut client = connector.connect().await?;
+
+        let mut moved = 0;
+        loop {
+            let batch = self.service.start_moving(slot, MIGRATE_BATCH)?;
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut result = Ok(());
+            for hmset in &batch {
+                let cmd = CommandRequest::new_hmset(&hmset.table, hmset.pairs.clone());
+                result = client
+                    .execute(CommandRequest::new_asking(cmd))
+                    .await
+                    .and_then(|res| res.into_result().map(|_| ()));
+                if result.is_err() {
+                    break;
+                }
+            }
+            let count: usize = batch.iter().map(|hmset| hmset.pairs.len()).sum();
+            // 失败时这批 key 留在本地，可以重新迁移
+            self.service.finish_moving(batch, result.is_ok())?;
+            result?;
+            moved += count;
+        }
+
+        info!("Migrated {} keys of slot {} to {}", moved, slot, addr);
+        Ok(moved)
+    }
+}
+
+/// 连接开启了集群模式的 kvs 节点，缓存 slot 到节点的映射
+///
+/// 命令按 (table, key) 所在的 slot 发给对应的节点。收到 MOVED 时更新缓存再重试；
+/// 收到 ASK 时把命令包在 Asking 里发给目标节点，不更新缓存；收到 TRYAGAIN 时稍后重试。
+/// 多 key 的命令按 slot 拆开并发执行，拆开的写命令不是原子的
+#[derive(Clone)]
+pub struct ClusterClient {
+    seeds: Vec<String>,
+    tls: Option<TlsClientConnector>,
+    config: PoolConfig,
+    // 节点地址到它的管理端口，迁移 slot 的管理命令通过管理端口发送
+    admins: HashMap<String, KvConnector>,
+    state: Arc<ClusterState>,
+}
+
+// 在 clone 出来的 client 之间共享
+struct ClusterState {
+    // 每个 slot 所在节点的地址，还没有分配的 slot 为 None
+    slots: RwLock<Vec<Option<Arc<str>>>>,
+    pools: Mutex<HashMap<String, KvPool>>,
+}
+
+impl ClusterClient {
+    /// seeds 是集群中任意几个节点的地址，用来获取 slot 的分配
+    pub fn new(seeds: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            seeds: seeds.into_iter().map(Into::into).collect(),
+            tls: None,
+            config: PoolConfig::default(),
+            admins: HashMap::new(),
+            state: Arc::new(ClusterState {
+                slots: RwLock::new(vec![None; SLOTS as usize]),
+                pools: Default::default(),
+            }),
+        }
+    }
+
+    /// 使用 TLS 连接节点
+    pub fn with_tls(mut self, tls: TlsClientConnector) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// addr 节点的管理端口，migrate_slot 需要通过管理端口修改节点的 slot 状态
+    pub fn with_admin(mut self, addr: impl Into<String>, connector: KvConnector) -> Self {
+        self.admins.insert(addr.into(), connector);
+        self
+    }
+
+    /// 每个节点的连接池参数
+    pub fn with_pool_config(mut self, config: PoolConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// 从 seeds 和已知的节点获取最新的 slot 分配
+    pub async fn refresh(&self) -> Result<(), KvError> {
+        let mut addrs = self.nodes();
+        addrs.extend(self.seeds.iter().cloned());
+
+        let mut last_error = KvError::Internal("No node in the cluster client".into());
+        for addr in addrs {
+            let pool = self.pool(&addr).await?;
+            match pool.execute(CommandRequest::new_cluster_slots()).await {
+                Ok(res) => {
+                    self.update(&res.slots);
+                    return Ok(());
+                }
+                Err(e) => {
+                    debug!("Failed to get slots from {}: {:?}", addr, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// 缓存的 slot 分配
+    pub fn slots(&self) -> Vec<SlotRange> {
+        let slots = self.state.slots.read().unwrap();
+        let mut ranges: Vec<SlotRange> = Vec::new();
+        for (slot, addr) in slots.iter().enumerate() {
+            let (slot, addr) = match addr {
+                Some(addr) => (slot as u32, addr),
+                None => continue,
+            };
+            match ranges.last_mut() {
+                Some(range) if range.end + 1 == slot && *range.addr == **addr => range.end = slot,
+                _ => ranges.push(SlotRange {
+                    start: slot,
+                    end: slot,
+                    addr: addr.to_string(),
+                }),
+            }
+        }
+        ranges
+    }
+
+    /// 缓存中 slot 所在的节点
+    pub fn node(&self, slot: u32) -> Option<String> {
+        let slots = self.state.slots.read().unwrap();
+        slots
+            .get(slot as usize)?
+            .as_ref()
+            .map(|addr| addr.to_string())
+    }
+
+    /// 执行命令，非 200 的响应转换成 KvError
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let data = match cmd.request_data {
+            Some(data) => data,
+            None => return Err(KvError::InvalidCommand("Request has no data".into())),
+        };
+        if self.nodes().is_empty() {
+            self.refresh().await?;
+        }
+
+        match data {
+            RequestData::Hget(ref v) => self.route(key_slot(&v.table, &v.key), data).await,
+            RequestData::Hdel(ref v) => self.route(key_slot(&v.table, &v.key), data).await,
+            RequestData::Hexist(ref v) => self.route(key_slot(&v.table, &v.key), data).await,
+            RequestData::Hset(ref v) => {
+                let key = v.pair.as_ref().map(|p| p.key.as_str()).unwrap_or_default();
+                self.route(key_slot(&v.table, key), data).await
+            }
+            RequestData::Hmget(Hmget { table, keys }) => {
+                self.fan_out(
+                    &table,
+                    keys,
+                    |k| k,
+                    |table, keys| RequestData::Hmget(Hmget { table, keys }),
+                )
+                .await
+            }
+            RequestData::Hmdel(Hmdel { table, keys }) => {
+                self.fan_out(
+                    &table,
+                    keys,
+                    |k| k,
+                    |table, keys| RequestData::Hmdel(Hmdel { table, keys }),
+                )
+                .await
+            }
+            RequestData::Hmexist(Hmexist { table, keys }) => {
+                self.fan_out(
+                    &table,
+                    keys,
+                    |k| k,
+                    |table, keys| RequestData::Hmexist(Hmexist { table, keys }),
+                )
+                .await
+            }
+            RequestData::Hmset(Hmset { table, pairs }) => {
+                self.fan_out(
+                    &table,
+                    pairs,
+                    |p: &Kvpair| &p.key,
+                    |table, pairs| RequestData::Hmset(Hmset { table, pairs }),
+                )
+                .await
+            }
+            // 每个节点只返回自己的数据，需要发给所有节点
+            RequestData::Hgetall(Hgetall { table }) => {
+                let cmd = RequestData::Hgetall(Hgetall { table });
+                let pairs = self
+                    .execute_nodes(cmd)
+                    .await?
+                    .into_iter()
+                    .flat_map(|res| res.pairs)
+                    .collect();
+                Ok(CommandResponse {
+                    pairs,
+                    ..CommandResponse::ok()
+                })
+            }
+            RequestData::Ping(_) => {
+                self.execute_nodes(data).await?;
+                Ok(CommandResponse::ok())
+            }
+            RequestData::Hello(_)
+            | RequestData::Watch(_)
+            | RequestData::Replicate(_)
+            | RequestData::Raft(_)
+            | RequestData::Asking(_)
+            | RequestData::ClusterSlots(_)
+            | RequestData::ClusterSetSlot(_)
+            | RequestData::MigrateSlot(_)
+            | RequestData::Subscribe(_)
+            | RequestData::Backup(_)
+            | RequestData::Restore(_) => Err(KvError::UnsupportedCommand(data.name().into())),
+        }
+    }
+
+    /// 把 slot 从当前的节点迁移到 addr，迁移过程中客户端可以继续读写
+    pub async fn migrate_slot(&self, slot: u32, addr: &str) -> Result<usize, KvError> {
+        if self.nodes().is_empty() {
+            self.refresh().await?;
+        }
+        let from = self
+            .node(slot)
+            .ok_or_else(|| KvError::Internal(format!("Slot {} is not assigned", slot)))?;
+        if from == addr {
+            return Ok(0);
+        }
+
+        let set_slot = |state, peer: &str| CommandRequest::new_cluster_set_slot(slot, state, peer);
+        self.admin_call(addr, set_slot(SlotState::Importing, &from))
+            .await?;
+        self.admin_call(&from, set_slot(SlotState::Migrating, addr))
+            .await?;
+        let res = self
+            .admin_call(&from, CommandRequest::new_migrate_slot(slot, addr))
+            .await?;
+
+        // 先通知目标节点，这样源节点返回的 MOVED 指向的节点一定已经接管了 slot
+        let mut nodes = self.nodes();
+        nodes.remove(addr);
+        nodes.remove(&from);
+        for node in [addr.to_string(), from].into_iter().chain(nodes) {
+            self.admin_call(&node, set_slot(SlotState::Stable, addr))
+                .await?;
+        }
+        self.set_node(slot, addr);
+
+        let moved: i64 = match res.values.into_iter().next() {
+            Some(v) => v.try_into()?,
+            None => 0,
+        };
+        Ok(moved as usize)
+    }
+
+    // 发给 slot 所在的节点，跟随重定向
+    async fn route(&self, slot: u32, data: RequestData) -> Result<CommandResponse, KvError> {
+        let mut addr = self.owner(slot)?;
+        let mut asking = false;
+        for _ in 0..MAX_REDIRECTS {
+            let mut cmd = CommandRequest {
+                request_data: Some(data.clone()),
+            };
+            if asking {
+                cmd = CommandRequest::new_asking(cmd);
+            }
+            match self.call(&addr, cmd).await {
+                Err(KvError::Moved(slot, to)) => {
+                    debug!("Slot {} is moved to {}", slot, to);
+                    self.set_node(slot, &to);
+                    addr = to;
+                    asking = false;
+                }
+                Err(KvError::Ask(_, to)) => {
+                    addr = to;
+                    asking = true;
+                }
+                Err(KvError::TryAgain(_)) => {
+                    time::sleep(TRY_AGAIN_DELAY).await;
+                    addr = self.owner(slot)?;
+                    asking = false;
+                }
+                res => return res,
+            }
+        }
+        Err(KvError::Internal(format!(
+            "Too many redirects for slot {}",
+            slot
+        )))
+    }
+
+    // 按 slot 拆分，每个 slot 的 values 按原来的下标放回去
+    async fn fan_out<T>(
+        &self,
+        table: &str,
+        items: Vec<T>,
+        key: impl Fn(&T) -> &String,
+        build: impl Fn(String, Vec<T>) -> RequestData,
+    ) -> Result<CommandResponse, KvError> {
+        let mut shards: HashMap<u32, (Vec<usize>, Vec<T>)> = HashMap::new();
+        for (i, item) in items.into_iter().enumerate() {
+            let shard = shards.entry(key_slot(table, key(&item))).or_default();
+            shard.0.push(i);
+            shard.1.push(item);
+        }
+        let (indexes, futures): (Vec<_>, Vec<_>) = shards
+            .into_iter()
+            .map(|(slot, (indexes, items))| (indexes, self.route(slot, build(table.into(), items))))
+            .unzip();
+
+        merge_values(indexes, future::try_join_all(futures).await?)
+    }
+
+    // 发给缓存中所有的节点
+    async fn execute_nodes(&self, data: RequestData) -> Result<Vec<CommandResponse>, KvError> {
+        let mut pools = Vec::new();
+        for addr in self.nodes() {
+            pools.push(self.pool(&addr).await?);
+        }
+        execute_all(pools.iter().map(|pool| (pool, data.clone()))).await
+    }
+
+    async fn call(&self, addr: &str, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.pool(addr).await?.execute(cmd).await
+    }
+
+    // 管理命令发到节点的管理端口，没有配置管理端口时返回错误
+    async fn admin_call(
+        &self,
+        addr: &str,
+        cmd: CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        let connector = self.admins.get(addr).ok_or_else(|| {
+            KvError::InvalidCommand(format!("No admin address for node {}", addr))
+        })?;
+        connector.connect().await?.execute(cmd).await
+    }
+
+    async fn pool(&self, addr: &str) -> Result<KvPool, KvError> {
+        let mut pools = self.state.pools.lock().await;
+        if let Some(pool) = pools.get(addr) {
+            return Ok(pool.clone());
+        }
+        let mut connector = KvConnector::new(addr);
+        if let Some(tls) = &self.tls {
+            connector = connector.with_tls(tls.clone());
+        }
+        let pool = KvPool::new(connector, self.config).await?;
+        pools.insert(addr.into(), pool.clone());
+        Ok(pool)
+    }
+
+    fn owner(&self, slot: u32) -> Result<String, KvError> {
+        self.node(slot)
+            .ok_or_else(|| KvError::Internal(format!("Slot {} is not assigned", slot)))
+    }
+
+    fn nodes(&self) -> BTreeSet<String> {
+        let slots = self.state.slots.read().unwrap();
+        slots
+            .iter()
+            .flatten()
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+
+    fn set_node(&self, slot: u32, addr: &str) {
+        let mut slots = self.state.slots.write().unwrap();
+        if let Some(node) = slots.get_mut(slot as usize) {
+            *node = Some(addr.into());
+        }
+    }
+
+    fn update(&self, ranges: &[SlotRange]) {
+        let mut slots = self.state.slots.write().unwrap();
+        slots.iter_mut().for_each(|slot| *slot = None);
+        for range in ranges {
+            let addr: Arc<str> = range.addr.as_str().into();
+            for slot in range.start..=range.end.min(SLOTS - 1) {
+                slots[slot as usize] = Some(addr.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{MemTable, ProstServerStream, ServiceInner, Value};
+
+    // 返回每个节点的地址和管理端口的地址
+    async fn start_cluster(n: usize) -> Result<(Vec<String>, Vec<String>)> {
+        let mut listeners = Vec::new();
+        let mut addrs = Vec::new();
+        let mut admins = Vec::new();
+        for _ in 0..n {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let admin = TcpListener::bind("127.0.0.1:0").await?;
+            addrs.push(listener.local_addr()?.to_string());
+            admins.push(admin.local_addr()?.to_string());
+            listeners.push((listener, admin));
+        }
+        // slot 平均分给所有节点
+        let step = SLOTS / n as u32;
+        let ranges: Vec<_> = addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| SlotRange {
+                start: i as u32 * step,
+                end: if i == n - 1 {
+                    SLOTS - 1
+                } else {
+                    (i as u32 + 1) * step - 1
+                },
+                addr: addr.clone(),
+            })
+            .collect();
+
+        for ((listener, admin), addr) in listeners.into_iter().zip(&addrs) {
+            let service: Service = ServiceInner::new(MemTable::new())
+                .with_cluster(addr, &ranges)
+                .into();
+            let migrator = SlotMigrator::new(service.clone());
+            let svc = service.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(ProstServerStream::new(stream, svc.clone()).process());
+                }
+            });
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = admin.accept().await.unwrap();
+                    let stream = ProstServerStream::new(stream, service.clone())
+                        .with_admin()
+                        .with_migrator(migrator.clone());
+                    tokio::spawn(stream.process());
+                }
+            });
+        }
+        Ok((addrs, admins))
+    }
+
+    fn keys_in_slot(slot: u32, n: usize) -> Vec<String> {
+        (0..)
+            .map(|i| format!("key{}", i))
+            .filter(|k| key_slot("t1", k) == slot)
+            .take(n)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cluster_client_should_route_by_slot() -> Result<()> {
+        let (addrs, _) = start_cluster(2).await?;
+        let client = ClusterClient::new([addrs[0].clone()]);
+
+        let keys: Vec<_> = (0..50).map(|i| format!("k{}", i)).collect();
+        let pairs = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| Kvpair::new(k, Value::from(i as i64)))
+            .collect();
+        client
+            .execute(CommandRequest::new_hmset("t1", pairs))
+            .await?;
+        assert_eq!(client.slots().len(), 2);
+
+        let res = client
+            .execute(CommandRequest::new_hmget("t1", keys.clone()))
+            .await?;
+        let values: Vec<_> = (0..50).map(|i| Value::from(i as i64)).collect();
+        assert_eq!(res.values, values);
+        let res = client.execute(CommandRequest::new_hgetall("t1")).await?;
+        assert_eq!(res.pairs.len(), 50);
+
+        // 直接发给不负责这个 slot 的节点会收到 MOVED
+        let key = keys_in_slot(SLOTS - 1, 1).remove(0);
+        let mut raw = KvConnector::new(&addrs[0]).connect().await?;
+        let res = raw.execute(CommandRequest::new_hget("t1", &key)).await;
+        assert!(
+            matches!(res, Err(KvError::Moved(slot, addr)) if slot == SLOTS - 1 && addr == addrs[1])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_slot_should_not_block_clients() -> Result<()> {
+        let (addrs, admins) = start_cluster(2).await?;
+        let client = addrs.iter().zip(&admins).fold(
+            ClusterClient::new([addrs[0].clone()]),
+            |c, (addr, admin)| c.with_admin(addr, KvConnector::new(admin)),
+        );
+        // 另一个 client 的缓存在迁移之后过期
+        let stale = ClusterClient::new([addrs[1].clone()]);
+        stale.refresh().await?;
+
+        let slot = 7;
+        let keys = keys_in_slot(slot, 300);
+        for (i, key) in keys.iter().enumerate() {
+            let cmd = CommandRequest::new_hset("t1", key, Value::from(i as i64));
+            client.execute(cmd).await?;
+        }
+
+        // 迁移过程中持续读写这个 slot 的 key
+        let writer = client.clone();
+        let writer_keys = keys.clone();
+        let handle = tokio::spawn(async move {
+            for round in 0..3 {
+                for (i, key) in writer_keys.iter().enumerate() {
+                    let res = writer.execute(CommandRequest::new_hget("t1", key)).await?;
+                    assert!(!res.values.is_empty());
+                    let value = Value::from((round * 1000 + i) as i64);
+                    writer
+                        .execute(CommandRequest::new_hset("t1", key, value))
+                        .await?;
+                }
+            }
+            Ok::<_, KvError>(())
+        });
+
+        let moved = client.migrate_slot(slot, &addrs[1]).await?;
+        assert!(moved <= keys.len());
+        handle.await??;
+        assert_eq!(client.node(slot).unwrap(), addrs[1]);
+
+        // 过期的缓存收到 MOVED 后更新
+        assert_eq!(stale.node(slot).unwrap(), addrs[0]);
+        let res = stale
+            .execute(CommandRequest::new_hmget("t1", keys.clone()))
+            .await?;
+        let values: Vec<_> = (0..keys.len())
+            .map(|i| Value::from((2000 + i) as i64))
+            .collect();
+        assert_eq!(res.values, values);
+        assert_eq!(stale.node(slot).unwrap(), addrs[1]);
+
+        // 源节点上已经没有这个 slot 的数据
+        let source = ClusterClient::new([addrs[0].clone()]);
+        let res = source.execute(CommandRequest::new_hgetall("t1")).await?;
+        assert_eq!(res.pairs.len(), keys.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cluster_admin_commands_should_require_admin_connection() -> Result<()> {
+        let (addrs, admins) = start_cluster(2).await?;
+        let mut client = KvConnector::new(&addrs[0]).connect().await?;
+        let cmd = CommandRequest::new_cluster_set_slot(7, SlotState::Stable, &addrs[1]);
+        let res = client.execute(cmd.clone()).await;
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+        let res = client
+            .execute(CommandRequest::new_migrate_slot(7, &addrs[1]))
+            .await;
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+
+        // 没有配置管理端口时不能迁移
+        let cluster = ClusterClient::new([addrs[0].clone()]);
+        assert!(cluster.migrate_slot(7, &addrs[1]).await.is_err());
+
+        let mut admin = KvConnector::new(&admins[0]).connect().await?;
+        admin.execute(cmd).await?;
+        let cluster = ClusterClient::new([addrs[0].clone()]);
+        cluster.refresh().await?;
+        assert_eq!(cluster.node(7).unwrap(), addrs[1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use std::pin::Pin;
+
+use futures::{stream, Stream};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::{
+    command_request::RequestData, kv_service_server::KvService, CommandRequest, CommandResponse,
+    ErrorDetail, ErrorKind, Hdel, Hexist, Hget, Hgetall, Hmdel, Hmexist, Hmget, Hmset, Hset,
+    KvError, Kvpair, Scan, Service, Watch, WatchEvent,
+};
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + Sync>>;
+
+// scan 最多缓存的 kv pair，客户端读得慢时读取数据的线程会等待
+const SCAN_BUFFER: usize = 64;
+
+/// gRPC 接口的实现，所有请求都交给和自定义 frame 协议共享的 Service 执行
+///
+/// 命令的执行结果（包括 404 之类的错误）放在 CommandResponse 里，
+/// stream 的错误通过 gRPC 的 Status 返回，状态码按照错误的类型选择
+#[derive(Clone)]
+pub struct GrpcService {
+    service: Service,
+}
+
+impl GrpcService {
+    pub fn new(service: Service) -> Self {
+        Self { service }
+    }
+
+    fn execute(&self, data: RequestData) -> CommandResponse {
+        let cmd = CommandRequest {
+            request_data: Some(data),
+        };
+        info!("process gRPC cmd: {:?}", cmd);
+        self.service.execute(cmd)
+    }
+}
+
+#[tonic::async_trait]
+impl KvService for GrpcService {
+    async fn hget(&self, req: Request<Hget>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hget(req.into_inner())),
+        ))
+    }
+
+    async fn hgetall(&self, req: Request<Hgetall>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hgetall(req.into_inner())),
+        ))
+    }
+
+    async fn hmget(&self, req: Request<Hmget>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hmget(req.into_inner())),
+        ))
+    }
+
+    async fn hset(&self, req: Request<Hset>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hset(req.into_inner())),
+        ))
+    }
+
+    async fn hmset(&self, req: Request<Hmset>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hmset(req.into_inner())),
+        ))
+    }
+
+    async fn hdel(&self, req: Request<Hdel>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hdel(req.into_inner())),
+        ))
+    }
+
+    async fn hmdel(&self, req: Request<Hmdel>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hmdel(req.into_inner())),
+        ))
+    }
+
+    async fn hexist(&self, req: Request<Hexist>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hexist(req.into_inner())),
+        ))
+    }
+
+    async fn hmexist(&self, req: Request<Hmexist>) -> Result<Response<CommandResponse>, Status> {
+        Ok(Response::new(
+            self.execute(RequestData::Hmexist(req.into_inner())),
+        ))
+    }
+
+    type ScanStream = ResponseStream<Kvpair>;
+
+    // 在阻塞线程里逐个读出数据，通过有界的 channel 交给 gRPC 的 stream，
+    // 内存中不会有整个 table 的数据
+    async fn scan(&self, req: Request<Scan>) -> Result<Response<Self::ScanStream>, Status> {
+        let Scan { table, prefix } = req.into_inner();
+        info!("process gRPC scan: {}/{}", table, prefix);
+        let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+        let service = self.service.clone();
+        tokio::task::spawn_blocking(move || {
+            let pairs = match service.scan(&table, prefix) {
+                Ok(pairs) => pairs,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(error_status(&e)));
+                    return;
+                }
+            };
+            for pair in pairs {
+                // 客户端已经断开
+                if tx.blocking_send(Ok(pair)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let pairs = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|pair| (pair, rx))
+        });
+        Ok(Response::new(Box::pin(pairs)))
+    }
+
+    type WatchStream = ResponseStream<WatchEvent>;
+
+    async fn watch(&self, req: Request<Watch>) -> Result<Response<Self::WatchStream>, Status> {
+        let watch = req.into_inner();
+        let rx = self.service.watch();
+        let events = stream::unfold(Some(rx), move |rx| {
+            let watch = watch.clone();
+            async move {
+                let mut rx = rx?;
+                loop {
+                    match rx.recv().await {
+                        Ok(event) if watch.matches(&event) => return Some((Ok(event), Some(rx))),
+                        Ok(_) => continue,
+                        // 丢失了通知，客户端需要重新读取数据后再 watch，所以直接结束 stream
+                        Err(RecvError::Lagged(n)) => {
+                            warn!("Watcher lagged behind, {} events dropped", n);
+                            let status = Status::data_loss(format!("{} events dropped", n));
+                            return Some((Err(status), None));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+// 按照错误的类型选择 gRPC 的状态码
+fn error_status(e: &KvError) -> Status {
+    let message = e.to_string();
+    match ErrorKind::from_i32(ErrorDetail::from(e).kind).unwrap_or_default() {
+        ErrorKind::NotFound => Status::not_found(message),
+        ErrorKind::InvalidCommand | ErrorKind::InvalidFrame | ErrorKind::InvalidBackup => {
+            Status::invalid_argument(message)
+        }
+        ErrorKind::InvalidOffset => Status::out_of_range(message),
+        ErrorKind::PermissionDenied => Status::permission_denied(message),
+        ErrorKind::UnsupportedCommand | ErrorKind::UnsupportedVersion => {
+            Status::unimplemented(message)
+        }
+        ErrorKind::HandshakeFailed | ErrorKind::Moved | ErrorKind::Ask => {
+            Status::failed_precondition(message)
+        }
+        ErrorKind::OutOfMemory => Status::resource_exhausted(message),
+        ErrorKind::NoLeader | ErrorKind::TryAgain => Status::unavailable(message),
+        ErrorKind::WatchLagged => Status::data_loss(message),
+        ErrorKind::Unknown => Status::internal(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv_service_client::KvServiceClient, kv_service_server::KvServiceServer, MemTable,
+        ServiceInner, Value,
+    };
+    use futures::StreamExt;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{
+        transport::{Channel, Server},
+        Code,
+    };
+
+    async fn start_server() -> anyhow::Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(KvServiceServer::new(GrpcService::new(service)))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .unwrap();
+        });
+        Ok(addr)
+    }
+
+    async fn client(addr: SocketAddr) -> anyhow::Result<KvServiceClient<Channel>> {
+        Ok(KvServiceClient::connect(format!("http://{}", addr)).await?)
+    }
+
+    fn hset(table: &str, key: &str, value: Value) -> Hset {
+        Hset {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value)),
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_unary_should_work() -> anyhow::Result<()> {
+        let mut client = client(start_server().await?).await?;
+        client.hset(hset("t1", "k1", "v1".into())).await?;
+
+        let req = Hget {
+            table: "t1".into(),
+            key: "k1".into(),
+        };
+        let res = client.hget(req).await?.into_inner();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        // 命令执行的错误放在 CommandResponse 里
+        let req = Hget {
+            table: "t1".into(),
+            key: "k2".into(),
+        };
+        let res = client.hget(req).await?.into_inner();
+        assert_eq!(res.status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_scan_should_work() -> anyhow::Result<()> {
+        let mut client = client(start_server().await?).await?;
+        for key in ["a1", "a2", "b1"] {
+            client.hset(hset("t1", key, 1.into())).await?;
+        }
+
+        let req = Scan {
+            table: "t1".into(),
+            prefix: "a".into(),
+        };
+        let stream = client.scan(req).await?.into_inner();
+        let mut keys: Vec<_> = stream.map(|pair| pair.unwrap().key).collect().await;
+        keys.sort();
+        assert_eq!(keys, vec!["a1", "a2"]);
+
+        // 超过 channel 容量的数据也能全部读到
+        let pairs = (0..SCAN_BUFFER * 3)
+            .map(|i| Kvpair::new(format!("c{}", i), (i as i64).into()))
+            .collect();
+        let req = Hmset {
+            table: "t1".into(),
+            pairs,
+        };
+        client.hmset(req).await?;
+        let req = Scan {
+            table: "t1".into(),
+            prefix: "c".into(),
+        };
+        let stream = client.scan(req).await?.into_inner();
+        assert_eq!(stream.count().await, SCAN_BUFFER * 3);
+        Ok(())
+    }
+
+    #[test]
+    fn error_status_should_follow_error_kind() {
+        let cases = [
+            (KvError::NotFound("t1".into(), "k1".into()), Code::NotFound),
+            (KvError::InvalidCommand("bad".into()), Code::InvalidArgument),
+            (
+                KvError::PermissionDenied("no".into()),
+                Code::PermissionDenied,
+            ),
+            (KvError::NoLeader, Code::Unavailable),
+            (KvError::Internal("oops".into()), Code::Internal),
+        ];
+        for (e, code) in cases {
+            let status = error_status(&e);
+            assert_eq!(status.code(), code);
+            assert_eq!(status.message(), e.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_watch_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let mut client = client(addr).await?;
+        let req = Watch {
+            table: "t1".into(),
+            prefix: "a".into(),
+        };
+        let mut stream = client.watch(req).await?.into_inner();
+
+        let mut writer = self::client(addr).await?;
+        writer.hset(hset("t2", "a1", 1.into())).await?;
+        writer.hset(hset("t1", "b1", 1.into())).await?;
+        writer.hset(hset("t1", "a1", 2.into())).await?;
+        let req = Hmdel {
+            table: "t1".into(),
+            keys: vec!["a0".into(), "a1".into()],
+        };
+        writer.hmdel(req).await?;
+
+        let event = stream.next().await.unwrap()?;
+        assert_eq!(event, WatchEvent::new("t1", "a1", 2.into()));
+        // 删除不存在的 a0 不会有通知
+        let event = stream.next().await.unwrap()?;
+        assert_eq!(event, WatchEvent::deleted("t1", "a1"));
+        Ok(())
+    }
+}
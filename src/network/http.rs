@@ -0,0 +1,273 @@
+use std::convert::Infallible;
+
+use bytes::{Bytes, BytesMut};
+use hyper::{
+    body::HttpBody,
+    header::{ALLOW, CONTENT_TYPE},
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::{CommandRequest, CommandResponse, KvError, Service, Value, DEFAULT_MAX_FRAME};
+
+/// 把 HTTP/JSON 请求翻译成 CommandRequest，交给和二进制协议共享的 Service 执行
+///
+/// 支持的路由：
+/// - `GET /tables/{t}`：返回整个 table，格式为 `{"key": value, ...}`
+/// - `GET /tables/{t}/keys/{k}`：返回 value
+/// - `PUT /tables/{t}/keys/{k}`：body 是 JSON 格式的 value，返回之前的 value
+/// - `DELETE /tables/{t}/keys/{k}`：返回删除的 value
+///
+/// HTTP 状态码就是 CommandResponse 的 status，出错时 body 为 `{"error": message}`
+#[derive(Clone)]
+pub struct HttpGateway {
+    service: Service,
+    max_body: usize,
+}
+
+// 请求的资源
+#[derive(Debug, PartialEq)]
+enum Route {
+    Table(String),
+    Key(String, String),
+}
+
+impl HttpGateway {
+    pub fn new(service: Service) -> Self {
+        Self {
+            service,
+            max_body: DEFAULT_MAX_FRAME,
+        }
+    }
+
+    /// PUT 请求 body 的最大长度
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// 在 listener 上处理 HTTP 请求，直到出错
+    pub async fn serve(self, listener: TcpListener) -> Result<(), KvError> {
+        info!("Start HTTP gateway on {}", listener.local_addr()?);
+        let incoming = AddrIncoming::from_listener(listener)?;
+        let make_svc = make_service_fn(move |_| {
+            let gateway = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let gateway = gateway.clone();
+                    async move { Ok::<_, Infallible>(gateway.handle(req).await) }
+                }))
+            }
+        });
+        Server::builder(incoming).serve(make_svc).await?;
+        Ok(())
+    }
+
+    /// 处理单个 HTTP 请求
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let route = match parse_route(req.uri().path()) {
+            Ok(Some(route)) => route,
+            Ok(None) => return error_response(StatusCode::NOT_FOUND, "No such route"),
+            Err(e) => return json_response((&e).into()),
+        };
+
+        let cmd = match (req.method(), route) {
+            (&Method::GET, Route::Table(table)) => CommandRequest::new_hgetall(table),
+            (&Method::GET, Route::Key(table, key)) => CommandRequest::new_hget(table, key),
+            (&Method::PUT, Route::Key(table, key)) => {
+                match self.read_value(req.into_body()).await {
+                    Ok(value) => CommandRequest::new_hset(table, key, value),
+                    Err(e) => return json_response((&e).into()),
+                }
+            }
+            (&Method::DELETE, Route::Key(table, key)) => CommandRequest::new_hdel(table, key),
+            (_, route) => {
+                let allow = match route {
+                    Route::Table(_) => "GET",
+                    Route::Key(_, _) => "GET, PUT, DELETE",
+                };
+                let mut res = error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+                res.headers_mut().insert(ALLOW, allow.parse().unwrap());
+                return res;
+            }
+        };
+
+        info!("process HTTP cmd: {:?}", cmd);
+        json_response(self.service.execute(cmd))
+    }
+
+    async fn read_value(&self, mut body: Body) -> Result<Value, KvError> {
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if buf.len() + chunk.len() > self.max_body {
+                return Err(KvError::FrameTooLarge(
+                    buf.len() + chunk.len(),
+                    self.max_body,
+                ));
+            }
+            buf.extend_from_slice(&chunk);
+        }
+
+        let json: serde_json::Value = serde_json::from_slice(&buf)
+            .map_err(|e| KvError::InvalidCommand(format!("Invalid JSON: {}", e)))?;
+        json.try_into()
+    }
+}
+
+// 解析 /tables/{t} 和 /tables/{t}/keys/{k}，table 和 key 可以是 percent-encoded
+fn parse_route(path: &str) -> Result<Option<Route>, KvError> {
+    let segments = path
+        .trim_matches('/')
+        .split('/')
+        .map(|s| {
+            percent_decode_str(s)
+                .decode_utf8()
+                .map(|s| s.into_owned())
+                .map_err(|_| KvError::InvalidCommand(format!("Invalid path segment: {}", s)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let route = match segments.as_slice() {
+        [tables, t] if tables == "tables" && !t.is_empty() => Some(Route::Table(t.clone())),
+        [tables, t, keys, k] if tables == "tables" && keys == "keys" && !t.is_empty() => {
+            Some(Route::Key(t.clone(), k.clone()))
+        }
+        _ => None,
+    };
+    Ok(route)
+}
+
+// 成功时 body 是 value 或者整个 table，失败时是错误信息
+fn json_response(res: CommandResponse) -> Response<Body> {
+    let status = StatusCode::from_u16(res.status as _).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    if !status.is_success() {
+        return error_response(status, &res.message);
+    }
+
+    let body: serde_json::Value = if !res.pairs.is_empty() || res.values.is_empty() {
+        res.pairs
+            .into_iter()
+            .map(|p| (p.key, p.value.unwrap_or_default().into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    } else {
+        res.values.into_iter().next().unwrap_or_default().into()
+    };
+    build_response(status, body)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    build_response(status, serde_json::json!({ "error": message }))
+}
+
+fn build_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ServiceInner};
+    use serde_json::json;
+
+    fn gateway() -> HttpGateway {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        HttpGateway::new(service)
+    }
+
+    async fn call(
+        gw: &HttpGateway,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let body = body.map_or_else(Body::empty, |v| Body::from(v.to_string()));
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body)
+            .unwrap();
+        let res = gw.handle(req).await;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn http_gateway_should_work() {
+        let gw = gateway();
+        let (status, body) = call(&gw, Method::PUT, "/tables/t1/keys/k1", Some(json!(10))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!(null));
+
+        let (_, body) = call(&gw, Method::PUT, "/tables/t1/keys/k1", Some(json!("v1"))).await;
+        assert_eq!(body, json!(10));
+        call(&gw, Method::PUT, "/tables/t1/keys/k%202", Some(json!(1.5))).await;
+
+        let (status, body) = call(&gw, Method::GET, "/tables/t1/keys/k1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!("v1"));
+
+        let (_, body) = call(&gw, Method::GET, "/tables/t1", None).await;
+        assert_eq!(body, json!({"k1": "v1", "k 2": 1.5}));
+
+        let (_, body) = call(&gw, Method::DELETE, "/tables/t1/keys/k1", None).await;
+        assert_eq!(body, json!("v1"));
+        let (status, body) = call(&gw, Method::GET, "/tables/t1/keys/k1", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].as_str().unwrap().contains("k1"));
+    }
+
+    #[tokio::test]
+    async fn http_gateway_should_map_json_values() {
+        let gw = gateway();
+        let values = [
+            json!(true),
+            json!(-3),
+            json!(0.25),
+            json!("hello"),
+            json!({"binary": "AAEC"}),
+        ];
+        for v in values {
+            call(&gw, Method::PUT, "/tables/t1/keys/k", Some(v.clone())).await;
+            let (_, body) = call(&gw, Method::GET, "/tables/t1/keys/k", None).await;
+            assert_eq!(body, v);
+        }
+
+        let v: Value = json!({"binary": "AAEC"}).try_into().unwrap();
+        assert_eq!(v, b"\x00\x01\x02".into());
+    }
+
+    #[tokio::test]
+    async fn http_gateway_should_reject_bad_requests() {
+        let gw = gateway();
+        let (status, _) = call(&gw, Method::GET, "/foo", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&gw, Method::POST, "/tables/t1", None).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let (status, _) = call(&gw, Method::PUT, "/tables/t1/keys/k", Some(json!([1]))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri("/tables/t1/keys/k")
+            .body(Body::from("{not json"))
+            .unwrap();
+        assert_eq!(gw.handle(req).await.status(), StatusCode::BAD_REQUEST);
+
+        let gw = gw.with_max_body(4);
+        let (status, _) = call(&gw, Method::PUT, "/tables/t1/keys/k", Some(json!("hello"))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,401 @@
+use bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::KvError;
+
+/// 命令行的最大长度，memcached 的 key 最长 250 字节，get 可以带很多 key
+const MAX_LINE: usize = 64 * 1024;
+/// key 的最大长度
+const MAX_KEY: usize = 250;
+/// 默认的最大 item 大小，和 memcached 一样是 1 MiB
+pub const DEFAULT_MAX_ITEM: usize = 1024 * 1024;
+
+/// 存储类命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreOp {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas,
+}
+
+/// 解析后的 memcached 命令
+#[derive(Debug, Clone, PartialEq)]
+pub enum McCommand {
+    Get {
+        keys: Vec<String>,
+        with_cas: bool,
+    },
+    Store {
+        op: StoreOp,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        cas: u64,
+        data: Bytes,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Incr {
+        key: String,
+        delta: u64,
+        decr: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: String,
+        exptime: i64,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+    /// 无法执行的命令，内容是要回复给客户端的错误，比如 `CLIENT_ERROR bad command line format`
+    Invalid(String),
+}
+
+/// memcached 文本协议的编解码
+///
+/// 存储类命令的数据块和命令行一起解码；过大的数据块会被跳过，回复错误后连接可以继续使用
+#[derive(Debug)]
+pub struct McCodec {
+    max_item: usize,
+    // 还需要丢弃的数据块的字节数
+    discard: usize,
+    // 数据块格式错误时，丢弃到下一个换行为止
+    skip_line: bool,
+}
+
+impl Default for McCodec {
+    fn default() -> Self {
+        Self {
+            max_item: DEFAULT_MAX_ITEM,
+            discard: 0,
+            skip_line: false,
+        }
+    }
+}
+
+impl McCodec {
+    /// 数据块的最大长度
+    pub fn with_max_item(mut self, max_item: usize) -> Self {
+        self.max_item = max_item;
+        self
+    }
+}
+
+impl Decoder for McCodec {
+    type Item = McCommand;
+    type Error = KvError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.discard > 0 {
+            let n = self.discard.min(buf.len());
+            buf.advance(n);
+            self.discard -= n;
+            if self.discard > 0 {
+                return Ok(None);
+            }
+        }
+        if self.skip_line {
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(eol) => {
+                    buf.advance(eol + 1);
+                    self.skip_line = false;
+                }
+                None => {
+                    buf.clear();
+                    return Ok(None);
+                }
+            }
+        }
+
+        let eol = match buf.iter().position(|&b| b == b'\n') {
+            Some(eol) => eol,
+            None if buf.len() > MAX_LINE => {
+                return Err(KvError::InvalidFrame("Command line is too long".into()))
+            }
+            None => return Ok(None),
+        };
+        let line_len = eol + 1;
+        let line = String::from_utf8_lossy(&buf[..eol]);
+        let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
+
+        let cmd = match tokens.split_first() {
+            Some((&name, args)) => match name {
+                "set" => Some(StoreOp::Set),
+                "add" => Some(StoreOp::Add),
+                "replace" => Some(StoreOp::Replace),
+                "append" => Some(StoreOp::Append),
+                "prepend" => Some(StoreOp::Prepend),
+                "cas" => Some(StoreOp::Cas),
+                _ => None,
+            }
+            .map_or_else(|| Ok(parse_command(name, args)), |op| parse_store(op, args)),
+            None => Ok(McCommand::Invalid("ERROR".into())),
+        };
+
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // 数据块的长度已知，等整个数据块到达后再处理
+            Err(header) => {
+                if header.len > self.max_item {
+                    buf.advance(line_len);
+                    self.discard = header.len + 2;
+                    let msg = "SERVER_ERROR object too large for cache";
+                    return Ok(Some(McCommand::Invalid(msg.into())));
+                }
+
+                let total = line_len + header.len + 2;
+                if buf.len() < total {
+                    buf.reserve(total - buf.len());
+                    return Ok(None);
+                }
+
+                let mut frame = buf.split_to(total);
+                frame.advance(line_len);
+                if &frame[header.len..] != b"\r\n" {
+                    self.skip_line = frame.last() != Some(&b'\n');
+                    return Ok(Some(McCommand::Invalid(
+                        "CLIENT_ERROR bad data chunk".into(),
+                    )));
+                }
+                frame.truncate(header.len);
+                return Ok(Some(header.into_command(frame.freeze())));
+            }
+        };
+
+        buf.advance(line_len);
+        Ok(Some(cmd))
+    }
+}
+
+impl Encoder<Bytes> for McCodec {
+    type Error = KvError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+// 存储类命令的命令行，数据块还没有读取
+struct StoreHeader {
+    op: StoreOp,
+    key: String,
+    flags: u32,
+    exptime: i64,
+    len: usize,
+    cas: u64,
+    noreply: bool,
+}
+
+impl StoreHeader {
+    fn into_command(self, data: Bytes) -> McCommand {
+        McCommand::Store {
+            op: self.op,
+            key: self.key,
+            flags: self.flags,
+            exptime: self.exptime,
+            cas: self.cas,
+            data,
+            noreply: self.noreply,
+        }
+    }
+}
+
+// <cmd> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]
+// 命令行格式正确时返回 Err(header)，表示需要继续读取数据块
+fn parse_store(op: StoreOp, args: &[&str]) -> Result<McCommand, StoreHeader> {
+    let n = if op == StoreOp::Cas { 5 } else { 4 };
+    let (args, noreply) = split_noreply(args);
+    if args.len() != n {
+        return Ok(bad_format());
+    }
+
+    let key = match check_key(args[0]) {
+        Some(key) => key,
+        None => return Ok(bad_format()),
+    };
+    let cas = if op == StoreOp::Cas {
+        args[4].parse().ok()
+    } else {
+        Some(0)
+    };
+    match (args[1].parse(), args[2].parse(), args[3].parse(), cas) {
+        (Ok(flags), Ok(exptime), Ok(len), Some(cas)) => Err(StoreHeader {
+            op,
+            key,
+            flags,
+            exptime,
+            len,
+            cas,
+            noreply,
+        }),
+        _ => Ok(bad_format()),
+    }
+}
+
+fn parse_command(name: &str, args: &[&str]) -> McCommand {
+    let (rest, noreply) = split_noreply(args);
+    let cmd = match (name, args, rest) {
+        ("get" | "gets", [_, ..], _) => args
+            .iter()
+            .map(|k| check_key(k))
+            .collect::<Option<Vec<_>>>()
+            .map(|keys| McCommand::Get {
+                keys,
+                with_cas: name == "gets",
+            }),
+        ("delete", _, [key]) => check_key(key).map(|key| McCommand::Delete { key, noreply }),
+        ("incr" | "decr", _, [key, delta]) => match (check_key(key), delta.parse()) {
+            (Some(key), Ok(delta)) => Some(McCommand::Incr {
+                key,
+                delta,
+                decr: name == "decr",
+                noreply,
+            }),
+            (Some(_), Err(_)) => {
+                let msg = "CLIENT_ERROR invalid numeric delta argument";
+                return McCommand::Invalid(msg.into());
+            }
+            _ => None,
+        },
+        ("touch", _, [key, exptime]) => match (check_key(key), exptime.parse()) {
+            (Some(key), Ok(exptime)) => Some(McCommand::Touch {
+                key,
+                exptime,
+                noreply,
+            }),
+            _ => None,
+        },
+        ("version", [], _) => Some(McCommand::Version),
+        ("quit", [], _) => Some(McCommand::Quit),
+        ("get" | "gets" | "delete" | "incr" | "decr" | "touch" | "version" | "quit", _, _) => None,
+        _ => return McCommand::Invalid("ERROR".into()),
+    };
+    cmd.unwrap_or_else(bad_format)
+}
+
+fn split_noreply<'a, 'b>(args: &'a [&'b str]) -> (&'a [&'b str], bool) {
+    match args.split_last() {
+        Some((&"noreply", rest)) => (rest, true),
+        _ => (args, false),
+    }
+}
+
+// key 不能超过 250 字节，不能有控制字符
+fn check_key(key: &str) -> Option<String> {
+    let valid = key.len() <= MAX_KEY && !key.bytes().any(|b| b.is_ascii_control());
+    valid.then(|| key.to_string())
+}
+
+fn bad_format() -> McCommand {
+    McCommand::Invalid("CLIENT_ERROR bad command line format".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(data: &[u8]) -> Vec<McCommand> {
+        let mut codec = McCodec::default().with_max_item(8);
+        let mut buf = BytesMut::from(data);
+        let mut result = vec![];
+        while let Some(cmd) = codec.decode(&mut buf).unwrap() {
+            result.push(cmd);
+        }
+        result
+    }
+
+    #[test]
+    fn decode_commands_should_work() {
+        let cmds = decode_all(
+            b"get a b\r\ngets a\r\ndelete a noreply\r\nincr a 5\r\ntouch a 10\r\nversion\r\n",
+        );
+        assert_eq!(
+            cmds,
+            vec![
+                McCommand::Get {
+                    keys: vec!["a".into(), "b".into()],
+                    with_cas: false
+                },
+                McCommand::Get {
+                    keys: vec!["a".into()],
+                    with_cas: true
+                },
+                McCommand::Delete {
+                    key: "a".into(),
+                    noreply: true
+                },
+                McCommand::Incr {
+                    key: "a".into(),
+                    delta: 5,
+                    decr: false,
+                    noreply: false
+                },
+                McCommand::Touch {
+                    key: "a".into(),
+                    exptime: 10,
+                    noreply: false
+                },
+                McCommand::Version,
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_store_should_wait_for_data() {
+        let mut codec = McCodec::default();
+        let mut buf = BytesMut::from(&b"cas k 3 100 5 42 noreply\r\nhel"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"lo\r\n");
+        let cmd = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            cmd,
+            McCommand::Store {
+                op: StoreOp::Cas,
+                key: "k".into(),
+                flags: 3,
+                exptime: 100,
+                cas: 42,
+                data: Bytes::from_static(b"hello"),
+                noreply: true,
+            }
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_invalid_commands_should_work() {
+        let cmds = decode_all(b"foo\r\nset k x 0 1\r\nincr k abc\r\nset k 0 0 2\r\nabc\r\n");
+        assert_eq!(
+            cmds,
+            vec![
+                McCommand::Invalid("ERROR".into()),
+                bad_format(),
+                McCommand::Invalid("CLIENT_ERROR invalid numeric delta argument".into()),
+                McCommand::Invalid("CLIENT_ERROR bad data chunk".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn too_large_item_should_be_skipped() {
+        let cmds = decode_all(b"set k 0 0 10\r\n0123456789\r\nget k\r\n");
+        assert_eq!(
+            cmds,
+            vec![
+                McCommand::Invalid("SERVER_ERROR object too large for cache".into()),
+                McCommand::Get {
+                    keys: vec!["k".into()],
+                    with_cas: false
+                },
+            ]
+        );
+    }
+}
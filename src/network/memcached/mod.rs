@@ -0,0 +1,675 @@
+mod codec;
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+use tracing::{info, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{value, CloseReason, CommandRequest, CommandResponse, Service, Value};
+pub use codec::{McCodec, McCommand, StoreOp, DEFAULT_MAX_ITEM};
+
+/// memcached 默认使用的 table
+pub const DEFAULT_MEMCACHED_TABLE: &str = "memcached";
+
+/// 后台清理过期 item 的默认间隔
+pub const DEFAULT_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// 相对过期时间的上限，超过 30 天的 exptime 是 unix 时间戳，和 memcached 一致
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+// key 按 hash 分到这么多把锁上，不同的 key 大多可以并发执行
+const LOCK_STRIPES: usize = 64;
+
+// memcached 写入的 item 的开头，后面是 4 字节 flags、8 字节过期时间、8 字节 version 和数据
+const ITEM_MAGIC: &[u8] = b"\xffMC1";
+const ITEM_HEADER: usize = ITEM_MAGIC.len() + 20;
+
+/// 把 memcached 的 key 映射到 Service 里的一个 table
+///
+/// 每个 item 的数据、flags、过期时间和 cas unique 编码在同一个 Binary 里，用一个命令写入；
+/// 其它协议写入的值也能读到，flags 为 0、永不过期。过期的 key 在下次访问时删除，
+/// 没有访问的 key 由 run_expiry 在后台定期删除。
+/// cas unique 是单调递增的 version，每次修改都会变化，改回原来的内容也不会和旧的相同。
+///
+/// 同一个 key 的命令在同一把锁里执行，add / cas / incr 这样先读后写的命令在 memcached
+/// 客户端之间是原子的；不同的 key 分散在多把锁上
+#[derive(Clone)]
+pub struct MemcachedStore {
+    service: Service,
+    table: String,
+    locks: Arc<Vec<Mutex<()>>>,
+    version: Arc<AtomicU64>,
+}
+
+/// 处理 memcached 客户端的连接
+pub struct MemcachedServerStream<S> {
+    inner: Framed<S, McCodec>,
+    store: MemcachedStore,
+}
+
+// 一个 memcached item
+#[derive(Debug)]
+struct Item {
+    data: Bytes,
+    meta: ItemMeta,
+    // cas unique，其它协议写入的值是 0
+    version: u64,
+    // 其它协议写入的原始值
+    foreign: Option<Value>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ItemMeta {
+    flags: u32,
+    // 过期的 unix 时间戳（秒），None 表示永不过期
+    expires_at: Option<u64>,
+}
+
+type Reply = Result<Bytes, String>;
+
+impl<S> MemcachedServerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S, store: MemcachedStore) -> Self {
+        Self {
+            inner: Framed::new(stream, McCodec::default()),
+            store,
+        }
+    }
+
+    pub async fn process(mut self) -> CloseReason {
+        loop {
+            let cmd = match self.inner.next().await {
+                Some(Ok(cmd)) => cmd,
+                Some(Err(e)) => {
+                    warn!("Failed to decode memcached command: {:?}", e);
+                    return CloseReason::ReadError(e);
+                }
+                None => return CloseReason::ClientClosed,
+            };
+
+            info!("process memcached cmd: {:?}", cmd);
+            if cmd == McCommand::Quit {
+                let _ = self.inner.close().await;
+                return CloseReason::ClientClosed;
+            }
+            if let Some(reply) = self.store.execute(cmd) {
+                if let Err(e) = self.inner.send(reply).await {
+                    return CloseReason::WriteError(e);
+                }
+            }
+        }
+    }
+}
+
+impl MemcachedStore {
+    pub fn new(service: Service) -> Self {
+        // 从当前时间（微秒）开始，重启之后的 version 也比之前的大
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        Self {
+            service,
+            table: DEFAULT_MEMCACHED_TABLE.into(),
+            locks: Arc::new((0..LOCK_STRIPES).map(|_| Mutex::new(())).collect()),
+            version: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    /// 使用的 table
+    pub fn with_table(mut self, table: impl Into<String>) -> Self {
+        self.table = table.into();
+        self
+    }
+
+    /// 执行一个命令，返回要发给客户端的回复，noreply 的命令返回 None
+    pub fn execute(&self, cmd: McCommand) -> Option<Bytes> {
+        let now = now();
+        let (reply, noreply) = match cmd {
+            McCommand::Get { keys, with_cas } => (self.get(&keys, with_cas, now), false),
+            McCommand::Store {
+                op,
+                key,
+                flags,
+                exptime,
+                cas,
+                data,
+                noreply,
+            } => {
+                let meta = ItemMeta {
+                    flags,
+                    expires_at: expires_at(exptime, now),
+                };
+                let _guard = self.lock(&key);
+                (self.store(op, &key, meta, cas, data, now), noreply)
+            }
+            McCommand::Delete { key, noreply } => {
+                let _guard = self.lock(&key);
+                (self.delete(&key, now), noreply)
+            }
+            McCommand::Incr {
+                key,
+                delta,
+                decr,
+                noreply,
+            } => {
+                let _guard = self.lock(&key);
+                (self.incr(&key, delta, decr, now), noreply)
+            }
+            McCommand::Touch {
+                key,
+                exptime,
+                noreply,
+            } => {
+                let _guard = self.lock(&key);
+                (self.touch(&key, expires_at(exptime, now), now), noreply)
+            }
+            McCommand::Version => (
+                Ok(line(&format!("VERSION {}", env!("CARGO_PKG_VERSION")))),
+                false,
+            ),
+            McCommand::Quit => return None,
+            McCommand::Invalid(msg) => (Err(msg), false),
+        };
+
+        match reply {
+            _ if noreply => None,
+            Ok(reply) => Some(reply),
+            Err(msg) => Some(line(&msg)),
+        }
+    }
+
+    /// 删除所有过期的 item，返回删除的数量
+    pub fn remove_expired(&self) -> usize {
+        let now = now();
+        // 扫描 table 时不持有锁，删除之前在锁里重新检查
+        let pairs = match self.service.scan(&self.table, "") {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                warn!("Failed to scan memcached items: {}", e);
+                return 0;
+            }
+        };
+        let expired: Vec<_> = pairs
+            .filter(|pair| {
+                let item = Item::from(pair.value.clone().unwrap_or_default());
+                item.meta.expires_at.is_some_and(|t| t <= now)
+            })
+            .map(|pair| pair.key)
+            .collect();
+
+        let mut removed = 0;
+        for key in expired {
+            let _guard = self.lock(&key);
+            match self.get_item(&key, now) {
+                Ok(None) => removed += 1,
+                Ok(Some(_)) => {}
+                Err(e) => warn!("Failed to remove expired memcached item {}: {}", key, e),
+            }
+        }
+        removed
+    }
+
+    /// 每隔 interval 删除一次过期的 item
+    pub async fn run_expiry(self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let store = self.clone();
+            match tokio::task::spawn_blocking(move || store.remove_expired()).await {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired memcached items", n),
+                Err(e) => warn!("Failed to remove expired memcached items: {:?}", e),
+            }
+        }
+    }
+
+    // 只在读写 Service 的时候持有，不会跨过 await
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let stripe = xxh3_64(key.as_bytes()) as usize % self.locks.len();
+        self.locks[stripe].lock().unwrap()
+    }
+
+    // VALUE <key> <flags> <bytes> [<cas unique>]\r\n<data>\r\n ... END\r\n
+    fn get(&self, keys: &[String], with_cas: bool, now: u64) -> Reply {
+        let mut buf = BytesMut::new();
+        for key in keys {
+            let item = {
+                let _guard = self.lock(key);
+                self.get_item(key, now)?
+            };
+            if let Some(item) = item {
+                let header = if with_cas {
+                    format!(
+                        "VALUE {} {} {} {}\r\n",
+                        key,
+                        item.meta.flags,
+                        item.data.len(),
+                        item.version
+                    )
+                } else {
+                    format!("VALUE {} {} {}\r\n", key, item.meta.flags, item.data.len())
+                };
+                buf.put_slice(header.as_bytes());
+                buf.put_slice(&item.data);
+                buf.put_slice(b"\r\n");
+            }
+        }
+        buf.put_slice(b"END\r\n");
+        Ok(buf.freeze())
+    }
+
+    fn store(
+        &self,
+        op: StoreOp,
+        key: &str,
+        meta: ItemMeta,
+        cas: u64,
+        data: Bytes,
+        now: u64,
+    ) -> Reply {
+        let item = self.get_item(key, now)?;
+        let (data, meta) = match (op, &item) {
+            (StoreOp::Set, _) | (StoreOp::Add, None) | (StoreOp::Replace, Some(_)) => (data, meta),
+            (StoreOp::Cas, None) => return Ok(line("NOT_FOUND")),
+            (StoreOp::Cas, Some(item)) if item.version != cas => return Ok(line("EXISTS")),
+            (StoreOp::Cas, Some(_)) => (data, meta),
+            // append / prepend 忽略 flags 和 exptime
+            (StoreOp::Append, Some(item)) => {
+                ([&item.data[..], &data[..]].concat().into(), item.meta)
+            }
+            (StoreOp::Prepend, Some(item)) => {
+                ([&data[..], &item.data[..]].concat().into(), item.meta)
+            }
+            _ => return Ok(line("NOT_STORED")),
+        };
+
+        let item = Item {
+            data,
+            meta,
+            version: self.next_version(item.as_ref()),
+            foreign: None,
+        };
+        self.set_item(key, item.into())?;
+        Ok(line("STORED"))
+    }
+
+    fn delete(&self, key: &str, now: u64) -> Reply {
+        match self.get_item(key, now)? {
+            Some(_) => {
+                self.remove_item(key)?;
+                Ok(line("DELETED"))
+            }
+            None => Ok(line("NOT_FOUND")),
+        }
+    }
+
+    // memcached 的计数器是 64 位无符号整数：incr 溢出时回绕，decr 最小到 0
+    fn incr(&self, key: &str, delta: u64, decr: bool, now: u64) -> Reply {
+        let item = match self.get_item(key, now)? {
+            Some(item) => item,
+            None => return Ok(line("NOT_FOUND")),
+        };
+
+        let current: u64 = std::str::from_utf8(&item.data)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or("CLIENT_ERROR cannot increment or decrement non-numeric value")?;
+        let n = if decr {
+            current.saturating_sub(delta)
+        } else {
+            current.wrapping_add(delta)
+        };
+
+        // 其它协议写入的整数仍然存成整数，放不进 i64 时存成 item
+        let value = match (&item.foreign, i64::try_from(n)) {
+            (
+                Some(Value {
+                    value: Some(value::Value::Integer(_)),
+                }),
+                Ok(i),
+            ) => i.into(),
+            _ => Item {
+                data: n.to_string().into(),
+                meta: item.meta,
+                version: self.next_version(Some(&item)),
+                foreign: None,
+            }
+            .into(),
+        };
+        self.set_item(key, value)?;
+        Ok(line(&n.to_string()))
+    }
+
+    // 只修改过期时间，cas unique 不变
+    fn touch(&self, key: &str, expires_at: Option<u64>, now: u64) -> Reply {
+        let item = match self.get_item(key, now)? {
+            Some(item) => item,
+            None => return Ok(line("NOT_FOUND")),
+        };
+        let meta = ItemMeta {
+            expires_at,
+            ..item.meta
+        };
+        let version = match item.foreign {
+            Some(_) => self.next_version(None),
+            None => item.version,
+        };
+        let item = Item {
+            meta,
+            version,
+            foreign: None,
+            ..item
+        };
+        self.set_item(key, item.into())?;
+        Ok(line("TOUCHED"))
+    }
+
+    // 读取 item，过期的 item 会被删除
+    fn get_item(&self, key: &str, now: u64) -> Result<Option<Item>, String> {
+        let item = match self.hget(&self.table, key)? {
+            Some(value) => Item::from(value),
+            None => return Ok(None),
+        };
+        if item.meta.expires_at.is_some_and(|t| t <= now) {
+            self.remove_item(key)?;
+            return Ok(None);
+        }
+        Ok(Some(item))
+    }
+
+    // 比当前的 version 和之前发出的 version 都大
+    fn next_version(&self, item: Option<&Item>) -> u64 {
+        let current = item.map(|item| item.version).unwrap_or_default();
+        let next = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+        next.max(current + 1)
+    }
+
+    fn set_item(&self, key: &str, value: Value) -> Result<(), String> {
+        self.execute_cmd(CommandRequest::new_hset(&self.table, key, value))?;
+        Ok(())
+    }
+
+    fn remove_item(&self, key: &str) -> Result<(), String> {
+        self.execute_cmd(CommandRequest::new_hdel(&self.table, key))?;
+        Ok(())
+    }
+
+    fn hget(&self, table: &str, key: &str) -> Result<Option<Value>, String> {
+        let res = self.service.execute(CommandRequest::new_hget(table, key));
+        match res.status {
+            404 => Ok(None),
+            200 => Ok(res.values.into_iter().next()),
+            _ => Err(format!("SERVER_ERROR {}", res.message)),
+        }
+    }
+
+    fn execute_cmd(&self, cmd: CommandRequest) -> Result<CommandResponse, String> {
+        let res = self.service.execute(cmd);
+        if res.status != 200 {
+            return Err(format!("SERVER_ERROR {}", res.message));
+        }
+        Ok(res)
+    }
+}
+
+// item 编码成一个 Binary，数据、flags、过期时间和 version 一起写入
+impl From<Item> for Value {
+    fn from(item: Item) -> Self {
+        let mut buf = BytesMut::with_capacity(ITEM_HEADER + item.data.len());
+        buf.put_slice(ITEM_MAGIC);
+        buf.put_u32(item.meta.flags);
+        buf.put_u64(item.meta.expires_at.unwrap_or_default());
+        buf.put_u64(item.version);
+        buf.put_slice(&item.data);
+        buf.freeze().into()
+    }
+}
+
+impl From<Value> for Item {
+    fn from(value: Value) -> Self {
+        match &value.value {
+            Some(value::Value::Binary(b))
+                if b.len() >= ITEM_HEADER && b.starts_with(ITEM_MAGIC) =>
+            {
+                let mut data = b.slice(ITEM_MAGIC.len()..);
+                let flags = data.get_u32();
+                let expires_at = Some(data.get_u64()).filter(|&t| t != 0);
+                let version = data.get_u64();
+                Self {
+                    data,
+                    meta: ItemMeta { flags, expires_at },
+                    version,
+                    foreign: None,
+                }
+            }
+            _ => Self {
+                data: to_bytes(&value),
+                meta: ItemMeta::default(),
+                version: 0,
+                foreign: Some(value),
+            },
+        }
+    }
+}
+
+// 0 表示永不过期，负数表示已经过期
+fn expires_at(exptime: i64, now: u64) -> Option<u64> {
+    match exptime {
+        0 => None,
+        t if t < 0 => Some(1),
+        t if t <= MAX_RELATIVE_EXPTIME => Some(now + t as u64),
+        t => Some(t as u64),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn line(s: &str) -> Bytes {
+    format!("{}\r\n", s).into()
+}
+
+// 其它协议写入的值转换成文本，数字和 memcached 的计数器一样用十进制表示
+fn to_bytes(v: &Value) -> Bytes {
+    match &v.value {
+        Some(value::Value::String(s)) => Bytes::from(s.clone()),
+        Some(value::Value::Binary(b)) => b.clone(),
+        Some(value::Value::Integer(i)) => i.to_string().into(),
+        Some(value::Value::Float(f)) => f.to_string().into(),
+        Some(value::Value::Bool(b)) => if *b { "1" } else { "0" }.into(),
+        None => Bytes::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ServiceInner};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn store() -> MemcachedStore {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        MemcachedStore::new(service).with_table("mc")
+    }
+
+    fn run(store: &MemcachedStore, input: &[u8]) -> String {
+        let mut codec = McCodec::default();
+        let mut buf = BytesMut::from(input);
+        let mut out = String::new();
+        while let Some(cmd) = tokio_util::codec::Decoder::decode(&mut codec, &mut buf).unwrap() {
+            if let Some(reply) = store.execute(cmd) {
+                out.push_str(&String::from_utf8_lossy(&reply));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn storage_commands_should_work() {
+        let store = store();
+        assert_eq!(run(&store, b"get k\r\n"), "END\r\n");
+        assert_eq!(run(&store, b"set k 5 0 5\r\nhello\r\n"), "STORED\r\n");
+        assert_eq!(run(&store, b"add k 0 0 1\r\nx\r\n"), "NOT_STORED\r\n");
+        assert_eq!(run(&store, b"replace j 0 0 1\r\nx\r\n"), "NOT_STORED\r\n");
+        assert_eq!(run(&store, b"append k 0 0 1\r\n!\r\n"), "STORED\r\n");
+        assert_eq!(run(&store, b"prepend k 0 0 1\r\n>\r\n"), "STORED\r\n");
+        assert_eq!(
+            run(&store, b"get k j\r\n"),
+            "VALUE k 5 7\r\n>hello!\r\nEND\r\n"
+        );
+        assert_eq!(
+            run(&store, b"delete k\r\ndelete k\r\n"),
+            "DELETED\r\nNOT_FOUND\r\n"
+        );
+        assert_eq!(run(&store, b"set k 0 0 1 noreply\r\nx\r\n"), "");
+    }
+
+    #[test]
+    fn cas_should_work() {
+        let store = store();
+        assert_eq!(run(&store, b"cas k 0 0 1 1\r\nx\r\n"), "NOT_FOUND\r\n");
+        run(&store, b"set k 0 0 1\r\nx\r\n");
+        let res = run(&store, b"gets k\r\n");
+        let cas: u64 = res.split_whitespace().nth(4).unwrap().parse().unwrap();
+
+        let cmd = format!("cas k 0 0 1 {}\r\ny\r\n", cas);
+        assert_eq!(run(&store, cmd.as_bytes()), "STORED\r\n");
+        // value 已经变了，同样的 cas unique 会失败
+        assert_eq!(run(&store, cmd.as_bytes()), "EXISTS\r\n");
+
+        // 改回原来的内容，旧的 cas unique 也不能再用
+        let res = run(&store, b"gets k\r\n");
+        let cas: u64 = res.split_whitespace().nth(4).unwrap().parse().unwrap();
+        run(&store, b"set k 0 0 1\r\nz\r\n");
+        run(&store, b"set k 0 0 1\r\ny\r\n");
+        let cmd = format!("cas k 0 0 1 {}\r\nw\r\n", cas);
+        assert_eq!(run(&store, cmd.as_bytes()), "EXISTS\r\n");
+    }
+
+    #[test]
+    fn item_should_be_stored_in_one_record() {
+        let store = store();
+        run(&store, b"set k 3 100 2\r\nhi\r\n");
+        let res = store.service.execute(CommandRequest::new_hgetall("mc"));
+        assert_eq!(res.pairs.len(), 1);
+        let item = Item::from(res.pairs[0].value.clone().unwrap());
+        assert_eq!(item.data, Bytes::from("hi"));
+        assert_eq!(item.meta.flags, 3);
+        assert!(item.version > 0);
+
+        // 其它协议写入的值没有 flags 和过期时间
+        store
+            .service
+            .execute(CommandRequest::new_hset("mc", "s", "abc".into()));
+        assert_eq!(
+            run(&store, b"gets s\r\n"),
+            "VALUE s 0 3 0\r\nabc\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn incr_decr_should_work() {
+        let store = store();
+        assert_eq!(run(&store, b"incr k 1\r\n"), "NOT_FOUND\r\n");
+        run(&store, b"set k 0 0 2\r\n10\r\n");
+        assert_eq!(run(&store, b"incr k 5\r\n"), "15\r\n");
+        assert_eq!(run(&store, b"decr k 100\r\n"), "0\r\n");
+
+        // 其它协议写入的整数
+        store
+            .service
+            .execute(CommandRequest::new_hset("mc", "n", 41.into()));
+        assert_eq!(run(&store, b"incr n 1\r\n"), "42\r\n");
+        let res = store.service.execute(CommandRequest::new_hget("mc", "n"));
+        assert_eq!(res.values, vec![42.into()]);
+
+        run(&store, b"set s 0 0 3\r\nabc\r\n");
+        assert_eq!(
+            run(&store, b"incr s 1\r\n"),
+            "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+        );
+    }
+
+    #[test]
+    fn exptime_should_work() {
+        let store = store();
+        run(&store, b"set k 0 -1 1\r\nx\r\n");
+        assert_eq!(run(&store, b"get k\r\n"), "END\r\n");
+        // 过期的 item 被删掉了
+        let res = store.service.execute(CommandRequest::new_hget("mc", "k"));
+        assert_eq!(res.status, 404);
+
+        run(&store, b"set k 3 100 1\r\nx\r\n");
+        let item = store.hget("mc", "k").unwrap().map(Item::from).unwrap();
+        assert_eq!(item.meta.flags, 3);
+        assert!(item.meta.expires_at.unwrap() >= now() + 99);
+
+        // touch 不改变 cas unique
+        assert_eq!(run(&store, b"touch k 200\r\n"), "TOUCHED\r\n");
+        let touched = store.hget("mc", "k").unwrap().map(Item::from).unwrap();
+        assert_eq!(touched.version, item.version);
+        assert!(touched.meta.expires_at.unwrap() >= now() + 199);
+
+        assert_eq!(run(&store, b"touch k -1\r\n"), "TOUCHED\r\n");
+        assert_eq!(run(&store, b"get k\r\n"), "END\r\n");
+        assert_eq!(expires_at(3_000_000_000, 10), Some(3_000_000_000));
+    }
+
+    #[test]
+    fn remove_expired_should_clean_up_items() {
+        let store = store();
+        run(&store, b"set k1 0 -1 1\r\nx\r\n");
+        run(&store, b"set k2 0 100 1\r\nx\r\n");
+        run(&store, b"set k3 3 -1 1\r\nx\r\n");
+        store
+            .service
+            .execute(CommandRequest::new_hset("mc", "k4", "x".into()));
+
+        assert_eq!(store.remove_expired(), 2);
+        for key in ["k1", "k3"] {
+            assert_eq!(store.hget("mc", key), Ok(None));
+        }
+        for key in ["k2", "k4"] {
+            assert!(store.hget("mc", key).unwrap().is_some());
+        }
+        assert_eq!(store.remove_expired(), 0);
+    }
+
+    #[tokio::test]
+    async fn memcached_stream_should_work() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(MemcachedServerStream::new(server, store()).process());
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(b"set k 0 0 2\r\nhi\r\nget k\r\nversion\r\nquit\r\n")
+            .await?;
+        let mut out = String::new();
+        reader.read_to_string(&mut out).await?;
+        assert_eq!(
+            out,
+            format!(
+                "STORED\r\nVALUE k 0 2\r\nhi\r\nEND\r\nVERSION {}\r\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        Ok(())
+    }
+}
@@ -1,17 +1,64 @@
+mod client;
+mod cluster;
 mod frame;
+mod grpc;
+mod http;
+mod memcached;
 mod multiplex;
+mod pool;
+mod replica;
+mod resp;
+mod shard;
 mod stream;
 mod tls;
+#[cfg(unix)]
+mod unix;
+mod ws;
 
-use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::info;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
-use crate::{CommandRequest, CommandResponse, KvError, Service};
-pub use frame::FrameCoder;
+use futures::{future, SinkExt, Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{info, warn};
+
+use crate::{
+    command_request::RequestData, ChangeEvent, Checksum, CommandRequest, CommandResponse,
+    Compression, Hello, KvError, RaftNode, ReplicationEntry, ReplicationFeed, Service, Value,
+    Watch, WatchEvent,
+};
+pub use client::*;
+pub use cluster::*;
+pub use frame::{
+    FrameCodec, FrameCoder, FrameConfig, COMPRESSION_LIMIT, DEFAULT_MAX_FRAME, SUPPORTED_CHECKSUMS,
+    SUPPORTED_COMPRESSIONS,
+};
+pub use grpc::*;
+pub use http::*;
+pub use memcached::*;
 pub use multiplex::*;
+pub use pool::*;
+pub use replica::*;
+pub use resp::*;
+pub use shard::*;
 pub use stream::*;
 pub use tls::*;
+#[cfg(unix)]
+pub use unix::*;
+pub use ws::*;
+
+// 推送变更记录时每次从日志里读取的条数
+const CHANGE_BATCH: usize = 1000;
+
+/// 当前的协议版本，每次修改协议时增加
+pub const PROTOCOL_VERSION: u32 = 2;
+/// 仍然兼容的最低协议版本，没有 Hello 握手的旧协议相当于版本 1，不再兼容
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
 
 // 处理服务器端某个 accept 下来的 socket 的读写
 // pub struct ProstServerStream<S> {
@@ -22,6 +69,104 @@ pub use tls::*;
 pub struct ProstServerStream<S> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service,
+    stats: Arc<StreamStats>,
+    watcher: Watcher,
+    raft: Option<RaftNode>,
+    // 是否接收其它节点发来的 raft 消息，只有节点之间的连接才可以
+    raft_peer: bool,
+    // 是否可以执行 ClusterSetSlot 和 MigrateSlot 这样修改集群的命令
+    admin: bool,
+    migrator: Option<SlotMigrator>,
+}
+
+// 连接上的 watch，匹配的数据变化会直接推送给客户端
+#[derive(Default)]
+struct Watcher {
+    rx: Option<broadcast::Receiver<WatchEvent>>,
+    watches: Vec<Watch>,
+}
+
+impl Watcher {
+    fn add(&mut self, service: &Service, watch: Watch) {
+        self.rx.get_or_insert_with(|| service.watch());
+        self.watches.push(watch);
+    }
+
+    // 没有 watch 时永远不会返回
+    async fn next_event(&mut self) -> Result<WatchEvent, KvError> {
+        let Self { rx, watches } = self;
+        let receiver = match rx {
+            Some(rx) => rx,
+            None => return future::pending().await,
+        };
+
+        loop {
+            match receiver.recv().await {
+                Ok(event) if watches.iter().any(|w| w.matches(&event)) => return Ok(event),
+                Ok(_) => continue,
+                // 丢失了通知，客户端需要重新读取数据后再 watch
+                Err(RecvError::Lagged(n)) => {
+                    *rx = None;
+                    watches.clear();
+                    return Err(KvError::WatchLagged(n));
+                }
+                Err(RecvError::Closed) => {
+                    *rx = None;
+                    return future::pending().await;
+                }
+            }
+        }
+    }
+}
+
+/// 连接关闭的原因
+#[derive(Debug)]
+pub enum CloseReason {
+    /// 客户端关闭了连接
+    ClientClosed,
+    /// 读取 frame 失败，stream 无法再同步，只能关闭
+    ReadError(KvError),
+    /// 写回 response 失败
+    WriteError(KvError),
+    /// 握手失败，比如客户端没有发送 Hello 或者协议版本不兼容
+    HandshakeFailed(KvError),
+}
+
+/// 服务器端处理 stream 时的错误计数，可以在多个连接之间共享
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    decode_errors: AtomicU64,
+    checksum_errors: AtomicU64,
+    oversized_frames: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+}
+
+impl StreamStats {
+    /// 无法 decode 的 frame 数量
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// 校验和不匹配的 frame 数量
+    pub fn checksum_errors(&self) -> u64 {
+        self.checksum_errors.load(Ordering::Relaxed)
+    }
+
+    /// 超过 max_frame 被拒绝的 frame 数量，包括请求和响应
+    pub fn oversized_frames(&self) -> u64 {
+        self.oversized_frames.load(Ordering::Relaxed)
+    }
+
+    /// 因为读取失败而关闭的连接数量
+    pub fn read_errors(&self) -> u64 {
+        self.read_errors.load(Ordering::Relaxed)
+    }
+
+    /// 因为写入失败而关闭的连接数量
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
 }
 
 // 处理客户端 socket 读写
@@ -31,6 +176,12 @@ pub struct ProstServerStream<S> {
 
 pub struct ProstClientStream<S> {
     inner: ProstStream<S, CommandResponse, CommandRequest>,
+    // 握手时希望使用的压缩算法，按优先级从高到低排列
+    compressions: Vec<Compression>,
+    // 握手时希望使用的校验和算法，为空表示不使用
+    checksums: Vec<Checksum>,
+    // 握手后服务器返回的 Hello
+    server_hello: Option<Hello>,
 }
 
 impl<S> ProstServerStream<S>
@@ -41,19 +192,370 @@ where
         Self {
             inner: ProstStream::new(stream),
             service,
+            stats: Default::default(),
+            watcher: Default::default(),
+            raft: None,
+            raft_peer: false,
+            admin: false,
+            migrator: None,
         }
     }
 
-    pub async fn process(mut self) -> Result<(), KvError> {
-        let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
-            info!("process cmd: {:?}", cmd);
-            let resp = self.service.execute(cmd);
-            stream.send(resp).await.unwrap();
+    /// 使用共享的错误计数
+    pub fn with_stats(mut self, stats: Arc<StreamStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// 使用指定的 frame 参数，比如最大 frame 大小
+    pub fn with_frame_config(mut self, config: FrameConfig) -> Self {
+        self.inner.set_config(config);
+        self
+    }
+
+    /// 命令交给 raft 节点执行，客户端发来的 raft 消息会被拒绝
+    pub fn with_raft(mut self, node: RaftNode) -> Self {
+        self.raft = Some(node);
+        self
+    }
+
+    /// 其它 raft 节点的连接，除了执行命令之外还接收 raft 消息；
+    /// 调用者需要确认对方是集群中的节点，比如只在双向 TLS 认证的端口上使用
+    pub fn with_raft_peer(mut self, node: RaftNode) -> Self {
+        self.raft = Some(node);
+        self.raft_peer = true;
+        self
+    }
+
+    /// 管理连接，可以执行 ClusterSetSlot、MigrateSlot、Backup 和 Restore 这样的管理命令；
+    /// 调用者需要确认对方是管理员，比如只在双向 TLS 认证的端口上使用
+    pub fn with_admin(mut self) -> Self {
+        self.admin = true;
+        self
+    }
+
+    /// 管理连接上的 MigrateSlot 由 migrator 处理，把本节点上的 slot 搬到其它节点
+    pub fn with_migrator(mut self, migrator: SlotMigrator) -> Self {
+        self.migrator = Some(migrator);
+        self
+    }
+
+    pub async fn process(mut self) -> CloseReason {
+        if let Err(reason) = self.handshake().await {
+            return reason;
+        }
+
+        loop {
+            let frame = tokio::select! {
+                frame = self.inner.next() => frame,
+                event = self.watcher.next_event() => {
+                    let resp = match event {
+                        Ok(event) => event.into(),
+                        Err(e) => {
+                            warn!("Stop watching: {:?}", e);
+                            e.into()
+                        }
+                    };
+                    if let Err(reason) = self.send(resp).await {
+                        return reason;
+                    }
+                    continue;
+                }
+            };
+
+            let resp = match frame {
+                Some(Ok(CommandRequest {
+                    request_data: Some(RequestData::Watch(watch)),
+                })) => {
+                    info!("watch: {:?}", watch);
+                    self.watcher.add(&self.service, watch);
+                    CommandResponse::ok()
+                }
+                Some(Ok(CommandRequest {
+                    request_data: Some(RequestData::Replicate(req)),
+                })) => {
+                    info!("replicate: {:?}", req);
+                    match self.service.replicate(&req) {
+                        Ok(feed) => {
+                            if let Err(reason) = self.send(CommandResponse::ok()).await {
+                                return reason;
+                            }
+                            // 之后连接只用来给副本推送复制记录
+                            return self.serve_replica(feed).await;
+                        }
+                        Err(e) => e.into(),
+                    }
+                }
+                Some(Ok(CommandRequest {
+                    request_data: Some(RequestData::Subscribe(req)),
+                })) => {
+                    info!("subscribe: {:?}", req);
+                    match self.service.subscribe(&req) {
+                        Ok((next, receiver)) => {
+                            if let Err(reason) = self.send(CommandResponse::ok()).await {
+                                return reason;
+                            }
+                            // 之后连接只用来推送变更记录
+                            return self.serve_changes(next, receiver).await;
+                        }
+                        Err(e) => e.into(),
+                    }
+                }
+                Some(Ok(CommandRequest {
+                    request_data: Some(RequestData::Raft(msg)),
+                })) if self.raft.is_some() => match (&self.raft, self.raft_peer) {
+                    (Some(node), true) => {
+                        node.step(msg);
+                        CommandResponse::ok()
+                    }
+                    _ => {
+                        warn!("Rejected raft message from a client connection");
+                        let e = KvError::PermissionDenied(
+                            "Raft messages are only accepted from peers".into(),
+                        );
+                        e.into()
+                    }
+                },
+                Some(Ok(CommandRequest {
+                    request_data: Some(RequestData::MigrateSlot(req)),
+                })) if self.admin && self.migrator.is_some() => {
+                    info!("migrate slot: {:?}", req);
+                    let migrator = self.migrator.as_ref().unwrap();
+                    match migrator.migrate(req.slot, &req.addr).await {
+                        Ok(moved) => Value::from(moved as i64).into(),
+                        Err(e) => e.into(),
+                    }
+                }
+                Some(Ok(cmd)) => {
+                    info!("process cmd: {:?}", cmd);
+                    match &self.raft {
+                        Some(node) => node.execute(cmd).await,
+                        None if self.admin => self.service.execute_admin(cmd),
+                        None => self.service.execute(cmd),
+                    }
+                }
+                // frame 已经完整读出，只是内容不对，回复 400 后继续处理下一个 frame
+                Some(Err(e @ KvError::InvalidFrame(_))) => {
+                    warn!("Failed to decode frame: {:?}", e);
+                    self.stats.decode_errors.fetch_add(1, Ordering::Relaxed);
+                    e.into()
+                }
+                // 过大的 frame 已经被丢弃，回复 413 后继续
+                Some(Err(e @ KvError::FrameTooLarge(_, _))) => {
+                    warn!("Rejected frame: {:?}", e);
+                    self.stats.oversized_frames.fetch_add(1, Ordering::Relaxed);
+                    e.into()
+                }
+                // 数据已经损坏，无法确定后面的 frame 是否还能对齐，回复错误后关闭连接
+                Some(Err(e @ KvError::ChecksumMismatch(_, _))) => {
+                    warn!("Corrupted frame: {:?}", e);
+                    self.stats.checksum_errors.fetch_add(1, Ordering::Relaxed);
+                    if let Err(reason) = self.send((&e).into()).await {
+                        return reason;
+                    }
+                    return CloseReason::ReadError(e);
+                }
+                Some(Err(e)) => {
+                    warn!("Failed to read frame: {:?}", e);
+                    self.stats.read_errors.fetch_add(1, Ordering::Relaxed);
+                    return CloseReason::ReadError(e);
+                }
+                None => return CloseReason::ClientClosed,
+            };
+
+            if let Err(reason) = self.send(resp).await {
+                return reason;
+            }
+        }
+    }
+
+    // 给副本推送复制记录，直到副本断开或者落后太多
+    async fn serve_replica(&mut self, feed: ReplicationFeed) -> CloseReason {
+        let ReplicationFeed {
+            initial,
+            mut receiver,
+        } = feed;
+        for entry in initial {
+            if let Err(reason) = self.send(entry.into()).await {
+                return reason;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                frame = self.inner.next() => match frame {
+                    // 副本不应该再发送命令，忽略
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        warn!("Failed to read frame: {:?}", e);
+                        self.stats.read_errors.fetch_add(1, Ordering::Relaxed);
+                        return CloseReason::ReadError(e);
+                    }
+                    None => return CloseReason::ClientClosed,
+                },
+                entry = receiver.recv() => match entry {
+                    Ok(entry) => {
+                        if let Err(reason) = self.send(entry.into()).await {
+                            return reason;
+                        }
+                    }
+                    // 副本丢失了写命令，需要重新连接，从 offset 继续或者全量同步
+                    Err(RecvError::Lagged(n)) => {
+                        let e = KvError::WatchLagged(n);
+                        warn!("Replica lagged: {:?}", e);
+                        if let Err(reason) = self.send((&e).into()).await {
+                            return reason;
+                        }
+                        let _ = self.inner.close().await;
+                        return CloseReason::WriteError(e);
+                    }
+                    Err(RecvError::Closed) => return CloseReason::ClientClosed,
+                },
+            }
+        }
+    }
+
+    // 给订阅者推送从 next 开始的变更记录，直到订阅者断开
+    async fn serve_changes(
+        &mut self,
+        mut next: u64,
+        mut receiver: broadcast::Receiver<ChangeEvent>,
+    ) -> CloseReason {
+        if let Err(reason) = self.send_changes(&mut next).await {
+            return reason;
+        }
+
+        loop {
+            tokio::select! {
+                frame = self.inner.next() => match frame {
+                    // 订阅者不应该再发送命令，忽略
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        warn!("Failed to read frame: {:?}", e);
+                        self.stats.read_errors.fetch_add(1, Ordering::Relaxed);
+                        return CloseReason::ReadError(e);
+                    }
+                    None => return CloseReason::ClientClosed,
+                },
+                change = receiver.recv() => match change {
+                    // 已经从日志里读出来发送过了
+                    Ok(change) if change.seq < next => continue,
+                    Ok(change) => {
+                        next = change.seq + 1;
+                        if let Err(reason) = self.send(change.into()).await {
+                            return reason;
+                        }
+                    }
+                    // 来不及推送的记录还在日志里，重新读取
+                    Err(RecvError::Lagged(_)) => {
+                        if let Err(reason) = self.send_changes(&mut next).await {
+                            return reason;
+                        }
+                    }
+                    Err(RecvError::Closed) => return CloseReason::ClientClosed,
+                },
+            }
+        }
+    }
+
+    // 从日志里读取并发送 next 之后已有的记录，读取失败时发送错误并关闭连接
+    async fn send_changes(&mut self, next: &mut u64) -> Result<(), CloseReason> {
+        loop {
+            let changes = match self.service.changes(*next, CHANGE_BATCH) {
+                Ok(changes) if changes.is_empty() => return Ok(()),
+                Ok(changes) => changes,
+                Err(e) => {
+                    warn!("Failed to read change log: {:?}", e);
+                    self.send((&e).into()).await?;
+                    let _ = self.inner.close().await;
+                    return Err(CloseReason::WriteError(e));
+                }
+            };
+            for change in changes {
+                *next = change.seq + 1;
+                self.send(change.into()).await?;
+            }
+        }
+    }
+
+    // 连接的第一个 frame 必须是 Hello，协商协议版本、压缩算法和最大 frame
+    async fn handshake(&mut self) -> Result<(), CloseReason> {
+        let hello = match self.inner.next().await {
+            Some(Ok(CommandRequest {
+                request_data: Some(RequestData::Hello(hello)),
+            })) => hello,
+            Some(Ok(_)) => {
+                let e = KvError::HandshakeError("Hello must be the first frame".into());
+                return Err(self.reject(e).await);
+            }
+            Some(Err(e @ KvError::InvalidFrame(_))) | Some(Err(e @ KvError::FrameTooLarge(..))) => {
+                return Err(self.reject(KvError::HandshakeError(e.to_string())).await);
+            }
+            Some(Err(e)) => {
+                warn!("Failed to read frame: {:?}", e);
+                self.stats.read_errors.fetch_add(1, Ordering::Relaxed);
+                return Err(CloseReason::ReadError(e));
+            }
+            None => return Err(CloseReason::ClientClosed),
+        };
+
+        let version = hello.protocol_version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            let e = KvError::UnsupportedVersion(hello.protocol_version);
+            return Err(self.reject(e).await);
+        }
+
+        let compression = hello.select_compression(SUPPORTED_COMPRESSIONS);
+        let checksum = hello.select_checksum(SUPPORTED_CHECKSUMS);
+        let mut config = self
+            .inner
+            .config()
+            .with_compression(compression)
+            .with_checksum(checksum);
+        if hello.max_frame > 0 {
+            config = config.with_max_frame(config.max_frame.min(hello.max_frame as _));
         }
+        info!(
+            "Client {} (protocol {}) connected, use {:?} {:?}",
+            hello.version, version, compression, checksum
+        );
 
+        let mut server_hello =
+            Hello::new(&[compression], config.max_frame).with_checksums(&[checksum]);
+        server_hello.protocol_version = version;
+
+        // 客户端收到 Hello 之后才会使用新的参数，所以回应之后再切换
+        self.send(server_hello.into()).await?;
+        self.inner.set_config(config);
         Ok(())
     }
+
+    // 告诉客户端握手失败的原因，然后关闭连接
+    async fn reject(&mut self, e: KvError) -> CloseReason {
+        warn!("Handshake failed: {:?}", e);
+        if let Err(reason) = self.send((&e).into()).await {
+            return reason;
+        }
+        // 客户端可能不再读取，关闭失败也没有关系
+        let _ = self.inner.close().await;
+        CloseReason::HandshakeFailed(e)
+    }
+
+    async fn send(&mut self, resp: CommandResponse) -> Result<(), CloseReason> {
+        let mut result = self.inner.send(resp).await;
+        // response 太大发不出去，改为告诉客户端出错的原因
+        if let Err(e @ KvError::FrameTooLarge(_, _)) = result {
+            warn!("Response is too large: {:?}", e);
+            self.stats.oversized_frames.fetch_add(1, Ordering::Relaxed);
+            result = self.inner.send(e.into()).await;
+        }
+
+        result.map_err(|e| {
+            warn!("Failed to send response: {:?}", e);
+            self.stats.write_errors.fetch_add(1, Ordering::Relaxed);
+            CloseReason::WriteError(e)
+        })
+    }
 }
 
 impl<S> ProstClientStream<S>
@@ -63,10 +565,156 @@ where
     pub fn new(stream: S) -> Self {
         Self {
             inner: ProstStream::new(stream),
+            compressions: SUPPORTED_COMPRESSIONS.to_vec(),
+            checksums: vec![],
+            server_hello: None,
         }
     }
 
+    /// 使用指定的 frame 参数，比如最大 frame 大小
+    pub fn with_frame_config(mut self, config: FrameConfig) -> Self {
+        self.inner.set_config(config);
+        self
+    }
+
+    /// 握手时希望使用的压缩算法，按优先级从高到低排列
+    pub fn with_compressions(mut self, compressions: &[Compression]) -> Self {
+        self.compressions = compressions.to_vec();
+        self
+    }
+
+    /// 握手时希望使用的校验和算法，按优先级从高到低排列
+    pub fn with_checksums(mut self, checksums: &[Checksum]) -> Self {
+        self.checksums = checksums.to_vec();
+        self
+    }
+
+    /// 和服务器握手，协商协议版本、压缩算法、校验和以及最大 frame
+    ///
+    /// 第一次 execute 时如果还没有握手，会自动进行
+    pub async fn handshake(&mut self) -> Result<&Hello, KvError> {
+        let config = self.inner.config();
+        let hello =
+            Hello::new(&self.compressions, config.max_frame).with_checksums(&self.checksums);
+        let res = self.call(CommandRequest::new_hello(hello)).await?;
+
+        let hello = match res.hello {
+            Some(hello) if res.status == 200 => hello,
+            _ if res.status != 200 => return Err(KvError::HandshakeError(res.message)),
+            _ => return Err(KvError::HandshakeError("Server didn't send Hello".into())),
+        };
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol_version) {
+            return Err(KvError::UnsupportedVersion(hello.protocol_version));
+        }
+
+        let compression = hello.select_compression(&self.compressions);
+        let checksum = hello.select_checksum(&self.checksums);
+        let mut config = config.with_compression(compression).with_checksum(checksum);
+        if hello.max_frame > 0 {
+            config = config.with_max_frame(config.max_frame.min(hello.max_frame as _));
+        }
+        self.inner.set_config(config);
+
+        Ok(self.server_hello.insert(hello))
+    }
+
+    /// 握手后服务器返回的 Hello
+    pub fn server_hello(&self) -> Option<&Hello> {
+        self.server_hello.as_ref()
+    }
+
     pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let hello = match self.server_hello {
+            Some(ref hello) => hello,
+            None => self.handshake().await?,
+        };
+
+        // 服务器不认识的命令直接返回错误，不要发给服务器
+        if let Some(data) = cmd.request_data.as_ref() {
+            if !hello.supports(data) {
+                return Err(KvError::UnsupportedCommand(data.name().into()));
+            }
+        }
+
+        self.call(cmd).await
+    }
+
+    /// 订阅 table 中 key 以 prefix 开头的数据变化
+    ///
+    /// 之后服务器会在这个连接上推送通知，所以 watch 会消耗掉 client
+    pub async fn watch(
+        mut self,
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+    ) -> Result<impl Stream<Item = Result<WatchEvent, KvError>>, KvError> {
+        let res = self
+            .execute(CommandRequest::new_watch(table, prefix))
+            .await?;
+        if res.status != 200 {
+            return Err(KvError::Internal(res.message));
+        }
+
+        Ok(self.inner.filter_map(|res| async move {
+            match res {
+                Ok(CommandResponse {
+                    event: Some(event), ..
+                }) => Some(Ok(event)),
+                Ok(res) if res.status != 200 => Some(Err(KvError::Internal(res.message))),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+
+    /// 从主节点复制数据，id 和 offset 是副本已经同步到的位置，id 为空时全量同步
+    ///
+    /// 之后服务器会在这个连接上推送复制记录，所以 replicate 会消耗掉 client
+    pub async fn replicate(
+        mut self,
+        id: impl Into<String>,
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<ReplicationEntry, KvError>>, KvError> {
+        self.execute(CommandRequest::new_replicate(id, offset))
+            .await?
+            .into_result()?;
+
+        Ok(self.inner.filter_map(|res| async move {
+            match res {
+                Ok(CommandResponse {
+                    entry: Some(entry), ..
+                }) => Some(Ok(entry)),
+                Ok(res) => Some(res.into_result().and(Err(KvError::Internal(
+                    "Unexpected response on replication stream".into(),
+                )))),
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+
+    /// 从 offset 开始订阅变更日志，offset 不在保留的范围内时返回 InvalidOffset
+    pub async fn subscribe(
+        mut self,
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<ChangeEvent, KvError>>, KvError> {
+        self.execute(CommandRequest::new_subscribe(offset))
+            .await?
+            .into_result()?;
+
+        Ok(self.inner.filter_map(|res| async move {
+            match res {
+                Ok(CommandResponse {
+                    change: Some(change),
+                    ..
+                }) => Some(Ok(change)),
+                Ok(res) => Some(res.into_result().and(Err(KvError::Internal(
+                    "Unexpected response on change stream".into(),
+                )))),
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+
+    async fn call(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
         let stream = &mut self.inner;
         stream.send(cmd).await?;
 
@@ -95,8 +743,9 @@ pub mod utils {
             _cx: &mut std::task::Context<'_>,
             buf: &mut tokio::io::ReadBuf<'_>,
         ) -> std::task::Poll<std::io::Result<()>> {
-            let len = buf.capacity();
-            let data = self.get_mut().buf.split_to(len);
+            let this = self.get_mut();
+            let len = buf.remaining().min(this.buf.len());
+            let data = this.buf.split_to(len);
             buf.put_slice(&data);
             Poll::Ready(Ok(()))
         }
@@ -126,16 +775,83 @@ pub mod utils {
             Poll::Ready(Ok(()))
         }
     }
+
+    /// 每次最多返回 chunk 个字节，并且每次读取前先返回一次 Pending
+    /// 用来模拟数据分段到达的网络
+    pub struct ChunkedStream {
+        buf: BytesMut,
+        chunk: usize,
+        ready: bool,
+    }
+
+    impl ChunkedStream {
+        pub fn new(buf: BytesMut, chunk: usize) -> Self {
+            Self {
+                buf,
+                chunk,
+                ready: false,
+            }
+        }
+    }
+
+    impl AsyncRead for ChunkedStream {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            if !this.ready {
+                this.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            this.ready = false;
+            let len = buf.remaining().min(this.chunk).min(this.buf.len());
+            let data = this.buf.split_to(len);
+            buf.put_slice(&data);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for ChunkedStream {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, std::io::Error>> {
+            self.get_mut().buf.put_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
-    use bytes::Bytes;
+    use bytes::{BufMut, Bytes, BytesMut};
     use std::net::SocketAddr;
-    use tokio::net::{TcpListener, TcpStream};
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
 
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{assert_res_ok, utils::DummyStream, MemTable, ServiceInner, Value};
 
     use super::*;
 
@@ -172,7 +888,7 @@ mod tests {
         let mut client = ProstClientStream::new(stream);
 
         let v: Value = Bytes::from(vec![0u8; 16384]).into();
-        let cmd = CommandRequest::new_hset("t2", "k2", v.clone().into());
+        let cmd = CommandRequest::new_hset("t2", "k2", v.clone());
         let res = client.execute(cmd).await?;
 
         assert_res_ok(res, &[Value::default()], &[]);
@@ -180,8 +896,333 @@ mod tests {
         let cmd = CommandRequest::new_hget("t2", "k2");
         let res = client.execute(cmd).await?;
 
-        assert_res_ok(res, &[v.into()], &[]);
+        assert_res_ok(res, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_handshake_should_work() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream)
+            .with_compressions(&[Compression::Lz4, Compression::Gzip])
+            .with_frame_config(FrameConfig::default().with_max_frame(1024 * 1024));
+        let hello = client.handshake().await?;
+        assert_eq!(hello.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(hello.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            hello.compressions().collect::<Vec<_>>(),
+            vec![Compression::Lz4]
+        );
+        assert!(hello.commands.iter().any(|c| c == "hmexist"));
+        // 服务器也会遵守客户端的 max_frame
+        assert_eq!(hello.max_frame, 1024 * 1024);
+        assert_eq!(client.inner.config().compression, Compression::Lz4);
+
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let cmd = CommandRequest::new_hset("t5", "k5", v.clone());
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = client.execute(CommandRequest::new_hget("t5", "k5")).await?;
+        assert_res_ok(res, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_frame_should_get_400_and_keep_connection() -> Result<()> {
+        let addr = start_server().await?;
+
+        // 先发送 Hello，然后是一个长度正确，但内容不是合法 protobuf 的 frame
+        let config = FrameConfig::default();
+        let mut buf = BytesMut::new();
+        let hello = Hello::new(SUPPORTED_COMPRESSIONS, config.max_frame);
+        CommandRequest::new_hello(hello).encode_frame(&mut buf, &config)?;
+        buf.put_u32(4);
+        buf.put_slice(&[0xff; 4]);
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&buf).await?;
+
+        let mut client = ProstClientStream::new(stream);
+        let res = client.inner.next().await.unwrap()?;
+        client.server_hello = res.hello;
+        let res = client.inner.next().await.unwrap()?;
+        assert_eq!(res.status, 400);
+        assert!(res.message.contains("Cannot decode frame"));
+
+        // 连接仍然可用
+        let cmd = CommandRequest::new_hset("t3", "k3", "v3".into());
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_should_get_413_and_keep_connection() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let stats = Arc::new(StreamStats::default());
+        let server_stats = stats.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let server = ProstServerStream::new(stream, service)
+                .with_stats(server_stats)
+                .with_frame_config(FrameConfig::default().with_max_frame(1024));
+            server.process().await;
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let hello = client.handshake().await?;
+        assert_eq!(hello.max_frame, 1024);
+
+        // 握手后客户端也遵守服务器的 max_frame，请求太大在本地就会出错
+        let v: Value = Bytes::from(b"a".repeat(1400)).into();
+        let cmd = CommandRequest::new_hset("t4", "k4", v);
+        let result = client.execute(cmd.clone()).await;
+        assert!(matches!(result, Err(KvError::FrameTooLarge(_, 1024))));
+
+        // 不遵守的客户端，服务器拒绝
+        client.inner.set_config(FrameConfig::default());
+        let res = client.execute(cmd).await?;
+        assert_eq!(res.status, 413);
+
+        // 响应太大，服务器返回错误而不是断开
+        let v: Value = Bytes::from(b"a".repeat(800)).into();
+        client
+            .execute(CommandRequest::new_hset("t4", "k1", v.clone()))
+            .await?;
+        client
+            .execute(CommandRequest::new_hset("t4", "k2", v))
+            .await?;
+        let res = client.execute(CommandRequest::new_hgetall("t4")).await?;
+        assert_eq!(res.status, 413);
+
+        let res = client
+            .execute(CommandRequest::new_hexist("t4", "k1"))
+            .await?;
+        assert_res_ok(res, &[true.into()], &[]);
+        assert_eq!(stats.oversized_frames(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_checksum_should_work() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_checksums(&[Checksum::Xxh3]);
+        let hello = client.handshake().await?;
+        assert_eq!(hello.checksums().collect::<Vec<_>>(), vec![Checksum::Xxh3]);
+        assert_eq!(client.inner.config().checksum, Checksum::Xxh3);
+
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let res = client
+            .execute(CommandRequest::new_hset("t6", "k6", v.clone()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t6", "k6")).await?;
+        assert_res_ok(res, &[v], &[]);
+
+        // 不要求校验和的客户端不受影响
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        client.handshake().await?;
+        assert_eq!(client.inner.config().checksum, Checksum::None);
+        let res = client.execute(CommandRequest::new_hget("t6", "k6")).await?;
+        assert_eq!(res.status, 404);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupted_frame_should_get_error_and_close() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_checksums(&[Checksum::Crc32c]);
+        client.handshake().await?;
+
+        // 故意使用错误的校验和算法，模拟传输中的损坏
+        let config = client.inner.config();
+        client
+            .inner
+            .set_config(config.with_checksum(Checksum::Xxh3));
+        client
+            .inner
+            .send(CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        client.inner.set_config(config);
+
+        let res = client.inner.next().await.unwrap()?;
+        assert_eq!(res.status, 400);
+        assert!(res.message.contains("checksum mismatch"));
+        assert!(client.inner.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_without_hello_should_be_rejected() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let res = client.call(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 426);
+
+        // 服务器随后关闭连接
+        assert!(client.inner.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incompatible_protocol_version_should_be_rejected() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let mut hello = Hello::new(SUPPORTED_COMPRESSIONS, DEFAULT_MAX_FRAME);
+        hello.protocol_version = MIN_PROTOCOL_VERSION - 1;
+        let res = client.call(CommandRequest::new_hello(hello)).await?;
+        assert_eq!(res.status, 505);
+        assert!(res.hello.is_none());
+
+        // 更新的客户端会被降级到服务器支持的版本
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let mut hello = Hello::new(SUPPORTED_COMPRESSIONS, DEFAULT_MAX_FRAME);
+        hello.protocol_version = PROTOCOL_VERSION + 1;
+        let res = client.call(CommandRequest::new_hello(hello)).await?;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.hello.unwrap().protocol_version, PROTOCOL_VERSION);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unsupported_command_should_not_be_sent() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        client.handshake().await?;
+        // 模拟一个只支持 hget 的旧服务器
+        client.server_hello.as_mut().unwrap().commands = vec!["hget".into()];
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let result = client.execute(cmd).await;
+        assert!(matches!(result, Err(KvError::UnsupportedCommand(c)) if c == "hset"));
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn process_should_return_close_reason() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let stats = Arc::new(StreamStats::default());
+        let server = ProstServerStream::new(DummyStream::default(), service.clone())
+            .with_stats(stats.clone());
+        assert!(matches!(server.process().await, CloseReason::ClientClosed));
+
+        // header 声明了 16 字节，实际只有 2 字节，读取失败
+        let mut buf = BytesMut::new();
+        buf.put_u32(16);
+        buf.put_u16(42);
+        let server = ProstServerStream::new(DummyStream { buf }, service).with_stats(stats.clone());
+        assert!(matches!(server.process().await, CloseReason::ReadError(_)));
+        assert_eq!(stats.read_errors(), 1);
+        assert_eq!(stats.decode_errors(), 0);
+    }
+
+    #[tokio::test]
+    async fn backup_should_require_admin_connection() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_backup_dir(dir.path())
+            .into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let svc = service.clone();
+        tokio::spawn(async move {
+            // 第一个连接是普通连接，之后的是管理连接
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(ProstServerStream::new(stream, svc.clone()).process());
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(ProstServerStream::new(stream, svc).with_admin().process());
+        });
+
+        let mut client = crate::KvClient::connect(addr.clone()).await?;
+        client.hset("t1", "k1", 1).await?;
+        let e = client.backup("b1", Compression::None).await.err().unwrap();
+        assert!(matches!(e, KvError::PermissionDenied(_)));
+        let e = client.restore("b1").await.err().unwrap();
+        assert!(matches!(e, KvError::PermissionDenied(_)));
+
+        let mut admin = crate::KvClient::connect(addr).await?;
+        assert_eq!(admin.backup("b1", Compression::None).await?.pairs, 1);
+        assert_eq!(admin.restore("b1").await?.pairs, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribe_should_resume_from_offset() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_change_log(crate::MemChangeStore::new(3))?
+            .into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let svc = service.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, svc.clone()).process());
+            }
+        });
+
+        let mut client = crate::KvClient::connect(addr.clone()).await?;
+        client.hset("t1", "k1", 1).await?;
+        let stream = crate::KvClient::connect(addr.clone()).await?;
+        let mut changes = Box::pin(stream.subscribe(0).await?);
+        client.hset("t1", "k1", 2).await?;
+        client.hdel("t1", "k1").await?;
+        // 删除不存在的 key 不会记录
+        client.hdel("t1", "k1").await?;
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(changes.next().await.unwrap()?);
+        }
+        let seqs: Vec<_> = received.iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        assert_eq!(received[1].old_value, Some(1.into()));
+        assert_eq!(received[1].new_value, Some(2.into()));
+        assert_eq!(received[2].new_value, None);
+        drop(changes);
+
+        // 断开期间的修改在重新订阅后收到
+        client.hset("t1", "k2", 3).await?;
+        let stream = crate::KvClient::connect(addr.clone()).await?;
+        let mut changes = Box::pin(stream.subscribe(4).await?);
+        let change = changes.next().await.unwrap()?;
+        assert_eq!((change.seq, change.key.as_str()), (4, "k2"));
 
+        // 日志只保留最近 3 条
+        let e = crate::KvClient::connect(addr)
+            .await?
+            .subscribe(1)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(e, KvError::InvalidOffset(1, 2, 5)));
         Ok(())
     }
 
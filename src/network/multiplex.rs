@@ -58,7 +58,7 @@ where
 
         Self {
             ctrl,
-            _conn: PhantomData::default(),
+            _conn: PhantomData,
         }
     }
 
@@ -130,7 +130,7 @@ mod tests {
                 let svc = service.clone();
                 async move {
                     let stream = ProstServerStream::new(s.compat(), svc);
-                    stream.process().await.unwrap();
+                    stream.process().await;
                     Ok(())
                 }
             });
@@ -166,12 +166,12 @@ mod tests {
         let mut client = ProstClientStream::new(stream);
 
         let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
-        client.execute_unary(&cmd).await.unwrap();
+        client.execute(cmd).await.unwrap();
 
         let cmd = CommandRequest::new_hget("t1", "k1");
-        let res = client.execute_unary(&cmd).await.unwrap();
-        assert_res_ok(&res, &["v1".into()], &[]);
+        let res = client.execute(cmd).await.unwrap();
+        assert_res_ok(res, &["v1".into()], &[]);
 
         Ok(())
     }
-}
\ No newline at end of file
+}
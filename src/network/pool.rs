@@ -0,0 +1,546 @@
+use std::{
+    future::Future,
+    io,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time,
+};
+use tracing::{info, warn};
+
+use crate::{CommandRequest, CommandResponse, KvClient, KvConnector, KvError};
+
+/// 连接池的参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// 最少保持的连接数，健康检查时会补足
+    pub min_connections: usize,
+    /// 最多同时使用的连接数，超过时 get 会等待
+    pub max_connections: usize,
+    /// 建立连接和重试读命令的最大重试次数
+    pub max_retries: u32,
+    /// 第一次重试前等待的时间，之后每次翻倍
+    pub initial_backoff: Duration,
+    /// 重试前等待的最长时间
+    pub max_backoff: Duration,
+    /// 对空闲连接发送 ping 的间隔
+    pub health_check_interval: Duration,
+    /// 建立连接（包括握手）的超时
+    pub connect_timeout: Duration,
+    /// 等待命令响应的超时
+    pub request_timeout: Duration,
+    /// 健康检查时等待 ping 响应的超时
+    pub ping_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_connections: 1,
+            max_connections: 16,
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            health_check_interval: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            ping_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// 最少和最多的连接数
+    pub fn with_connections(mut self, min: usize, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self.min_connections = min.min(self.max_connections);
+        self
+    }
+
+    /// 最大重试次数
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// 指数退避的初始等待时间和最长等待时间
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// 健康检查的间隔
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// 建立连接、等待命令响应和等待 ping 响应的超时
+    pub fn with_timeouts(mut self, connect: Duration, request: Duration, ping: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.request_timeout = request;
+        self.ping_timeout = ping;
+        self
+    }
+
+    // 第 attempt 次重试前等待的时间
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// 客户端连接池，可以在多个 task 之间 clone
+///
+/// 连接断开时会用指数退避重新连接。通过 `execute` 执行的读命令在连接出错时会自动重试，
+/// 写命令可能已经被服务器执行了，所以不会重试，错误直接返回给调用者。
+/// 超时和连接出错一样处理，卡住的服务器不会一直占着连接
+#[derive(Clone)]
+pub struct KvPool {
+    inner: Arc<PoolInner>,
+}
+
+/// 从连接池取出的连接，drop 时放回连接池
+pub struct PooledClient {
+    client: Option<KvClient>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+struct PoolInner {
+    connector: KvConnector,
+    config: PoolConfig,
+    idle: Mutex<Vec<KvClient>>,
+    permits: Arc<Semaphore>,
+}
+
+impl KvPool {
+    /// 建立最少数量的连接，并启动健康检查
+    pub async fn new(connector: KvConnector, config: PoolConfig) -> Result<Self, KvError> {
+        let inner = Arc::new(PoolInner {
+            connector,
+            config,
+            idle: Mutex::new(Vec::with_capacity(config.max_connections)),
+            permits: Arc::new(Semaphore::new(config.max_connections)),
+        });
+
+        for _ in 0..config.min_connections {
+            let client = inner.connect().await?;
+            inner.put(client);
+        }
+        spawn_health_check(Arc::downgrade(&inner), config.health_check_interval);
+        Ok(Self { inner })
+    }
+
+    /// 取出一个连接，没有空闲连接时新建，连接数达到上限时等待其它连接归还
+    pub async fn get(&self) -> Result<PooledClient, KvError> {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| KvError::Internal("Connection pool is closed".into()))?;
+
+        let client = match self.inner.take() {
+            Some(client) => client,
+            None => self.inner.connect().await?,
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self.inner.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// 执行命令，非 200 的响应转换成 KvError；连接出错时读命令会换一个连接重试
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let retries = match &cmd.request_data {
+            Some(data) if data.is_idempotent() => self.inner.config.max_retries,
+            _ => 0,
+        };
+
+        let mut attempt = 0;
+        loop {
+            let mut client = self.get().await?;
+            let timeout = self.inner.config.request_timeout;
+            let e = match with_timeout(timeout, client.stream_mut().execute(cmd.clone())).await {
+                Ok(res) => return res.into_result(),
+                // 请求本身的问题，换一个连接也一样
+                Err(e @ (KvError::UnsupportedCommand(_) | KvError::FrameTooLarge(_, _))) => {
+                    return Err(e)
+                }
+                Err(e) => e,
+            };
+
+            // 连接出错，通常意味着服务器重启了，其它空闲连接也不能再用
+            client.discard();
+            self.inner.clear();
+            if attempt >= retries {
+                return Err(e);
+            }
+            warn!("Connection failed: {:?}, retry #{}", e, attempt + 1);
+            time::sleep(self.inner.config.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// 空闲的连接数
+    pub fn idle_connections(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+}
+
+impl PooledClient {
+    /// 连接已经不可用，不再放回连接池
+    pub fn discard(mut self) {
+        self.client = None;
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = KvClient;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.put(client);
+        }
+    }
+}
+
+impl PoolInner {
+    // 建立连接，失败时按指数退避重试
+    async fn connect(&self) -> Result<KvClient, KvError> {
+        let mut attempt = 0;
+        loop {
+            match self.connect_once().await {
+                Ok(client) => return Ok(client),
+                Err(e) if attempt < self.config.max_retries => {
+                    let backoff = self.config.backoff(attempt);
+                    warn!(
+                        "Failed to connect to {}: {:?}, retry in {:?}",
+                        self.connector.addr(),
+                        e,
+                        backoff
+                    );
+                    time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn connect_once(&self) -> Result<KvClient, KvError> {
+        with_timeout(self.config.connect_timeout, self.connector.connect()).await
+    }
+
+    fn take(&self) -> Option<KvClient> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    fn put(&self, client: KvClient) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.config.max_connections {
+            idle.push(client);
+        }
+    }
+
+    fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    // ping 所有空闲连接，去掉不可用的，再补足最少连接数
+    async fn check(&self) {
+        let idle = std::mem::take(&mut *self.idle.lock().unwrap());
+        for mut client in idle {
+            match with_timeout(self.config.ping_timeout, client.ping()).await {
+                // 旧服务器不支持 ping，无法检查，只能保留连接
+                Ok(()) | Err(KvError::UnsupportedCommand(_)) => self.put(client),
+                Err(e) => warn!("Drop broken connection: {:?}", e),
+            }
+        }
+
+        let in_use = self.config.max_connections - self.permits.available_permits();
+        let mut total = in_use + self.idle.lock().unwrap().len();
+        while total < self.config.min_connections {
+            match self.connect_once().await {
+                Ok(client) => self.put(client),
+                Err(e) => {
+                    warn!("Failed to connect to {}: {:?}", self.connector.addr(), e);
+                    break;
+                }
+            }
+            total += 1;
+        }
+    }
+}
+
+// 超时的连接处于未知的状态，当作连接出错
+async fn with_timeout<T>(
+    timeout: Duration,
+    fut: impl Future<Output = Result<T, KvError>>,
+) -> Result<T, KvError> {
+    match time::timeout(timeout, fut).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No response in {:?}", timeout),
+        )
+        .into()),
+    }
+}
+
+// 连接池被 drop 之后健康检查自动结束
+fn spawn_health_check(pool: Weak<PoolInner>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => break,
+            };
+            pool.check().await;
+        }
+        info!("Connection pool is dropped, stop health check");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tls_utils::{tls_acceptor, tls_connector},
+        MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor, Value,
+    };
+    use anyhow::Result;
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::broadcast,
+    };
+
+    // 可以断开所有连接的测试服务器，模拟服务器重启
+    struct TestServer {
+        addr: SocketAddr,
+        kill: broadcast::Sender<()>,
+    }
+
+    impl TestServer {
+        async fn start(tls: Option<TlsServerAcceptor>) -> Result<Self> {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            Ok(Self::serve(listener, tls))
+        }
+
+        fn serve(listener: TcpListener, tls: Option<TlsServerAcceptor>) -> Self {
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let addr = listener.local_addr().unwrap();
+            let (kill, _) = broadcast::channel(1);
+            let tx = kill.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let svc = service.clone();
+                    let tls = tls.clone();
+                    let mut kill = tx.subscribe();
+                    tokio::spawn(async move {
+                        let process = async move {
+                            match tls {
+                                Some(tls) => {
+                                    let stream = tls.accept(stream).await.unwrap();
+                                    ProstServerStream::new(stream, svc).process().await
+                                }
+                                None => ProstServerStream::new(stream, svc).process().await,
+                            };
+                        };
+                        tokio::select! {
+                            _ = process => {},
+                            _ = kill.recv() => {},
+                        }
+                    });
+                }
+            });
+            Self { addr, kill }
+        }
+
+        fn kill_connections(&self) {
+            let _ = self.kill.send(());
+        }
+    }
+
+    // 转发到 target 的代理，hang 之后丢掉客户端发来的数据，模拟卡住的服务器
+    async fn hanging_proxy(target: SocketAddr, hang: Arc<AtomicBool>) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                let server = TcpStream::connect(target).await.unwrap();
+                let (mut client_read, mut client_write) = client.into_split();
+                let (mut server_read, mut server_write) = server.into_split();
+                tokio::spawn(async move {
+                    let _ = tokio::io::copy(&mut server_read, &mut client_write).await;
+                });
+                let hang = hang.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4096];
+                    while let Ok(n @ 1..) = client_read.read(&mut buf).await {
+                        if !hang.load(Ordering::SeqCst) {
+                            server_write.write_all(&buf[..n]).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    fn config() -> PoolConfig {
+        PoolConfig::default()
+            .with_connections(2, 4)
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(100))
+    }
+
+    #[tokio::test]
+    async fn pool_should_work() -> Result<()> {
+        let server = TestServer::start(None).await?;
+        let pool = KvPool::new(KvConnector::new(server.addr.to_string()), config()).await?;
+        assert_eq!(pool.idle_connections(), 2);
+
+        pool.execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        let mut client = pool.get().await?;
+        let v: String = client.hget("t1", "k1").await?;
+        assert_eq!(v, "v1");
+
+        // 最多同时使用 4 个连接
+        let mut clients = vec![client];
+        for _ in 0..3 {
+            clients.push(pool.get().await?);
+        }
+        let res = time::timeout(Duration::from_millis(50), pool.get()).await;
+        assert!(res.is_err());
+        drop(clients);
+        assert_eq!(pool.idle_connections(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_should_retry_reads_but_not_writes() -> Result<()> {
+        let server = TestServer::start(Some(tls_acceptor(false)?)).await?;
+        let connector = KvConnector::new(server.addr.to_string()).with_tls(tls_connector(false)?);
+        let pool = KvPool::new(connector, config()).await?;
+        pool.execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+
+        server.kill_connections();
+        time::sleep(Duration::from_millis(50)).await;
+        let res = pool.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.values, vec![Value::from("v1")]);
+
+        server.kill_connections();
+        time::sleep(Duration::from_millis(50)).await;
+        let res = pool
+            .execute(CommandRequest::new_hset("t1", "k1", "v2".into()))
+            .await;
+        assert!(res.is_err());
+        // 写命令失败之后连接池会重新连接
+        pool.execute(CommandRequest::new_hset("t1", "k1", "v2".into()))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_should_reconnect_with_backoff() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+
+        // 服务器晚一点才启动，连接池需要重试几次
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(30)).await;
+            let listener = TcpListener::bind(addr).await.unwrap();
+            TestServer::serve(listener, None);
+        });
+        let config = config().with_max_retries(10);
+        let pool = KvPool::new(KvConnector::new(addr.to_string()), config).await?;
+        pool.execute(CommandRequest::new_ping()).await?;
+
+        // 服务器一直不启动，重试几次后返回错误
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        let res = KvPool::new(KvConnector::new(addr.to_string()), config).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_check_should_replace_broken_connections() -> Result<()> {
+        let server = TestServer::start(None).await?;
+        let config = config().with_health_check_interval(Duration::from_millis(20));
+        let pool = KvPool::new(KvConnector::new(server.addr.to_string()), config).await?;
+
+        server.kill_connections();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pool.idle_connections(), 2);
+        // 空闲连接已经换成新的，写命令不需要重试也能成功
+        pool.execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_should_time_out_hung_server() -> Result<()> {
+        let server = TestServer::start(None).await?;
+        let hang = Arc::new(AtomicBool::new(false));
+        let addr = hanging_proxy(server.addr, hang.clone()).await?;
+        let timeout = Duration::from_millis(100);
+        let config = config()
+            .with_connections(1, 1)
+            .with_max_retries(1)
+            .with_timeouts(timeout, timeout, timeout);
+        let pool = KvPool::new(KvConnector::new(addr.to_string()), config).await?;
+        pool.execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+
+        // 服务器卡住时请求和重新连接都会超时，而不是一直等待
+        hang.store(true, Ordering::SeqCst);
+        let res = time::timeout(
+            Duration::from_secs(2),
+            pool.execute(CommandRequest::new_hget("t1", "k1")),
+        )
+        .await?;
+        assert!(res.is_err());
+
+        // 超时的连接被丢掉，唯一的连接名额已经归还
+        hang.store(false, Ordering::SeqCst);
+        let res = pool.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.values, vec![Value::from("v1")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{replication_entry::Entry, KvConnector, KvError, ReplicationEntry, Service};
+
+// 重新连接主节点前等待的时间，每次失败翻倍
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// 副本：从主节点接收写命令并应用到本地的 Service
+///
+/// 连接断开后会从已经同步到的 offset 继续，主节点不再保留这些写命令时重新全量同步
+#[derive(Clone)]
+pub struct Replica {
+    service: Service,
+    connector: KvConnector,
+    state: Arc<ReplicaState>,
+}
+
+#[derive(Default)]
+struct ReplicaState {
+    // 主节点复制日志的 id，为空时表示还没有同步过
+    id: Mutex<String>,
+    // 已经应用的最后一条记录的 offset
+    offset: AtomicU64,
+}
+
+impl Replica {
+    pub fn new(service: Service, connector: KvConnector) -> Self {
+        Self {
+            service,
+            connector,
+            state: Default::default(),
+        }
+    }
+
+    /// 已经应用的最后一条记录的 offset
+    pub fn offset(&self) -> u64 {
+        self.state.offset.load(Ordering::Acquire)
+    }
+
+    /// 一直从主节点复制数据，出错时重新连接
+    pub async fn run(self) {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match self.sync().await {
+                // 收到过数据说明连接是正常的，下次重连不需要等太久
+                Ok(true) => backoff = INITIAL_BACKOFF,
+                Ok(false) => {}
+                Err(e) => warn!("Replication from {} failed: {:?}", self.connector.addr(), e),
+            }
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    // 连接主节点并应用复制记录，直到连接断开；返回是否收到过记录
+    async fn sync(&self) -> Result<bool, KvError> {
+        let id = self.state.id.lock().unwrap().clone();
+        let next = self.offset() + 1;
+        let client = self.connector.connect().await?;
+        let stream = client.replicate(id, next).await?;
+        info!(
+            "Replicating from {} at offset {}",
+            self.connector.addr(),
+            next
+        );
+        self.apply(stream).await
+    }
+
+    // 应用复制记录。全量同步收到所有 snapshot 之后才更新 id 和 offset，
+    // 中途断开时下次重新全量同步
+    async fn apply(
+        &self,
+        stream: impl Stream<Item = Result<ReplicationEntry, KvError>>,
+    ) -> Result<bool, KvError> {
+        let mut stream = Box::pin(stream);
+        // 正在进行的全量同步：主节点的 id、完成之后的 offset 和还没有收到的 snapshot 数量
+        let mut full_sync: Option<(String, u64, u64)> = None;
+        let mut received = false;
+        while let Some(entry) = stream.next().await {
+            let entry = entry?;
+            let offset = entry.offset;
+            match &entry.entry {
+                Some(Entry::Reset(reset)) => {
+                    info!("Full sync from {} at offset {}", reset.id, offset);
+                    // 数据马上会被清空，之前的 id 不能再用来增量同步
+                    self.state.id.lock().unwrap().clear();
+                    full_sync = Some((reset.id.clone(), offset, reset.chunks));
+                }
+                Some(Entry::Snapshot(_)) => match &mut full_sync {
+                    Some((_, _, remaining)) if *remaining > 0 => *remaining -= 1,
+                    _ => {
+                        return Err(KvError::Internal(
+                            "Unexpected snapshot outside of full sync".into(),
+                        ))
+                    }
+                },
+                _ if full_sync.is_some() => {
+                    return Err(KvError::Internal(
+                        "Command received before full sync completed".into(),
+                    ))
+                }
+                _ => {}
+            }
+            self.service.apply_replicated(entry)?;
+            received = true;
+
+            match full_sync.take() {
+                Some((id, offset, 0)) => {
+                    *self.state.id.lock().unwrap() = id;
+                    self.state.offset.store(offset, Ordering::Release);
+                }
+                Some(pending) => full_sync = Some(pending),
+                None => self.state.offset.store(offset, Ordering::Release),
+            }
+        }
+        match full_sync {
+            Some((_, _, remaining)) => Err(KvError::Internal(format!(
+                "Replication stream ended with {} snapshot chunks missing",
+                remaining
+            ))),
+            None => Ok(received),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{CommandRequest, KvClient, MemTable, ProstServerStream, ServiceInner, Value};
+
+    // 启动一个 kv server，返回地址
+    async fn start_server(service: Service) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let svc = service.clone();
+                tokio::spawn(ProstServerStream::new(stream, svc).process());
+            }
+        });
+        Ok(addr)
+    }
+
+    fn primary(backlog: usize) -> Service {
+        ServiceInner::new(MemTable::new())
+            .with_replication_log(backlog)
+            .into()
+    }
+
+    fn replica(addr: &str) -> (Service, Replica) {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_read_only(true)
+            .into();
+        let replica = Replica::new(service.clone(), KvConnector::new(addr));
+        (service, replica)
+    }
+
+    // 等待副本同步到 offset
+    async fn wait_for(replica: &Replica, offset: u64) {
+        for _ in 0..200 {
+            if replica.offset() >= offset {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("replica is at {}, expect {}", replica.offset(), offset);
+    }
+
+    fn hget(service: &Service, key: &str) -> Option<Value> {
+        let res = service.execute(CommandRequest::new_hget("t1", key));
+        res.into_result()
+            .ok()
+            .and_then(|res| res.values.into_iter().next())
+    }
+
+    #[tokio::test]
+    async fn replicas_should_sync_snapshot_and_new_writes() -> Result<()> {
+        let service = primary(100);
+        let addr = start_server(service.clone()).await?;
+        let mut client = KvClient::connect(addr.clone()).await?;
+        client.hset("t1", "k1", "v1").await?;
+        client.hset("t2", "k2", 2).await?;
+
+        // 两个副本都先全量同步，再收到之后的写命令
+        let (svc1, replica1) = replica(&addr);
+        let (svc2, replica2) = replica(&addr);
+        tokio::spawn(replica1.clone().run());
+        tokio::spawn(replica2.clone().run());
+        wait_for(&replica1, 2).await;
+        wait_for(&replica2, 2).await;
+
+        client.hdel("t1", "k1").await?;
+        client.hset("t1", "k3", "v3").await?;
+        wait_for(&replica1, 4).await;
+        wait_for(&replica2, 4).await;
+
+        for svc in [&svc1, &svc2] {
+            assert_eq!(hget(svc, "k1"), None);
+            assert_eq!(hget(svc, "k3"), Some("v3".into()));
+            let res = svc.execute(CommandRequest::new_hget("t2", "k2"));
+            assert_eq!(res.values, vec![2.into()]);
+
+            // 副本是只读的
+            let res = svc.execute(CommandRequest::new_hset("t1", "k4", "v4".into()));
+            assert_eq!(res.status, 403);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replica_should_catch_up_from_offset() -> Result<()> {
+        let service = primary(2);
+        let addr = start_server(service.clone()).await?;
+        let mut client = KvClient::connect(addr.clone()).await?;
+        client.hset("t1", "k1", "v1").await?;
+
+        let (svc, replica) = replica(&addr);
+        let replicate = tokio::spawn(replica.clone().run());
+        wait_for(&replica, 1).await;
+        replicate.abort();
+
+        // 断开期间的写命令还在日志里，增量同步不会清空数据
+        svc.apply_replicated(crate::ReplicationEntry::new(
+            1,
+            Entry::Command(CommandRequest::new_hset("t1", "local", "x".into())),
+        ))?;
+        client.hset("t1", "k2", "v2").await?;
+        let replicate = tokio::spawn(replica.clone().run());
+        wait_for(&replica, 2).await;
+        assert_eq!(hget(&svc, "k2"), Some("v2".into()));
+        assert_eq!(hget(&svc, "local"), Some("x".into()));
+        replicate.abort();
+
+        // 落后太多时全量同步
+        for i in 3..=6 {
+            client.hset("t1", "k1", i).await?;
+        }
+        let replicate = tokio::spawn(replica.clone().run());
+        wait_for(&replica, 6).await;
+        assert_eq!(hget(&svc, "k1"), Some(6.into()));
+        assert_eq!(hget(&svc, "local"), None);
+        replicate.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicate_should_fail_without_replication_log() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let addr = start_server(service).await?;
+        let (_, replica) = replica(&addr);
+        let e = replica.sync().await.err().unwrap();
+        assert!(matches!(e, KvError::InvalidCommand(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_full_sync_should_start_over() -> Result<()> {
+        let (svc, replica) = replica("127.0.0.1:0");
+        replica.state.offset.store(5, Ordering::Release);
+        *replica.state.id.lock().unwrap() = "old".into();
+
+        let reset = |chunks| {
+            let reset = crate::Reset {
+                id: "new".into(),
+                chunks,
+            };
+            Ok(ReplicationEntry::new(9, Entry::Reset(reset)))
+        };
+        let chunk = |key: &str| {
+            let hmset = crate::Hmset {
+                table: "t1".into(),
+                pairs: vec![crate::Kvpair::new(key, "v".into())],
+            };
+            Ok(ReplicationEntry::new(9, Entry::Snapshot(hmset)))
+        };
+
+        // 收到一半 snapshot 时连接断开
+        let stream = futures::stream::iter(vec![reset(2), chunk("k1")]);
+        assert!(replica.apply(stream).await.is_err());
+        assert_eq!(hget(&svc, "k1"), Some("v".into()));
+        // 下次连接会重新全量同步
+        assert_eq!(replica.offset(), 5);
+        assert!(replica.state.id.lock().unwrap().is_empty());
+
+        let stream = futures::stream::iter(vec![reset(2), chunk("k1"), chunk("k2")]);
+        assert!(replica.apply(stream).await?);
+        assert_eq!(replica.offset(), 9);
+        assert_eq!(*replica.state.id.lock().unwrap(), "new");
+        assert_eq!(hget(&svc, "k2"), Some("v".into()));
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+use std::io::Write;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{KvError, DEFAULT_MAX_FRAME};
+
+/// inline 命令（比如在 telnet 里直接输入 `PING`）一行的最大长度
+const MAX_INLINE: usize = 64 * 1024;
+/// 一个命令最多带多少个参数
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// 回复给 Redis 客户端的数据
+///
+/// RESP3 的类型在 RESP2 下会自动降级：Double 变成 bulk string，Boolean 变成整数，
+/// Map 变成 key / value 交替的数组
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespFrame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<RespFrame>),
+    Double(f64),
+    Boolean(bool),
+    Map(Vec<(RespFrame, RespFrame)>),
+}
+
+impl RespFrame {
+    pub fn ok() -> Self {
+        Self::Simple("OK".into())
+    }
+
+    pub fn error(msg: impl Into<String>) -> Self {
+        Self::Error(msg.into())
+    }
+}
+
+impl From<&str> for RespFrame {
+    fn from(s: &str) -> Self {
+        Self::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+    }
+}
+
+impl From<String> for RespFrame {
+    fn from(s: String) -> Self {
+        Self::Bulk(s.into())
+    }
+}
+
+/// 解码 Redis 客户端发来的命令，编码回复
+///
+/// 命令被解码成参数列表，第一个参数是命令名。参数是从读缓冲区里切出来的，不会复制。
+/// 数据不完整时记住已经解析的参数，下次从上次停下的位置继续解析
+#[derive(Debug, Clone)]
+pub struct RespCodec {
+    version: u8,
+    max_bulk: usize,
+    max_command: usize,
+    // 正在解析的 multibulk 命令
+    partial: Option<Multibulk>,
+}
+
+// 解析了一部分的 multibulk 命令
+#[derive(Debug, Clone)]
+struct Multibulk {
+    // 还没有解析的参数个数
+    remaining: usize,
+    // 已经解析的参数在 buf 中的位置
+    ranges: Vec<(usize, usize)>,
+    // 下一个参数开始的位置
+    pos: usize,
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            max_bulk: DEFAULT_MAX_FRAME,
+            max_command: DEFAULT_MAX_FRAME,
+            partial: None,
+        }
+    }
+}
+
+impl RespCodec {
+    /// 当前使用的协议版本，2 或者 3
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// 客户端通过 HELLO 切换协议版本
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    /// 单个参数的最大长度
+    pub fn with_max_bulk(mut self, max_bulk: usize) -> Self {
+        self.max_bulk = max_bulk;
+        self
+    }
+
+    /// 一个 multibulk 命令的最大长度，包括所有参数和协议本身的开销
+    pub fn with_max_command(mut self, max_command: usize) -> Self {
+        self.max_command = max_command;
+        self
+    }
+
+    // 解析 `*<n>\r\n$<len>\r\n<data>\r\n...`，返回每个参数在 buf 中的位置，以及整个命令的长度
+    fn parse_multibulk(&mut self, buf: &[u8]) -> Result<Parsed, KvError> {
+        let mut cmd = match self.partial.take() {
+            Some(cmd) => cmd,
+            None => {
+                let (count, pos) = match read_number(buf, 0, b'*')? {
+                    Some(v) => v,
+                    None => return Ok(None),
+                };
+                if count > MAX_ARGS as i64 {
+                    return Err(protocol_error("invalid multibulk length"));
+                }
+                let count = count.max(0) as usize;
+                Multibulk {
+                    remaining: count,
+                    ranges: Vec::with_capacity(count.min(64)),
+                    pos,
+                }
+            }
+        };
+
+        while cmd.remaining > 0 {
+            let pos = cmd.pos;
+            if pos >= buf.len() {
+                break;
+            }
+            if buf[pos] != b'$' {
+                return Err(protocol_error(format!(
+                    "expected '$', got '{}'",
+                    buf[pos] as char
+                )));
+            }
+            let (len, start) = match read_number(buf, pos, b'$')? {
+                Some(v) => v,
+                None => break,
+            };
+            if len < 0 || len as usize > self.max_bulk {
+                return Err(protocol_error("invalid bulk length"));
+            }
+            let end = start + len as usize;
+            if end + 2 > self.max_command {
+                return Err(protocol_error("too big multibulk request"));
+            }
+            if buf.len() < end + 2 {
+                break;
+            }
+            if &buf[end..end + 2] != b"\r\n" {
+                return Err(protocol_error("bulk string is not terminated by CRLF"));
+            }
+            cmd.ranges.push((start, end));
+            cmd.remaining -= 1;
+            cmd.pos = end + 2;
+        }
+
+        if cmd.remaining > 0 {
+            self.partial = Some(cmd);
+            return Ok(None);
+        }
+        Ok(Some((cmd.ranges, cmd.pos)))
+    }
+
+    fn encode_frame(&self, frame: &RespFrame, buf: &mut BytesMut) {
+        match frame {
+            RespFrame::Simple(s) => write_line(buf, b'+', s),
+            // 错误信息里不能有换行
+            RespFrame::Error(s) => write_line(buf, b'-', s.replace(['\r', '\n'], " ")),
+            RespFrame::Integer(i) => write_line(buf, b':', i),
+            RespFrame::Bulk(b) => {
+                write_line(buf, b'$', b.len());
+                buf.put_slice(b);
+                buf.put_slice(b"\r\n");
+            }
+            RespFrame::Null if self.version >= 3 => buf.put_slice(b"_\r\n"),
+            RespFrame::Null => buf.put_slice(b"$-1\r\n"),
+            RespFrame::Array(items) => {
+                write_line(buf, b'*', items.len());
+                items.iter().for_each(|item| self.encode_frame(item, buf));
+            }
+            RespFrame::Double(f) => {
+                let s = format_double(*f);
+                if self.version >= 3 {
+                    write_line(buf, b',', s);
+                } else {
+                    self.encode_frame(&s.into(), buf);
+                }
+            }
+            RespFrame::Boolean(b) if self.version >= 3 => {
+                buf.put_slice(if *b { b"#t\r\n" } else { b"#f\r\n" })
+            }
+            RespFrame::Boolean(b) => write_line(buf, b':', *b as i64),
+            RespFrame::Map(pairs) => {
+                if self.version >= 3 {
+                    write_line(buf, b'%', pairs.len());
+                } else {
+                    write_line(buf, b'*', pairs.len() * 2);
+                }
+                for (k, v) in pairs {
+                    self.encode_frame(k, buf);
+                    self.encode_frame(v, buf);
+                }
+            }
+        }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Bytes>;
+    type Error = KvError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // 跳过 inline 命令之间的空行，解析到一半的命令不会以空行开头
+        while self.partial.is_none() && (buf.starts_with(b"\r\n") || buf.starts_with(b"\n")) {
+            let n = if buf[0] == b'\r' { 2 } else { 1 };
+            buf.advance(n);
+        }
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let parsed = if buf[0] == b'*' {
+            self.parse_multibulk(buf)?
+        } else {
+            parse_inline(buf)?
+        };
+
+        match parsed {
+            Some((ranges, len)) => {
+                let data = buf.split_to(len).freeze();
+                Ok(Some(
+                    ranges.into_iter().map(|(s, e)| data.slice(s..e)).collect(),
+                ))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<RespFrame> for RespCodec {
+    type Error = KvError;
+
+    fn encode(&mut self, item: RespFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_frame(&item, dst);
+        Ok(())
+    }
+}
+
+type Parsed = Option<(Vec<(usize, usize)>, usize)>;
+
+// 解析 inline 命令：一行用空白分隔的参数
+fn parse_inline(buf: &[u8]) -> Result<Parsed, KvError> {
+    let eol = match buf.iter().position(|&b| b == b'\n') {
+        Some(eol) => eol,
+        None if buf.len() > MAX_INLINE => return Err(protocol_error("too big inline request")),
+        None => return Ok(None),
+    };
+
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for (i, b) in buf[..eol].iter().enumerate() {
+        match (b.is_ascii_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                ranges.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        ranges.push((s, eol));
+    }
+
+    Ok(Some((ranges, eol + 1)))
+}
+
+// 读取 `<prefix><number>\r\n`，返回数字和下一行的位置
+fn read_number(buf: &[u8], pos: usize, prefix: u8) -> Result<Option<(i64, usize)>, KvError> {
+    let line = &buf[pos..];
+    let eol = match line.windows(2).position(|w| w == b"\r\n") {
+        Some(eol) => eol,
+        // 数字最多 20 位，再长肯定是错误的数据
+        None if line.len() > 32 => return Err(protocol_error("length is too long")),
+        None => return Ok(None),
+    };
+    debug_assert_eq!(line[0], prefix);
+
+    let n = std::str::from_utf8(&line[1..eol])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error(format!("invalid length after '{}'", prefix as char)))?;
+    Ok(Some((n, pos + eol + 2)))
+}
+
+fn protocol_error(msg: impl Into<String>) -> KvError {
+    KvError::InvalidFrame(format!("Protocol error: {}", msg.into()))
+}
+
+fn write_line(buf: &mut BytesMut, prefix: u8, v: impl std::fmt::Display) {
+    buf.put_u8(prefix);
+    // 写入 BytesMut 不会失败
+    write!(buf.writer(), "{}\r\n", v).unwrap();
+}
+
+// RESP3 规定 double 的特殊值写成 inf / -inf / nan
+fn format_double(f: f64) -> String {
+    if f.is_nan() {
+        "nan".into()
+    } else if f.is_infinite() {
+        if f > 0.0 { "inf" } else { "-inf" }.into()
+    } else {
+        f.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(version: u8, frame: RespFrame) -> BytesMut {
+        let mut codec = RespCodec::default();
+        codec.set_version(version);
+        let mut buf = BytesMut::new();
+        codec.encode(frame, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn decode_multibulk_should_work() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*3\r\n$4\r\nHGET\r\n$2\r\nt1\r\n$0\r\n\r\n*1\r\n"[..]);
+        let args = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(args, vec!["HGET", "t1", ""]);
+        // 下一个命令还不完整
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], b"*1\r\n");
+    }
+
+    #[test]
+    fn decode_should_wait_for_partial_data() {
+        let data = b"*2\r\n$4\r\nPING\r\n$5\r\nhello\r\n";
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::new();
+        for (i, b) in data.iter().enumerate() {
+            buf.put_u8(*b);
+            let res = codec.decode(&mut buf).unwrap();
+            if i + 1 < data.len() {
+                assert!(res.is_none());
+            } else {
+                assert_eq!(res.unwrap(), vec!["PING", "hello"]);
+            }
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_should_resume_from_parsed_args() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*3\r\n$4\r\nHGET\r\n$2\r\nt1\r\n$2\r\n"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        // 已经解析的参数不会重新解析
+        let partial = codec.partial.as_ref().unwrap();
+        assert_eq!(partial.ranges.len(), 2);
+        assert_eq!(partial.pos, 22);
+
+        buf.put_slice(b"k1\r\n");
+        let args = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(args, vec!["HGET", "t1", "k1"]);
+        assert!(codec.partial.is_none());
+    }
+
+    #[test]
+    fn decode_should_limit_command_size() {
+        let mut codec = RespCodec::default().with_max_bulk(8).with_max_command(32);
+        let mut buf = BytesMut::from(&b"*4\r\n$8\r\naaaaaaaa\r\n$8\r\nbbbbbbbb\r\n$8\r\n"[..]);
+        // 每个参数都没有超过限制，但是整个命令超过了
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn decode_inline_should_work() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"\r\nHSET  t1 k1 v1\r\nPING\n"[..]);
+        let args = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(args, vec!["HSET", "t1", "k1", "v1"]);
+        let args = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(args, vec!["PING"]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_invalid_data_should_fail() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*1\r\n:10\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"*x\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut codec = RespCodec::default().with_max_bulk(4);
+        let mut buf = BytesMut::from(&b"*1\r\n$5\r\nhello\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_resp2_should_work() {
+        let frame = RespFrame::Array(vec![
+            RespFrame::ok(),
+            RespFrame::error("ERR oops"),
+            RespFrame::Integer(-1),
+            "hello".into(),
+            RespFrame::Null,
+            RespFrame::Double(1.5),
+            RespFrame::Boolean(true),
+        ]);
+        assert_eq!(
+            &encode(2, frame)[..],
+            b"*7\r\n+OK\r\n-ERR oops\r\n:-1\r\n$5\r\nhello\r\n$-1\r\n$3\r\n1.5\r\n:1\r\n"
+        );
+
+        let frame = RespFrame::Map(vec![("k".into(), RespFrame::Integer(1))]);
+        assert_eq!(&encode(2, frame)[..], b"*2\r\n$1\r\nk\r\n:1\r\n");
+    }
+
+    #[test]
+    fn encode_resp3_should_work() {
+        let frame = RespFrame::Map(vec![
+            ("a".into(), RespFrame::Null),
+            ("b".into(), RespFrame::Double(f64::INFINITY)),
+            ("c".into(), RespFrame::Boolean(false)),
+        ]);
+        assert_eq!(
+            &encode(3, frame)[..],
+            b"%3\r\n$1\r\na\r\n_\r\n$1\r\nb\r\n,inf\r\n$1\r\nc\r\n#f\r\n"
+        );
+    }
+
+    #[test]
+    fn encode_error_should_strip_newlines() {
+        let buf = encode(2, RespFrame::error("ERR a\r\nb"));
+        assert_eq!(&buf[..], b"-ERR a  b\r\n");
+    }
+}
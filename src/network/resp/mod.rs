@@ -0,0 +1,358 @@
+mod codec;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+use tracing::{info, warn};
+
+use crate::{value, CloseReason, CommandRequest, CommandResponse, KvError, Kvpair, Service, Value};
+pub use codec::{RespCodec, RespFrame};
+
+/// 处理 Redis 客户端的连接，把 RESP 命令翻译成 CommandRequest 交给 Service 执行
+///
+/// Redis 里的 hash key 对应 kv 的 table，field 对应 table 里的 key
+pub struct RespServerStream<S> {
+    inner: Framed<S, RespCodec>,
+    service: Service,
+}
+
+// 把 CommandResponse 转换成 RESP 回复的方式，和 Redis 命令的语义一一对应
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reply {
+    // 单个 value，找不到时返回 nil
+    Value,
+    // value 数组
+    Values,
+    // 整个 table
+    Pairs,
+    Keys,
+    Vals,
+    Len,
+    // 新增的 field 个数：之前没有值的 field
+    Added,
+    // 删除的 field 个数：之前有值的 field
+    Removed,
+    // 存在返回 1，不存在返回 0
+    Exists,
+    Ok,
+}
+
+impl<S> RespServerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S, service: Service) -> Self {
+        Self {
+            inner: Framed::new(stream, RespCodec::default()),
+            service,
+        }
+    }
+
+    pub async fn process(mut self) -> CloseReason {
+        loop {
+            let args = match self.inner.next().await {
+                Some(Ok(args)) if args.is_empty() => continue,
+                Some(Ok(args)) => args,
+                // 协议错误之后无法再找到下一个命令的开始，和 Redis 一样回复错误后关闭连接
+                Some(Err(e @ KvError::InvalidFrame(_))) => {
+                    warn!("Failed to decode RESP command: {:?}", e);
+                    let msg = format!("ERR {}", e).replace("Cannot decode frame: ", "");
+                    if let Err(e) = self.inner.send(RespFrame::Error(msg)).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    return CloseReason::ReadError(e);
+                }
+                Some(Err(e)) => return CloseReason::ReadError(e),
+                None => return CloseReason::ClientClosed,
+            };
+
+            let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+            info!("process RESP cmd: {} ({} args)", name, args.len() - 1);
+            let reply = self.handle(&name, &args[1..]);
+            if let Err(e) = self.inner.send(reply).await {
+                return CloseReason::WriteError(e);
+            }
+            if name == "QUIT" {
+                let _ = self.inner.close().await;
+                return CloseReason::ClientClosed;
+            }
+        }
+    }
+
+    fn handle(&mut self, name: &str, args: &[Bytes]) -> RespFrame {
+        match name {
+            "PING" => match args {
+                [] => RespFrame::Simple("PONG".into()),
+                [msg] => RespFrame::Bulk(msg.clone()),
+                _ => wrong_args(name),
+            },
+            "ECHO" => match args {
+                [msg] => RespFrame::Bulk(msg.clone()),
+                _ => wrong_args(name),
+            },
+            "HELLO" => self.hello(args),
+            // redis-cli 启动时会查询命令的文档，返回空即可
+            "COMMAND" => RespFrame::Array(vec![]),
+            "QUIT" => RespFrame::ok(),
+            "SELECT" => match args {
+                [db] if db.as_ref() == b"0" => RespFrame::ok(),
+                [_] => RespFrame::error("ERR DB index is out of range"),
+                _ => wrong_args(name),
+            },
+            "CLIENT" => RespFrame::ok(),
+            _ => match to_request(name, args) {
+                Ok((cmd, reply)) => to_frame(self.service.execute(cmd), reply),
+                Err(e) => e,
+            },
+        }
+    }
+
+    // HELLO [protover [AUTH username password] [SETNAME clientname]]
+    fn hello(&mut self, args: &[Bytes]) -> RespFrame {
+        if let Some(v) = args.first() {
+            match v.as_ref() {
+                b"2" => self.inner.codec_mut().set_version(2),
+                b"3" => self.inner.codec_mut().set_version(3),
+                _ => return RespFrame::error("NOPROTO unsupported protocol version"),
+            }
+        }
+
+        let version = self.inner.codec().version();
+        RespFrame::Map(vec![
+            ("server".into(), "kv".into()),
+            ("version".into(), env!("CARGO_PKG_VERSION").into()),
+            ("proto".into(), RespFrame::Integer(version as _)),
+            ("mode".into(), "standalone".into()),
+            ("role".into(), "master".into()),
+            ("modules".into(), RespFrame::Array(vec![])),
+        ])
+    }
+}
+
+// 把 Redis 的 hash 命令翻译成 CommandRequest
+fn to_request(name: &str, args: &[Bytes]) -> Result<(CommandRequest, Reply), RespFrame> {
+    let (cmd, reply) = match (name, args) {
+        ("HGET", [table, key]) => (
+            CommandRequest::new_hget(to_str(table)?, to_str(key)?),
+            Reply::Value,
+        ),
+        ("HMGET", [table, keys @ ..]) if !keys.is_empty() => (
+            CommandRequest::new_hmget(to_str(table)?, to_strings(keys)?),
+            Reply::Values,
+        ),
+        ("HGETALL", [table]) => (CommandRequest::new_hgetall(to_str(table)?), Reply::Pairs),
+        ("HKEYS", [table]) => (CommandRequest::new_hgetall(to_str(table)?), Reply::Keys),
+        ("HVALS", [table]) => (CommandRequest::new_hgetall(to_str(table)?), Reply::Vals),
+        ("HLEN", [table]) => (CommandRequest::new_hgetall(to_str(table)?), Reply::Len),
+        ("HSET" | "HMSET", [table, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let pairs = pairs
+                .chunks(2)
+                .map(|kv| Ok(Kvpair::new(to_str(&kv[0])?, to_value(&kv[1]))))
+                .collect::<Result<Vec<_>, RespFrame>>()?;
+            let reply = if name == "HSET" {
+                Reply::Added
+            } else {
+                Reply::Ok
+            };
+            (CommandRequest::new_hmset(to_str(table)?, pairs), reply)
+        }
+        ("HDEL", [table, keys @ ..]) if !keys.is_empty() => (
+            CommandRequest::new_hmdel(to_str(table)?, to_strings(keys)?),
+            Reply::Removed,
+        ),
+        ("HEXISTS", [table, key]) => (
+            CommandRequest::new_hexist(to_str(table)?, to_str(key)?),
+            Reply::Exists,
+        ),
+        (
+            "HGET" | "HMGET" | "HGETALL" | "HKEYS" | "HVALS" | "HLEN" | "HSET" | "HMSET" | "HDEL"
+            | "HEXISTS",
+            _,
+        ) => return Err(wrong_args(name)),
+        _ => {
+            let msg = format!("ERR unknown command '{}'", name.to_ascii_lowercase());
+            return Err(RespFrame::Error(msg));
+        }
+    };
+
+    Ok((cmd, reply))
+}
+
+// 把 CommandResponse 转换成 Redis 客户端期望的回复
+fn to_frame(res: CommandResponse, reply: Reply) -> RespFrame {
+    if res.status == 404 && reply == Reply::Value {
+        return RespFrame::Null;
+    }
+    if res.status != 200 {
+        return RespFrame::Error(format!("ERR {}", res.message));
+    }
+
+    let count = |f: fn(&Value) -> bool| res.values.iter().filter(|v| f(v)).count() as i64;
+    match reply {
+        Reply::Value => res
+            .values
+            .into_iter()
+            .next()
+            .map_or(RespFrame::Null, to_resp),
+        Reply::Values => RespFrame::Array(res.values.into_iter().map(to_resp).collect()),
+        Reply::Pairs => RespFrame::Map(
+            res.pairs
+                .into_iter()
+                .map(|p| (p.key.into(), p.value.map_or(RespFrame::Null, to_resp)))
+                .collect(),
+        ),
+        Reply::Keys => RespFrame::Array(res.pairs.into_iter().map(|p| p.key.into()).collect()),
+        Reply::Vals => RespFrame::Array(
+            res.pairs
+                .into_iter()
+                .map(|p| p.value.map_or(RespFrame::Null, to_resp))
+                .collect(),
+        ),
+        Reply::Len => RespFrame::Integer(res.pairs.len() as _),
+        Reply::Added => RespFrame::Integer(count(|v| v.value.is_none())),
+        Reply::Removed => RespFrame::Integer(count(|v| v.value.is_some())),
+        Reply::Exists => RespFrame::Integer(count(|v| v.value == Some(value::Value::Bool(true)))),
+        Reply::Ok => RespFrame::ok(),
+    }
+}
+
+// Value 到 RESP 类型的映射，RESP2 下 Float / Bool 由 codec 降级
+fn to_resp(v: Value) -> RespFrame {
+    match v.value {
+        Some(value::Value::String(s)) => s.into(),
+        Some(value::Value::Binary(b)) => RespFrame::Bulk(b),
+        Some(value::Value::Integer(i)) => RespFrame::Integer(i),
+        Some(value::Value::Float(f)) => RespFrame::Double(f),
+        Some(value::Value::Bool(b)) => RespFrame::Boolean(b),
+        None => RespFrame::Null,
+    }
+}
+
+// Redis 的值都是二进制安全的字符串，合法的 UTF-8 存成 String，否则存成 Binary
+fn to_value(b: &Bytes) -> Value {
+    match std::str::from_utf8(b) {
+        Ok(s) => s.into(),
+        Err(_) => b.clone().into(),
+    }
+}
+
+fn to_str(b: &Bytes) -> Result<String, RespFrame> {
+    String::from_utf8(b.to_vec()).map_err(|_| RespFrame::error("ERR key must be valid UTF-8"))
+}
+
+fn to_strings(keys: &[Bytes]) -> Result<Vec<String>, RespFrame> {
+    keys.iter().map(to_str).collect()
+}
+
+fn wrong_args(name: &str) -> RespFrame {
+    RespFrame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ServiceInner};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    async fn start_server() -> DuplexStream {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            RespServerStream::new(server, service).process().await;
+        });
+        client
+    }
+
+    // 发送命令，读取指定长度的回复
+    async fn call(stream: &mut DuplexStream, cmd: &[u8], expected: &[u8]) {
+        stream.write_all(cmd).await.unwrap();
+        let mut buf = vec![0; expected.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn resp_hash_commands_should_work() {
+        let mut s = start_server().await;
+        call(&mut s, b"PING\r\n", b"+PONG\r\n").await;
+        call(&mut s, b"HSET t1 k1 v1 k2 v2\r\n", b":2\r\n").await;
+        call(&mut s, b"HSET t1 k1 v3\r\n", b":0\r\n").await;
+        call(&mut s, b"HGET t1 k1\r\n", b"$2\r\nv3\r\n").await;
+        call(&mut s, b"HGET t1 nope\r\n", b"$-1\r\n").await;
+        call(
+            &mut s,
+            b"HMGET t1 k2 nope\r\n",
+            b"*2\r\n$2\r\nv2\r\n$-1\r\n",
+        )
+        .await;
+        call(&mut s, b"HEXISTS t1 k2\r\n", b":1\r\n").await;
+        call(&mut s, b"HEXISTS t1 nope\r\n", b":0\r\n").await;
+        call(&mut s, b"HLEN t1\r\n", b":2\r\n").await;
+        call(&mut s, b"HDEL t1 k1 nope\r\n", b":1\r\n").await;
+        call(&mut s, b"HGETALL t1\r\n", b"*2\r\n$2\r\nk2\r\n$2\r\nv2\r\n").await;
+        call(&mut s, b"HMSET t1 k3 v3\r\n", b"+OK\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn resp3_should_use_native_types() {
+        let mut s = start_server().await;
+        s.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")
+            .await
+            .unwrap();
+        // HELLO 的回复本身就是 RESP3 的 map
+        let mut buf = [0; 3];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"%6\r");
+        let mut rest = vec![0; 128];
+        let n = s.read(&mut rest).await.unwrap();
+        assert!(String::from_utf8_lossy(&rest[..n]).contains("proto"));
+
+        call(&mut s, b"HSET t1 k1 v1\r\n", b":1\r\n").await;
+        call(&mut s, b"HGETALL t1\r\n", b"%1\r\n$2\r\nk1\r\n$2\r\nv1\r\n").await;
+        call(&mut s, b"HGET t1 nope\r\n", b"_\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn resp_errors_should_be_reported() {
+        let mut s = start_server().await;
+        call(
+            &mut s,
+            b"HGET t1\r\n",
+            b"-ERR wrong number of arguments for 'hget' command\r\n",
+        )
+        .await;
+        call(&mut s, b"FOO\r\n", b"-ERR unknown command 'foo'\r\n").await;
+        call(
+            &mut s,
+            b"HELLO 4\r\n",
+            b"-NOPROTO unsupported protocol version\r\n",
+        )
+        .await;
+        // 协议错误之后连接会被关闭
+        call(
+            &mut s,
+            b"*1\r\n:1\r\n",
+            b"-ERR Protocol error: expected '$', got ':'\r\n",
+        )
+        .await;
+        let mut buf = [0; 1];
+        assert_eq!(s.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn value_should_map_to_resp() {
+        assert_eq!(to_resp("a".into()), "a".into());
+        assert_eq!(to_resp(10.into()), RespFrame::Integer(10));
+        assert_eq!(to_resp(1.5.into()), RespFrame::Double(1.5));
+        assert_eq!(to_resp(true.into()), RespFrame::Boolean(true));
+        assert_eq!(to_resp(Value::default()), RespFrame::Null);
+        assert_eq!(to_value(&Bytes::from_static(b"\xff")), b"\xff".into());
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::{BTreeMap, HashMap};
+
+use futures::future;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    command_request::RequestData, key_hash, CommandRequest, CommandResponse, Hgetall, Hmdel,
+    Hmexist, Hmget, Hmset, KvError, KvPool, Kvpair, Value,
+};
+
+/// 每个节点默认在哈希环上的虚拟节点数
+pub const DEFAULT_VNODES: usize = 160;
+
+/// 一致性哈希环，按 (table, key) 选择节点
+///
+/// 每个节点在环上有多个虚拟节点，增加或删除一个节点时，
+/// 只有落在这个节点上的 key 会重新映射，其它 key 保持不变
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    ring: BTreeMap<u64, String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_VNODES)
+    }
+}
+
+impl HashRing {
+    pub fn new(vnodes: usize) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            ring: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.vnodes {
+            self.ring.insert(vnode_hash(node, i), node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, n| n != node);
+    }
+
+    /// key 所在的节点，环上没有节点时返回 None
+    pub fn node(&self, table: &str, key: &str) -> Option<&str> {
+        let hash = key_hash(table, key);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+/// 客户端分片：按 (table, key) 把命令发到对应的 kvs 节点
+///
+/// 多 key 的命令按节点拆开并发执行，结果按原来 key 的顺序拼回去；
+/// hgetall 会发给所有节点再合并。拆开的写命令在不同节点上不是原子的，
+/// 某个节点出错时其它节点可能已经写入
+#[derive(Clone, Default)]
+pub struct ShardRouter {
+    ring: HashRing,
+    nodes: HashMap<String, KvPool>,
+}
+
+impl ShardRouter {
+    pub fn new(ring: HashRing) -> Self {
+        Self {
+            ring,
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// 加入节点，name 决定节点在哈希环上的位置，同一个节点应该一直使用同一个 name
+    pub fn add_node(&mut self, name: impl Into<String>, pool: KvPool) {
+        let name = name.into();
+        self.ring.add(&name);
+        self.nodes.insert(name, pool);
+    }
+
+    pub fn remove_node(&mut self, name: &str) -> Option<KvPool> {
+        self.ring.remove(name);
+        self.nodes.remove(name)
+    }
+
+    /// key 所在的节点
+    pub fn node(&self, table: &str, key: &str) -> Option<&str> {
+        self.ring.node(table, key)
+    }
+
+    /// 执行命令，非 200 的响应转换成 KvError
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let data = match cmd.request_data {
+            Some(data) => data,
+            None => return Err(KvError::InvalidCommand("Request has no data".into())),
+        };
+
+        match data {
+            RequestData::Hget(ref v) => self.route(&v.table, &v.key, data.clone()).await,
+            RequestData::Hdel(ref v) => self.route(&v.table, &v.key, data.clone()).await,
+            RequestData::Hexist(ref v) => self.route(&v.table, &v.key, data.clone()).await,
+            RequestData::Hset(ref v) => {
+                let key = v.pair.as_ref().map(|p| p.key.as_str()).unwrap_or_default();
+                self.route(&v.table, key, data.clone()).await
+            }
+            RequestData::Hmget(Hmget { table, keys }) => {
+                self.fan_out(&table, keys, |table, keys| {
+                    RequestData::Hmget(Hmget { table, keys })
+                })
+                .await
+            }
+            RequestData::Hmdel(Hmdel { table, keys }) => {
+                self.fan_out(&table, keys, |table, keys| {
+                    RequestData::Hmdel(Hmdel { table, keys })
+                })
+                .await
+            }
+            RequestData::Hmexist(Hmexist { table, keys }) => {
+                self.fan_out(&table, keys, |table, keys| {
+                    RequestData::Hmexist(Hmexist { table, keys })
+                })
+                .await
+            }
+            RequestData::Hmset(Hmset { table, pairs }) => self.hmset(table, pairs).await,
+            RequestData::Hgetall(Hgetall { table }) => {
+                let cmd = RequestData::Hgetall(Hgetall { table });
+                let pools = self.nodes.values().map(|pool| (pool, cmd.clone()));
+                let pairs = execute_all(pools)
+                    .await?
+                    .into_iter()
+                    .flat_map(|res| res.pairs)
+                    .collect();
+                Ok(CommandResponse {
+                    pairs,
+                    ..CommandResponse::ok()
+                })
+            }
+            RequestData::Ping(_) => {
+                let pools = self.nodes.values().map(|pool| (pool, data.clone()));
+                execute_all(pools).await?;
+                Ok(CommandResponse::ok())
+            }
+            RequestData::Hello(_)
+            | RequestData::Watch(_)
+            | RequestData::Replicate(_)
+            | RequestData::Raft(_)
+            | RequestData::Asking(_)
+            | RequestData::ClusterSlots(_)
+            | RequestData::ClusterSetSlot(_)
+            | RequestData::MigrateSlot(_)
+            | RequestData::Subscribe(_)
+            | RequestData::Backup(_)
+            | RequestData::Restore(_) => Err(KvError::UnsupportedCommand(data.name().into())),
+        }
+    }
+
+    async fn route(
+        &self,
+        table: &str,
+        key: &str,
+        data: RequestData,
+    ) -> Result<CommandResponse, KvError> {
+        let pool = self.pool(table, key)?;
+        pool.execute(CommandRequest {
+            request_data: Some(data),
+        })
+        .await
+    }
+
+    // 按节点拆分 key，每个节点的 values 按原来的下标放回去
+    async fn fan_out(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+        build: impl Fn(String, Vec<String>) -> RequestData,
+    ) -> Result<CommandResponse, KvError> {
+        let mut shards: HashMap<&str, (Vec<usize>, Vec<String>)> = HashMap::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let node = self.ring.node(table, &key).ok_or_else(no_node)?;
+            let shard = shards.entry(node).or_default();
+            shard.0.push(i);
+            shard.1.push(key);
+        }
+        let (indexes, cmds): (Vec<_>, Vec<_>) = shards
+            .into_iter()
+            .map(|(node, (indexes, keys))| {
+                (indexes, (&self.nodes[node], build(table.into(), keys)))
+            })
+            .unzip();
+
+        merge_values(indexes, execute_all(cmds).await?)
+    }
+
+    async fn hmset(&self, table: String, pairs: Vec<Kvpair>) -> Result<CommandResponse, KvError> {
+        let mut shards: HashMap<&str, (Vec<usize>, Vec<Kvpair>)> = HashMap::new();
+        for (i, pair) in pairs.into_iter().enumerate() {
+            let node = self.ring.node(&table, &pair.key).ok_or_else(no_node)?;
+            let shard = shards.entry(node).or_default();
+            shard.0.push(i);
+            shard.1.push(pair);
+        }
+        let (indexes, cmds): (Vec<_>, Vec<_>) = shards
+            .into_iter()
+            .map(|(node, (indexes, pairs))| {
+                let table = table.clone();
+                let data = RequestData::Hmset(Hmset { table, pairs });
+                (indexes, (&self.nodes[node], data))
+            })
+            .unzip();
+
+        merge_values(indexes, execute_all(cmds).await?)
+    }
+
+    fn pool(&self, table: &str, key: &str) -> Result<&KvPool, KvError> {
+        let node = self.ring.node(table, key).ok_or_else(no_node)?;
+        Ok(&self.nodes[node])
+    }
+}
+
+// 并发执行，任何一个节点出错就返回错误
+pub(crate) async fn execute_all<'a>(
+    cmds: impl IntoIterator<Item = (&'a KvPool, RequestData)>,
+) -> Result<Vec<CommandResponse>, KvError> {
+    let futures = cmds.into_iter().map(|(pool, data)| {
+        pool.execute(CommandRequest {
+            request_data: Some(data),
+        })
+    });
+    future::try_join_all(futures).await
+}
+
+pub(crate) fn merge_values(
+    indexes: Vec<Vec<usize>>,
+    responses: Vec<CommandResponse>,
+) -> Result<CommandResponse, KvError> {
+    let len = indexes.iter().map(Vec::len).sum();
+    let mut values = vec![Value::default(); len];
+    for (indexes, res) in indexes.into_iter().zip(responses) {
+        if res.values.len() != indexes.len() {
+            return Err(KvError::Internal(format!(
+                "Expect {} values from shard, got {}",
+                indexes.len(),
+                res.values.len()
+            )));
+        }
+        for (i, v) in indexes.into_iter().zip(res.values) {
+            values[i] = v;
+        }
+    }
+    Ok(CommandResponse {
+        values,
+        ..CommandResponse::ok()
+    })
+}
+
+fn no_node() -> KvError {
+    KvError::Internal("No node in the shard router".into())
+}
+
+fn vnode_hash(node: &str, i: usize) -> u64 {
+    xxh3_64(format!("{}#{}", node, i).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvConnector, MemTable, PoolConfig, ProstServerStream, Service, ServiceInner};
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    fn keys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("key{}", i)).collect()
+    }
+
+    fn assign(ring: &HashRing, keys: &[String]) -> Vec<String> {
+        keys.iter()
+            .map(|k| ring.node("t1", k).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn hash_ring_should_remap_minimal_keys() {
+        let keys = keys(10000);
+        let mut ring = HashRing::default();
+        for node in ["n1", "n2", "n3"] {
+            ring.add(node);
+        }
+        let before = assign(&ring, &keys);
+        for node in ["n1", "n2", "n3"] {
+            let n = before.iter().filter(|&v| v == node).count();
+            assert!(n > 2500 && n < 4200, "{} has {} keys", node, n);
+        }
+
+        // 新节点只从其它节点拿走 key，不会在旧节点之间移动
+        ring.add("n4");
+        let after = assign(&ring, &keys);
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(a, b)| a != b).collect();
+        assert!(moved.iter().all(|(_, b)| b.as_str() == "n4"));
+        assert!(moved.len() > 1800 && moved.len() < 3200);
+
+        // 删除节点只影响这个节点上的 key
+        ring.remove("n4");
+        assert_eq!(assign(&ring, &keys), before);
+        ring.remove("n2");
+        let after = assign(&ring, &keys);
+        for (a, b) in before.iter().zip(&after) {
+            assert!(a == b || a == "n2");
+        }
+    }
+
+    async fn start_node() -> Result<KvPool> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+            }
+        });
+        let pool = KvPool::new(KvConnector::new(addr.to_string()), PoolConfig::default()).await?;
+        Ok(pool)
+    }
+
+    async fn router() -> Result<ShardRouter> {
+        let mut router = ShardRouter::default();
+        for name in ["n1", "n2", "n3"] {
+            router.add_node(name, start_node().await?);
+        }
+        Ok(router)
+    }
+
+    #[tokio::test]
+    async fn router_should_fan_out_multi_key_commands() -> Result<()> {
+        let router = router().await?;
+        let keys = keys(20);
+        let pairs = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| Kvpair::new(k, (i as i64).into()))
+            .collect();
+        let res = router
+            .execute(CommandRequest::new_hmset("t1", pairs))
+            .await?;
+        assert_eq!(res.values, vec![Value::default(); 20]);
+
+        // 每个 key 只写到了它所在的节点
+        for key in &keys {
+            let node = router.node("t1", key).unwrap();
+            let res = router.nodes[node]
+                .execute(CommandRequest::new_hexist("t1", key))
+                .await?;
+            assert_eq!(res.values, vec![true.into()]);
+        }
+
+        let query = vec!["key5".into(), "nope".into(), "key0".into(), "key19".into()];
+        let res = router
+            .execute(CommandRequest::new_hmget("t1", query.clone()))
+            .await?;
+        assert_eq!(
+            res.values,
+            vec![5.into(), Value::default(), 0.into(), 19.into()]
+        );
+
+        let res = router
+            .execute(CommandRequest::new_hmexist("t1", query.clone()))
+            .await?;
+        assert_eq!(
+            res.values,
+            vec![true.into(), false.into(), true.into(), true.into()]
+        );
+
+        let res = router.execute(CommandRequest::new_hgetall("t1")).await?;
+        assert_eq!(res.pairs.len(), 20);
+
+        let res = router
+            .execute(CommandRequest::new_hmdel("t1", query))
+            .await?;
+        assert_eq!(
+            res.values,
+            vec![5.into(), Value::default(), 0.into(), 19.into()]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn router_should_route_single_key_commands() -> Result<()> {
+        let router = router().await?;
+        router
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        let res = router.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        let e = router
+            .execute(CommandRequest::new_hget("t1", "k2"))
+            .await
+            .unwrap_err();
+        assert!(matches!(e, KvError::NotFound(_, _)));
+
+        let e = router
+            .execute(CommandRequest::new_watch("t1", ""))
+            .await
+            .unwrap_err();
+        assert!(matches!(e, KvError::UnsupportedCommand(_)));
+
+        let router = ShardRouter::default();
+        assert!(router
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -1,26 +1,15 @@
-use std::{marker::PhantomData, pin::Pin, task::Poll};
+use std::{pin::Pin, task::Poll};
 
-use bytes::BytesMut;
-
-use futures::{ready, FutureExt, Sink, Stream};
+use futures::{ready, Sink, Stream};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
 
-use crate::{network::frame::read_frame, FrameCoder, KvError};
+use crate::{FrameCodec, FrameCoder, FrameConfig, KvError};
 
 /// 处理Kv Server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
-    // inner stream
-    stream: S,
-    // 写缓存
-    wbuf: BytesMut,
-    // 写入了多少字节
-    written: usize,
-    // 读缓存
-    rbuf: BytesMut,
-
-    // 类型占位符
-    _in: PhantomData<In>,
-    _out: PhantomData<Out>,
+    // 使用 FrameCodec 处理读写缓存，数据分段到达也能正确拼出 frame
+    inner: Framed<S, FrameCodec<In, Out>>,
 }
 
 impl<S, In, Out> Stream for ProstStream<S, In, Out>
@@ -36,17 +25,9 @@ where
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        // 上次调用结束后,rbuf应该为空
-        assert!(self.rbuf.len() == 0);
-        // 从rbuf中分离rest
-        let mut rest = self.rbuf.split_off(0);
-        // 使用read_frame来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
-
-        self.rbuf.unsplit(rest);
-
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+        // 单个 frame 的错误和 I/O 错误都返回给调用者，由调用者决定是否继续
+        let item = ready!(Pin::new(&mut self.inner).poll_next(cx));
+        Poll::Ready(item.map(|v| v.and_then(|msg| msg)))
     }
 }
 
@@ -59,45 +40,28 @@ where
     type Error = KvError;
 
     fn poll_ready(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+        Pin::new(&mut self.inner).poll_ready(cx)
     }
 
-    fn start_send(self: std::pin::Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
-        let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
-
-        Ok(())
+    fn start_send(mut self: std::pin::Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item)
     }
 
     fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
+        mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        let this = self.get_mut();
-
-        while this.written != this.wbuf.len() {
-            let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.wbuf[this.written..]))?;
-            this.written += n;
-        }
-
-        // 清除 wbuf
-        this.wbuf.clear();
-        this.written = 0;
-        // 调用stream的poll_flush确保写入
-        ready!(Pin::new(&mut this.stream).poll_flush(cx))?;
-        Poll::Ready(Ok(()))
+        Pin::new(&mut self.inner).poll_flush(cx)
     }
 
     fn poll_close(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        ready!(self.as_mut().poll_flush(cx))?;
-        ready!(Pin::new(&mut self.get_mut().stream).poll_shutdown(cx))?;
-        Poll::Ready(Ok(()))
+        Pin::new(&mut self.inner).poll_close(cx)
     }
 }
 
@@ -106,25 +70,37 @@ where
     S: AsyncRead + AsyncWrite + Send + Unpin,
 {
     pub fn new(stream: S) -> Self {
+        Self::with_config(stream, FrameConfig::default())
+    }
+
+    /// 使用指定的 frame 参数创建 ProstStream
+    pub fn with_config(stream: S, config: FrameConfig) -> Self {
         Self {
-            stream,
-            written: 0,
-            wbuf: BytesMut::new(),
-            rbuf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default(),
+            inner: Framed::new(stream, FrameCodec::new(config)),
         }
     }
-}
 
-impl<S, Req, Res> Unpin for ProstStream<S, Req, Res> where S: Unpin {}
+    /// 当前使用的 frame 参数
+    pub fn config(&self) -> FrameConfig {
+        self.inner.codec().config()
+    }
+
+    /// 修改 frame 参数，之后的读写都会使用新的参数
+    pub fn set_config(&mut self, config: FrameConfig) {
+        self.inner.codec_mut().set_config(config);
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::{utils::DummyStream, CommandRequest};
+    use crate::{
+        utils::{ChunkedStream, DummyStream},
+        CommandRequest, Value,
+    };
 
     use super::*;
     use anyhow::Result;
+    use bytes::{Bytes, BytesMut};
     use futures::{SinkExt, StreamExt};
 
     #[tokio::test]
@@ -137,8 +113,54 @@ mod tests {
         if let Some(Ok(s)) = stream.next().await {
             assert_eq!(s, cmd);
         } else {
-            assert!(false);
+            panic!("should get a frame");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_work_with_chunked_reads() -> Result<()> {
+        // 先写入几个 frame，包括一个压缩的
+        let mut writer =
+            ProstStream::<_, CommandRequest, CommandRequest>::new(DummyStream::default());
+        let v: Value = Bytes::from(vec![1u8; 4096]).into();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", v),
+            CommandRequest::new_hdel("t1", "k1"),
+            CommandRequest::new_hget("t1", "k1"),
+        ];
+        for cmd in cmds.iter() {
+            writer.send(cmd.clone()).await?;
+        }
+        let buf = writer.inner.into_inner().buf;
+
+        // 每次只返回几个字节，中间穿插 Pending
+        let stream = ChunkedStream::new(buf, 3);
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        for cmd in cmds {
+            let cmd1 = stream.next().await.unwrap()?;
+            assert_eq!(cmd, cmd1);
         }
+        assert!(stream.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_fail_on_truncated_frame() -> Result<()> {
+        let mut writer =
+            ProstStream::<_, CommandRequest, CommandRequest>::new(DummyStream::default());
+        writer.send(CommandRequest::new_hdel("t1", "k1")).await?;
+        let mut buf = writer.inner.into_inner().buf;
+        buf.truncate(buf.len() - 1);
+
+        let stream = ChunkedStream::new(buf, 2);
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(KvError::IoError(_)))
+        ));
 
         Ok(())
     }
@@ -104,7 +104,7 @@ impl TlsServerAcceptor {
         config
             .set_single_cert(certs, key)
             .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
-        config.set_protocols(&[Vec::from(&ALPN_KV[..])]);
+        config.set_protocols(&[Vec::from(ALPN_KV)]);
 
         Ok(Self {
             inner: Arc::new(config),
@@ -148,7 +148,6 @@ fn load_key(key: &str) -> Result<PrivateKey, KvError> {
     Err(KvError::CertifcateParseError("private", "key"))
 }
 
-
 #[cfg(test)]
 pub mod tls_utils {
     use crate::{KvError, TlsClientConnector, TlsServerAcceptor};
@@ -0,0 +1,148 @@
+use std::{io, os::unix::fs::FileTypeExt, path::Path};
+
+use tokio::net::{unix::UCred, UnixListener, UnixStream};
+
+use crate::KvError;
+
+/// 在 path 上监听 Unix socket
+///
+/// 上一次运行留下的 socket 文件会被删除；但如果还有服务器在这个 path 上监听，
+/// 返回 AddrInUse，不会抢占别人的 socket
+pub fn bind_unix(path: impl AsRef<Path>) -> Result<UnixListener, KvError> {
+    let path = path.as_ref();
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            let msg = format!("{} exists and is not a socket", path.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            let msg = format!("{} is used by another server", path.display());
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg).into());
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(UnixListener::bind(path)?)
+}
+
+/// 根据 Unix socket 对端进程的 uid / gid 做授权
+///
+/// 没有配置任何 uid / gid 时允许所有本机用户连接；否则对端的 uid 或 gid
+/// 只要有一个在允许的列表里就可以连接
+#[derive(Debug, Clone, Default)]
+pub struct PeerCredAuthorizer {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl PeerCredAuthorizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 允许某个用户连接
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.uids.push(uid);
+        self
+    }
+
+    /// 允许某个组里的用户连接
+    pub fn allow_gid(mut self, gid: u32) -> Self {
+        self.gids.push(gid);
+        self
+    }
+
+    /// 检查对端的身份，返回对端的 credentials
+    pub fn authorize(&self, stream: &UnixStream) -> Result<UCred, KvError> {
+        let cred = stream.peer_cred()?;
+        if self.allows(cred.uid(), cred.gid()) {
+            Ok(cred)
+        } else {
+            Err(KvError::PermissionDenied(format!(
+                "uid {} gid {} is not allowed",
+                cred.uid(),
+                cred.gid()
+            )))
+        }
+    }
+
+    fn allows(&self, uid: u32, gid: u32) -> bool {
+        if self.uids.is_empty() && self.gids.is_empty() {
+            return true;
+        }
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, CommandRequest, MemTable, ProstClientStream, ProstServerStream, Service,
+        ServiceInner,
+    };
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn unix_socket_should_work() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+        let listener = bind_unix(&path)?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ProstServerStream::new(stream, service).process().await;
+        });
+
+        let stream = UnixStream::connect(&path).await?;
+        let mut client = ProstClientStream::new(stream);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        client.execute(cmd).await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bind_unix_should_replace_stale_socket() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+        drop(bind_unix(&path)?);
+        assert!(path.exists());
+
+        // 之前的 listener 已经不在了，可以重新绑定
+        let _listener = bind_unix(&path)?;
+        // 还在监听的 socket 不能被抢占
+        assert!(bind_unix(&path).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bind_unix_should_not_remove_regular_file() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+        std::fs::write(&path, b"hello")?;
+        assert!(bind_unix(&path).is_err());
+        assert_eq!(std::fs::read(&path)?, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn peer_cred_authorizer_should_work() -> anyhow::Result<()> {
+        let (a, _b) = UnixStream::pair()?;
+        let cred = a.peer_cred()?;
+
+        assert!(PeerCredAuthorizer::new().authorize(&a).is_ok());
+        let auth = PeerCredAuthorizer::new().allow_uid(cred.uid());
+        assert_eq!(auth.authorize(&a)?.uid(), cred.uid());
+        let auth = PeerCredAuthorizer::new().allow_gid(cred.gid());
+        assert!(auth.authorize(&a).is_ok());
+
+        let auth = PeerCredAuthorizer::new().allow_uid(cred.uid().wrapping_add(1));
+        assert!(matches!(
+            auth.authorize(&a),
+            Err(KvError::PermissionDenied(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{self, Message},
+    WebSocketStream,
+};
+
+use crate::KvError;
+
+/// 把 WebSocket 适配成 AsyncRead + AsyncWrite，这样 ProstServerStream / ProstClientStream
+/// 可以直接跑在 WebSocket 上
+///
+/// 两个方向的 binary message 都当作字节流：读取时把收到的 message 依次拼接起来，
+/// 一个 frame 可以分在多个 message 里；写入的数据在 flush 时作为一个 message 发出，
+/// 所以发出的 message 只包含完整的 frame，但连续写入多个 frame 之后才 flush 时，
+/// 一个 message 里会有多个 frame。浏览器需要按 frame 头部的长度依次解析 message 里的 frame
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Bytes,
+    write_buf: BytesMut,
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Bytes::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// 服务器端完成 WebSocket 握手
+    pub async fn accept(stream: S) -> Result<Self, KvError> {
+        let inner = tokio_tungstenite::accept_async(stream)
+            .await
+            .map_err(to_io_error)?;
+        Ok(Self::new(inner))
+    }
+
+    /// 客户端在已经建立的连接上完成 WebSocket 握手，url 形如 `ws://host:port/path`
+    pub async fn connect(url: &str, stream: S) -> Result<Self, KvError> {
+        let (inner, _) = tokio_tungstenite::client_async(url, stream)
+            .await
+            .map_err(to_io_error)?;
+        Ok(Self::new(inner))
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_buf.is_empty() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.read_buf = data.into(),
+                // ping / pong 由 tungstenite 自动处理
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Text(_))) => {
+                    let e = io::Error::new(io::ErrorKind::InvalidData, "expect binary message");
+                    return Poll::Ready(Err(e));
+                }
+                // 对方关闭了连接，相当于读到 EOF
+                Some(Ok(Message::Close(_) | Message::Frame(_))) | None => {
+                    return Poll::Ready(Ok(()))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+
+        let len = buf.remaining().min(this.read_buf.len());
+        buf.put_slice(&this.read_buf[..len]);
+        this.read_buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut inner = Pin::new(&mut this.inner);
+        if !this.write_buf.is_empty() {
+            ready!(inner.as_mut().poll_ready(cx)).map_err(to_io_error)?;
+            let data = this.write_buf.split().to_vec();
+            inner
+                .as_mut()
+                .start_send(Message::Binary(data))
+                .map_err(to_io_error)?;
+        }
+        inner.poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        match ready!(Pin::new(&mut self.inner).poll_close(cx)) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(to_io_error(e))),
+        }
+    }
+}
+
+fn to_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, CommandRequest, CommandResponse, FrameCoder, Hello, MemTable,
+        ProstClientStream, ProstServerStream, Service, ServiceInner, Value, WatchEvent,
+    };
+    use anyhow::Result;
+    use futures::{SinkExt, StreamExt};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn start_server() -> Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let svc = service.clone();
+                tokio::spawn(async move {
+                    let stream = WsStream::accept(stream).await.unwrap();
+                    ProstServerStream::new(stream, svc).process().await;
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn connect(addr: SocketAddr) -> Result<ProstClientStream<WsStream<TcpStream>>> {
+        let stream = TcpStream::connect(addr).await?;
+        let stream = WsStream::connect(&format!("ws://{}/", addr), stream).await?;
+        Ok(ProstClientStream::new(stream))
+    }
+
+    #[tokio::test]
+    async fn websocket_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let mut client = connect(addr).await?;
+
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn websocket_frame_should_be_one_message() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let (mut ws, _) =
+            tokio_tungstenite::client_async(format!("ws://{}/", addr), stream).await?;
+
+        // 像浏览器一样自己拼 frame：4 字节长度 + 没有压缩的 protobuf
+        let hello = CommandRequest::new_hello(Hello::new(&[], 0));
+        let mut buf = BytesMut::new();
+        hello.encode_frame(&mut buf, &Default::default())?;
+        ws.send(Message::Binary(buf.to_vec())).await?;
+
+        let msg = ws.next().await.unwrap()?;
+        let mut data = BytesMut::from(&msg.into_data()[..]);
+        let res = CommandResponse::decode_frame(&mut data, &Default::default())?;
+        assert_eq!(res.status, 200);
+        assert!(res.hello.is_some());
+        // 整个 message 正好是一个 frame
+        assert!(data.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn websocket_messages_should_be_byte_stream() -> Result<()> {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (server, client) = tokio::join!(
+            WsStream::accept(a),
+            tokio_tungstenite::client_async("ws://localhost/", b)
+        );
+        let mut server = server?;
+        let (mut ws, _) = client?;
+
+        let mut buf = BytesMut::new();
+        let cmds = [
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hdel("t1", "k2"),
+        ];
+        for cmd in &cmds {
+            cmd.encode_frame(&mut buf, &Default::default())?;
+        }
+
+        // 写入两个 frame 之后才 flush，两个 frame 在同一个 message 里
+        server.write_all(&buf).await?;
+        server.flush().await?;
+        let msg = ws.next().await.unwrap()?;
+        let mut data = BytesMut::from(&msg.into_data()[..]);
+        for cmd in &cmds {
+            let decoded = CommandRequest::decode_frame(&mut data, &Default::default())?;
+            assert_eq!(&decoded, cmd);
+        }
+        assert!(data.is_empty());
+
+        // 分在两个 message 里的 frame 也能完整读到
+        let (first, second) = buf.split_at(5);
+        ws.send(Message::Binary(first.to_vec())).await?;
+        ws.send(Message::Binary(second.to_vec())).await?;
+        let mut received = vec![0u8; buf.len()];
+        server.read_exact(&mut received).await?;
+        assert_eq!(received, buf.to_vec());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn websocket_watch_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let watcher = connect(addr).await?;
+        let mut events = Box::pin(watcher.watch("t1", "k").await?);
+
+        let mut client = connect(addr).await?;
+        client
+            .execute(CommandRequest::new_hset("t1", "x1", 1.into()))
+            .await?;
+        client
+            .execute(CommandRequest::new_hset("t1", "k1", 2.into()))
+            .await?;
+
+        let event = events.next().await.unwrap()?;
+        assert_eq!(event, WatchEvent::new("t1", "k1", 2.into()));
+        Ok(())
+    }
+}
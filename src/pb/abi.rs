@@ -1,163 +1,1295 @@
 /// 来自客户端的命令请求
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    #[prost(
+        oneof = "command_request::RequestData",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21"
+    )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
-        #[prost(message, tag="1")]
+        #[prost(message, tag = "1")]
         Hget(super::Hget),
-        #[prost(message, tag="2")]
+        #[prost(message, tag = "2")]
         Hgetall(super::Hgetall),
-        #[prost(message, tag="3")]
+        #[prost(message, tag = "3")]
         Hmget(super::Hmget),
-        #[prost(message, tag="4")]
+        #[prost(message, tag = "4")]
         Hset(super::Hset),
-        #[prost(message, tag="5")]
+        #[prost(message, tag = "5")]
         Hmset(super::Hmset),
-        #[prost(message, tag="6")]
+        #[prost(message, tag = "6")]
         Hdel(super::Hdel),
-        #[prost(message, tag="7")]
+        #[prost(message, tag = "7")]
         Hmdel(super::Hmdel),
-        #[prost(message, tag="8")]
+        #[prost(message, tag = "8")]
         Hexist(super::Hexist),
-        #[prost(message, tag="9")]
+        #[prost(message, tag = "9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag = "10")]
+        Hello(super::Hello),
+        #[prost(message, tag = "11")]
+        Watch(super::Watch),
+        #[prost(message, tag = "12")]
+        Ping(super::Ping),
+        #[prost(message, tag = "13")]
+        Replicate(super::Replicate),
+        #[prost(message, tag = "14")]
+        Raft(super::RaftMessage),
+        #[prost(message, tag = "15")]
+        Asking(::prost::alloc::boxed::Box<super::Asking>),
+        #[prost(message, tag = "16")]
+        ClusterSlots(super::ClusterSlots),
+        #[prost(message, tag = "17")]
+        ClusterSetSlot(super::ClusterSetSlot),
+        #[prost(message, tag = "18")]
+        MigrateSlot(super::MigrateSlot),
+        #[prost(message, tag = "19")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag = "20")]
+        Backup(super::Backup),
+        #[prost(message, tag = "21")]
+        Restore(super::Restore),
     }
 }
 /// 服务器的响应
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
-    #[prost(uint32, tag="1")]
+    #[prost(uint32, tag = "1")]
     pub status: u32,
     /// 如果不是 2xx，message 里包含详细的信息
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub message: ::prost::alloc::string::String,
     /// 成功返回的 values
-    #[prost(message, repeated, tag="3")]
+    #[prost(message, repeated, tag = "3")]
     pub values: ::prost::alloc::vec::Vec<Value>,
     /// 成功返回的 kv pairs
-    #[prost(message, repeated, tag="4")]
+    #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 服务器对 Hello 的回应
+    #[prost(message, optional, tag = "5")]
+    pub hello: ::core::option::Option<Hello>,
+    /// watch 之后服务器推送的数据变化
+    #[prost(message, optional, tag = "6")]
+    pub event: ::core::option::Option<WatchEvent>,
+    /// replicate 之后主节点推送的复制记录
+    #[prost(message, optional, tag = "7")]
+    pub entry: ::core::option::Option<ReplicationEntry>,
+    /// key 所在的 slot 不在这个节点上时，告诉客户端去哪个节点
+    #[prost(message, optional, tag = "8")]
+    pub redirect: ::core::option::Option<Redirect>,
+    /// cluster_slots 返回的 slot 分配
+    #[prost(message, repeated, tag = "9")]
+    pub slots: ::prost::alloc::vec::Vec<SlotRange>,
+    /// subscribe 之后服务器推送的变更记录
+    #[prost(message, optional, tag = "10")]
+    pub change: ::core::option::Option<ChangeEvent>,
+    /// backup 和 restore 返回的备份信息
+    #[prost(message, optional, tag = "11")]
+    pub backup: ::core::option::Option<BackupInfo>,
+    /// 如果不是 2xx，error 里是错误的类型和参数，客户端据此还原错误
+    #[prost(message, optional, tag = "12")]
+    pub error: ::core::option::Option<ErrorDetail>,
+}
+/// 错误的详细信息
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ErrorDetail {
+    #[prost(enumeration = "ErrorKind", tag = "1")]
+    pub kind: i32,
+    /// 字符串参数，例如 NotFound 的 table 和 key
+    #[prost(string, repeated, tag = "2")]
+    pub args: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 数字参数，例如 InvalidOffset 的 offset 和保留的范围
+    #[prost(uint64, repeated, tag = "3")]
+    pub numbers: ::prost::alloc::vec::Vec<u64>,
 }
 /// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中获取所有的 Kvpair
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一组 key，返回它们的 value
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
+    #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 返回的值
-#[derive(PartialOrd)]
-#[derive(Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
-    #[derive(PartialOrd)]
-    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
-        #[prost(string, tag="1")]
+        #[prost(string, tag = "1")]
         String(::prost::alloc::string::String),
-        #[prost(bytes, tag="2")]
+        #[prost(bytes, tag = "2")]
         Binary(::prost::bytes::Bytes),
-        #[prost(int64, tag="3")]
+        #[prost(int64, tag = "3")]
         Integer(i64),
-        #[prost(double, tag="4")]
+        #[prost(double, tag = "4")]
         Float(f64),
-        #[prost(bool, tag="5")]
+        #[prost(bool, tag = "5")]
         Bool(bool),
     }
 }
 /// 返回的 kvpair
-#[derive(PartialOrd)]
-#[derive(Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub key: ::prost::alloc::string::String,
-    #[prost(message, optional, tag="2")]
+    #[prost(message, optional, tag = "2")]
     pub value: ::core::option::Option<Value>,
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, optional, tag="2")]
+    #[prost(message, optional, tag = "2")]
     pub pair: ::core::option::Option<Kvpair>,
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, repeated, tag="2")]
+    #[prost(message, repeated, tag = "2")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
 }
 /// 从 table 中删除一个 key，返回它之前的值
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
+    #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 查看 key 是否存在
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
+    #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 连接建立时，客户端和服务器交换各自支持的能力
+/// 客户端必须在连接的第一个 frame 发送 Hello
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    /// 支持的压缩算法，按优先级从高到低排列
+    #[prost(enumeration = "Compression", repeated, tag = "1")]
+    pub compressions: ::prost::alloc::vec::Vec<i32>,
+    /// 协议版本，服务器回应的是双方都支持的版本
+    #[prost(uint32, tag = "2")]
+    pub protocol_version: u32,
+    /// 软件版本
+    #[prost(string, tag = "3")]
+    pub version: ::prost::alloc::string::String,
+    /// 支持的命令，比如 hget, hmset
+    #[prost(string, repeated, tag = "4")]
+    pub commands: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 愿意接收的最大 frame
+    #[prost(uint64, tag = "5")]
+    pub max_frame: u64,
+    /// 支持的校验和算法，按优先级从高到低排列，为空表示不使用校验和
+    #[prost(enumeration = "Checksum", repeated, tag = "6")]
+    pub checksums: ::prost::alloc::vec::Vec<i32>,
+}
+/// 按 key 的前缀遍历 table，prefix 为空时返回整个 table
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Scan {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 订阅 table 中 key 以 prefix 开头的数据变化
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 检查连接是否可用，服务器直接返回 200
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ping {}
+/// 副本从 offset 开始复制主节点的写命令
+/// id 和主节点不一致，或者 offset 不在主节点保留的范围内时，先做全量同步
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {
+    /// 上次全量同步时主节点的复制 id，为空表示需要全量同步
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    /// 副本需要的下一条记录的 offset
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+}
+/// 复制记录
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicationEntry {
+    /// 应用这条记录之后副本所在的 offset
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(oneof = "replication_entry::Entry", tags = "2, 3, 4")]
+    pub entry: ::core::option::Option<replication_entry::Entry>,
+}
+/// Nested message and enum types in `ReplicationEntry`.
+pub mod replication_entry {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Entry {
+        /// 增量复制的写命令
+        #[prost(message, tag = "2")]
+        Command(super::CommandRequest),
+        /// 全量同步开始，副本需要清空所有数据
+        #[prost(message, tag = "3")]
+        Reset(super::Reset),
+        /// 全量同步的数据
+        #[prost(message, tag = "4")]
+        Snapshot(super::Hmset),
+    }
+}
+/// 全量同步开始，id 是主节点的复制 id，之后增量复制时带上
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Reset {
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    /// 之后还有多少条 snapshot 记录，全部收到之后全量同步才算完成
+    #[prost(uint64, tag = "2")]
+    pub chunks: u64,
+}
+/// raft 节点之间的消息，term 是发送者当前的 term
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMessage {
+    #[prost(uint64, tag = "1")]
+    pub from: u64,
+    #[prost(uint64, tag = "2")]
+    pub to: u64,
+    #[prost(uint64, tag = "3")]
+    pub term: u64,
+    #[prost(oneof = "raft_message::Message", tags = "4, 5, 6, 7, 8, 9")]
+    pub message: ::core::option::Option<raft_message::Message>,
+}
+/// Nested message and enum types in `RaftMessage`.
+pub mod raft_message {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Message {
+        #[prost(message, tag = "4")]
+        Vote(super::RequestVote),
+        #[prost(message, tag = "5")]
+        VoteResponse(super::VoteResponse),
+        #[prost(message, tag = "6")]
+        Append(super::AppendEntries),
+        #[prost(message, tag = "7")]
+        AppendResponse(super::AppendResponse),
+        #[prost(message, tag = "8")]
+        Snapshot(super::InstallSnapshot),
+        #[prost(message, tag = "9")]
+        SnapshotResponse(super::SnapshotResponse),
+    }
+}
+/// candidate 请求投票，带上自己最后一条日志的位置
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestVote {
+    #[prost(uint64, tag = "1")]
+    pub last_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub last_term: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VoteResponse {
+    #[prost(bool, tag = "1")]
+    pub granted: bool,
+}
+/// leader 复制日志，entries 为空时是心跳
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendEntries {
+    #[prost(uint64, tag = "1")]
+    pub prev_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub prev_term: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag = "4")]
+    pub commit: u64,
+    /// 发送时 leader 最新的 read index 请求，follower 原样返回，用来确认 leader 的身份
+    #[prost(uint64, tag = "5")]
+    pub read_id: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendResponse {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    /// 成功时是 follower 和 leader 一致的最后一条日志，失败时是 follower 最后一条日志
+    #[prost(uint64, tag = "2")]
+    pub match_index: u64,
+    #[prost(uint64, tag = "3")]
+    pub read_id: u64,
+}
+/// follower 需要的日志已经被压缩，leader 发送快照
+///
+/// 快照分成多个消息发送，每个消息只带一部分 table，offset 是这一部分在快照中的位置
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InstallSnapshot {
+    #[prost(uint64, tag = "1")]
+    pub last_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub last_term: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub tables: ::prost::alloc::vec::Vec<Hmset>,
+    #[prost(uint64, tag = "4")]
+    pub offset: u64,
+    /// 快照的最后一部分
+    #[prost(bool, tag = "5")]
+    pub done: bool,
+}
+/// follower 收到快照的一部分之后，告诉 leader 下一个需要的 offset
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotResponse {
+    #[prost(uint64, tag = "1")]
+    pub last_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+}
+/// raft 日志，command 为空的是 leader 当选时写入的空日志
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    #[prost(uint64, tag = "2")]
+    pub index: u64,
+    #[prost(message, optional, tag = "3")]
+    pub command: ::core::option::Option<CommandRequest>,
+}
+/// 需要持久化的 raft 状态
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftHardState {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    #[prost(uint64, tag = "2")]
+    pub vote: u64,
+}
+/// 持久化的快照信息，快照的数据分成 chunks 个 Hmset 单独保存
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftSnapshotMeta {
+    #[prost(uint64, tag = "1")]
+    pub last_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub last_term: u64,
+    #[prost(uint64, tag = "3")]
+    pub chunks: u64,
+}
+/// 收到 ASK 重定向之后，把命令包在 Asking 里发给迁移的目标节点
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Asking {
+    #[prost(message, optional, boxed, tag = "1")]
+    pub command: ::core::option::Option<::prost::alloc::boxed::Box<CommandRequest>>,
+}
+/// 查询集群的 slot 分配
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClusterSlots {}
+/// 一段连续的 slot，[start, end] 都由 addr 上的节点负责
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlotRange {
+    #[prost(uint32, tag = "1")]
+    pub start: u32,
+    #[prost(uint32, tag = "2")]
+    pub end: u32,
+    #[prost(string, tag = "3")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 修改这个节点上 slot 的状态，集群的所有节点需要分别修改
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClusterSetSlot {
+    #[prost(uint32, tag = "1")]
+    pub slot: u32,
+    #[prost(enumeration = "SlotState", tag = "2")]
+    pub state: i32,
+    #[prost(string, tag = "3")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 把 slot 里的数据迁移到 addr，slot 需要先设置成 MIGRATING
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MigrateSlot {
+    #[prost(uint32, tag = "1")]
+    pub slot: u32,
+    #[prost(string, tag = "2")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// MOVED：slot 已经由 addr 负责，客户端应该更新缓存
+/// ASK：slot 正在迁移，这个 key 需要用 Asking 发到 addr，不要更新缓存
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Redirect {
+    #[prost(bool, tag = "1")]
+    pub ask: bool,
+    #[prost(uint32, tag = "2")]
+    pub slot: u32,
+    #[prost(string, tag = "3")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 从 offset 开始订阅变更日志，offset 为 0 表示从保留的最早一条开始
+/// 之后连接只用来接收变更记录，消费者重启后用最后收到的 seq + 1 继续订阅
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+}
+/// 变更日志里的一条记录，对应一个被修改的 key
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChangeEvent {
+    /// 从 1 开始递增的序号
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    /// 修改时的 unix 时间，单位是毫秒
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+    #[prost(string, tag = "3")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub key: ::prost::alloc::string::String,
+    /// 修改前的值，key 原来不存在时为空
+    #[prost(message, optional, tag = "5")]
+    pub old_value: ::core::option::Option<Value>,
+    /// 修改后的值，key 被删除时为空
+    #[prost(message, optional, tag = "6")]
+    pub new_value: ::core::option::Option<Value>,
+    /// 同一个写命令的记录属于同一批，batch 是这一批第一条记录的 seq
+    #[prost(uint64, tag = "7")]
+    pub batch: u64,
+}
+/// 把所有 table 的数据备份到服务器备份目录下的 path 文件里
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Backup {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(enumeration = "Compression", tag = "2")]
+    pub compression: i32,
+}
+/// 用服务器备份目录下的 path 文件替换所有数据
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Restore {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 备份文件的信息，文件开头保存 version、timestamp 和 compression
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BackupInfo {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    /// 备份时的 unix 时间，单位是毫秒
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+    #[prost(enumeration = "Compression", tag = "3")]
+    pub compression: i32,
+    #[prost(uint64, tag = "4")]
+    pub tables: u64,
+    #[prost(uint64, tag = "5")]
+    pub pairs: u64,
+}
+/// 备份文件中的一条记录
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BackupRecord {
+    #[prost(oneof = "backup_record::Record", tags = "1, 2")]
+    pub record: ::core::option::Option<backup_record::Record>,
+}
+/// Nested message and enum types in `BackupRecord`.
+pub mod backup_record {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Record {
+        #[prost(message, tag = "1")]
+        Chunk(super::Hmset),
+        /// 最后一条记录，用来检查备份是否完整
+        #[prost(message, tag = "2")]
+        End(super::BackupEnd),
+    }
+}
+/// checksum 是之前所有记录的 xxh3
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BackupEnd {
+    #[prost(uint64, tag = "1")]
+    pub tables: u64,
+    #[prost(uint64, tag = "2")]
+    pub pairs: u64,
+    #[prost(uint64, tag = "3")]
+    pub checksum: u64,
+}
+/// 数据变化的通知
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WatchEvent {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    /// 新的值，key 被删除时为空
+    #[prost(message, optional, tag = "3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 错误的类型，同一个状态码可能对应多种错误
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorKind {
+    /// 没有更多信息的错误，客户端只能使用 message
+    Unknown = 0,
+    NotFound = 1,
+    InvalidCommand = 2,
+    InvalidFrame = 3,
+    InvalidBackup = 4,
+    HandshakeFailed = 5,
+    UnsupportedVersion = 6,
+    UnsupportedCommand = 7,
+    WatchLagged = 8,
+    InvalidOffset = 9,
+    PermissionDenied = 10,
+    OutOfMemory = 11,
+    NoLeader = 12,
+    TryAgain = 13,
+    Moved = 14,
+    Ask = 15,
+}
+/// frame 使用的压缩算法
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Compression {
+    None = 0,
+    Gzip = 1,
+    Lz4 = 2,
+    Zstd = 3,
+}
+/// frame 末尾附加的校验和
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Checksum {
+    None = 0,
+    Crc32c = 1,
+    Xxh3 = 2,
+}
+/// slot 的状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum SlotState {
+    /// 由 addr 上的节点负责，同时结束迁移
+    Stable = 0,
+    /// 这个节点正在把 slot 迁移到 addr
+    Migrating = 1,
+    /// 这个节点正在从 addr 导入 slot
+    Importing = 2,
+}
+#[doc = r" Generated client implementations."]
+pub mod kv_service_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    #[doc = " gRPC 接口，每个命令对应一个 unary RPC，和自定义的 frame 协议共享同一个 Service"]
+    #[doc = " 命令的执行结果放在 CommandResponse.status 里，gRPC 的 status 只表示传输层的错误"]
+    #[doc = " rpc 和 message 同名，所以参数要写全名"]
+    #[derive(Debug, Clone)]
+    pub struct KvServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl KvServiceClient<tonic::transport::Channel> {
+        #[doc = r" Attempt to create a new client by connecting to a given endpoint."]
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> KvServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::ResponseBody: Body + Send + Sync + 'static,
+        T::Error: Into<StdError>,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> KvServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
+                Into<StdError> + Send + Sync,
+        {
+            KvServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        #[doc = r" Compress requests with `gzip`."]
+        #[doc = r""]
+        #[doc = r" This requires the server to support it otherwise it might respond with an"]
+        #[doc = r" error."]
+        pub fn send_gzip(mut self) -> Self {
+            self.inner = self.inner.send_gzip();
+            self
+        }
+        #[doc = r" Enable decompressing responses with `gzip`."]
+        pub fn accept_gzip(mut self) -> Self {
+            self.inner = self.inner.accept_gzip();
+            self
+        }
+        pub async fn hget(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hget");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hgetall(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hgetall>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hgetall");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmget(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmget");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hset(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hset");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmset(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmset");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hdel(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hdel");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmdel(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmdel");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hexist(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hexist");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmexist(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmexist");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn scan(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Scan>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::Kvpair>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Scan");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+        pub async fn watch(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Watch>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::WatchEvent>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Watch");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+    }
+}
+#[doc = r" Generated server implementations."]
+pub mod kv_service_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    #[doc = "Generated trait containing gRPC methods that should be implemented for use with KvServiceServer."]
+    #[async_trait]
+    pub trait KvService: Send + Sync + 'static {
+        async fn hget(
+            &self,
+            request: tonic::Request<super::Hget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hgetall(
+            &self,
+            request: tonic::Request<super::Hgetall>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmget(
+            &self,
+            request: tonic::Request<super::Hmget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hset(
+            &self,
+            request: tonic::Request<super::Hset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmset(
+            &self,
+            request: tonic::Request<super::Hmset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hdel(
+            &self,
+            request: tonic::Request<super::Hdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmdel(
+            &self,
+            request: tonic::Request<super::Hmdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hexist(
+            &self,
+            request: tonic::Request<super::Hexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmexist(
+            &self,
+            request: tonic::Request<super::Hmexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        #[doc = "Server streaming response type for the Scan method."]
+        type ScanStream: futures_core::Stream<Item = Result<super::Kvpair, tonic::Status>>
+            + Send
+            + Sync
+            + 'static;
+        async fn scan(
+            &self,
+            request: tonic::Request<super::Scan>,
+        ) -> Result<tonic::Response<Self::ScanStream>, tonic::Status>;
+        #[doc = "Server streaming response type for the Watch method."]
+        type WatchStream: futures_core::Stream<Item = Result<super::WatchEvent, tonic::Status>>
+            + Send
+            + Sync
+            + 'static;
+        async fn watch(
+            &self,
+            request: tonic::Request<super::Watch>,
+        ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status>;
+    }
+    #[doc = " gRPC 接口，每个命令对应一个 unary RPC，和自定义的 frame 协议共享同一个 Service"]
+    #[doc = " 命令的执行结果放在 CommandResponse.status 里，gRPC 的 status 只表示传输层的错误"]
+    #[doc = " rpc 和 message 同名，所以参数要写全名"]
+    #[derive(Debug)]
+    pub struct KvServiceServer<T: KvService> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: KvService> KvServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            let inner = Arc::new(inner);
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for KvServiceServer<T>
+    where
+        T: KvService,
+        B: Body + Send + Sync + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Never;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/abi.KvService/Hget" => {
+                    #[allow(non_camel_case_types)]
+                    struct HgetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hget> for HgetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Hget>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hget(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HgetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hgetall" => {
+                    #[allow(non_camel_case_types)]
+                    struct HgetallSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hgetall> for HgetallSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hgetall>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hgetall(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HgetallSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmget" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmgetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmget> for HmgetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Hmget>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmget(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmgetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hset" => {
+                    #[allow(non_camel_case_types)]
+                    struct HsetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hset> for HsetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Hset>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hset(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HsetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmset" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmsetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmset> for HmsetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Hmset>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmset(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmsetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hdel" => {
+                    #[allow(non_camel_case_types)]
+                    struct HdelSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hdel> for HdelSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Hdel>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hdel(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HdelSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmdel" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmdelSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmdel> for HmdelSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Hmdel>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmdel(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmdelSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hexist" => {
+                    #[allow(non_camel_case_types)]
+                    struct HexistSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hexist> for HexistSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Hexist>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hexist(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HexistSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmexist" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmexistSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmexist> for HmexistSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hmexist>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmexist(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmexistSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Scan" => {
+                    #[allow(non_camel_case_types)]
+                    struct ScanSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::ServerStreamingService<super::Scan> for ScanSvc<T> {
+                        type Response = super::Kvpair;
+                        type ResponseStream = T::ScanStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Scan>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).scan(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ScanSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Watch" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::ServerStreamingService<super::Watch> for WatchSvc<T> {
+                        type Response = super::WatchEvent;
+                        type ResponseStream = T::WatchStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Watch>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).watch(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: KvService> Clone for KvServiceServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: KvService> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: KvService> tonic::transport::NamedService for KvServiceServer<T> {
+        const NAME: &'static str = "abi.KvService";
+    }
+}
@@ -93,6 +93,207 @@ impl CommandRequest {
             })),
         }
     }
+
+    // 创建 WATCH 命令，订阅 table 里以 prefix 开头的 key 的变化
+    pub fn new_watch(table: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                prefix: prefix.into(),
+            })),
+        }
+    }
+
+    pub fn new_hello(hello: Hello) -> Self {
+        Self {
+            request_data: Some(RequestData::Hello(hello)),
+        }
+    }
+
+    pub fn new_ping() -> Self {
+        Self {
+            request_data: Some(RequestData::Ping(Ping {})),
+        }
+    }
+
+    // 创建 REPLICATE 命令，id 为空时从头全量同步
+    pub fn new_replicate(id: impl Into<String>, offset: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Replicate(Replicate {
+                id: id.into(),
+                offset,
+            })),
+        }
+    }
+
+    // 从 offset 开始订阅变更日志
+    pub fn new_subscribe(offset: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe { offset })),
+        }
+    }
+
+    // 把所有数据备份到服务器备份目录下的 path 文件
+    pub fn new_backup(path: impl Into<String>, compression: Compression) -> Self {
+        Self {
+            request_data: Some(RequestData::Backup(Backup {
+                path: path.into(),
+                compression: compression as _,
+            })),
+        }
+    }
+
+    // 用服务器备份目录下的 path 文件恢复数据
+    pub fn new_restore(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Restore(Restore { path: path.into() })),
+        }
+    }
+
+    // 创建 RAFT 命令，用于在 raft 节点之间传递消息
+    pub fn new_raft(msg: RaftMessage) -> Self {
+        Self {
+            request_data: Some(RequestData::Raft(msg)),
+        }
+    }
+
+    // 把命令包在 ASKING 里，发给正在导入 slot 的节点
+    pub fn new_asking(cmd: CommandRequest) -> Self {
+        Self {
+            request_data: Some(RequestData::Asking(Box::new(Asking {
+                command: Some(Box::new(cmd)),
+            }))),
+        }
+    }
+
+    pub fn new_cluster_slots() -> Self {
+        Self {
+            request_data: Some(RequestData::ClusterSlots(ClusterSlots {})),
+        }
+    }
+
+    pub fn new_cluster_set_slot(slot: u32, state: SlotState, addr: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::ClusterSetSlot(ClusterSetSlot {
+                slot,
+                state: state as _,
+                addr: addr.into(),
+            })),
+        }
+    }
+
+    pub fn new_migrate_slot(slot: u32, addr: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::MigrateSlot(MigrateSlot {
+                slot,
+                addr: addr.into(),
+            })),
+        }
+    }
+}
+
+impl Hello {
+    // 创建一个新的 Hello
+    pub fn new(compressions: &[Compression], max_frame: usize) -> Self {
+        Self {
+            compressions: compressions.iter().map(|c| *c as i32).collect(),
+            protocol_version: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").into(),
+            commands: COMMANDS.iter().map(|c| c.to_string()).collect(),
+            max_frame: max_frame as _,
+            checksums: vec![],
+        }
+    }
+
+    // 设置支持的校验和算法
+    pub fn with_checksums(mut self, checksums: &[Checksum]) -> Self {
+        self.checksums = checksums.iter().map(|c| *c as i32).collect();
+        self
+    }
+
+    /// 对方是否支持这个命令
+    pub fn supports(&self, data: &RequestData) -> bool {
+        let name = data.name();
+        self.commands.iter().any(|c| c == name)
+    }
+
+    /// 按照对方给出的优先级，选出第一个我们也支持的压缩算法
+    pub fn select_compression(&self, supported: &[Compression]) -> Compression {
+        self.compressions()
+            .find(|c| supported.contains(c))
+            .unwrap_or(Compression::None)
+    }
+
+    /// 按照对方给出的优先级，选出第一个我们也支持的校验和算法
+    pub fn select_checksum(&self, supported: &[Checksum]) -> Checksum {
+        self.checksums()
+            .find(|c| supported.contains(c))
+            .unwrap_or(Checksum::None)
+    }
+}
+
+impl RequestData {
+    // 命令的名字，握手时用来告诉对方支持哪些命令
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestData::Hget(_) => "hget",
+            RequestData::Hgetall(_) => "hgetall",
+            RequestData::Hmget(_) => "hmget",
+            RequestData::Hset(_) => "hset",
+            RequestData::Hmset(_) => "hmset",
+            RequestData::Hdel(_) => "hdel",
+            RequestData::Hmdel(_) => "hmdel",
+            RequestData::Hexist(_) => "hexist",
+            RequestData::Hmexist(_) => "hmexist",
+            RequestData::Hello(_) => "hello",
+            RequestData::Watch(_) => "watch",
+            RequestData::Ping(_) => "ping",
+            RequestData::Replicate(_) => "replicate",
+            RequestData::Raft(_) => "raft",
+            RequestData::Asking(_) => "asking",
+            RequestData::ClusterSlots(_) => "cluster_slots",
+            RequestData::ClusterSetSlot(_) => "cluster_set_slot",
+            RequestData::MigrateSlot(_) => "migrate_slot",
+            RequestData::Subscribe(_) => "subscribe",
+            RequestData::Backup(_) => "backup",
+            RequestData::Restore(_) => "restore",
+        }
+    }
+
+    /// 是否会修改数据
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            RequestData::Hset(_)
+                | RequestData::Hmset(_)
+                | RequestData::Hdel(_)
+                | RequestData::Hmdel(_)
+        )
+    }
+
+    /// 修改集群、备份和替换数据的管理命令，只能在管理连接上执行
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            RequestData::ClusterSetSlot(_)
+                | RequestData::MigrateSlot(_)
+                | RequestData::Backup(_)
+                | RequestData::Restore(_)
+        )
+    }
+
+    /// 重复执行是否安全，连接断开时只有这些命令可以自动重试
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            RequestData::Hget(_)
+                | RequestData::Hgetall(_)
+                | RequestData::Hmget(_)
+                | RequestData::Hexist(_)
+                | RequestData::Hmexist(_)
+                | RequestData::Ping(_)
+        )
+    }
 }
 
 impl Kvpair {
@@ -105,6 +306,115 @@ impl Kvpair {
     }
 }
 
+impl CommandResponse {
+    /// 没有返回数据的成功响应
+    pub fn ok() -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        }
+    }
+
+    /// 把错误状态转换回 KvError，是 `From<&KvError> for CommandResponse` 的逆过程
+    ///
+    /// 按 error 里的错误类型还原，无法还原的错误统一转换成 Internal
+    pub fn into_result(self) -> Result<Self, KvError> {
+        if self.status == StatusCode::OK.as_u16() as u32 {
+            return Ok(self);
+        }
+
+        let msg = self.message;
+        let detail = self.error.unwrap_or_default();
+        let mut args = detail.args.into_iter();
+        let mut arg = || args.next().unwrap_or_default();
+        let mut numbers = detail.numbers.into_iter();
+        let mut number = || numbers.next().unwrap_or_default();
+        let e = match ErrorKind::from_i32(detail.kind).unwrap_or_default() {
+            ErrorKind::Unknown => KvError::Internal(msg),
+            ErrorKind::NotFound => KvError::NotFound(arg(), arg()),
+            ErrorKind::InvalidCommand => KvError::InvalidCommand(arg()),
+            ErrorKind::InvalidFrame => KvError::InvalidFrame(arg()),
+            ErrorKind::InvalidBackup => KvError::InvalidBackup(arg()),
+            ErrorKind::HandshakeFailed => KvError::HandshakeError(arg()),
+            ErrorKind::UnsupportedVersion => KvError::UnsupportedVersion(number() as _),
+            ErrorKind::UnsupportedCommand => KvError::UnsupportedCommand(arg()),
+            ErrorKind::WatchLagged => KvError::WatchLagged(number()),
+            ErrorKind::InvalidOffset => KvError::InvalidOffset(number(), number(), number()),
+            ErrorKind::PermissionDenied => KvError::PermissionDenied(arg()),
+            ErrorKind::OutOfMemory => KvError::OutOfMemory(number()),
+            ErrorKind::NoLeader => KvError::NoLeader,
+            ErrorKind::TryAgain => KvError::TryAgain(number() as _),
+            ErrorKind::Moved => KvError::Moved(number() as _, arg()),
+            ErrorKind::Ask => KvError::Ask(number() as _, arg()),
+        };
+        Err(e)
+    }
+}
+
+impl ErrorDetail {
+    fn new(kind: ErrorKind, args: &[&String], numbers: &[u64]) -> Self {
+        Self {
+            kind: kind as _,
+            args: args.iter().map(|s| s.to_string()).collect(),
+            numbers: numbers.to_vec(),
+        }
+    }
+}
+
+impl From<&KvError> for ErrorDetail {
+    fn from(e: &KvError) -> Self {
+        match e {
+            KvError::NotFound(table, key) => Self::new(ErrorKind::NotFound, &[table, key], &[]),
+            KvError::InvalidCommand(s) => Self::new(ErrorKind::InvalidCommand, &[s], &[]),
+            KvError::InvalidFrame(s) => Self::new(ErrorKind::InvalidFrame, &[s], &[]),
+            KvError::InvalidBackup(s) => Self::new(ErrorKind::InvalidBackup, &[s], &[]),
+            KvError::HandshakeError(s) => Self::new(ErrorKind::HandshakeFailed, &[s], &[]),
+            KvError::UnsupportedVersion(v) => {
+                Self::new(ErrorKind::UnsupportedVersion, &[], &[*v as _])
+            }
+            KvError::UnsupportedCommand(s) => Self::new(ErrorKind::UnsupportedCommand, &[s], &[]),
+            KvError::WatchLagged(n) => Self::new(ErrorKind::WatchLagged, &[], &[*n]),
+            KvError::InvalidOffset(offset, first, last) => {
+                Self::new(ErrorKind::InvalidOffset, &[], &[*offset, *first, *last])
+            }
+            KvError::PermissionDenied(s) => Self::new(ErrorKind::PermissionDenied, &[s], &[]),
+            KvError::OutOfMemory(max) => Self::new(ErrorKind::OutOfMemory, &[], &[*max]),
+            KvError::NoLeader => Self::new(ErrorKind::NoLeader, &[], &[]),
+            KvError::TryAgain(slot) => Self::new(ErrorKind::TryAgain, &[], &[*slot as _]),
+            KvError::Moved(slot, addr) => Self::new(ErrorKind::Moved, &[addr], &[*slot as _]),
+            KvError::Ask(slot, addr) => Self::new(ErrorKind::Ask, &[addr], &[*slot as _]),
+            _ => Self::default(),
+        }
+    }
+}
+
+impl Watch {
+    /// 通知是否是这个 watch 关心的
+    pub fn matches(&self, event: &WatchEvent) -> bool {
+        event.table == self.table && event.key.starts_with(&self.prefix)
+    }
+}
+
+impl WatchEvent {
+    /// 创建 key 被设置为新值的通知
+    pub fn new(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            value: Some(value),
+        }
+    }
+
+    /// 创建 key 被删除的通知
+    pub fn deleted(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            value: None,
+        }
+    }
+}
+
 // 从 String 转化为 Value
 impl From<String> for Value {
     fn from(s: String) -> Self {
@@ -175,6 +485,17 @@ impl TryFrom<Value> for i64 {
     }
 }
 
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v, "String")),
+        }
+    }
+}
+
 impl TryFrom<Value> for f64 {
     type Error = KvError;
 
@@ -226,6 +547,54 @@ impl TryFrom<&[u8]> for Value {
     }
 }
 
+// 从 JSON 转化为 Value：整数转化为 Integer，其它数字转化为 Float，
+// 二进制数据用 {"binary": "<base64>"} 表示
+impl TryFrom<serde_json::Value> for Value {
+    type Error = KvError;
+
+    fn try_from(json: serde_json::Value) -> Result<Self, Self::Error> {
+        use serde_json::Value as Json;
+        match json {
+            Json::Null => Ok(Value::default()),
+            Json::Bool(b) => Ok(b.into()),
+            Json::Number(n) => match n.as_i64() {
+                Some(i) => Ok(i.into()),
+                None => Ok(n.as_f64().unwrap_or(f64::NAN).into()),
+            },
+            Json::String(s) => Ok(s.into()),
+            Json::Object(o) if o.len() == 1 => match o.get("binary") {
+                Some(Json::String(s)) => match base64::decode(s) {
+                    Ok(data) => Ok(Bytes::from(data).into()),
+                    Err(e) => Err(KvError::InvalidCommand(format!("Invalid base64: {}", e))),
+                },
+                _ => Err(KvError::InvalidCommand(format!(
+                    "Cannot convert JSON {:?} to Value",
+                    o
+                ))),
+            },
+            json => Err(KvError::InvalidCommand(format!(
+                "Cannot convert JSON {} to Value",
+                json
+            ))),
+        }
+    }
+}
+
+// 从 Value 转化为 JSON，NaN 和无穷大在 JSON 里没有对应的表示，转化为 null
+impl From<Value> for serde_json::Value {
+    fn from(v: Value) -> Self {
+        use serde_json::Value as Json;
+        match v.value {
+            Some(value::Value::String(s)) => Json::String(s),
+            Some(value::Value::Binary(b)) => serde_json::json!({ "binary": base64::encode(b) }),
+            Some(value::Value::Integer(i)) => i.into(),
+            Some(value::Value::Float(f)) => f.into(),
+            Some(value::Value::Bool(b)) => Json::Bool(b),
+            None => Json::Null,
+        }
+    }
+}
+
 // 从 Value 转化成 CommandResponse
 impl From<Value> for CommandResponse {
     fn from(v: Value) -> Self {
@@ -251,16 +620,63 @@ impl From<Vec<Kvpair>> for CommandResponse {
 // 从 KvError 转化成 CommandResponse
 impl From<KvError> for CommandResponse {
     fn from(e: KvError) -> Self {
+        (&e).into()
+    }
+}
+
+// 从 &KvError 转化成 CommandResponse，保留 error 以便后续使用
+impl From<&KvError> for CommandResponse {
+    fn from(e: &KvError) -> Self {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            error: Some(e.into()),
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::InvalidCommand(_)
+            | KvError::InvalidFrame(_)
+            | KvError::InvalidBackup(_)
+            | KvError::ChecksumMismatch(_, _) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
+            KvError::FrameTooLarge(_, _) => {
+                result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _
+            }
+            KvError::HandshakeError(_) => {
+                result.status = StatusCode::UPGRADE_REQUIRED.as_u16() as _
+            }
+            KvError::UnsupportedVersion(_) => {
+                result.status = StatusCode::HTTP_VERSION_NOT_SUPPORTED.as_u16() as _
+            }
+            KvError::UnsupportedCommand(_) => {
+                result.status = StatusCode::NOT_IMPLEMENTED.as_u16() as _
+            }
+            KvError::WatchLagged(_) => result.status = StatusCode::GONE.as_u16() as _,
+            KvError::InvalidOffset(..) => {
+                result.status = StatusCode::RANGE_NOT_SATISFIABLE.as_u16() as _
+            }
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::OutOfMemory(_) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
+            KvError::NoLeader | KvError::TryAgain(_) => {
+                result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _
+            }
+            KvError::Moved(slot, addr) | KvError::Ask(slot, addr) => {
+                let ask = matches!(e, KvError::Ask(..));
+                result.status = match ask {
+                    true => StatusCode::TEMPORARY_REDIRECT.as_u16() as _,
+                    false => StatusCode::PERMANENT_REDIRECT.as_u16() as _,
+                };
+                result.redirect = Some(Redirect {
+                    ask,
+                    slot: *slot,
+                    addr: addr.clone(),
+                });
+            }
             _ => {}
         }
 
@@ -268,6 +684,61 @@ impl From<KvError> for CommandResponse {
     }
 }
 
+// 从 WatchEvent 转化成 CommandResponse，用于推送给 watch 的客户端
+impl From<WatchEvent> for CommandResponse {
+    fn from(event: WatchEvent) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            event: Some(event),
+            ..Default::default()
+        }
+    }
+}
+
+// 从 ReplicationEntry 转化成 CommandResponse，用于推送给副本
+impl From<ReplicationEntry> for CommandResponse {
+    fn from(entry: ReplicationEntry) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            entry: Some(entry),
+            ..Default::default()
+        }
+    }
+}
+
+// 从 ChangeEvent 转化成 CommandResponse，用于推送给订阅者
+impl From<ChangeEvent> for CommandResponse {
+    fn from(change: ChangeEvent) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            change: Some(change),
+            ..Default::default()
+        }
+    }
+}
+
+// 从 BackupInfo 转化成 CommandResponse
+impl From<BackupInfo> for CommandResponse {
+    fn from(info: BackupInfo) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            backup: Some(info),
+            ..Default::default()
+        }
+    }
+}
+
+// 从 Hello 转化成 CommandResponse
+impl From<Hello> for CommandResponse {
+    fn from(hello: Hello) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            hello: Some(hello),
+            ..Default::default()
+        }
+    }
+}
+
 // 从Vec<Value> 转化为 CommandResponse
 impl From<Vec<Value>> for CommandResponse {
     fn from(value: Vec<Value>) -> Self {
@@ -285,3 +756,42 @@ impl From<(String, Value)> for Kvpair {
         Kvpair::new(data.0, data.1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_result_should_restore_error_kind() {
+        // 同样是 503，需要按错误类型区分
+        let res: CommandResponse = KvError::TryAgain(7).into();
+        assert!(matches!(res.into_result(), Err(KvError::TryAgain(7))));
+        let res: CommandResponse = KvError::NoLeader.into();
+        assert!(matches!(res.into_result(), Err(KvError::NoLeader)));
+
+        // 参数里包含 message 的分隔符也能正确还原
+        let res: CommandResponse = KvError::NotFound("t, key: 1".into(), "k".into()).into();
+        match res.into_result() {
+            Err(KvError::NotFound(table, key)) => {
+                assert_eq!((table, key), ("t, key: 1".into(), "k".into()))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let res: CommandResponse = KvError::InvalidOffset(1, 2, 4).into();
+        assert!(matches!(
+            res.into_result(),
+            Err(KvError::InvalidOffset(1, 2, 4))
+        ));
+    }
+
+    #[test]
+    fn into_result_without_detail_should_be_internal() {
+        let res = CommandResponse {
+            status: StatusCode::SERVICE_UNAVAILABLE.as_u16() as _,
+            message: "Slot 7 is migrating, try again later".into(),
+            ..Default::default()
+        };
+        assert!(matches!(res.into_result(), Err(KvError::Internal(_))));
+        assert!(CommandResponse::ok().into_result().is_ok());
+    }
+}
@@ -0,0 +1,759 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    raft_message::Message, AppendEntries, AppendResponse, CommandRequest, CommandResponse, Hmset,
+    InstallSnapshot, KvError, RaftConfig, RaftEntry, RaftHardState, RaftMessage, RaftRole,
+    RaftStatus, RaftStorage, RequestVote, Service, SnapshotResponse, VoteResponse,
+};
+
+pub(crate) type ProposalSender = oneshot::Sender<Result<CommandResponse, KvError>>;
+pub(crate) type ReadSender = oneshot::Sender<Result<(), KvError>>;
+
+// leader 记录的每个 follower 的复制进度
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    // 下一条要发送的日志
+    next: u64,
+    // 已经确认和 leader 一致的最后一条日志
+    matched: u64,
+    // 正在发送的快照：快照的 last_index 和下一个要发送的 offset
+    snapshot: Option<(u64, u64)>,
+}
+
+// follower 正在接收的快照，收到最后一部分之后才替换数据
+struct Receiving {
+    leader: u64,
+    last_index: u64,
+    last_term: u64,
+    // 下一个需要的 offset
+    offset: u64,
+    tables: Vec<Hmset>,
+}
+
+// 等待确认的读请求，多数节点确认 leader 的身份并且 index 已经应用之后才能读
+struct ReadIndex {
+    id: u64,
+    index: u64,
+    acks: HashSet<u64>,
+    tx: ReadSender,
+}
+
+/// raft 的状态机，不涉及 IO 和时间：调用者负责 tick、传入消息，再把 msgs 发送出去
+///
+/// 提交的写命令直接应用到 Service 上；快照由调用者在后台生成，生成期间暂停应用日志
+pub(crate) struct Raft {
+    id: u64,
+    peers: Vec<u64>,
+    config: RaftConfig,
+    storage: RaftStorage,
+    service: Service,
+
+    term: u64,
+    // 当前 term 投票给的节点，0 表示还没有投票
+    vote: u64,
+    role: RaftRole,
+    // 0 表示不知道 leader
+    leader: u64,
+    commit: u64,
+    applied: u64,
+
+    progress: HashMap<u64, Progress>,
+    // leader 当选时写入的空日志，它提交之后才能确定之前 term 的日志都已经提交
+    term_start: u64,
+    // 一个选举周期内回应过 leader 的节点，少于多数时 leader 主动退位
+    active: HashSet<u64>,
+    votes: HashSet<u64>,
+
+    election_elapsed: u32,
+    heartbeat_elapsed: u32,
+    election_timeout: u32,
+
+    // index -> (term, sender)，日志应用后把结果发给等待的客户端
+    proposals: HashMap<u64, (u64, ProposalSender)>,
+    read_id: u64,
+    reads: VecDeque<ReadIndex>,
+    msgs: Vec<RaftMessage>,
+
+    receiving: Option<Receiving>,
+    // 正在后台生成的快照的位置
+    building: Option<u64>,
+    // 还没有交给调用者的快照请求 (index, term)
+    snapshot_request: Option<(u64, u64)>,
+}
+
+impl Raft {
+    pub fn new(
+        id: u64,
+        peers: Vec<u64>,
+        config: RaftConfig,
+        storage: RaftStorage,
+        service: Service,
+    ) -> Result<Self, KvError> {
+        if id == 0 || peers.contains(&0) {
+            return Err(KvError::InvalidCommand("Raft node id must not be 0".into()));
+        }
+        let peers = peers.into_iter().filter(|p| *p != id).collect();
+        let state = storage.hard_state()?;
+        let mut raft = Self {
+            id,
+            peers,
+            config,
+            storage,
+            service,
+            term: state.term,
+            vote: state.vote,
+            role: RaftRole::Follower,
+            leader: 0,
+            commit: 0,
+            applied: 0,
+            progress: HashMap::new(),
+            term_start: 0,
+            active: HashSet::new(),
+            votes: HashSet::new(),
+            election_elapsed: 0,
+            heartbeat_elapsed: 0,
+            election_timeout: 0,
+            proposals: HashMap::new(),
+            read_id: 0,
+            reads: VecDeque::new(),
+            msgs: Vec::new(),
+            receiving: None,
+            building: None,
+            snapshot_request: None,
+        };
+
+        // 快照之后的日志等 leader 告诉我们 commit 之后再应用
+        if let Some(snapshot) = raft.storage.snapshot()? {
+            raft.service.restore(snapshot.tables)?;
+            raft.commit = snapshot.last_index;
+            raft.applied = snapshot.last_index;
+        }
+        raft.reset_election_timeout();
+        Ok(raft)
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        RaftStatus {
+            id: self.id,
+            term: self.term,
+            role: self.role,
+            leader: (self.leader != 0).then_some(self.leader),
+            commit: self.commit,
+            applied: self.applied,
+            last_index: self.storage.last_index(),
+        }
+    }
+
+    /// 需要发送给其它节点的消息
+    pub fn take_messages(&mut self) -> Vec<RaftMessage> {
+        mem::take(&mut self.msgs)
+    }
+
+    /// 需要在后台生成的快照的位置 (index, term)，生成之后交给 finish_snapshot
+    pub fn take_snapshot_request(&mut self) -> Option<(u64, u64)> {
+        self.snapshot_request.take()
+    }
+
+    /// 保存后台生成的快照，然后继续应用日志
+    pub fn finish_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        tables: Result<Vec<Hmset>, KvError>,
+    ) -> Result<(), KvError> {
+        // 生成期间安装了 leader 发来的快照，这个快照已经过时了
+        if self.building != Some(index) {
+            return Ok(());
+        }
+        self.building = None;
+        match tables {
+            Ok(tables) => {
+                let snapshot = InstallSnapshot {
+                    last_index: index,
+                    last_term: term,
+                    tables,
+                    ..Default::default()
+                };
+                self.storage.save_snapshot(&snapshot)?;
+                info!("Raft node {} takes snapshot at {}", self.id, index);
+            }
+            Err(e) => warn!(
+                "Raft node {} failed to take snapshot at {}: {:?}",
+                self.id, index, e
+            ),
+        }
+        self.apply()
+    }
+
+    pub fn tick(&mut self) -> Result<(), KvError> {
+        self.election_elapsed += 1;
+        if self.role != RaftRole::Leader {
+            if self.election_elapsed >= self.election_timeout {
+                self.campaign()?;
+            }
+            return Ok(());
+        }
+
+        self.heartbeat_elapsed += 1;
+        if self.heartbeat_elapsed >= self.config.heartbeat_ticks {
+            self.heartbeat_elapsed = 0;
+            self.broadcast_append()?;
+        }
+        if self.election_elapsed >= self.config.election_ticks {
+            self.election_elapsed = 0;
+            // 和多数节点失去联系的 leader 不能再处理请求，否则客户端会一直等待
+            if self.active.len() + 1 < self.quorum() {
+                warn!("Raft node {} lost contact with the quorum", self.id);
+                self.become_follower(self.term, 0)?;
+            }
+            self.active.clear();
+        }
+        Ok(())
+    }
+
+    /// 作为 leader 把写命令追加到日志，提交并应用后通过 tx 返回结果
+    pub fn propose(&mut self, cmd: CommandRequest, tx: ProposalSender) -> Result<(), KvError> {
+        if self.role != RaftRole::Leader {
+            let _ = tx.send(Err(KvError::NoLeader));
+            return Ok(());
+        }
+
+        let index = self.storage.last_index() + 1;
+        self.storage
+            .append(&[RaftEntry::new(self.term, index, Some(cmd))])?;
+        self.proposals.insert(index, (self.term, tx));
+        self.broadcast_append()?;
+        self.maybe_commit()
+    }
+
+    /// 作为 leader 处理读请求：记录当前的 commit，等多数节点确认 leader 的身份之后才能读
+    pub fn read_index(&mut self, tx: ReadSender) -> Result<(), KvError> {
+        if self.role != RaftRole::Leader {
+            let _ = tx.send(Err(KvError::NoLeader));
+            return Ok(());
+        }
+
+        self.read_id += 1;
+        self.reads.push_back(ReadIndex {
+            id: self.read_id,
+            index: self.commit.max(self.term_start),
+            acks: HashSet::from([self.id]),
+            tx,
+        });
+        self.broadcast_append()?;
+        self.advance_reads();
+        Ok(())
+    }
+
+    pub fn step(&mut self, msg: RaftMessage) -> Result<(), KvError> {
+        let from = msg.from;
+        let message = match msg.message {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+
+        if msg.term > self.term {
+            let leader = match message {
+                Message::Append(_) | Message::Snapshot(_) => from,
+                _ => 0,
+            };
+            self.become_follower(msg.term, leader)?;
+        } else if msg.term < self.term {
+            // 告诉过期的 leader 和 candidate 新的 term
+            match message {
+                Message::Append(_) | Message::Snapshot(_) => {
+                    let resp = AppendResponse {
+                        success: false,
+                        match_index: self.storage.last_index(),
+                        read_id: 0,
+                    };
+                    self.send(from, Message::AppendResponse(resp));
+                }
+                Message::Vote(_) => self.send(from, Message::VoteResponse(Default::default())),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match message {
+            Message::Vote(req) => self.handle_vote(from, req),
+            Message::VoteResponse(resp) => self.handle_vote_response(from, resp),
+            Message::Append(req) => self.handle_append(from, req),
+            Message::AppendResponse(resp) => self.handle_append_response(from, resp),
+            Message::Snapshot(snapshot) => self.handle_snapshot(from, snapshot),
+            Message::SnapshotResponse(resp) => self.handle_snapshot_response(from, resp),
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        // 包括自己在内超过半数
+        self.peers.len().div_ceil(2) + 1
+    }
+
+    fn reset_election_timeout(&mut self) {
+        // 随机的选举超时，避免多个节点同时发起选举
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let seed = format!("{}-{}-{}", self.id, self.term, now);
+        let ticks = self.config.election_ticks.max(1);
+        self.election_timeout = ticks + (xxh3_64(seed.as_bytes()) % ticks as u64) as u32;
+        self.election_elapsed = 0;
+    }
+
+    fn save_hard_state(&self) -> Result<(), KvError> {
+        self.storage.set_hard_state(&RaftHardState {
+            term: self.term,
+            vote: self.vote,
+        })
+    }
+
+    fn send(&mut self, to: u64, message: Message) {
+        self.msgs.push(RaftMessage {
+            from: self.id,
+            to,
+            term: self.term,
+            message: Some(message),
+        });
+    }
+
+    fn campaign(&mut self) -> Result<(), KvError> {
+        self.term += 1;
+        self.vote = self.id;
+        self.save_hard_state()?;
+        self.role = RaftRole::Candidate;
+        self.leader = 0;
+        self.votes = HashSet::from([self.id]);
+        self.reset_election_timeout();
+        info!(
+            "Raft node {} starts election for term {}",
+            self.id, self.term
+        );
+
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let req = RequestVote {
+            last_index: self.storage.last_index(),
+            last_term: self.storage.last_term(),
+        };
+        for peer in self.peers.clone() {
+            self.send(peer, Message::Vote(req.clone()));
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: u64) -> Result<(), KvError> {
+        let newer = term > self.term;
+        if newer {
+            self.term = term;
+            self.vote = 0;
+            self.save_hard_state()?;
+        }
+        if self.role == RaftRole::Leader {
+            info!("Raft node {} steps down in term {}", self.id, self.term);
+            self.fail_pending();
+        }
+        if newer || self.role != RaftRole::Follower {
+            self.reset_election_timeout();
+        }
+        self.role = RaftRole::Follower;
+        self.leader = leader;
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<(), KvError> {
+        info!("Raft node {} becomes leader in term {}", self.id, self.term);
+        self.role = RaftRole::Leader;
+        self.leader = self.id;
+        self.heartbeat_elapsed = 0;
+        self.election_elapsed = 0;
+        self.active.clear();
+
+        let next = self.storage.last_index() + 1;
+        self.progress = self
+            .peers
+            .iter()
+            .map(|p| {
+                let progress = Progress {
+                    next,
+                    matched: 0,
+                    snapshot: None,
+                };
+                (*p, progress)
+            })
+            .collect();
+
+        // 提交一条空日志，顺带提交之前 term 留下的日志
+        self.term_start = next;
+        self.storage
+            .append(&[RaftEntry::new(self.term, next, None)])?;
+        self.broadcast_append()?;
+        self.maybe_commit()
+    }
+
+    // 失去 leader 身份时，等待的写命令可能提交也可能不会，读请求可以重试
+    fn fail_pending(&mut self) {
+        for (_, (_, tx)) in self.proposals.drain() {
+            let _ = tx.send(Err(leadership_lost()));
+        }
+        for read in self.reads.drain(..) {
+            let _ = read.tx.send(Err(KvError::NoLeader));
+        }
+    }
+
+    fn broadcast_append(&mut self) -> Result<(), KvError> {
+        for peer in self.peers.clone() {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    fn send_append(&mut self, peer: u64) -> Result<(), KvError> {
+        let next = match self.progress.get(&peer) {
+            Some(progress) => progress.next,
+            None => return Ok(()),
+        };
+        let prev_index = next - 1;
+        // 需要的日志已经被压缩，改为发送快照
+        let prev_term = match self.storage.term(prev_index)? {
+            Some(term) => term,
+            None => return self.send_snapshot(peer),
+        };
+
+        let req = AppendEntries {
+            prev_index,
+            prev_term,
+            entries: self.storage.entries(next, self.config.max_append_entries)?,
+            commit: self.commit,
+            read_id: self.read_id,
+        };
+        self.send(peer, Message::Append(req));
+        Ok(())
+    }
+
+    // 每次只发送快照的一部分，follower 确认之后再发送下一部分，避免超过 frame 的大小限制
+    fn send_snapshot(&mut self, peer: u64) -> Result<(), KvError> {
+        let last_index = self.storage.snapshot_index();
+        let offset = match self.progress.get(&peer).and_then(|p| p.snapshot) {
+            Some((index, offset)) if index == last_index => offset,
+            _ => {
+                info!(
+                    "Raft node {} sends snapshot at {} to {}",
+                    self.id, last_index, peer
+                );
+                0
+            }
+        };
+        match self.storage.snapshot_chunk(offset)? {
+            Some(chunk) => {
+                if let Some(progress) = self.progress.get_mut(&peer) {
+                    progress.snapshot = Some((last_index, offset));
+                }
+                self.send(peer, Message::Snapshot(chunk));
+                Ok(())
+            }
+            None => Err(KvError::Internal(
+                "Raft log is compacted without snapshot".into(),
+            )),
+        }
+    }
+
+    fn handle_vote(&mut self, from: u64, req: RequestVote) -> Result<(), KvError> {
+        let last = (self.storage.last_term(), self.storage.last_index());
+        let up_to_date = (req.last_term, req.last_index) >= last;
+        let granted = (self.vote == 0 || self.vote == from) && up_to_date;
+        if granted {
+            self.vote = from;
+            self.save_hard_state()?;
+            self.election_elapsed = 0;
+        }
+        self.send(from, Message::VoteResponse(VoteResponse { granted }));
+        Ok(())
+    }
+
+    fn handle_vote_response(&mut self, from: u64, resp: VoteResponse) -> Result<(), KvError> {
+        if self.role != RaftRole::Candidate || !resp.granted {
+            return Ok(());
+        }
+        self.votes.insert(from);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn handle_append(&mut self, from: u64, req: AppendEntries) -> Result<(), KvError> {
+        // 同一个 term 只有一个 leader，candidate 收到后直接成为 follower
+        self.become_follower(self.term, from)?;
+        self.election_elapsed = 0;
+
+        let reply = |success, match_index| {
+            Message::AppendResponse(AppendResponse {
+                success,
+                match_index,
+                read_id: req.read_id,
+            })
+        };
+
+        let mut entries = req.entries.clone();
+        let mut prev_index = req.prev_index;
+        if prev_index < self.commit {
+            // 已经提交的日志一定和 leader 一致
+            entries.retain(|e| e.index > self.commit);
+            prev_index = self.commit;
+        } else if self.storage.term(prev_index)? != Some(req.prev_term) {
+            let hint = self.storage.last_index().min(prev_index.saturating_sub(1));
+            self.send(from, reply(false, hint));
+            return Ok(());
+        }
+
+        // 跳过已经有的日志，重复或者乱序的消息不能截断后面的日志
+        let last_new = prev_index + entries.len() as u64;
+        let mut skip = entries.len();
+        for (i, entry) in entries.iter().enumerate() {
+            if self.storage.term(entry.index)? != Some(entry.term) {
+                skip = i;
+                break;
+            }
+        }
+        self.storage.append(&entries[skip..])?;
+
+        if req.commit > self.commit {
+            self.commit = req.commit.min(last_new);
+            self.apply()?;
+        }
+        self.send(from, reply(true, last_new));
+        Ok(())
+    }
+
+    fn handle_append_response(&mut self, from: u64, resp: AppendResponse) -> Result<(), KvError> {
+        if self.role != RaftRole::Leader {
+            return Ok(());
+        }
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        self.active.insert(from);
+
+        if resp.success {
+            progress.snapshot = None;
+            progress.matched = progress.matched.max(resp.match_index);
+            progress.next = progress.next.max(progress.matched + 1);
+            let behind = progress.next <= self.storage.last_index();
+            self.maybe_commit()?;
+            if behind {
+                self.send_append(from)?;
+            }
+        } else {
+            // 退回到 follower 最后一条日志之后重试，但不会早于已经确认的日志
+            progress.next = (progress.next - 1)
+                .min(resp.match_index + 1)
+                .max(progress.matched + 1);
+            self.send_append(from)?;
+        }
+
+        if resp.read_id > 0 {
+            for read in self.reads.iter_mut().filter(|r| r.id <= resp.read_id) {
+                read.acks.insert(from);
+            }
+            self.advance_reads();
+        }
+        Ok(())
+    }
+
+    fn handle_snapshot_response(
+        &mut self,
+        from: u64,
+        resp: SnapshotResponse,
+    ) -> Result<(), KvError> {
+        if self.role != RaftRole::Leader {
+            return Ok(());
+        }
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        self.active.insert(from);
+
+        // 心跳会重发当前的部分，重复的回应不再发送，否则会有多串快照同时在发送
+        match progress.snapshot {
+            Some((index, offset)) if index == resp.last_index && offset != resp.offset => {
+                progress.snapshot = Some((index, resp.offset));
+                self.send_append(from)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_snapshot(&mut self, from: u64, chunk: InstallSnapshot) -> Result<(), KvError> {
+        self.become_follower(self.term, from)?;
+        self.election_elapsed = 0;
+
+        let last_index = chunk.last_index;
+        if last_index <= self.commit {
+            self.receiving = None;
+            return self.reply_snapshot_installed(from, last_index);
+        }
+
+        // 换了 leader 或者快照时从头开始接收，不同节点生成的快照里 table 的顺序可能不同
+        let current = |r: &Receiving| r.leader == from && r.last_index == last_index;
+        if chunk.offset == 0 && !self.receiving.as_ref().is_some_and(current) {
+            info!(
+                "Raft node {} receives snapshot at {} from {}",
+                self.id, last_index, from
+            );
+            self.receiving = Some(Receiving {
+                leader: from,
+                last_index,
+                last_term: chunk.last_term,
+                offset: 0,
+                tables: Vec::new(),
+            });
+        }
+        let offset = match self.receiving.as_mut() {
+            Some(receiving) if current(receiving) => {
+                // 重复或者乱序的部分直接丢掉，告诉 leader 需要的 offset
+                if chunk.offset == receiving.offset {
+                    receiving.offset += 1;
+                    receiving.tables.extend(chunk.tables);
+                    if chunk.done {
+                        return self.install_snapshot(from);
+                    }
+                }
+                receiving.offset
+            }
+            _ => 0,
+        };
+
+        let resp = SnapshotResponse { last_index, offset };
+        self.send(from, Message::SnapshotResponse(resp));
+        Ok(())
+    }
+
+    // 收到了完整的快照，替换所有数据
+    fn install_snapshot(&mut self, from: u64) -> Result<(), KvError> {
+        let receiving = match self.receiving.take() {
+            Some(receiving) => receiving,
+            None => return Ok(()),
+        };
+        let last_index = receiving.last_index;
+        info!(
+            "Raft node {} installs snapshot at {} from {}",
+            self.id, last_index, from
+        );
+        let snapshot = InstallSnapshot {
+            last_index,
+            last_term: receiving.last_term,
+            tables: receiving.tables,
+            ..Default::default()
+        };
+        self.storage.save_snapshot(&snapshot)?;
+        self.service.restore(snapshot.tables)?;
+        self.commit = last_index;
+        self.applied = last_index;
+        // 正在后台生成的快照读到的数据已经被替换了
+        self.building = None;
+        self.snapshot_request = None;
+        self.reply_snapshot_installed(from, last_index)
+    }
+
+    fn reply_snapshot_installed(&mut self, from: u64, last_index: u64) -> Result<(), KvError> {
+        let resp = AppendResponse {
+            success: true,
+            match_index: last_index,
+            read_id: 0,
+        };
+        self.send(from, Message::AppendResponse(resp));
+        Ok(())
+    }
+
+    // 多数节点都有的日志，如果是当前 term 的，就可以提交了
+    fn maybe_commit(&mut self) -> Result<(), KvError> {
+        let mut matched: Vec<u64> = self.progress.values().map(|p| p.matched).collect();
+        matched.push(self.storage.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.storage.term(index)? == Some(self.term) {
+            self.commit = index;
+            self.apply()?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<(), KvError> {
+        // 生成快照期间不能修改数据，否则快照和它的位置不一致
+        while self.applied < self.commit && self.building.is_none() {
+            let entries = self
+                .storage
+                .entries(self.applied + 1, self.config.max_append_entries)?;
+            if entries.is_empty() {
+                break;
+            }
+            for entry in entries.into_iter().take_while(|e| e.index <= self.commit) {
+                let res = match entry.command {
+                    Some(cmd) => self.service.apply(cmd),
+                    None => CommandResponse::ok(),
+                };
+                self.applied = entry.index;
+                if let Some((term, tx)) = self.proposals.remove(&entry.index) {
+                    // 这个位置的日志已经被新 leader 的日志替换了
+                    let res = if term == entry.term {
+                        Ok(res)
+                    } else {
+                        Err(leadership_lost())
+                    };
+                    let _ = tx.send(res);
+                }
+            }
+        }
+
+        self.advance_reads();
+        self.maybe_snapshot()
+    }
+
+    fn advance_reads(&mut self) {
+        let quorum = self.quorum();
+        while let Some(read) = self.reads.front() {
+            if read.acks.len() < quorum || read.index > self.applied {
+                break;
+            }
+            if let Some(read) = self.reads.pop_front() {
+                let _ = read.tx.send(Ok(()));
+            }
+        }
+    }
+
+    // 应用的日志足够多时请求调用者在后台生成快照，保存之后删除之前的日志
+    //
+    // 序列化所有数据很慢，在 raft 的主循环里做会耽误心跳和选举
+    fn maybe_snapshot(&mut self) -> Result<(), KvError> {
+        if self.building.is_some()
+            || self.applied - self.storage.snapshot_index() < self.config.snapshot_threshold
+        {
+            return Ok(());
+        }
+        let last_term = match self.storage.term(self.applied)? {
+            Some(term) => term,
+            None => return Ok(()),
+        };
+        self.building = Some(self.applied);
+        self.snapshot_request = Some((self.applied, last_term));
+        Ok(())
+    }
+}
+
+fn leadership_lost() -> KvError {
+    KvError::Internal("Raft leadership changed, the command may or may not be applied".into())
+}
@@ -0,0 +1,93 @@
+mod consensus;
+mod node;
+mod storage;
+mod transport;
+
+use std::time::Duration;
+
+pub use node::*;
+pub use storage::*;
+pub use transport::*;
+
+/// raft 的参数，时间都以 tick 为单位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaftConfig {
+    /// 一个 tick 的时长
+    pub tick: Duration,
+    /// 最短的选举超时，实际的超时在 [election_ticks, 2 * election_ticks) 之间随机
+    pub election_ticks: u32,
+    /// leader 发送心跳的间隔
+    pub heartbeat_ticks: u32,
+    /// 一条 AppendEntries 最多包含的日志
+    pub max_append_entries: usize,
+    /// 上次快照之后应用了这么多条日志时生成新的快照
+    pub snapshot_threshold: u64,
+    /// 客户端请求的超时，包括等待选举和转发给 leader 的时间
+    pub request_timeout: Duration,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            tick: Duration::from_millis(50),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            max_append_entries: 100,
+            snapshot_threshold: 10000,
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RaftConfig {
+    /// tick 的时长
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    /// 选举超时和心跳间隔
+    pub fn with_election_ticks(mut self, election: u32, heartbeat: u32) -> Self {
+        self.election_ticks = election.max(1);
+        self.heartbeat_ticks = heartbeat.clamp(1, self.election_ticks);
+        self
+    }
+
+    /// 一条 AppendEntries 最多包含的日志
+    pub fn with_max_append_entries(mut self, max: usize) -> Self {
+        self.max_append_entries = max.max(1);
+        self
+    }
+
+    /// 生成快照的间隔
+    pub fn with_snapshot_threshold(mut self, threshold: u64) -> Self {
+        self.snapshot_threshold = threshold.max(1);
+        self
+    }
+
+    /// 客户端请求的超时
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+}
+
+/// 节点在 raft 中的角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 节点当前的状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    pub id: u64,
+    pub term: u64,
+    pub role: RaftRole,
+    pub leader: Option<u64>,
+    pub commit: u64,
+    pub applied: u64,
+    pub last_index: u64,
+}
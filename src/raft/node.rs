@@ -0,0 +1,446 @@
+use std::sync::Arc;
+
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time,
+};
+use tracing::warn;
+
+use super::consensus::{ProposalSender, Raft, ReadSender};
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, Hmset, KvError, RaftConfig,
+    RaftMessage, RaftStatus, RaftStorage, RaftTransport, Service,
+};
+
+/// 一个 raft 节点，可以在多个 task 之间 clone
+///
+/// 写命令提交到 raft 日志，多数节点持久化之后再应用到 Service；读命令通过 read index
+/// 确认 leader 的身份之后在 leader 上读，所以读写都是线性一致的。
+/// 不是 leader 的节点会把命令转发给 leader
+#[derive(Clone)]
+pub struct RaftNode {
+    inner: Arc<NodeInner>,
+}
+
+struct NodeInner {
+    id: u64,
+    config: RaftConfig,
+    service: Service,
+    transport: Arc<dyn RaftTransport>,
+    events: mpsc::UnboundedSender<Event>,
+    status: watch::Receiver<RaftStatus>,
+}
+
+enum Event {
+    Message(RaftMessage),
+    Propose(CommandRequest, ProposalSender),
+    Read(ReadSender),
+    Stop(oneshot::Sender<()>),
+}
+
+impl RaftNode {
+    /// 启动节点，peers 是集群里所有节点的 id
+    ///
+    /// service 的数据完全由 raft 管理，启动时会被替换成快照里的数据；
+    /// 为了避免绕过 raft 直接修改数据，service 应该是只读的
+    pub fn start(
+        id: u64,
+        peers: Vec<u64>,
+        config: RaftConfig,
+        storage: RaftStorage,
+        service: Service,
+        transport: Arc<dyn RaftTransport>,
+    ) -> Result<Self, KvError> {
+        let raft = Raft::new(id, peers, config, storage, service.clone())?;
+        let (events, rx) = mpsc::unbounded_channel();
+        let (status_tx, status) = watch::channel(raft.status());
+        tokio::spawn(run(
+            raft,
+            config,
+            service.clone(),
+            rx,
+            status_tx,
+            transport.clone(),
+        ));
+
+        Ok(Self {
+            inner: Arc::new(NodeInner {
+                id,
+                config,
+                service,
+                transport,
+                events,
+                status,
+            }),
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        self.inner.status.borrow().clone()
+    }
+
+    /// 处理其它节点发来的消息
+    pub fn step(&self, msg: RaftMessage) {
+        // 节点已经停止时丢掉消息
+        let _ = self.inner.events.send(Event::Message(msg));
+    }
+
+    /// 执行客户端的命令，出错时返回对应的错误状态
+    pub async fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        match time::timeout(self.inner.config.request_timeout, self.try_execute(cmd)).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => e.into(),
+            Err(_) => KvError::Internal("Raft request timed out".into()).into(),
+        }
+    }
+
+    /// 停止节点，返回之后可以用同样的 storage 重新启动
+    pub async fn stop(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.inner.events.send(Event::Stop(tx)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    async fn try_execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let write = match &cmd.request_data {
+            Some(data) if data.is_write() => true,
+            Some(RequestData::Ping(_)) => return Ok(self.inner.service.execute(cmd)),
+            Some(data) if data.is_idempotent() => false,
+            _ => return Ok(self.inner.service.execute(cmd)),
+        };
+
+        loop {
+            let result = match write {
+                true => self.propose(cmd.clone()).await,
+                false => self
+                    .read_index()
+                    .await
+                    .map(|_| self.inner.service.execute(cmd.clone())),
+            };
+            match result {
+                Err(KvError::NoLeader) => {}
+                result => return result,
+            }
+
+            // 不是 leader 时转发给 leader，还没有选出 leader 时等一会儿再试
+            match self.status().leader {
+                Some(leader) if leader != self.id() => {
+                    return self.inner.transport.forward(leader, cmd).await;
+                }
+                _ => time::sleep(self.inner.config.tick).await,
+            }
+        }
+    }
+
+    async fn propose(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Event::Propose(cmd, tx))?;
+        rx.await.map_err(|_| stopped())?
+    }
+
+    async fn read_index(&self) -> Result<(), KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Event::Read(tx))?;
+        rx.await.map_err(|_| stopped())?
+    }
+
+    fn send(&self, event: Event) -> Result<(), KvError> {
+        self.inner.events.send(event).map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> KvError {
+    KvError::Internal("Raft node is stopped".into())
+}
+
+// 后台生成的快照：位置、term 和数据
+type Snapshot = (u64, u64, Result<Vec<Hmset>, KvError>);
+
+// 节点的主循环，raft 的状态只在这里修改
+async fn run(
+    mut raft: Raft,
+    config: RaftConfig,
+    service: Service,
+    mut events: mpsc::UnboundedReceiver<Event>,
+    status: watch::Sender<RaftStatus>,
+    transport: Arc<dyn RaftTransport>,
+) {
+    let mut ticker = time::interval(config.tick);
+    let (snapshot_tx, mut snapshots) = mpsc::unbounded_channel::<Snapshot>();
+    loop {
+        let result = tokio::select! {
+            _ = ticker.tick() => raft.tick(),
+            Some((index, term, tables)) = snapshots.recv() => {
+                raft.finish_snapshot(index, term, tables)
+            }
+            event = events.recv() => match event {
+                Some(Event::Message(msg)) => raft.step(msg),
+                Some(Event::Propose(cmd, tx)) => raft.propose(cmd, tx),
+                Some(Event::Read(tx)) => raft.read_index(tx),
+                // 先释放 storage，调用者才能重新打开
+                Some(Event::Stop(tx)) => {
+                    drop(raft);
+                    let _ = tx.send(());
+                    return;
+                }
+                None => return,
+            },
+        };
+
+        // 持久化失败时不能再继续，否则可能违反 raft 的安全性
+        if let Err(e) = result {
+            warn!("Raft node {} stopped: {:?}", raft.status().id, e);
+            return;
+        }
+        // 序列化所有数据很慢，放到阻塞线程里做，主循环继续处理心跳和选举
+        if let Some((index, term)) = raft.take_snapshot_request() {
+            let service = service.clone();
+            let tx = snapshot_tx.clone();
+            tokio::task::spawn_blocking(move || {
+                let _ = tx.send((index, term, service.snapshot()));
+            });
+        }
+        for msg in raft.take_messages() {
+            transport.send(msg);
+        }
+        let current = raft.status();
+        if *status.borrow() != current {
+            let _ = status.send(current);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::{Kvpair, LocalNetwork, MemTable, RaftRole, ServiceInner, Value};
+
+    const IDS: [u64; 3] = [1, 2, 3];
+
+    struct Cluster {
+        network: LocalNetwork,
+        config: RaftConfig,
+        _dirs: Vec<TempDir>,
+        // 重启节点时复用 db，sled 在 drop 之后不会马上释放文件锁
+        dbs: Vec<sled::Db>,
+        nodes: Vec<RaftNode>,
+    }
+
+    impl Cluster {
+        fn new(config: RaftConfig) -> Result<Self> {
+            let mut cluster = Self {
+                network: LocalNetwork::new(),
+                config,
+                _dirs: Vec::new(),
+                dbs: Vec::new(),
+                nodes: Vec::new(),
+            };
+            for id in IDS {
+                let dir = tempdir()?;
+                let db = sled::open(dir.path())?;
+                cluster.nodes.push(cluster.start(id, db.clone())?);
+                cluster._dirs.push(dir);
+                cluster.dbs.push(db);
+            }
+            Ok(cluster)
+        }
+
+        fn start(&self, id: u64, db: sled::Db) -> Result<RaftNode> {
+            let service: Service = ServiceInner::new(MemTable::new())
+                .with_read_only(true)
+                .into();
+            let storage = RaftStorage::new(db)?;
+            let transport = self.network.transport(id);
+            let node = RaftNode::start(id, IDS.to_vec(), self.config, storage, service, transport)?;
+            self.network.register(node.clone());
+            Ok(node)
+        }
+
+        fn node(&self, id: u64) -> &RaftNode {
+            &self.nodes[id as usize - 1]
+        }
+
+        // 停止节点后用同样的 storage 重新启动
+        async fn restart(&mut self, id: u64) -> Result<()> {
+            let i = id as usize - 1;
+            self.nodes[i].stop().await;
+            self.nodes[i] = self.start(id, self.dbs[i].clone())?;
+            Ok(())
+        }
+
+        // 等待多数节点承认的 leader，excluded 之外的节点
+        async fn leader(&self, excluded: &[u64]) -> u64 {
+            for _ in 0..200 {
+                let leaders: Vec<_> = self
+                    .nodes
+                    .iter()
+                    .filter(|n| !excluded.contains(&n.id()))
+                    .map(|n| n.status())
+                    .filter(|s| s.role == RaftRole::Leader)
+                    .collect();
+                if let [status] = leaders.as_slice() {
+                    return status.id;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("No leader is elected");
+        }
+
+        async fn wait_applied(&self, id: u64, index: u64) {
+            for _ in 0..200 {
+                if self.node(id).status().applied >= index {
+                    return;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("Node {} didn't apply {}", id, index);
+        }
+    }
+
+    fn config() -> RaftConfig {
+        RaftConfig::default()
+            .with_tick(Duration::from_millis(10))
+            .with_election_ticks(10, 2)
+            .with_request_timeout(Duration::from_secs(2))
+    }
+
+    async fn hset(node: &RaftNode, key: &str, value: impl Into<Value>) -> CommandResponse {
+        node.execute(CommandRequest::new_hset("t1", key, value.into()))
+            .await
+    }
+
+    async fn hget(node: &RaftNode, key: &str) -> CommandResponse {
+        node.execute(CommandRequest::new_hget("t1", key)).await
+    }
+
+    #[tokio::test]
+    async fn raft_cluster_should_replicate_writes() -> Result<()> {
+        let cluster = Cluster::new(config())?;
+        let leader = cluster.leader(&[]).await;
+        let follower = IDS.into_iter().find(|id| *id != leader).unwrap();
+
+        // follower 把写命令转发给 leader
+        let res = hset(cluster.node(follower), "k1", "v1").await;
+        assert_eq!(res.status, 200);
+        let res = hset(cluster.node(leader), "k1", "v2").await;
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        // 每个节点都能读到最新的值
+        for id in IDS {
+            let res = hget(cluster.node(id), "k1").await;
+            assert_eq!(res.values, vec!["v2".into()]);
+        }
+        let index = cluster.node(leader).status().commit;
+        for id in IDS {
+            cluster.wait_applied(id, index).await;
+        }
+
+        // 绕过 raft 直接写 service 会被拒绝
+        let res = cluster
+            .node(leader)
+            .inner
+            .service
+            .execute(CommandRequest::new_hset("t1", "k2", "v".into()));
+        assert_eq!(res.status, 403);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn raft_cluster_should_survive_leader_loss() -> Result<()> {
+        let cluster = Cluster::new(config())?;
+        let old = cluster.leader(&[]).await;
+        assert_eq!(hset(cluster.node(old), "k1", 1).await.status, 200);
+
+        // 旧 leader 和其它节点断开后选出新 leader，数据不会丢失
+        cluster.network.isolate(old);
+        let leader = cluster.leader(&[old]).await;
+        assert_ne!(leader, old);
+        assert_eq!(
+            hget(cluster.node(leader), "k1").await.values,
+            vec![1.into()]
+        );
+        assert_eq!(hset(cluster.node(leader), "k1", 2).await.status, 200);
+
+        // 被隔离的节点不能读到旧的数据，也不能写
+        assert_ne!(hget(cluster.node(old), "k1").await.status, 200);
+        assert_ne!(hset(cluster.node(old), "k2", 1).await.status, 200);
+
+        // 恢复之后追上新的数据
+        cluster.network.heal(old);
+        let res = hget(cluster.node(old), "k1").await;
+        assert_eq!(res.values, vec![2.into()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn raft_node_should_recover_from_log_and_snapshot() -> Result<()> {
+        let mut cluster = Cluster::new(config().with_snapshot_threshold(5))?;
+        let leader = cluster.leader(&[]).await;
+        let follower = IDS.into_iter().find(|id| *id != leader).unwrap();
+
+        // follower 停止期间的日志被压缩了，重启后通过快照追上
+        cluster.node(follower).stop().await;
+        for i in 0..12 {
+            let res = hset(cluster.node(leader), &format!("k{}", i), i).await;
+            assert_eq!(res.status, 200);
+        }
+        cluster.restart(follower).await?;
+        let index = cluster.node(leader).status().commit;
+        cluster.wait_applied(follower, index).await;
+        let res = cluster
+            .node(follower)
+            .inner
+            .service
+            .execute(CommandRequest::new_hget("t1", "k11"));
+        assert_eq!(res.values, vec![11.into()]);
+
+        // 所有节点重启之后数据还在
+        for id in IDS {
+            cluster.restart(id).await?;
+        }
+        cluster.leader(&[]).await;
+        for i in 0..12 {
+            let res = hget(cluster.node(follower), &format!("k{}", i)).await;
+            assert_eq!(res.values, vec![i.into()]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn raft_node_should_install_snapshot_in_chunks() -> Result<()> {
+        let cluster = Cluster::new(config().with_snapshot_threshold(5))?;
+        let leader = cluster.leader(&[]).await;
+        let follower = IDS.into_iter().find(|id| *id != leader).unwrap();
+
+        // 快照有多个 Hmset，分成多个消息发送给落后的 follower
+        cluster.network.isolate(follower);
+        for i in 0..6 {
+            let pairs = (0..500)
+                .map(|j| Kvpair::new(format!("k{}-{}", i, j), j.into()))
+                .collect();
+            let cmd = CommandRequest::new_hmset("t1", pairs);
+            assert_eq!(cluster.node(leader).execute(cmd).await.status, 200);
+        }
+        cluster.network.heal(follower);
+        let index = cluster.node(leader).status().commit;
+        cluster.wait_applied(follower, index).await;
+
+        let res = cluster
+            .node(follower)
+            .inner
+            .service
+            .execute(CommandRequest::new_hgetall("t1"));
+        assert_eq!(res.pairs.len(), 3000);
+        Ok(())
+    }
+}
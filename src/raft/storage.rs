@@ -0,0 +1,357 @@
+use std::path::Path;
+
+use prost::Message;
+
+use crate::{
+    CommandRequest, Hmset, InstallSnapshot, KvError, RaftEntry, RaftHardState, RaftSnapshotMeta,
+};
+
+const HARD_STATE_KEY: &str = "hard_state";
+const SNAPSHOT_KEY: &str = "snapshot";
+
+/// raft 日志、term / vote 和快照，保存在 sled 里
+///
+/// 日志的 key 是大端序的 index，这样 sled 里的顺序就是日志的顺序；
+/// 快照的每个 Hmset 单独保存，key 是大端序的 last_index 加上 offset，可以一部分一部分地读出来发送
+pub struct RaftStorage {
+    db: sled::Db,
+    log: sled::Tree,
+    chunks: sled::Tree,
+    // 快照之前的日志已经删除
+    snapshot_index: u64,
+    snapshot_term: u64,
+    last_index: u64,
+    last_term: u64,
+}
+
+impl RaftStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Self::new(sled::open(path)?)
+    }
+
+    pub fn new(db: sled::Db) -> Result<Self, KvError> {
+        let log = db.open_tree("raft_log")?;
+        let chunks = db.open_tree("raft_snapshot")?;
+        let mut storage = Self {
+            db,
+            log,
+            chunks,
+            snapshot_index: 0,
+            snapshot_term: 0,
+            last_index: 0,
+            last_term: 0,
+        };
+        if let Some(meta) = storage.snapshot_meta()? {
+            storage.snapshot_index = meta.last_index;
+            storage.snapshot_term = meta.last_term;
+        }
+        storage.reload_last()?;
+        Ok(storage)
+    }
+
+    /// 持久化的 term 和投票给的节点
+    pub fn hard_state(&self) -> Result<RaftHardState, KvError> {
+        match self.db.get(HARD_STATE_KEY)? {
+            Some(v) => Ok(RaftHardState::decode(v.as_ref())?),
+            None => Ok(RaftHardState::default()),
+        }
+    }
+
+    /// 投票之前必须先持久化，否则重启后可能在同一个 term 投两次票
+    pub fn set_hard_state(&self, state: &RaftHardState) -> Result<(), KvError> {
+        self.db.insert(HARD_STATE_KEY, state.encode_to_vec())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// 完整的快照
+    pub fn snapshot(&self) -> Result<Option<InstallSnapshot>, KvError> {
+        let meta = match self.snapshot_meta()? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+        let tables = self
+            .chunks
+            .scan_prefix(meta.last_index.to_be_bytes())
+            .values()
+            .take(meta.chunks as usize)
+            .map(|v| Ok(Hmset::decode(v?.as_ref())?))
+            .collect::<Result<_, KvError>>()?;
+        Ok(Some(InstallSnapshot {
+            last_index: meta.last_index,
+            last_term: meta.last_term,
+            tables,
+            offset: 0,
+            done: true,
+        }))
+    }
+
+    /// 快照在 offset 处的一部分，没有快照时返回 None
+    pub fn snapshot_chunk(&self, offset: u64) -> Result<Option<InstallSnapshot>, KvError> {
+        let meta = match self.snapshot_meta()? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+        let tables = match self.chunks.get(chunk_key(meta.last_index, offset))? {
+            Some(v) => vec![Hmset::decode(v.as_ref())?],
+            // 空的快照也要发送一次，follower 才知道快照的位置
+            None => vec![],
+        };
+        Ok(Some(InstallSnapshot {
+            last_index: meta.last_index,
+            last_term: meta.last_term,
+            tables,
+            offset,
+            done: offset + 1 >= meta.chunks,
+        }))
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.last_index
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.last_term
+    }
+
+    /// index 处日志的 term，日志不存在或者已经被压缩时返回 None
+    pub fn term(&self, index: u64) -> Result<Option<u64>, KvError> {
+        if index == self.snapshot_index {
+            return Ok(Some(self.snapshot_term));
+        }
+        if index < self.snapshot_index || index > self.last_index {
+            return Ok(None);
+        }
+        Ok(self.entry(index)?.map(|e| e.term))
+    }
+
+    pub fn entry(&self, index: u64) -> Result<Option<RaftEntry>, KvError> {
+        match self.log.get(index.to_be_bytes())? {
+            Some(v) => Ok(Some(RaftEntry::decode(v.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 从 from 开始最多 max 条日志
+    pub fn entries(&self, from: u64, max: usize) -> Result<Vec<RaftEntry>, KvError> {
+        self.log
+            .range(from.to_be_bytes()..)
+            .values()
+            .take(max)
+            .map(|v| Ok(RaftEntry::decode(v?.as_ref())?))
+            .collect()
+    }
+
+    /// 追加日志，和已有日志冲突时删除冲突的日志以及之后的所有日志
+    pub fn append(&mut self, entries: &[RaftEntry]) -> Result<(), KvError> {
+        let first = match entries.first() {
+            Some(entry) => entry.index,
+            None => return Ok(()),
+        };
+        if first <= self.last_index {
+            self.truncate(first)?;
+        }
+
+        let mut batch = sled::Batch::default();
+        for entry in entries {
+            batch.insert(&entry.index.to_be_bytes(), entry.encode_to_vec());
+        }
+        self.log.apply_batch(batch)?;
+        self.db.flush()?;
+        self.reload_last()
+    }
+
+    /// 保存快照，并删除快照包含的日志
+    pub fn save_snapshot(&mut self, snapshot: &InstallSnapshot) -> Result<(), KvError> {
+        // 之后的日志和快照不一致时全部删除
+        if self.term(snapshot.last_index)? != Some(snapshot.last_term) {
+            self.truncate(0)?;
+        }
+        // 先写新的数据，再切换到新的快照，最后删除旧的数据，中途崩溃也不会读到不完整的快照
+        let old = self.snapshot_meta()?;
+        let mut batch = sled::Batch::default();
+        for (offset, hmset) in snapshot.tables.iter().enumerate() {
+            batch.insert(
+                chunk_key(snapshot.last_index, offset as u64),
+                hmset.encode_to_vec(),
+            );
+        }
+        self.chunks.apply_batch(batch)?;
+        self.chunks.flush()?;
+        let meta = RaftSnapshotMeta {
+            last_index: snapshot.last_index,
+            last_term: snapshot.last_term,
+            chunks: snapshot.tables.len() as u64,
+        };
+        self.db.insert(SNAPSHOT_KEY, meta.encode_to_vec())?;
+        self.db.flush()?;
+
+        if let Some(old) = old.filter(|old| old.last_index != snapshot.last_index) {
+            for key in self.chunks.scan_prefix(old.last_index.to_be_bytes()).keys() {
+                self.chunks.remove(key?)?;
+            }
+        }
+        for key in self.log.range(..=snapshot.last_index.to_be_bytes()).keys() {
+            self.log.remove(key?)?;
+        }
+        self.db.flush()?;
+        self.snapshot_index = snapshot.last_index;
+        self.snapshot_term = snapshot.last_term;
+        self.reload_last()
+    }
+
+    fn snapshot_meta(&self) -> Result<Option<RaftSnapshotMeta>, KvError> {
+        match self.db.get(SNAPSHOT_KEY)? {
+            Some(v) => Ok(Some(RaftSnapshotMeta::decode(v.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    // 删除 from 及之后的日志
+    fn truncate(&mut self, from: u64) -> Result<(), KvError> {
+        for key in self.log.range(from.to_be_bytes()..).keys() {
+            self.log.remove(key?)?;
+        }
+        self.reload_last()
+    }
+
+    fn reload_last(&mut self) -> Result<(), KvError> {
+        (self.last_index, self.last_term) = match self.log.last()? {
+            Some((_, v)) => {
+                let entry = RaftEntry::decode(v.as_ref())?;
+                (entry.index, entry.term)
+            }
+            None => (self.snapshot_index, self.snapshot_term),
+        };
+        Ok(())
+    }
+}
+
+fn chunk_key(last_index: u64, offset: u64) -> Vec<u8> {
+    [last_index.to_be_bytes(), offset.to_be_bytes()].concat()
+}
+
+impl RaftEntry {
+    pub fn new(term: u64, index: u64, command: Option<CommandRequest>) -> Self {
+        Self {
+            term,
+            index,
+            command,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn entries(term: u64, range: std::ops::RangeInclusive<u64>) -> Vec<RaftEntry> {
+        range
+            .map(|i| RaftEntry::new(term, i, Some(CommandRequest::new_ping())))
+            .collect()
+    }
+
+    #[test]
+    fn raft_storage_should_append_and_truncate() -> Result<(), KvError> {
+        let dir = tempdir()?;
+        let db = sled::open(dir.path())?;
+        let mut storage = RaftStorage::new(db.clone())?;
+        storage.append(&entries(1, 1..=5))?;
+        assert_eq!((storage.last_index(), storage.last_term()), (5, 1));
+
+        // 冲突的日志被新 leader 的日志替换
+        storage.append(&entries(2, 3..=4))?;
+        assert_eq!((storage.last_index(), storage.last_term()), (4, 2));
+        assert_eq!(storage.term(2)?, Some(1));
+        assert_eq!(storage.term(5)?, None);
+        let terms: Vec<_> = storage.entries(2, 10)?.iter().map(|e| e.term).collect();
+        assert_eq!(terms, vec![1, 2, 2]);
+
+        let state = RaftHardState { term: 2, vote: 3 };
+        storage.set_hard_state(&state)?;
+        drop(storage);
+
+        // 重新加载之后数据还在；sled 在 drop 之后不会马上释放文件锁，所以复用同一个 db
+        let storage = RaftStorage::new(db)?;
+        assert_eq!(storage.hard_state()?, state);
+        assert_eq!(storage.last_index(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn raft_storage_should_compact_with_snapshot() -> Result<(), KvError> {
+        let dir = tempdir()?;
+        let db = sled::open(dir.path())?;
+        let mut storage = RaftStorage::new(db.clone())?;
+        storage.append(&entries(1, 1..=5))?;
+
+        let snapshot = InstallSnapshot {
+            last_index: 3,
+            last_term: 1,
+            ..Default::default()
+        };
+        storage.save_snapshot(&snapshot)?;
+        assert_eq!(storage.term(3)?, Some(1));
+        assert_eq!(storage.term(2)?, None);
+        assert_eq!(storage.entries(0, 10)?.len(), 2);
+        assert_eq!(storage.last_index(), 5);
+
+        // 快照比日志新并且不一致，日志全部删除
+        let snapshot = InstallSnapshot {
+            last_index: 8,
+            last_term: 3,
+            ..Default::default()
+        };
+        storage.save_snapshot(&snapshot)?;
+        assert_eq!((storage.last_index(), storage.last_term()), (8, 3));
+        assert!(storage.entries(0, 10)?.is_empty());
+        drop(storage);
+
+        let storage = RaftStorage::new(db)?;
+        assert_eq!(storage.snapshot_index(), 8);
+        assert_eq!(storage.snapshot()?.unwrap().last_term, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn raft_storage_should_read_snapshot_in_chunks() -> Result<(), KvError> {
+        let dir = tempdir()?;
+        let mut storage = RaftStorage::new(sled::open(dir.path())?)?;
+        assert!(storage.snapshot_chunk(0)?.is_none());
+
+        let tables: Vec<_> = (0..3)
+            .map(|i| Hmset {
+                table: format!("t{}", i),
+                pairs: vec![],
+            })
+            .collect();
+        storage.save_snapshot(&InstallSnapshot {
+            last_index: 3,
+            last_term: 1,
+            tables: tables.clone(),
+            ..Default::default()
+        })?;
+        let chunk = storage.snapshot_chunk(1)?.unwrap();
+        assert_eq!((chunk.last_index, chunk.offset, chunk.done), (3, 1, false));
+        assert_eq!(chunk.tables, vec![tables[1].clone()]);
+        assert!(storage.snapshot_chunk(2)?.unwrap().done);
+
+        // 新的快照替换旧的快照，旧的数据被删除
+        storage.save_snapshot(&InstallSnapshot {
+            last_index: 5,
+            last_term: 1,
+            tables: tables[..1].to_vec(),
+            ..Default::default()
+        })?;
+        assert_eq!(storage.snapshot()?.unwrap().tables, tables[..1].to_vec());
+        assert!(storage.snapshot_chunk(0)?.unwrap().done);
+        assert_eq!(storage.chunks.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+use tokio::sync::{mpsc, Mutex};
+use tracing::debug;
+
+use crate::{
+    CommandRequest, CommandResponse, KvConnector, KvError, KvPool, PoolConfig, RaftMessage,
+    RaftNode,
+};
+
+// 每个节点的发送队列长度，队列满了之后的消息直接丢掉，raft 会重试
+const SEND_QUEUE: usize = 1024;
+
+/// raft 节点之间的通信
+pub trait RaftTransport: Send + Sync + 'static {
+    /// 发送消息，不等待结果；丢失的消息由 raft 自己重试
+    fn send(&self, msg: RaftMessage);
+
+    /// 把客户端的命令转发给 leader 执行
+    fn forward(
+        &self,
+        to: u64,
+        cmd: CommandRequest,
+    ) -> BoxFuture<'static, Result<CommandResponse, KvError>>;
+}
+
+/// 同一个进程里的 raft 集群，用于测试；可以隔离节点来模拟网络分区
+#[derive(Clone, Default)]
+pub struct LocalNetwork {
+    inner: Arc<LocalInner>,
+}
+
+#[derive(Default)]
+struct LocalInner {
+    nodes: RwLock<HashMap<u64, RaftNode>>,
+    isolated: RwLock<HashSet<u64>>,
+}
+
+/// 节点 id 在 LocalNetwork 中使用的 transport
+pub struct LocalTransport {
+    id: u64,
+    network: LocalNetwork,
+}
+
+impl LocalNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 节点 id 使用的 transport
+    pub fn transport(&self, id: u64) -> Arc<dyn RaftTransport> {
+        Arc::new(LocalTransport {
+            id,
+            network: self.clone(),
+        })
+    }
+
+    /// 加入网络，同样 id 的旧节点会被替换
+    pub fn register(&self, node: RaftNode) {
+        let mut nodes = self.inner.nodes.write().unwrap();
+        nodes.insert(node.id(), node);
+    }
+
+    /// 断开节点和其它节点的连接
+    pub fn isolate(&self, id: u64) {
+        self.inner.isolated.write().unwrap().insert(id);
+    }
+
+    /// 恢复节点的连接
+    pub fn heal(&self, id: u64) {
+        self.inner.isolated.write().unwrap().remove(&id);
+    }
+
+    // from 能连接上 to 时返回 to
+    fn route(&self, from: u64, to: u64) -> Option<RaftNode> {
+        let isolated = self.inner.isolated.read().unwrap();
+        if isolated.contains(&from) || isolated.contains(&to) {
+            return None;
+        }
+        self.inner.nodes.read().unwrap().get(&to).cloned()
+    }
+}
+
+impl RaftTransport for LocalTransport {
+    fn send(&self, msg: RaftMessage) {
+        if let Some(node) = self.network.route(self.id, msg.to) {
+            node.step(msg);
+        }
+    }
+
+    fn forward(
+        &self,
+        to: u64,
+        cmd: CommandRequest,
+    ) -> BoxFuture<'static, Result<CommandResponse, KvError>> {
+        let node = self.network.route(self.id, to);
+        async move {
+            match node {
+                Some(node) => Ok(node.execute(cmd).await),
+                None => Err(KvError::Internal(format!(
+                    "Raft node {} is unreachable",
+                    to
+                ))),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// 通过 kv server 的连接和其它节点通信，raft 消息放在 `CommandRequest::Raft` 里发送
+#[derive(Clone, Default)]
+pub struct RemoteTransport {
+    peers: HashMap<u64, KvConnector>,
+    // 第一次使用时才建立连接池，其它节点可能还没有启动
+    pools: Arc<Mutex<HashMap<u64, KvPool>>>,
+    // 发给每个节点的 raft 消息放在同一个队列里，由一个 task 按顺序发送
+    queues: Arc<std::sync::Mutex<HashMap<u64, mpsc::Sender<RaftMessage>>>>,
+}
+
+impl RemoteTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加其它节点
+    pub fn with_peer(mut self, id: u64, connector: KvConnector) -> Self {
+        self.peers.insert(id, connector);
+        self
+    }
+
+    fn call(
+        &self,
+        to: u64,
+        cmd: CommandRequest,
+    ) -> BoxFuture<'static, Result<CommandResponse, KvError>> {
+        call_peer(to, self.peers.get(&to).cloned(), self.pools.clone(), cmd).boxed()
+    }
+}
+
+// 一个节点的发送队列，前一条消息发送完才发送下一条，消息不会乱序
+async fn send_queue(
+    to: u64,
+    connector: Option<KvConnector>,
+    pools: Arc<Mutex<HashMap<u64, KvPool>>>,
+    mut rx: mpsc::Receiver<RaftMessage>,
+) {
+    while let Some(msg) = rx.recv().await {
+        let cmd = CommandRequest::new_raft(msg);
+        if let Err(e) = call_peer(to, connector.clone(), pools.clone(), cmd).await {
+            debug!("Failed to send raft message to {}: {:?}", to, e);
+        }
+    }
+}
+
+async fn call_peer(
+    to: u64,
+    connector: Option<KvConnector>,
+    pools: Arc<Mutex<HashMap<u64, KvPool>>>,
+    cmd: CommandRequest,
+) -> Result<CommandResponse, KvError> {
+    let connector =
+        connector.ok_or_else(|| KvError::Internal(format!("Unknown raft node {}", to)))?;
+    let pool = {
+        let mut pools = pools.lock().await;
+        match pools.get(&to) {
+            Some(pool) => pool.clone(),
+            None => {
+                let config = PoolConfig::default().with_connections(0, 4);
+                let pool = KvPool::new(connector, config).await?;
+                pools.insert(to, pool.clone());
+                pool
+            }
+        }
+    };
+
+    let mut client = pool.get().await?;
+    match client.stream_mut().execute(cmd).await {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            client.discard();
+            Err(e)
+        }
+    }
+}
+
+impl RaftTransport for RemoteTransport {
+    fn send(&self, msg: RaftMessage) {
+        let to = msg.to;
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(to).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(SEND_QUEUE);
+            let connector = self.peers.get(&to).cloned();
+            tokio::spawn(send_queue(to, connector, self.pools.clone(), rx));
+            tx
+        });
+        if let Err(e) = queue.try_send(msg) {
+            debug!("Dropped raft message to {}: {:?}", to, e);
+        }
+    }
+
+    fn forward(
+        &self,
+        to: u64,
+        cmd: CommandRequest,
+    ) -> BoxFuture<'static, Result<CommandResponse, KvError>> {
+        self.call(to, cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use tokio::{net::TcpListener, time};
+
+    use super::*;
+    use crate::{
+        KvClient, MemTable, ProstServerStream, RaftConfig, RaftRole, RaftStorage, Service,
+        ServiceInner,
+    };
+
+    #[tokio::test]
+    async fn remote_transport_should_work() -> Result<()> {
+        let mut listeners = Vec::new();
+        let mut addrs = Vec::new();
+        for _ in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            addrs.push(listener.local_addr()?.to_string());
+            listeners.push(listener);
+        }
+
+        let config = RaftConfig::default()
+            .with_tick(Duration::from_millis(10))
+            .with_election_ticks(10, 2);
+        let dir = tempdir()?;
+        let mut nodes = Vec::new();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let id = i as u64 + 1;
+            let transport = addrs
+                .iter()
+                .enumerate()
+                .fold(RemoteTransport::new(), |t, (j, addr)| {
+                    t.with_peer(j as u64 + 1, KvConnector::new(addr))
+                });
+            let service: Service = ServiceInner::new(MemTable::new())
+                .with_read_only(true)
+                .into();
+            let storage = RaftStorage::open(dir.path().join(id.to_string()))?;
+            let node = RaftNode::start(
+                id,
+                vec![1, 2, 3],
+                config,
+                storage,
+                service.clone(),
+                Arc::new(transport),
+            )?;
+            nodes.push(node.clone());
+
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let stream = ProstServerStream::new(stream, service.clone());
+                    tokio::spawn(stream.with_raft_peer(node.clone()).process());
+                }
+            });
+        }
+
+        let mut leader = None;
+        for _ in 0..200 {
+            leader = nodes
+                .iter()
+                .find(|n| n.status().role == RaftRole::Leader)
+                .map(|n| n.id());
+            if leader.is_some() {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let leader = leader.expect("No leader is elected");
+
+        // 客户端连接任何一个节点都可以读写
+        for (i, addr) in addrs.iter().enumerate() {
+            let mut client = KvClient::connect(addr.clone()).await?;
+            let key = format!("k{}", i);
+            client.hset("t1", &key, i as i64).await?;
+            let value: i64 = client.hget("t1", &key).await?;
+            assert_eq!(value, i as i64);
+        }
+        let follower = nodes.iter().find(|n| n.id() != leader).unwrap();
+        let mut client = KvClient::connect(addrs[follower.id() as usize - 1].clone()).await?;
+        let value: i64 = client.hget("t1", "k0").await?;
+        assert_eq!(value, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn raft_messages_from_clients_should_be_rejected() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let dir = tempdir()?;
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_read_only(true)
+            .into();
+        let network = LocalNetwork::new();
+        let node = RaftNode::start(
+            1,
+            vec![1, 2],
+            RaftConfig::default(),
+            RaftStorage::open(dir.path())?,
+            service.clone(),
+            network.transport(1),
+        )?;
+        let term = node.status().term;
+
+        let client_node = node.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = ProstServerStream::new(stream, service).with_raft(client_node);
+            stream.process().await;
+        });
+
+        // 客户端端口上伪造一个更大的 term，不应该被节点接收
+        let mut client = KvClient::connect(addr).await?;
+        let msg = RaftMessage {
+            from: 2,
+            to: 1,
+            term: term + 100,
+            message: None,
+        };
+        let res = client.execute(CommandRequest::new_raft(msg)).await;
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+        assert!(node.status().term < term + 100);
+        Ok(())
+    }
+}
@@ -1,28 +1,515 @@
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+
 use anyhow::Result;
-use kv::{MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor};
+use futures::future::{self, BoxFuture, FutureExt};
+use kv::{
+    bind_unix, kv_service_server::KvServiceServer, AofConfig, EvictionPolicy, FsyncPolicy,
+    GrpcService, HttpGateway, KvConnector, MemChangeStore, MemTable, MemcachedServerStream,
+    MemcachedStore, MemoryStats, PeerCredAuthorizer, ProstServerStream, RaftConfig, RaftNode,
+    RaftStorage, RemoteTransport, Replica, RespServerStream, Service, ServiceInner, SlotMigrator,
+    SlotRange, StreamStats, TlsClientConnector, TlsServerAcceptor, WsStream,
+    DEFAULT_EXPIRY_INTERVAL, DEFAULT_REPLICATION_BACKLOG,
+};
 use tokio::net::TcpListener;
-use tracing::info;
+use tonic::transport::Server;
+use tracing::{info, warn};
+
+/// 默认监听的 TCP 地址，KV_ADDR 设为空字符串时不监听 TCP
+const DEFAULT_ADDR: &str = "127.0.0.1:9527";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let addr = "127.0.0.1:9527";
+    let addr = env::var("KV_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.into());
+
+    // 设置了 KV_REPLICA_OF 时作为只读副本运行，否则作为主节点记录复制日志
+    // 设置了 KV_RAFT_ID 时数据由 raft 管理，service 本身也是只读的
+    let primary = env::var("KV_REPLICA_OF").ok();
+    let raft_id = env::var("KV_RAFT_ID").ok();
+    if primary.is_some() && raft_id.is_some() {
+        anyhow::bail!("KV_REPLICA_OF and KV_RAFT_ID cannot be used together");
+    }
+    // 这些协议直接执行命令，不经过 raft，写入会失败，读到的也不一定是最新的数据
+    if raft_id.is_some() {
+        for var in [
+            "KV_RESP_ADDR",
+            "KV_HTTP_ADDR",
+            "KV_MEMCACHED_ADDR",
+            "KV_GRPC_ADDR",
+        ] {
+            if env::var(var).is_ok() {
+                anyhow::bail!("{} cannot be used together with KV_RAFT_ID", var);
+            }
+        }
+    }
+    let read_only = primary.is_some() || raft_id.is_some();
+    // 设置了 KV_AOF_DIR 时数据持久化到这个目录，启动时从快照和 AOF 恢复
+    let store = match env::var("KV_AOF_DIR") {
+        Ok(dir) => {
+            let mut config = AofConfig::default();
+            if let Ok(fsync) = env::var("KV_AOF_FSYNC") {
+                config = config.with_fsync(parse_fsync(&fsync)?);
+            }
+            if let Ok(secs) = env::var("KV_SNAPSHOT_INTERVAL") {
+                config = config.with_snapshot_interval(Duration::from_secs(secs.parse()?));
+            }
+            info!("Loading data from {} ({:?})", dir, config);
+            MemTable::open(dir, config)?
+        }
+        Err(_) => MemTable::new(),
+    };
+    // 设置了 KV_MAX_MEMORY 时限制内存使用，KV_EVICTION_POLICY 决定内存满了之后怎么处理
+    let store = match env::var("KV_MAX_MEMORY") {
+        Ok(max) => {
+            let policy = match env::var("KV_EVICTION_POLICY") {
+                Ok(policy) => parse_eviction(&policy)?,
+                Err(_) => EvictionPolicy::NoEviction,
+            };
+            let store = store.with_max_memory(max.parse()?, policy);
+            if let Some(stats) = store.memory_stats() {
+                tokio::spawn(report_memory(stats));
+            }
+            store
+        }
+        Err(_) => store,
+    };
+    let mut inner = ServiceInner::new(store).with_read_only(read_only);
+    if !read_only {
+        let backlog = match env::var("KV_REPLICATION_BACKLOG") {
+            Ok(v) => v.parse()?,
+            Err(_) => DEFAULT_REPLICATION_BACKLOG,
+        };
+        inner = inner.with_replication_log(backlog);
+    }
+    // 设置了 KV_CHANGE_LOG_CAPACITY 时在内存中保留最近的变更记录，可以用 subscribe 订阅
+    if let Ok(capacity) = env::var("KV_CHANGE_LOG_CAPACITY") {
+        inner = inner.with_change_log(MemChangeStore::new(capacity.parse()?))?;
+    }
+    // 设置了 KV_BACKUP_DIR 时可以在管理端口上用 backup 和 restore 命令备份和恢复数据
+    if let Ok(dir) = env::var("KV_BACKUP_DIR") {
+        inner = inner.with_backup_dir(dir);
+    }
+    // 设置了 KV_CLUSTER_SELF 时只处理分配给这个节点的 slot，其它 key 重定向到对应的节点
+    let cluster = env::var("KV_CLUSTER_SELF").ok();
+    if let Some(myself) = &cluster {
+        let ranges = parse_slots(&env::var("KV_CLUSTER_SLOTS")?)?;
+        info!("Cluster node {}, slots: {:?}", myself, ranges);
+        inner = inner.with_cluster(myself, &ranges);
+    }
+    let service: Service = inner.into();
+    if let Some(addr) = primary {
+        let ca_cert = include_str!("../fixtures/ca.cert");
+        let tls = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
+        let connector = KvConnector::new(addr.clone()).with_tls(tls);
+        info!("Replicating from {}", addr);
+        tokio::spawn(Replica::new(service.clone(), connector).run());
+    }
+    let raft = match raft_id {
+        Some(id) => Some(start_raft(id.parse()?, service.clone())?),
+        None => None,
+    };
+    let migrator = match cluster {
+        Some(_) => {
+            let ca_cert = include_str!("../fixtures/ca.cert");
+            let tls = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
+            Some(SlotMigrator::new(service.clone()).with_tls(tls))
+        }
+        None => None,
+    };
+    let stats = Arc::new(StreamStats::default());
 
+    // 每种监听方式都是一个一直运行的 future，任何一个出错服务器就退出
+    let mut servers: Vec<BoxFuture<Result<()>>> = Vec::new();
+    if !addr.is_empty() {
+        let tcp = serve_tcp(addr, service.clone(), stats.clone(), raft.clone());
+        servers.push(tcp.boxed());
+    }
+    if let Ok(path) = env::var("KV_UNIX_SOCKET") {
+        servers.push(serve_unix(path, service.clone(), stats.clone(), raft.clone()).boxed());
+    }
+    if let Ok(addr) = env::var("KV_RESP_ADDR") {
+        servers.push(serve_resp(addr, service.clone()).boxed());
+    }
+    if let Ok(addr) = env::var("KV_HTTP_ADDR") {
+        let gateway = HttpGateway::new(service.clone());
+        let listener = TcpListener::bind(&addr).await?;
+        servers.push(async move { Ok(gateway.serve(listener).await?) }.boxed());
+    }
+    if let Ok(addr) = env::var("KV_WS_ADDR") {
+        servers.push(serve_ws(addr, service.clone(), stats.clone(), raft.clone()).boxed());
+    }
+    if let Ok(addr) = env::var("KV_MEMCACHED_ADDR") {
+        let mut store = MemcachedStore::new(service.clone());
+        if let Ok(table) = env::var("KV_MEMCACHED_TABLE") {
+            store = store.with_table(table);
+        }
+        tokio::spawn(store.clone().run_expiry(DEFAULT_EXPIRY_INTERVAL));
+        servers.push(serve_memcached(addr, store).boxed());
+    }
+    // 修改 slot 分配、迁移 slot、备份和恢复的管理命令只在这个端口上接收，需要双向 TLS 认证；
+    // 集群模式下必须设置
+    let admin_addr = match &migrator {
+        Some(_) => Some(env::var("KV_CLUSTER_ADMIN_ADDR")?),
+        None => env::var("KV_CLUSTER_ADMIN_ADDR").ok(),
+    };
+    if let Some(addr) = admin_addr {
+        servers.push(serve_admin(addr, service.clone(), migrator).boxed());
+    }
+    // 节点之间的 raft 消息只在这个端口上接收，需要双向 TLS 认证
+    if let Some(node) = &raft {
+        let addr = env::var("KV_RAFT_ADDR")?;
+        servers.push(serve_raft(addr, service.clone(), node.clone()).boxed());
+    }
+    if let Ok(addr) = env::var("KV_GRPC_ADDR") {
+        let grpc = KvServiceServer::new(GrpcService::new(service));
+        let server = Server::builder().add_service(grpc).serve(addr.parse()?);
+        info!("Start listening on {} (gRPC)", addr);
+        servers.push(async { Ok(server.await?) }.boxed());
+    }
+    if servers.is_empty() {
+        anyhow::bail!("No listener is configured");
+    }
+    future::try_join_all(servers).await?;
+    Ok(())
+}
+
+// KV_RAFT_PEERS 是所有节点的 id 和 KV_RAFT_ADDR 地址，比如 1=127.0.0.1:9530,2=127.0.0.1:9531；
+// raft 日志和快照保存在 KV_RAFT_DIR 里
+fn start_raft(id: u64, service: Service) -> Result<RaftNode> {
+    let ca_cert = include_str!("../fixtures/ca.cert");
+    let client_cert = include_str!("../fixtures/client.cert");
+    let client_key = include_str!("../fixtures/client.key");
+    let identity = Some((client_cert, client_key));
+    let tls = TlsClientConnector::new("kvserver.acme.inc", identity, Some(ca_cert))?;
+
+    let mut peers = HashMap::new();
+    for peer in env::var("KV_RAFT_PEERS")?.split(',').map(str::trim) {
+        let (peer_id, addr) = peer
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid raft peer: {}", peer))?;
+        peers.insert(peer_id.parse::<u64>()?, addr.to_string());
+    }
+    let transport = peers
+        .iter()
+        .filter(|(peer_id, _)| **peer_id != id)
+        .fold(RemoteTransport::new(), |t, (peer_id, addr)| {
+            t.with_peer(*peer_id, KvConnector::new(addr).with_tls(tls.clone()))
+        });
+
+    let dir = env::var("KV_RAFT_DIR").unwrap_or_else(|_| format!("/tmp/kv-raft-{}", id));
+    let storage = RaftStorage::open(&dir)?;
+    info!("Start raft node {} in {}, peers: {:?}", id, dir, peers);
+    let ids = peers.into_keys().collect();
+    let node = RaftNode::start(
+        id,
+        ids,
+        RaftConfig::default(),
+        storage,
+        service,
+        Arc::new(transport),
+    )?;
+    Ok(node)
+}
+
+// KV_CLUSTER_SLOTS 是所有节点负责的 slot，比如 0-8191=127.0.0.1:9527,8192-16383=127.0.0.1:9528
+fn parse_slots(s: &str) -> Result<Vec<SlotRange>> {
+    let mut ranges = Vec::new();
+    for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (slots, addr) = item
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid cluster slots: {}", item))?;
+        let (start, end) = slots.split_once('-').unwrap_or((slots, slots));
+        ranges.push(SlotRange {
+            start: start.parse()?,
+            end: end.parse()?,
+            addr: addr.to_string(),
+        });
+    }
+    Ok(ranges)
+}
+
+// KV_AOF_FSYNC 是 always、everysec 或者 never
+fn parse_fsync(s: &str) -> Result<FsyncPolicy> {
+    match s {
+        "always" => Ok(FsyncPolicy::Always),
+        "everysec" => Ok(FsyncPolicy::EverySec),
+        "never" => Ok(FsyncPolicy::Never),
+        _ => anyhow::bail!("Invalid fsync policy: {}", s),
+    }
+}
+
+// KV_EVICTION_POLICY 是 noeviction、allkeys-lru 或者 allkeys-lfu
+fn parse_eviction(s: &str) -> Result<EvictionPolicy> {
+    match s {
+        "noeviction" => Ok(EvictionPolicy::NoEviction),
+        "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+        "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+        _ => anyhow::bail!("Invalid eviction policy: {}", s),
+    }
+}
+
+// 每分钟打印一次内存使用和淘汰的数量
+async fn report_memory(stats: Arc<MemoryStats>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        info!(
+            "Memory used: {} bytes, evicted keys: {}, rejected writes: {}",
+            stats.used(),
+            stats.evicted_keys(),
+            stats.rejected_writes()
+        );
+    }
+}
+
+async fn serve_tcp(
+    addr: String,
+    service: Service,
+    stats: Arc<StreamStats>,
+    raft: Option<RaftNode>,
+) -> Result<()> {
     let server_cert = include_str!("../fixtures/server.cert");
     let server_key = include_str!("../fixtures/server.key");
 
     let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
-    let service: Service = ServiceInner::new(MemTable::new()).into();
-    let listener = TcpListener::bind(addr).await?;
+    let listener = TcpListener::bind(&addr).await?;
     info!("Start listening on {}", addr);
     loop {
         let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept TCP connection: {:?}", e);
+                continue;
+            }
+        };
         info!("Client {:?} connected", addr);
-        let stream = tls.accept(stream).await?;
+        let svc = service.clone();
+        let stats = stats.clone();
+        let raft = raft.clone();
+        tokio::spawn(async move {
+            // TLS 握手失败只影响这一个连接
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to process TLS for {:?}: {:?}", addr, e);
+                    return;
+                }
+            };
+
+            let mut stream = ProstServerStream::new(stream, svc).with_stats(stats);
+            if let Some(node) = raft {
+                stream = stream.with_raft(node);
+            }
+            let reason = stream.process().await;
+            info!("Client {:?} disconnected: {:?}", addr, reason);
+        });
+    }
+}
+
+// 其它 raft 节点的连接，只接受 CA 签发的客户端证书
+async fn serve_raft(addr: String, service: Service, node: RaftNode) -> Result<()> {
+    let server_cert = include_str!("../fixtures/server.cert");
+    let server_key = include_str!("../fixtures/server.key");
+    let ca_cert = include_str!("../fixtures/ca.cert");
+
+    let acceptor = TlsServerAcceptor::new(server_cert, server_key, Some(ca_cert))?;
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Start listening on {} (raft peers)", addr);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept raft connection: {:?}", e);
+                continue;
+            }
+        };
+        let tls = acceptor.clone();
+        let svc = service.clone();
+        let node = node.clone();
+        tokio::spawn(async move {
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Rejected raft connection from {:?}: {:?}", addr, e);
+                    return;
+                }
+            };
+            info!("Raft peer {:?} connected", addr);
+            let reason = ProstServerStream::new(stream, svc)
+                .with_raft_peer(node)
+                .process()
+                .await;
+            info!("Raft peer {:?} disconnected: {:?}", addr, reason);
+        });
+    }
+}
+
+// 管理员的连接，只接受 CA 签发的客户端证书；集群模式下 MigrateSlot 由 migrator 处理
+async fn serve_admin(addr: String, service: Service, migrator: Option<SlotMigrator>) -> Result<()> {
+    let server_cert = include_str!("../fixtures/server.cert");
+    let server_key = include_str!("../fixtures/server.key");
+    let ca_cert = include_str!("../fixtures/ca.cert");
+
+    let acceptor = TlsServerAcceptor::new(server_cert, server_key, Some(ca_cert))?;
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Start listening on {} (admin)", addr);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept admin connection: {:?}", e);
+                continue;
+            }
+        };
+        let tls = acceptor.clone();
+        let svc = service.clone();
+        let migrator = migrator.clone();
+        tokio::spawn(async move {
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Rejected admin connection from {:?}: {:?}", addr, e);
+                    return;
+                }
+            };
+            info!("Admin {:?} connected", addr);
+            let mut server = ProstServerStream::new(stream, svc).with_admin();
+            if let Some(migrator) = migrator {
+                server = server.with_migrator(migrator);
+            }
+            let reason = server.process().await;
+            info!("Admin {:?} disconnected: {:?}", addr, reason);
+        });
+    }
+}
+
+// 本机的客户端走 Unix socket，不需要 TLS；KV_UNIX_ALLOW_UIDS 可以限制哪些用户能连接
+async fn serve_unix(
+    path: String,
+    service: Service,
+    stats: Arc<StreamStats>,
+    raft: Option<RaftNode>,
+) -> Result<()> {
+    let mut auth = PeerCredAuthorizer::new();
+    if let Ok(uids) = env::var("KV_UNIX_ALLOW_UIDS") {
+        for uid in uids.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            auth = auth.allow_uid(uid.parse()?);
+        }
+    }
+
+    let listener = bind_unix(&path)?;
+    info!("Start listening on unix:{}", path);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept Unix connection: {:?}", e);
+                continue;
+            }
+        };
+        let cred = match auth.authorize(&stream) {
+            Ok(cred) => cred,
+            Err(e) => {
+                warn!("Reject Unix connection: {:?}", e);
+                continue;
+            }
+        };
+        info!("Client {:?} connected", cred);
+        let svc = service.clone();
+        let stats = stats.clone();
+        let raft = raft.clone();
+        tokio::spawn(async move {
+            let mut stream = ProstServerStream::new(stream, svc).with_stats(stats);
+            if let Some(node) = raft {
+                stream = stream.with_raft(node);
+            }
+            let reason = stream.process().await;
+            info!("Client {:?} disconnected: {:?}", cred, reason);
+        });
+    }
+}
 
-        let stream = ProstServerStream::new(stream, service.clone());
+// 兼容 Redis 协议，redis-cli 等工具可以直接连接；和 Redis 一样不使用 TLS
+async fn serve_resp(addr: String, service: Service) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Start listening on {} (RESP)", addr);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept RESP connection: {:?}", e);
+                continue;
+            }
+        };
+        info!("Redis client {:?} connected", addr);
+        let svc = service.clone();
+        tokio::spawn(async move {
+            let reason = RespServerStream::new(stream, svc).process().await;
+            info!("Redis client {:?} disconnected: {:?}", addr, reason);
+        });
+    }
+}
 
-        tokio::spawn(async move { stream.process().await });
+// 兼容 memcached 文本协议，key 都在 KV_MEMCACHED_TABLE 指定的 table 里
+async fn serve_memcached(addr: String, store: MemcachedStore) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Start listening on {} (memcached)", addr);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept memcached connection: {:?}", e);
+                continue;
+            }
+        };
+        info!("Memcached client {:?} connected", addr);
+        let store = store.clone();
+        tokio::spawn(async move {
+            let reason = MemcachedServerStream::new(stream, store).process().await;
+            info!("Memcached client {:?} disconnected: {:?}", addr, reason);
+        });
+    }
+}
+
+// 浏览器通过 WebSocket 直接使用 frame 协议，binary message 当作字节流，按长度依次解析 frame
+async fn serve_ws(
+    addr: String,
+    service: Service,
+    stats: Arc<StreamStats>,
+    raft: Option<RaftNode>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Start listening on {} (WebSocket)", addr);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept WebSocket connection: {:?}", e);
+                continue;
+            }
+        };
+        let svc = service.clone();
+        let stats = stats.clone();
+        let raft = raft.clone();
+        tokio::spawn(async move {
+            let stream = match WsStream::accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(
+                        "Failed to process WebSocket handshake for {:?}: {:?}",
+                        addr, e
+                    );
+                    return;
+                }
+            };
+            info!("WebSocket client {:?} connected", addr);
+            let mut stream = ProstServerStream::new(stream, svc).with_stats(stats);
+            if let Some(node) = raft {
+                stream = stream.with_raft(node);
+            }
+            let reason = stream.process().await;
+            info!("WebSocket client {:?} disconnected: {:?}", addr, reason);
+        });
     }
 }
@@ -0,0 +1,501 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prost::Message;
+use tokio::sync::broadcast;
+
+use crate::{ChangeEvent, KvError, Kvpair, Storage, Value, WatchEvent};
+
+/// 内存中的变更日志默认保留的记录条数
+pub const DEFAULT_CHANGE_LOG_CAPACITY: usize = 100000;
+/// sled 中的变更日志默认保留的记录条数
+pub const DEFAULT_CHANGE_LOG_RETENTION: u64 = 1000000;
+// 新的变更记录最多缓存这么多条，订阅者落后更多时从存储里重新读取
+const CHANGE_CHANNEL_CAPACITY: usize = 1024;
+
+/// 变更记录的存储，seq 从 1 开始连续递增
+pub trait ChangeStore: Send + Sync + 'static {
+    /// 追加记录，记录的 seq 接在已有记录的后面
+    fn append(&self, changes: &[ChangeEvent]) -> Result<(), KvError>;
+    /// 从 seq 开始最多读取 limit 条记录
+    fn read(&self, seq: u64, limit: usize) -> Result<Vec<ChangeEvent>, KvError>;
+    /// 保留的第一条记录和最后一条记录的 seq，没有记录时为 None
+    fn range(&self) -> Result<Option<(u64, u64)>, KvError>;
+    /// 丢弃 seq 之前的记录
+    fn trim(&self, seq: u64) -> Result<(), KvError>;
+    /// 丢弃 seq 和之后的记录
+    fn truncate(&self, seq: u64) -> Result<(), KvError>;
+}
+
+/// 保存在内存中的变更日志，超过 capacity 之后丢弃最早的记录
+pub struct MemChangeStore {
+    capacity: usize,
+    changes: Mutex<VecDeque<ChangeEvent>>,
+}
+
+impl MemChangeStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            changes: Default::default(),
+        }
+    }
+}
+
+impl Default for MemChangeStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHANGE_LOG_CAPACITY)
+    }
+}
+
+impl ChangeStore for MemChangeStore {
+    fn append(&self, changes: &[ChangeEvent]) -> Result<(), KvError> {
+        let mut log = self.changes.lock().unwrap();
+        for change in changes {
+            if log.len() == self.capacity {
+                log.pop_front();
+            }
+            log.push_back(change.clone());
+        }
+        Ok(())
+    }
+
+    fn read(&self, seq: u64, limit: usize) -> Result<Vec<ChangeEvent>, KvError> {
+        let log = self.changes.lock().unwrap();
+        let first = log.front().map(|c| c.seq).unwrap_or_default();
+        let skip = seq.saturating_sub(first) as usize;
+        Ok(log.iter().skip(skip).take(limit).cloned().collect())
+    }
+
+    fn range(&self) -> Result<Option<(u64, u64)>, KvError> {
+        let log = self.changes.lock().unwrap();
+        Ok(log.front().zip(log.back()).map(|(f, b)| (f.seq, b.seq)))
+    }
+
+    fn trim(&self, seq: u64) -> Result<(), KvError> {
+        let mut log = self.changes.lock().unwrap();
+        while log.front().is_some_and(|c| c.seq < seq) {
+            log.pop_front();
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, seq: u64) -> Result<(), KvError> {
+        let mut log = self.changes.lock().unwrap();
+        while log.back().is_some_and(|c| c.seq >= seq) {
+            log.pop_back();
+        }
+        Ok(())
+    }
+}
+
+/// 保存在 sled tree 中的变更日志，key 是大端序的 seq，重启之后可以继续订阅
+///
+/// 默认最多保留 DEFAULT_CHANGE_LOG_RETENTION 条记录，不会一直占用磁盘
+pub struct SledChangeStore {
+    tree: sled::Tree,
+    // 最多保留的记录条数
+    retention: u64,
+}
+
+impl SledChangeStore {
+    pub fn new(tree: sled::Tree) -> Self {
+        Self {
+            tree,
+            retention: DEFAULT_CHANGE_LOG_RETENTION,
+        }
+    }
+
+    /// 最多保留 max 条记录，追加记录之后丢弃最早的记录
+    pub fn with_retention(mut self, max: u64) -> Self {
+        self.retention = max.max(1);
+        self
+    }
+}
+
+impl ChangeStore for SledChangeStore {
+    fn append(&self, changes: &[ChangeEvent]) -> Result<(), KvError> {
+        let mut batch = sled::Batch::default();
+        for change in changes {
+            batch.insert(&change.seq.to_be_bytes(), change.encode_to_vec());
+        }
+        self.tree.apply_batch(batch)?;
+        if let Some(last) = changes.last() {
+            self.trim((last.seq + 1).saturating_sub(self.retention))?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, seq: u64, limit: usize) -> Result<Vec<ChangeEvent>, KvError> {
+        self.tree
+            .range(seq.to_be_bytes()..)
+            .values()
+            .take(limit)
+            .map(|v| Ok(ChangeEvent::decode(v?.as_ref())?))
+            .collect()
+    }
+
+    fn range(&self) -> Result<Option<(u64, u64)>, KvError> {
+        let seq = |(k, _): (sled::IVec, sled::IVec)| -> Result<u64, KvError> {
+            let bytes = k
+                .as_ref()
+                .try_into()
+                .map_err(|_| KvError::Internal("Invalid key in change log".into()))?;
+            Ok(u64::from_be_bytes(bytes))
+        };
+        match (self.tree.first()?, self.tree.last()?) {
+            (Some(first), Some(last)) => Ok(Some((seq(first)?, seq(last)?))),
+            _ => Ok(None),
+        }
+    }
+
+    fn trim(&self, seq: u64) -> Result<(), KvError> {
+        self.remove(self.tree.range(..seq.to_be_bytes()).keys())
+    }
+
+    fn truncate(&self, seq: u64) -> Result<(), KvError> {
+        self.remove(self.tree.range(seq.to_be_bytes()..).keys())
+    }
+}
+
+impl SledChangeStore {
+    fn remove(&self, keys: impl Iterator<Item = sled::Result<sled::IVec>>) -> Result<(), KvError> {
+        let mut batch = sled::Batch::default();
+        for key in keys {
+            batch.remove(key?);
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+/// 变更日志：按顺序记录每个写命令修改的 key，以及修改前后的值
+///
+/// 写命令修改数据之前先写入记录（prepare），修改成功之后才让订阅者看到（commit），
+/// 修改失败时丢弃（abort）。进程在两步之间退出时，重启后用 recover 补上数据的修改
+pub struct ChangeLog {
+    store: Box<dyn ChangeStore>,
+    // 最后一条提交的记录的 seq
+    seq: Mutex<u64>,
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeLog {
+    pub fn new(store: impl ChangeStore) -> Result<Self, KvError> {
+        let seq = store.range()?.map(|(_, last)| last).unwrap_or_default();
+        Ok(Self {
+            store: Box::new(store),
+            seq: Mutex::new(seq),
+            sender: broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+        })
+    }
+
+    /// 最后一条记录的 seq
+    pub fn seq(&self) -> u64 {
+        *self.seq.lock().unwrap()
+    }
+
+    /// 重新应用最后一批记录里还没有写到 store 的修改
+    ///
+    /// 只有 store 里的值还是修改前的值时才会写入，所以已经写入的修改不会重复应用
+    pub(crate) fn recover(&self, store: &impl Storage) -> Result<(), KvError> {
+        let last = self.seq();
+        let batch = match self.store.read(last, 1)?.pop() {
+            Some(change) if change.seq == last => change.batch,
+            _ => return Ok(()),
+        };
+        // 这一批开头的记录可能已经被 trim 丢弃了
+        let tail = self.store.read(batch, (last + 1 - batch) as usize)?;
+        for change in tail.into_iter().filter(|c| c.batch == batch) {
+            if store.get(&change.table, &change.key)? != change.old_value {
+                continue;
+            }
+            match change.new_value {
+                Some(value) => store.set(&change.table, change.key, value)?,
+                None => store.del(&change.table, &change.key)?,
+            };
+        }
+        Ok(())
+    }
+
+    /// 写命令修改数据之前写入它会修改的 key，events 是写命令会修改的 key 和新的值，
+    /// 修改前的值从 store 中读取；调用者需要保证和数据的修改按相同的顺序调用
+    pub(crate) fn prepare(
+        &self,
+        events: &[WatchEvent],
+        store: &impl Storage,
+    ) -> Result<Vec<ChangeEvent>, KvError> {
+        // 同一个命令里重复的 key，修改前的值是前一次修改之后的值
+        let mut written: HashMap<(&str, &str), Option<Value>> = HashMap::new();
+        let mut changes = Vec::with_capacity(events.len());
+        for event in events {
+            let id = (event.table.as_str(), event.key.as_str());
+            let old = match written.get(&id) {
+                Some(value) => value.clone(),
+                None => store.get(&event.table, &event.key)?,
+            };
+            written.insert(id, event.value.clone());
+            // 删除不存在的 key 没有改变数据
+            if old.is_none() && event.value.is_none() {
+                continue;
+            }
+            changes.push(ChangeEvent {
+                table: event.table.clone(),
+                key: event.key.clone(),
+                old_value: old,
+                new_value: event.value.clone(),
+                ..Default::default()
+            });
+        }
+        self.write(&mut changes)?;
+        Ok(changes)
+    }
+
+    /// 数据修改成功，让订阅者看到 prepare 写入的记录
+    pub(crate) fn commit(&self, changes: &[ChangeEvent]) {
+        let mut seq = self.seq.lock().unwrap();
+        *seq += changes.len() as u64;
+        for change in changes {
+            // 没有订阅者时会出错，可以忽略
+            let _ = self.sender.send(change.clone());
+        }
+    }
+
+    /// 数据修改失败，丢弃 prepare 写入的记录
+    ///
+    /// 丢弃失败时返回错误：这些记录留在存储里，重启之后 recover 会把它们当作已经提交的修改
+    pub(crate) fn abort(&self) -> Result<(), KvError> {
+        self.store.truncate(self.seq() + 1)
+    }
+
+    /// 因为内存限制被淘汰的 key，记录为删除
+    pub(crate) fn append_evicted(&self, evicted: &[(String, Kvpair)]) -> Result<(), KvError> {
+        let mut changes: Vec<_> = evicted
+            .iter()
+            .map(|(table, pair)| ChangeEvent {
+                table: table.clone(),
+                key: pair.key.clone(),
+                old_value: pair.value.clone(),
+                ..Default::default()
+            })
+            .collect();
+        self.write(&mut changes)?;
+        self.commit(&changes);
+        Ok(())
+    }
+
+    /// 丢弃 seq 之前的记录，不会丢弃还没有提交的记录
+    pub fn trim(&self, seq: u64) -> Result<(), KvError> {
+        self.store.trim(seq.min(self.seq() + 1))
+    }
+
+    // 给记录分配 seq、批次和时间之后写入存储
+    fn write(&self, changes: &mut [ChangeEvent]) -> Result<(), KvError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let seq = self.seq();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        for (i, change) in changes.iter_mut().enumerate() {
+            change.seq = seq + i as u64 + 1;
+            change.batch = seq + 1;
+            change.timestamp = timestamp;
+        }
+        self.store.append(changes)
+    }
+
+    /// 订阅新的记录，返回的 receiver 会收到之后的所有记录
+    ///
+    /// 返回时 offset 之后已有的记录需要用 read 读取，它们和 receiver 收到的记录可能重叠
+    pub fn subscribe(
+        &self,
+        offset: u64,
+    ) -> Result<(u64, broadcast::Receiver<ChangeEvent>), KvError> {
+        let seq = self.seq.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        let first = match self.store.range()? {
+            Some((first, _)) => first,
+            None => *seq + 1,
+        };
+        // offset 超过最后一条记录，通常是内存中的日志在重启后重新开始了
+        if offset != 0 && (offset < first || offset > *seq + 1) {
+            return Err(KvError::InvalidOffset(offset, first, *seq + 1));
+        }
+        Ok((offset.max(first), receiver))
+    }
+
+    /// 从 seq 开始最多读取 limit 条记录，seq 之前的记录已经被丢弃时返回错误
+    pub fn read(&self, seq: u64, limit: usize) -> Result<Vec<ChangeEvent>, KvError> {
+        let last = self.seq();
+        let mut changes = self.store.read(seq, limit)?;
+        // 还没有提交的记录
+        changes.retain(|c| c.seq <= last);
+        match changes.first() {
+            Some(change) if change.seq != seq => {
+                Err(KvError::InvalidOffset(seq, change.seq, last + 1))
+            }
+            _ => Ok(changes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{dispatch, CommandRequest, MemTable, Value};
+
+    fn hset(log: &ChangeLog, store: &MemTable, key: &str, value: Value) {
+        let event = WatchEvent {
+            table: "t1".into(),
+            key: key.into(),
+            value: Some(value.clone()),
+        };
+        let changes = log.prepare(&[event], store).unwrap();
+        let cmd = CommandRequest::new_hset("t1", key, value);
+        assert_eq!(dispatch(cmd, store).status, 200);
+        log.commit(&changes);
+    }
+
+    #[test]
+    fn change_log_should_record_old_and_new_values() {
+        let log = ChangeLog::new(MemChangeStore::new(2)).unwrap();
+        let store = MemTable::new();
+        hset(&log, &store, "k1", 1.into());
+        hset(&log, &store, "k1", 2.into());
+        hset(&log, &store, "k2", 3.into());
+        assert_eq!(log.seq(), 3);
+
+        let changes = log.read(2, 10).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].seq, 2);
+        assert_eq!(changes[0].old_value, Some(1.into()));
+        assert_eq!(changes[0].new_value, Some(2.into()));
+        assert_eq!(changes[1].old_value, None);
+
+        // 第一条已经被丢弃了
+        assert!(matches!(
+            log.read(1, 10),
+            Err(KvError::InvalidOffset(1, 2, 4))
+        ));
+        assert!(matches!(
+            log.subscribe(1),
+            Err(KvError::InvalidOffset(1, 2, 4))
+        ));
+        assert!(matches!(
+            log.subscribe(5),
+            Err(KvError::InvalidOffset(5, 2, 4))
+        ));
+        assert_eq!(log.subscribe(0).unwrap().0, 2);
+        assert_eq!(log.subscribe(4).unwrap().0, 4);
+    }
+
+    #[test]
+    fn sled_change_store_should_survive_restart() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        let store = MemTable::new();
+        {
+            let log = ChangeLog::new(SledChangeStore::new(db.open_tree("changes").unwrap()));
+            let log = log.unwrap();
+            for i in 0..3 {
+                hset(&log, &store, &format!("k{}", i), i.into());
+            }
+        }
+
+        let log = ChangeLog::new(SledChangeStore::new(db.open_tree("changes").unwrap()));
+        let log = log.unwrap();
+        assert_eq!(log.seq(), 3);
+        hset(&log, &store, "k0", 10.into());
+        let changes = log.read(3, 10).unwrap();
+        let seqs: Vec<_> = changes.iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, vec![3, 4]);
+        assert_eq!(changes[1].old_value, Some(0.into()));
+    }
+
+    #[test]
+    fn recover_should_apply_prepared_changes_once() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        let store = MemTable::new();
+        {
+            let log = ChangeLog::new(SledChangeStore::new(db.open_tree("changes").unwrap()));
+            let log = log.unwrap();
+            hset(&log, &store, "k1", 1.into());
+            // 写入记录之后进程退出了，数据没有修改
+            let event = WatchEvent::new("t1", "k1", 2.into());
+            log.prepare(&[event], &store).unwrap();
+            assert_eq!(log.read(1, 10).unwrap().len(), 1);
+        }
+
+        let log = ChangeLog::new(SledChangeStore::new(db.open_tree("changes").unwrap()));
+        let log = log.unwrap();
+        log.recover(&store).unwrap();
+        assert_eq!(log.seq(), 2);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(2.into()));
+
+        // 已经应用过的修改不会覆盖之后的数据
+        store.set("t1", "k1".into(), 3.into()).unwrap();
+        log.recover(&store).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(3.into()));
+    }
+
+    #[test]
+    fn recover_should_only_apply_the_last_batch() {
+        let log = ChangeLog::new(MemChangeStore::default()).unwrap();
+        let store = MemTable::new();
+        hset(&log, &store, "k1", 1.into());
+        // 之后 k1 被其它方式改回了原来的值，同一毫秒内的前一批记录不能再应用
+        store.del("t1", "k1").unwrap();
+        let events = [
+            WatchEvent::new("t1", "k2", 2.into()),
+            WatchEvent::new("t1", "k3", 3.into()),
+        ];
+        let changes = log.prepare(&events, &store).unwrap();
+        assert!(changes.iter().all(|c| c.batch == 2));
+        log.commit(&changes);
+
+        log.recover(&store).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some(2.into()));
+        assert_eq!(store.get("t1", "k3").unwrap(), Some(3.into()));
+    }
+
+    #[test]
+    fn change_log_should_trim_and_keep_retention() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        let tree = db.open_tree("changes").unwrap();
+        assert_eq!(
+            SledChangeStore::new(tree.clone()).retention,
+            DEFAULT_CHANGE_LOG_RETENTION
+        );
+        let log = ChangeLog::new(SledChangeStore::new(tree).with_retention(3)).unwrap();
+        let store = MemTable::new();
+        for i in 0..5 {
+            hset(&log, &store, &format!("k{}", i), i.into());
+        }
+        assert_eq!(log.store.range().unwrap(), Some((3, 5)));
+
+        log.trim(5).unwrap();
+        assert!(matches!(
+            log.read(4, 10),
+            Err(KvError::InvalidOffset(4, 5, 6))
+        ));
+        // 失败的写命令的记录会被丢弃
+        let event = WatchEvent::new("t1", "k9", 9.into());
+        log.prepare(&[event], &store).unwrap();
+        assert_eq!(log.store.range().unwrap(), Some((5, 6)));
+        assert_eq!(log.read(5, 10).unwrap().len(), 1);
+        log.abort().unwrap();
+        assert_eq!(log.store.range().unwrap(), Some((5, 5)));
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, Kvpair, SlotRange,
+    SlotState, Storage,
+};
+
+/// (table, key) 空间被分成的 slot 数量
+pub const SLOTS: u32 = 16384;
+
+/// (table, key) 所在的 slot
+pub fn key_slot(table: &str, key: &str) -> u32 {
+    (key_hash(table, key) % SLOTS as u64) as u32
+}
+
+pub(crate) fn key_hash(table: &str, key: &str) -> u64 {
+    let mut buf = Vec::with_capacity(table.len() + key.len() + 1);
+    buf.extend_from_slice(table.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(key.as_bytes());
+    xxh3_64(&buf)
+}
+
+/// 集群模式下这个节点负责的 slot，以及正在迁移的 slot
+///
+/// 不属于这个节点的 key 返回 MOVED；迁移中的 slot，不在本地的 key 返回 ASK，
+/// 正在搬运的 key 返回 TRYAGAIN
+pub struct ClusterSlotMap {
+    myself: String,
+    // 执行命令时持有读锁，修改 slot 状态和搬运 key 时持有写锁
+    inner: RwLock<SlotMapInner>,
+}
+
+struct SlotMapInner {
+    // 每个 slot 所在节点的地址，空字符串表示还没有分配
+    owners: Vec<Arc<str>>,
+    // 迁移中的 slot 和对方的地址
+    migrating: HashMap<u32, String>,
+    importing: HashMap<u32, String>,
+    // 正在搬运到目标节点的 key
+    moving: HashSet<(String, String)>,
+    // 迁移中的 slot 还没有搬运的 key，第一次搬运时扫描得到
+    pending: HashMap<u32, Vec<(String, String)>>,
+}
+
+impl ClusterSlotMap {
+    /// myself 是这个节点对外的地址，ranges 是集群所有节点的 slot 分配
+    pub fn new(myself: impl Into<String>, ranges: &[SlotRange]) -> Self {
+        let mut owners = vec![Arc::<str>::from(""); SLOTS as usize];
+        for range in ranges {
+            let addr: Arc<str> = range.addr.as_str().into();
+            let end = range.end.min(SLOTS - 1);
+            for slot in range.start..=end {
+                owners[slot as usize] = addr.clone();
+            }
+        }
+        Self {
+            myself: myself.into(),
+            inner: RwLock::new(SlotMapInner {
+                owners,
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+                moving: HashSet::new(),
+                pending: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn myself(&self) -> &str {
+        &self.myself
+    }
+
+    /// 合并成连续区间的 slot 分配，没有分配的 slot 不包含在内
+    pub fn ranges(&self) -> Vec<SlotRange> {
+        let inner = self.inner.read().unwrap();
+        let mut ranges: Vec<SlotRange> = Vec::new();
+        for (slot, owner) in inner.owners.iter().enumerate() {
+            let slot = slot as u32;
+            match ranges.last_mut() {
+                Some(range) if range.end + 1 == slot && range.addr == **owner => range.end = slot,
+                _ if owner.is_empty() => {}
+                _ => ranges.push(SlotRange {
+                    start: slot,
+                    end: slot,
+                    addr: owner.to_string(),
+                }),
+            }
+        }
+        ranges
+    }
+
+    /// 修改 slot 的状态
+    pub fn set_slot(&self, slot: u32, state: SlotState, addr: String) -> Result<(), KvError> {
+        if slot >= SLOTS {
+            return Err(KvError::InvalidCommand(format!("Invalid slot {}", slot)));
+        }
+        let mut inner = self.inner.write().unwrap();
+        match state {
+            SlotState::Stable => {
+                inner.owners[slot as usize] = addr.into();
+                inner.migrating.remove(&slot);
+                inner.importing.remove(&slot);
+                inner.pending.remove(&slot);
+            }
+            SlotState::Migrating => {
+                if *inner.owners[slot as usize] != self.myself {
+                    return Err(KvError::InvalidCommand(format!(
+                        "Slot {} is not served by this node",
+                        slot
+                    )));
+                }
+                inner.migrating.insert(slot, addr);
+                inner.pending.remove(&slot);
+            }
+            SlotState::Importing => {
+                inner.importing.insert(slot, addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// 检查 cmd 是否应该由这个节点执行，可以执行时调用 f
+    ///
+    /// asking 表示客户端收到 ASK 之后发来的命令
+    pub(crate) fn execute(
+        &self,
+        cmd: CommandRequest,
+        asking: bool,
+        store: &impl Storage,
+        f: impl FnOnce(CommandRequest) -> CommandResponse,
+    ) -> CommandResponse {
+        let (table, keys) = match cmd.request_data.as_ref().and_then(keys) {
+            Some(v) => v,
+            // 不涉及 key 的命令，比如 hgetall 只返回这个节点上的数据
+            None => return f(cmd),
+        };
+        let slot = match same_slot(&table, &keys) {
+            Ok(slot) => slot,
+            Err(e) => return e.into(),
+        };
+
+        // 持有读锁执行，避免执行过程中 slot 的状态改变或者 key 被搬走
+        let inner = self.inner.read().unwrap();
+        if let Some(target) = inner.migrating.get(&slot) {
+            if keys
+                .iter()
+                .any(|k| inner.moving.contains(&(table.clone(), k.clone())))
+            {
+                return KvError::TryAgain(slot).into();
+            }
+            // 迁移过程中，已经搬走的 key 和新的 key 都在目标节点上
+            let present = keys
+                .iter()
+                .filter(|k| store.contains(&table, k).unwrap_or(false))
+                .count();
+            return match present {
+                0 => KvError::Ask(slot, target.clone()).into(),
+                n if n == keys.len() => f(cmd),
+                _ => KvError::TryAgain(slot).into(),
+            };
+        }
+
+        let owner = &inner.owners[slot as usize];
+        if **owner == self.myself || (asking && inner.importing.contains_key(&slot)) {
+            f(cmd)
+        } else if owner.is_empty() {
+            KvError::Internal(format!("Slot {} is not assigned", slot)).into()
+        } else {
+            KvError::Moved(slot, owner.to_string()).into()
+        }
+    }
+
+    /// 取出 slot 中最多 count 个 key 并标记为正在搬运，搬运期间这些 key 的请求返回 TRYAGAIN
+    ///
+    /// 迁移开始之后 slot 里不会有新的 key，第一次调用时扫描一次得到所有要搬运的 key，
+    /// 之后每次从中取出一批；扫描和读取 value 时都不持有锁
+    pub(crate) fn start_moving(
+        &self,
+        slot: u32,
+        count: usize,
+        store: &impl Storage,
+    ) -> Result<Vec<(String, Kvpair)>, KvError> {
+        let scanned = {
+            let inner = self.inner.read().unwrap();
+            if !inner.migrating.contains_key(&slot) {
+                return Err(not_migrating(slot));
+            }
+            inner.pending.contains_key(&slot)
+        };
+        if !scanned {
+            let mut keys = Vec::new();
+            for table in store.tables()? {
+                for pair in store.get_iter(&table)? {
+                    if key_slot(&table, &pair.key) == slot {
+                        keys.push((table.clone(), pair.key));
+                    }
+                }
+            }
+            self.inner
+                .write()
+                .unwrap()
+                .pending
+                .entry(slot)
+                .or_insert(keys);
+        }
+
+        loop {
+            let keys = {
+                let mut inner = self.inner.write().unwrap();
+                if !inner.migrating.contains_key(&slot) {
+                    return Err(not_migrating(slot));
+                }
+                let pending = inner.pending.entry(slot).or_default();
+                let keys = pending.split_off(pending.len().saturating_sub(count));
+                inner.moving.extend(keys.iter().cloned());
+                keys
+            };
+            if keys.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            // 这些 key 的请求已经返回 TRYAGAIN，value 不会再改变
+            let values: Result<Vec<_>, _> = keys.iter().map(|(t, k)| store.get(t, k)).collect();
+            let values = match values {
+                Ok(values) => values,
+                Err(e) => {
+                    self.finish_moving(keys, false);
+                    return Err(e);
+                }
+            };
+            let mut batch = Vec::new();
+            let mut deleted = Vec::new();
+            for ((table, key), value) in keys.into_iter().zip(values) {
+                match value {
+                    Some(value) => batch.push((table, Kvpair::new(key, value))),
+                    None => deleted.push((table, key)),
+                }
+            }
+            // 扫描之后被删除的 key 不需要搬运
+            self.finish_moving(deleted, true);
+            if !batch.is_empty() {
+                return Ok(batch);
+            }
+        }
+    }
+
+    /// 搬运结束，不管成功与否都要调用，之后 key 的请求按是否在本地处理；
+    /// 没有搬走的 key 放回去，下次重新搬运
+    pub(crate) fn finish_moving(
+        &self,
+        keys: impl IntoIterator<Item = (String, String)>,
+        moved: bool,
+    ) {
+        let mut inner = self.inner.write().unwrap();
+        for key in keys {
+            inner.moving.remove(&key);
+            if !moved {
+                let slot = key_slot(&key.0, &key.1);
+                if let Some(pending) = inner.pending.get_mut(&slot) {
+                    pending.push(key);
+                }
+            }
+        }
+    }
+}
+
+fn not_migrating(slot: u32) -> KvError {
+    KvError::InvalidCommand(format!("Slot {} is not migrating", slot))
+}
+
+// 命令涉及的 table 和 key
+fn keys(data: &RequestData) -> Option<(String, Vec<String>)> {
+    let (table, keys) = match data {
+        RequestData::Hget(v) => (&v.table, vec![v.key.clone()]),
+        RequestData::Hdel(v) => (&v.table, vec![v.key.clone()]),
+        RequestData::Hexist(v) => (&v.table, vec![v.key.clone()]),
+        RequestData::Hset(v) => (&v.table, v.pair.iter().map(|p| p.key.clone()).collect()),
+        RequestData::Hmget(v) => (&v.table, v.keys.clone()),
+        RequestData::Hmdel(v) => (&v.table, v.keys.clone()),
+        RequestData::Hmexist(v) => (&v.table, v.keys.clone()),
+        RequestData::Hmset(v) => (&v.table, v.pairs.iter().map(|p| p.key.clone()).collect()),
+        _ => return None,
+    };
+    Some((table.clone(), keys))
+}
+
+// 多 key 的命令要求所有 key 在同一个 slot，客户端需要按 slot 拆开
+fn same_slot(table: &str, keys: &[String]) -> Result<u32, KvError> {
+    let mut slots = keys.iter().map(|k| key_slot(table, k));
+    let slot = slots.next().unwrap_or_else(|| key_slot(table, ""));
+    match slots.all(|s| s == slot) {
+        true => Ok(slot),
+        false => Err(KvError::InvalidCommand(
+            "Keys in request don't hash to the same slot".into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, Value};
+
+    fn ok(_: CommandRequest) -> CommandResponse {
+        CommandResponse::ok()
+    }
+
+    fn keys_in_slot(slot: u32, n: usize) -> Vec<String> {
+        (0..)
+            .map(|i| format!("key{}", i))
+            .filter(|k| key_slot("t1", k) == slot)
+            .take(n)
+            .collect()
+    }
+
+    #[test]
+    fn slot_map_should_redirect_keys() {
+        let ranges = [
+            SlotRange {
+                start: 0,
+                end: 8191,
+                addr: "n1".into(),
+            },
+            SlotRange {
+                start: 8192,
+                end: SLOTS - 1,
+                addr: "n2".into(),
+            },
+        ];
+        let map = ClusterSlotMap::new("n1", &ranges);
+        assert_eq!(map.ranges(), ranges);
+        let store = MemTable::new();
+
+        let local = keys_in_slot(1, 2);
+        let remote = keys_in_slot(9000, 1);
+        let res = map.execute(CommandRequest::new_hget("t1", &local[0]), false, &store, ok);
+        assert_eq!(res.status, 200);
+        let res = map.execute(
+            CommandRequest::new_hget("t1", &remote[0]),
+            false,
+            &store,
+            ok,
+        );
+        assert_eq!(res.status, 308);
+        assert_eq!(res.redirect.unwrap().addr, "n2");
+
+        // 多个 key 必须在同一个 slot
+        let res = map.execute(
+            CommandRequest::new_hmget("t1", local.clone()),
+            false,
+            &store,
+            ok,
+        );
+        assert_eq!(res.status, 200);
+        let keys = vec![local[0].clone(), remote[0].clone()];
+        let res = map.execute(CommandRequest::new_hmget("t1", keys), false, &store, ok);
+        assert_eq!(res.status, 400);
+
+        // 导入中的 slot 只接受 asking 的命令
+        map.set_slot(9000, SlotState::Importing, "n2".into())
+            .unwrap();
+        let cmd = CommandRequest::new_hget("t1", &remote[0]);
+        assert_eq!(map.execute(cmd.clone(), false, &store, ok).status, 308);
+        assert_eq!(map.execute(cmd, true, &store, ok).status, 200);
+        map.set_slot(9000, SlotState::Stable, "n1".into()).unwrap();
+        let cmd = CommandRequest::new_hget("t1", &remote[0]);
+        assert_eq!(map.execute(cmd, false, &store, ok).status, 200);
+        assert_eq!(map.ranges().len(), 4);
+    }
+
+    #[test]
+    fn slot_map_should_ask_for_moved_keys() {
+        let ranges = [SlotRange {
+            start: 0,
+            end: SLOTS - 1,
+            addr: "n1".into(),
+        }];
+        let map = ClusterSlotMap::new("n1", &ranges);
+        let store = MemTable::new();
+        let keys = keys_in_slot(7, 3);
+        for key in &keys {
+            store.set("t1", key.clone(), Value::from(1)).unwrap();
+        }
+
+        map.set_slot(7, SlotState::Migrating, "n2".into()).unwrap();
+        let batch = map.start_moving(7, 2, &store).unwrap();
+        assert_eq!(batch.len(), 2);
+
+        // 正在搬运的 key 需要重试，还在本地的 key 直接执行
+        let moving = batch[0].1.key.clone();
+        let res = map.execute(CommandRequest::new_hget("t1", &moving), false, &store, ok);
+        assert_eq!(res.status, 503);
+        let rest = keys
+            .iter()
+            .find(|k| batch.iter().all(|(_, p)| &p.key != *k));
+        let res = map.execute(
+            CommandRequest::new_hget("t1", rest.unwrap()),
+            false,
+            &store,
+            ok,
+        );
+        assert_eq!(res.status, 200);
+
+        // 搬走之后去目标节点
+        for (table, pair) in &batch {
+            store.del(table, &pair.key).unwrap();
+        }
+        map.finish_moving(batch.into_iter().map(|(t, p)| (t, p.key)), true);
+        let res = map.execute(CommandRequest::new_hget("t1", &moving), false, &store, ok);
+        assert_eq!(res.status, 307);
+        assert_eq!(res.redirect.unwrap().addr, "n2");
+    }
+
+    #[test]
+    fn start_moving_should_resume_from_pending_keys() {
+        let ranges = [SlotRange {
+            start: 0,
+            end: SLOTS - 1,
+            addr: "n1".into(),
+        }];
+        let map = ClusterSlotMap::new("n1", &ranges);
+        let store = MemTable::new();
+        let keys = keys_in_slot(7, 5);
+        for key in &keys {
+            store.set("t1", key.clone(), Value::from(1)).unwrap();
+        }
+        map.set_slot(7, SlotState::Migrating, "n2".into()).unwrap();
+        let first = map.start_moving(7, 2, &store).unwrap();
+
+        // 扫描之后删除的 key 会被跳过，搬运失败的 key 下次重新搬运
+        let second = map.start_moving(7, 2, &store).unwrap();
+        store.del("t1", &second[0].1.key).unwrap();
+        map.finish_moving(first.iter().map(|(t, p)| (t.clone(), p.key.clone())), true);
+        map.finish_moving(
+            second.iter().map(|(t, p)| (t.clone(), p.key.clone())),
+            false,
+        );
+
+        let mut rest = Vec::new();
+        loop {
+            let batch = map.start_moving(7, 2, &store).unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            rest.extend(batch.iter().map(|(_, p)| p.key.clone()));
+            map.finish_moving(batch.into_iter().map(|(t, p)| (t, p.key)), true);
+        }
+        rest.sort();
+        let mut expected: Vec<_> = keys
+            .into_iter()
+            .filter(|k| first.iter().chain(&second[..1]).all(|(_, p)| &p.key != k))
+            .collect();
+        expected.sort();
+        assert_eq!(rest, expected);
+    }
+}
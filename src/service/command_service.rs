@@ -49,17 +49,22 @@ impl CommandService for Hmset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         let pairs = self.pairs;
         let table = self.table;
-        pairs
+        // 先为所有的 key 腾出内存，内存不足时一个 key 都不写入
+        if let Err(e) = store.reserve(&table, &pairs) {
+            return e.into();
+        }
+        // 存储出错时返回错误，之前的 key 已经写入了
+        let result: Result<Vec<_>, _> = pairs
             .into_iter()
             .map(|pair| {
                 let result = store.set(&table, pair.key, pair.value.unwrap_or_default());
-                match result {
-                    Ok(Some(v)) => v,
-                    _ => Value::default(),
-                }
+                result.map(Option::unwrap_or_default)
             })
-            .collect::<Vec<_>>()
-            .into()
+            .collect();
+        match result {
+            Ok(values) => values.into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
@@ -228,6 +233,28 @@ mod tests {
         assert_res_ok(res, &[true.into(), false.into()], &[]);
     }
 
+    #[test]
+    fn hmset_out_of_memory_should_write_nothing() {
+        // 每个 key 是 2 字节，value 编码之后是 4 字节，最多放下 4 个
+        let store = MemTable::new().with_max_memory(4 * 70, EvictionPolicy::NoEviction);
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+
+        let pairs = vec![
+            Kvpair::new("u1", "v3".into()),
+            Kvpair::new("u3", "v3".into()),
+        ];
+        let res = dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+        assert_eq!(res.status, 200);
+
+        let pairs = vec![
+            Kvpair::new("u4", "v4".into()),
+            Kvpair::new("u5", "v5".into()),
+        ];
+        let res = dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+        assert_res_error(res, 507, "Memory limit");
+        assert!(!store.contains("t1", "u4").unwrap());
+    }
+
     // 测试成功返回的结果
     fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
         res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
@@ -1,8 +1,23 @@
+mod change_log;
+mod cluster;
 mod command_service;
-
-use crate::{command_request::RequestData, *};
-use std::sync::Arc;
-use tracing::debug;
+mod replication;
+
+use crate::{command_request::RequestData, replication_entry::Entry, *};
+pub use change_log::*;
+pub use cluster::*;
+pub use replication::*;
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// 每个 watcher 最多缓存的变化通知，处理不过来的 watcher 会丢失通知
+const WATCH_CAPACITY: usize = 1024;
 
 // 对 Command 的处理的抽象
 pub trait CommandService {
@@ -18,19 +33,74 @@ pub struct ServiceInner<Store> {
     on_executed: Vec<fn(&CommandResponse)>,
     on_before_send: Vec<fn(&mut CommandResponse)>,
     on_after_send: Vec<fn()>,
+    watcher: broadcast::Sender<WatchEvent>,
+    replication: Option<ReplicationLog>,
+    read_only: bool,
+    cluster: Option<ClusterSlotMap>,
+    change_log: Option<ChangeLog>,
+    backup_dir: Option<PathBuf>,
+    // 写命令持有读锁，备份和恢复持有写锁，这样备份的数据是同一时刻的
+    writes: RwLock<()>,
+    // 有复制日志、变更日志或者 watcher 时，写命令在这个锁里执行、记录并通知 watcher，
+    // 日志和通知的顺序和数据修改的顺序一致
+    order: Mutex<()>,
 }
 
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
+        // 开始记录淘汰的 key，它们会和删除一样被复制和通知；不支持的存储返回空
+        let _ = store.take_evicted();
         Self {
             store,
             on_received: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            watcher: broadcast::channel(WATCH_CAPACITY).0,
+            replication: None,
+            read_only: false,
+            cluster: None,
+            change_log: None,
+            backup_dir: None,
+            writes: RwLock::new(()),
+            order: Mutex::new(()),
         }
     }
 
+    /// 记录写命令，副本可以从这个节点复制数据
+    pub fn with_replication_log(mut self, backlog: usize) -> Self {
+        self.replication = Some(ReplicationLog::new(backlog));
+        self
+    }
+
+    /// 拒绝客户端的写命令，副本只能通过复制修改数据
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// 开启集群模式，myself 是这个节点对外的地址，ranges 是所有节点的 slot 分配
+    pub fn with_cluster(mut self, myself: impl Into<String>, ranges: &[SlotRange]) -> Self {
+        self.cluster = Some(ClusterSlotMap::new(myself, ranges));
+        self
+    }
+
+    /// 记录每个 key 的修改，消费者可以从任意保留的 offset 开始订阅
+    ///
+    /// 打开时会补上最后一个写命令没有写到 store 的修改，所以变更日志需要和 store 一起持久化
+    pub fn with_change_log(mut self, store: impl ChangeStore) -> Result<Self, KvError> {
+        let log = ChangeLog::new(store)?;
+        log.recover(&self.store)?;
+        self.change_log = Some(log);
+        Ok(self)
+    }
+
+    /// 允许 backup 和 restore 命令，备份文件都在 dir 目录下
+    pub fn with_backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
     pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
         self.on_received.push(f);
         self
@@ -102,11 +172,32 @@ impl<Store> Clone for Service<Store> {
 }
 
 impl<Store: Storage> Service<Store> {
+    /// 执行客户端的命令，修改集群的管理命令会被拒绝
     pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        self.run(cmd, false)
+    }
+
+    /// 执行管理连接上的命令，包括修改集群的管理命令；调用者需要确认对方的身份
+    pub fn execute_admin(&self, cmd: CommandRequest) -> CommandResponse {
+        self.run(cmd, true)
+    }
+
+    fn run(&self, cmd: CommandRequest, admin: bool) -> CommandResponse {
         debug!("Got request: {:?}", cmd);
         self.inner.on_received.notify(&cmd);
 
-        let mut res = dispatch(cmd, &self.inner.store);
+        let mut res = match (&cmd.request_data, &self.inner.cluster) {
+            (Some(data), _) if data.is_admin() && !admin => {
+                warn!("Rejected {} from a client connection", data.name());
+                KvError::PermissionDenied(format!(
+                    "{} is only accepted on admin connections",
+                    data.name()
+                ))
+                .into()
+            }
+            (_, Some(cluster)) => self.execute_cluster(cluster, cmd),
+            (_, None) => self.execute_local(cmd),
+        };
 
         debug!("Executed resposne: {:?}", res);
 
@@ -117,8 +208,436 @@ impl<Store: Storage> Service<Store> {
         }
         res
     }
+
+    fn execute_local(&self, cmd: CommandRequest) -> CommandResponse {
+        match cmd.request_data {
+            Some(ref data) if data.is_write() && self.inner.read_only => {
+                KvError::PermissionDenied("Writes are not allowed on a read-only replica".into())
+                    .into()
+            }
+            Some(ref data) if data.is_write() => self.apply(cmd),
+            Some(RequestData::Backup(ref param)) => {
+                let compression = Compression::from_i32(param.compression).unwrap_or_default();
+                match self.backup(&param.path, compression) {
+                    Ok(info) => info.into(),
+                    Err(e) => e.into(),
+                }
+            }
+            Some(RequestData::Restore(ref param)) => match self.restore_backup(&param.path) {
+                Ok(info) => info.into(),
+                Err(e) => e.into(),
+            },
+            _ => dispatch(cmd, &self.inner.store),
+        }
+    }
+
+    // 集群命令在这里处理，其它命令先检查 key 所在的 slot
+    fn execute_cluster(&self, cluster: &ClusterSlotMap, cmd: CommandRequest) -> CommandResponse {
+        let (cmd, asking) = match cmd.request_data {
+            Some(RequestData::Asking(asking)) => {
+                (asking.command.map(|cmd| *cmd).unwrap_or_default(), true)
+            }
+            _ => (cmd, false),
+        };
+        match cmd.request_data {
+            Some(RequestData::ClusterSlots(_)) => CommandResponse {
+                slots: cluster.ranges(),
+                ..CommandResponse::ok()
+            },
+            Some(RequestData::ClusterSetSlot(param)) => {
+                let state = SlotState::from_i32(param.state).unwrap_or_default();
+                match cluster.set_slot(param.slot, state, param.addr) {
+                    Ok(_) => CommandResponse::ok(),
+                    Err(e) => e.into(),
+                }
+            }
+            _ => cluster.execute(cmd, asking, &self.inner.store, |cmd| {
+                self.execute_local(cmd)
+            }),
+        }
+    }
+
+    /// 迁移 slot 时，从本地取出最多 count 个 key；这些 key 在 finish_moving 之前不能访问
+    pub fn start_moving(&self, slot: u32, count: usize) -> Result<Vec<Hmset>, KvError> {
+        let batch = self
+            .cluster()?
+            .start_moving(slot, count, &self.inner.store)?;
+        let mut tables: Vec<Hmset> = Vec::new();
+        for (table, pair) in batch {
+            match tables.iter_mut().find(|t| t.table == table) {
+                Some(hmset) => hmset.pairs.push(pair),
+                None => tables.push(Hmset {
+                    table,
+                    pairs: vec![pair],
+                }),
+            }
+        }
+        Ok(tables)
+    }
+
+    /// 结束 key 的迁移，moved 为 true 时这些 key 已经写到目标节点，从本地删除
+    pub fn finish_moving(&self, batch: Vec<Hmset>, moved: bool) -> Result<(), KvError> {
+        let cluster = self.cluster()?;
+        let mut result = Ok(());
+        if moved {
+            for hmset in &batch {
+                let keys = hmset.pairs.iter().map(|p| p.key.clone()).collect();
+                let cmd = CommandRequest::new_hmdel(&hmset.table, keys);
+                if let Err(e) = self.apply(cmd).into_result() {
+                    result = Err(e);
+                }
+            }
+        }
+        let keys = batch
+            .into_iter()
+            .flat_map(|hmset| {
+                let table = hmset.table;
+                hmset.pairs.into_iter().map(move |p| (table.clone(), p.key))
+            })
+            .collect::<Vec<_>>();
+        cluster.finish_moving(keys, moved);
+        result
+    }
+
+    fn cluster(&self) -> Result<&ClusterSlotMap, KvError> {
+        self.inner
+            .cluster
+            .as_ref()
+            .ok_or_else(|| KvError::InvalidCommand("Cluster is not enabled on this node".into()))
+    }
+
+    /// 订阅变更日志，返回第一条需要读取的 seq 和接收新记录的 receiver
+    pub fn subscribe(
+        &self,
+        req: &Subscribe,
+    ) -> Result<(u64, broadcast::Receiver<ChangeEvent>), KvError> {
+        self.change_log()?.subscribe(req.offset)
+    }
+
+    /// 从 seq 开始最多读取 limit 条变更记录
+    pub fn changes(&self, seq: u64, limit: usize) -> Result<Vec<ChangeEvent>, KvError> {
+        self.change_log()?.read(seq, limit)
+    }
+
+    /// 丢弃 seq 之前的变更记录
+    pub fn trim_changes(&self, seq: u64) -> Result<(), KvError> {
+        self.change_log()?.trim(seq)
+    }
+
+    fn change_log(&self) -> Result<&ChangeLog, KvError> {
+        self.inner
+            .change_log
+            .as_ref()
+            .ok_or_else(|| KvError::InvalidCommand("Change log is not enabled on this node".into()))
+    }
+
+    /// 副本开始复制，返回需要发送给副本的记录
+    pub fn replicate(&self, req: &Replicate) -> Result<ReplicationFeed, KvError> {
+        match &self.inner.replication {
+            Some(log) => log.feed(req, &self.inner.store),
+            None => Err(KvError::InvalidCommand(
+                "Replication is not enabled on this node".into(),
+            )),
+        }
+    }
+
+    /// 副本应用主节点发来的复制记录，不受只读的限制
+    pub fn apply_replicated(&self, entry: ReplicationEntry) -> Result<(), KvError> {
+        match entry.entry {
+            Some(Entry::Command(cmd)) => {
+                self.apply(cmd).into_result()?;
+            }
+            // 全量同步之前清空所有数据
+            Some(Entry::Reset(_)) => self.clear()?,
+            Some(Entry::Snapshot(hmset)) => self.load(hmset)?,
+            None => return Err(KvError::InvalidCommand("Empty replication entry".into())),
+        }
+        Ok(())
+    }
+
+    /// 所有 table 的数据，每个 Hmset 最多包含 SNAPSHOT_CHUNK 个 kv pair
+    pub fn snapshot(&self) -> Result<Vec<Hmset>, KvError> {
+        snapshot(&self.inner.store)
+    }
+
+    /// 清空所有数据，换成快照里的数据
+    pub fn restore(&self, snapshot: Vec<Hmset>) -> Result<(), KvError> {
+        self.clear()?;
+        snapshot.into_iter().try_for_each(|hmset| self.load(hmset))
+    }
+
+    /// 把所有数据备份到备份目录下的 path 文件，返回备份的信息
+    ///
+    /// 所有 table 是同一个时间点的数据：读取数据期间写命令会等待，
+    /// 数据逐个 table 写到文件，内存中最多只有一个 table 的副本
+    pub fn backup(&self, path: &str, compression: Compression) -> Result<BackupInfo, KvError> {
+        let path = self.backup_path(path)?;
+        // 先写到临时文件，写完再改名，不会留下不完整的备份
+        let tmp = path.with_extension("tmp");
+        let result = self.write_backup(&tmp, compression).and_then(|info| {
+            fs::rename(&tmp, &path)?;
+            if let Some(dir) = path.parent() {
+                File::open(dir)?.sync_all()?;
+            }
+            Ok(info)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    // 把数据写到 tmp 文件，并且落盘
+    fn write_backup(&self, tmp: &Path, compression: Compression) -> Result<BackupInfo, KvError> {
+        let store = &self.inner.store;
+        let file = File::create(tmp)?;
+        let mut writer = BackupWriter::new(BufWriter::new(file.try_clone()?), compression)?;
+        {
+            let _writes = self.inner.writes.write().unwrap();
+            writer.write_storage(store)?;
+        }
+        let info = writer.finish()?;
+        file.sync_all()?;
+        Ok(info)
+    }
+
+    /// 用备份目录下的 path 文件替换所有数据，返回备份的信息
+    ///
+    /// 数据的修改和写命令一样会通知 watcher，并记录到复制日志和变更日志。
+    /// 内存放不下备份的数据时直接返回错误，不修改现有的数据
+    pub fn restore_backup(&self, path: &str) -> Result<BackupInfo, KvError> {
+        if self.inner.read_only {
+            return Err(KvError::PermissionDenied(
+                "Restore is not allowed on a read-only replica".into(),
+            ));
+        }
+        let path = self.backup_path(path)?;
+        // 备份完整之后才修改数据
+        let (info, chunks) = BackupReader::new(File::open(path)?)?.read_all()?;
+
+        let _writes = self.inner.writes.write().unwrap();
+        let store = &self.inner.store;
+        store.check_capacity(&chunks)?;
+        for table in store.tables()? {
+            let mut keys = store.get_iter(&table)?.map(|pair| pair.key).peekable();
+            while keys.peek().is_some() {
+                let batch = keys.by_ref().take(BACKUP_CHUNK).collect();
+                self.apply_unlocked(CommandRequest::new_hmdel(&table, batch))
+                    .into_result()?;
+            }
+        }
+        for hmset in chunks {
+            self.apply_unlocked(CommandRequest::new_hmset(hmset.table, hmset.pairs))
+                .into_result()?;
+        }
+        Ok(info)
+    }
+
+    // 备份文件只能在备份目录下，不能用绝对路径或者 .. 访问其它文件
+    fn backup_path(&self, path: &str) -> Result<PathBuf, KvError> {
+        let dir =
+            self.inner.backup_dir.as_ref().ok_or_else(|| {
+                KvError::InvalidCommand("Backup is not enabled on this node".into())
+            })?;
+        let relative = Path::new(path);
+        let valid = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if path.is_empty() || !valid {
+            return Err(KvError::InvalidCommand(format!(
+                "Invalid backup path: {}",
+                path
+            )));
+        }
+        Ok(dir.join(relative))
+    }
+
+    /// 执行写命令，通知 watcher 并记录到复制日志；不受只读的限制，用于复制和 raft
+    pub fn apply(&self, cmd: CommandRequest) -> CommandResponse {
+        let _writes = self.inner.writes.read().unwrap();
+        self.apply_unlocked(cmd)
+    }
+
+    fn apply_unlocked(&self, cmd: CommandRequest) -> CommandResponse {
+        let inner = &self.inner;
+        let watched = inner.watcher.receiver_count() > 0;
+        let logged = inner.replication.is_some() || inner.change_log.is_some();
+        // 没有 watcher 和变更日志时不需要记录数据变化
+        let events = match watched || inner.change_log.is_some() {
+            true => changes(&cmd),
+            false => Vec::new(),
+        };
+        let ordered = logged || (watched && !events.is_empty());
+        let _order = ordered.then(|| inner.order.lock().unwrap());
+        let replicated = inner.replication.as_ref().map(|_| cmd.clone());
+        // 先写变更日志再修改数据，写日志失败时不修改数据
+        let prepared = match &inner.change_log {
+            Some(log) => match log.prepare(&events, &inner.store) {
+                Ok(changes) => changes,
+                Err(e) => return e.into(),
+            },
+            None => Vec::new(),
+        };
+        let res = dispatch(cmd, &inner.store);
+
+        // 为了腾出内存淘汰的 key 在写命令之前就删除了，写命令失败时也需要记录
+        let evicted = inner.store.take_evicted().unwrap_or_else(|e| {
+            warn!("Failed to read evicted keys: {:?}", e);
+            Vec::new()
+        });
+        if res.status != 200 {
+            let aborted = match &inner.change_log {
+                Some(log) => log.abort(),
+                None => Ok(()),
+            };
+            self.record_evicted(evicted, &[]);
+            // 没能丢弃的记录会在重启之后被当作已经提交的修改，需要让调用者知道
+            return match aborted {
+                Ok(()) => res,
+                Err(e) => {
+                    warn!("Failed to discard change log of a failed command: {:?}", e);
+                    e.into()
+                }
+            };
+        }
+        if let Some(log) = &inner.change_log {
+            log.commit(&prepared);
+        }
+        self.record_evicted(evicted, &prepared);
+        if let (Some(log), Some(cmd)) = (&inner.replication, replicated) {
+            log.append(cmd);
+        }
+        self.publish(events, &res);
+        res
+    }
+
+    // 淘汰的 key 当作删除记录到复制日志和变更日志，并通知 watcher
+    //
+    // 写命令自己修改的 key 在变更日志里已经记录了修改前的值，淘汰之后又写入相当于覆盖，
+    // 不再记录删除
+    fn record_evicted(&self, evicted: Vec<(String, Kvpair)>, written: &[ChangeEvent]) {
+        if evicted.is_empty() {
+            return;
+        }
+        if let Some(log) = &self.inner.replication {
+            for (table, pair) in &evicted {
+                log.append(CommandRequest::new_hdel(table, &pair.key));
+            }
+        }
+        if let Some(log) = &self.inner.change_log {
+            let evicted: Vec<_> = evicted
+                .iter()
+                .filter(|(table, pair)| {
+                    !written
+                        .iter()
+                        .any(|c| &c.table == table && c.key == pair.key)
+                })
+                .cloned()
+                .collect();
+            if let Err(e) = log.append_evicted(&evicted) {
+                warn!("Failed to record evicted keys: {:?}", e);
+            }
+        }
+        for (table, pair) in evicted {
+            let _ = self
+                .inner
+                .watcher
+                .send(WatchEvent::deleted(&table, &pair.key));
+        }
+    }
+
+    fn clear(&self) -> Result<(), KvError> {
+        let store = &self.inner.store;
+        for table in store.tables()? {
+            for pair in store.get_all(&table)? {
+                store.del(&table, &pair.key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&self, hmset: Hmset) -> Result<(), KvError> {
+        for pair in hmset.pairs {
+            let value = pair.value.unwrap_or_default();
+            self.inner.store.set(&hmset.table, pair.key, value)?;
+        }
+        Ok(())
+    }
+
+    /// 逐个读出 table 里以 prefix 开头的数据，不会把整个 table 一次读到响应里
+    pub fn scan(
+        &self,
+        table: &str,
+        prefix: impl Into<String>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let prefix = prefix.into();
+        let pairs = self.inner.store.get_iter(table)?;
+        Ok(Box::new(
+            pairs.filter(move |pair| pair.key.starts_with(&prefix)),
+        ))
+    }
+
+    /// 订阅数据的变化，每个成功的写命令修改的 key 都会收到通知
+    pub fn watch(&self) -> broadcast::Receiver<WatchEvent> {
+        self.inner.watcher.subscribe()
+    }
+
+    fn publish(&self, events: Vec<WatchEvent>, res: &CommandResponse) {
+        for (i, event) in events.into_iter().enumerate() {
+            // 删除不存在的 key 没有改变数据，不需要通知
+            let removed = res.values.get(i).is_some_and(|v| v.value.is_some());
+            if event.value.is_some() || removed {
+                // 所有 watcher 都已经退出时会出错，可以忽略
+                let _ = self.inner.watcher.send(event);
+            }
+        }
+    }
 }
 
+// 写命令会修改的 key 和新的值，删除的 key 值为空
+fn changes(cmd: &CommandRequest) -> Vec<WatchEvent> {
+    let set = |table: &str, pair: &Kvpair| {
+        WatchEvent::new(table, &pair.key, pair.value.clone().unwrap_or_default())
+    };
+    match &cmd.request_data {
+        Some(RequestData::Hset(param)) => param.pair.iter().map(|p| set(&param.table, p)).collect(),
+        Some(RequestData::Hmset(param)) => {
+            param.pairs.iter().map(|p| set(&param.table, p)).collect()
+        }
+        Some(RequestData::Hdel(param)) => vec![WatchEvent::deleted(&param.table, &param.key)],
+        Some(RequestData::Hmdel(param)) => param
+            .keys
+            .iter()
+            .map(|key| WatchEvent::deleted(&param.table, key))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Service 支持的命令，会在握手时告诉客户端
+pub const COMMANDS: &[&str] = &[
+    "hget",
+    "hgetall",
+    "hmget",
+    "hset",
+    "hmset",
+    "hdel",
+    "hmdel",
+    "hexist",
+    "hmexist",
+    "watch",
+    "ping",
+    "replicate",
+    "raft",
+    "asking",
+    "cluster_slots",
+    "cluster_set_slot",
+    "migrate_slot",
+    "subscribe",
+    "backup",
+    "restore",
+];
+
 // 从 Request 中得到 Response,已经实现了9个方法。
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
     match cmd.request_data {
@@ -131,6 +650,44 @@ pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         Some(RequestData::Hmdel(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
+        // Hello 由连接在建立时处理，不会走到 Service
+        Some(RequestData::Hello(_)) => {
+            KvError::InvalidCommand("Hello can only be sent at the start of a connection".into())
+                .into()
+        }
+        // Watch 需要推送通知，由连接处理
+        Some(RequestData::Watch(_)) => {
+            KvError::InvalidCommand("Watch can only be sent over a stream connection".into()).into()
+        }
+        Some(RequestData::Ping(_)) => CommandResponse::ok(),
+        // 复制需要推送数据，由连接处理
+        Some(RequestData::Replicate(_)) => {
+            KvError::InvalidCommand("Replicate can only be sent over a stream connection".into())
+                .into()
+        }
+        // raft 消息由开启了 raft 的连接处理
+        Some(RequestData::Raft(_)) => {
+            KvError::InvalidCommand("Raft is not enabled on this node".into()).into()
+        }
+        // 集群命令由开启了集群模式的 Service 处理
+        Some(RequestData::Asking(_))
+        | Some(RequestData::ClusterSlots(_))
+        | Some(RequestData::ClusterSetSlot(_)) => {
+            KvError::InvalidCommand("Cluster is not enabled on this node".into()).into()
+        }
+        // 备份需要访问 Service 的备份目录
+        Some(RequestData::Backup(_)) | Some(RequestData::Restore(_)) => {
+            KvError::InvalidCommand("Backup is not enabled on this node".into()).into()
+        }
+        // 订阅需要推送数据，由连接处理
+        Some(RequestData::Subscribe(_)) => {
+            KvError::InvalidCommand("Subscribe can only be sent over a stream connection".into())
+                .into()
+        }
+        // 迁移 slot 需要连接其它节点，由连接处理
+        Some(RequestData::MigrateSlot(_)) => {
+            KvError::InvalidCommand("Slot migration is not enabled on this node".into()).into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
     }
 }
@@ -204,8 +761,163 @@ mod tests {
 
         let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
 
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
+
+    #[test]
+    fn watch_should_receive_changes() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut rx = service.watch();
+
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hget("t1", "k1"));
+        service.execute(CommandRequest::new_hmdel(
+            "t1",
+            vec!["k0".into(), "k1".into()],
+        ));
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            WatchEvent::new("t1", "k1", "v1".into())
+        );
+        assert_eq!(rx.try_recv().unwrap(), WatchEvent::deleted("t1", "k1"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn watch_events_should_follow_write_order() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut rx = service.watch();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let service = service.clone();
+                std::thread::spawn(move || {
+                    for j in 0..100 {
+                        let value = format!("v{}-{}", i, j).into();
+                        service.execute(CommandRequest::new_hset("t1", "k1", value));
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        // 最后收到的通知就是最后写入的值
+        let events: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(events.len(), 400);
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &[events[399].value.clone().unwrap()], &[]);
+    }
+
+    #[test]
+    fn backup_and_restore_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_backup_dir(dir.path())
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hset("t2", "k2", "v2".into()));
+
+        // 普通连接不能备份和恢复
+        let res = service.execute(CommandRequest::new_backup("b1", Compression::Zstd));
+        assert_eq!(res.status, 403);
+        let res = service.execute(CommandRequest::new_restore("b1"));
+        assert_eq!(res.status, 403);
+
+        let res = service.execute_admin(CommandRequest::new_backup("b1", Compression::Zstd));
+        assert_eq!(res.status, 200);
+        assert_eq!(res.backup.unwrap().pairs, 2);
+
+        // 恢复之后备份之后写入的数据都会被删除
+        service.execute(CommandRequest::new_hset("t1", "k1", "v3".into()));
+        service.execute(CommandRequest::new_hset("t3", "k3", "v3".into()));
+        let res = service.execute_admin(CommandRequest::new_restore("b1"));
+        assert_eq!(res.status, 200);
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = service.execute(CommandRequest::new_hexist("t3", "k3"));
+        assert_res_ok(res, &[false.into()], &[]);
+
+        // 不能访问备份目录之外的文件
+        for path in ["../b1", "/tmp/b1", ""] {
+            let res = service.execute_admin(CommandRequest::new_backup(path, Compression::None));
+            assert_eq!(res.status, 400);
+        }
+
+        let replica: Service = ServiceInner::new(MemTable::default())
+            .with_backup_dir(dir.path())
+            .with_read_only(true)
+            .into();
+        let res = replica.execute_admin(CommandRequest::new_restore("b1"));
+        assert_eq!(res.status, 403);
+    }
+
+    #[test]
+    fn restore_larger_than_memory_limit_should_keep_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_backup_dir(dir.path())
+            .into();
+        for i in 1..=3 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), format!("v{}", i).into());
+            service.execute(cmd);
+        }
+        let res = service.execute_admin(CommandRequest::new_backup("b1", Compression::None));
+        assert_eq!(res.status, 200);
+        // 备份写完之后不会留下临时文件
+        assert!(!dir.path().join("b1.tmp").exists());
+
+        // 每个 key 大约 70 字节，最多放下 2 个
+        let store = MemTable::new().with_max_memory(140, EvictionPolicy::NoEviction);
+        let service: Service = ServiceInner::new(store).with_backup_dir(dir.path()).into();
+        service.execute(CommandRequest::new_hset("t2", "k9", "v9".into()));
+        let res = service.execute_admin(CommandRequest::new_restore("b1"));
+        assert_eq!(res.status, 507);
+        let res = service.execute(CommandRequest::new_hget("t2", "k9"));
+        assert_res_ok(res, &["v9".into()], &[]);
+    }
+
+    #[test]
+    fn evicted_keys_should_be_logged_as_deletes() {
+        // 每个 key 大约 70 字节，最多放下 2 个
+        let store = MemTable::new().with_max_memory(140, EvictionPolicy::AllKeysLru);
+        let service: Service = ServiceInner::new(store)
+            .with_replication_log(DEFAULT_REPLICATION_BACKLOG)
+            .with_change_log(MemChangeStore::default())
+            .unwrap()
+            .into();
+        let mut rx = service.watch();
+        for i in 1..=3 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), format!("v{}", i).into());
+            assert_eq!(service.execute(cmd).status, 200);
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        // k1 被淘汰，删除记录在写入 k3 之前
+        let events: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(events[2], WatchEvent::deleted("t1", "k1"));
+        assert_eq!(events[3], WatchEvent::new("t1", "k3", "v3".into()));
+
+        // 变更日志先写 k3 的记录，k1 的删除在数据修改之后记录
+        let changes = service.changes(1, 10).unwrap();
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[2].key, "k3");
+        assert_eq!(changes[3].key, "k1");
+        assert_eq!(changes[3].old_value, Some("v1".into()));
+        assert_eq!(changes[3].new_value, None);
+
+        let log = service.inner.replication.as_ref().unwrap();
+        let req = Replicate {
+            id: log.id().into(),
+            offset: 3,
+        };
+        let entry = log
+            .feed(&req, &service.inner.store)
+            .unwrap()
+            .initial
+            .remove(0);
+        let expected = CommandRequest::new_hdel("t1", "k1");
+        assert_eq!(entry.entry, Some(Entry::Command(expected)));
+    }
 }
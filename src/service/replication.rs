@@ -0,0 +1,232 @@
+use std::{
+    collections::VecDeque,
+    process,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::broadcast;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    replication_entry::Entry, CommandRequest, CommandResponse, Hmset, KvError, Kvpair, Replicate,
+    ReplicationEntry, Reset, Storage,
+};
+
+/// 主节点默认保留的写命令条数，副本落后更多时需要全量同步
+pub const DEFAULT_REPLICATION_BACKLOG: usize = 10000;
+// 全量同步和快照中每个 Hmset 最多包含的 kv pair
+const SNAPSHOT_CHUNK: usize = 1000;
+
+/// 主节点的复制日志，记录每个成功的写命令和它的 offset
+///
+/// 写命令在锁里执行并记录，日志的顺序和数据修改的顺序一致
+pub struct ReplicationLog {
+    // 每次启动都不一样，副本用它判断 offset 是否还有效
+    id: String,
+    backlog: usize,
+    state: Mutex<LogState>,
+    sender: broadcast::Sender<ReplicationEntry>,
+}
+
+#[derive(Default)]
+struct LogState {
+    entries: VecDeque<ReplicationEntry>,
+    // 最后一条记录的 offset
+    offset: u64,
+}
+
+/// 副本需要接收的复制记录：先发送 initial，然后从 receiver 接收新的写命令
+pub struct ReplicationFeed {
+    pub initial: Vec<ReplicationEntry>,
+    pub receiver: broadcast::Receiver<ReplicationEntry>,
+}
+
+impl ReplicationLog {
+    pub fn new(backlog: usize) -> Self {
+        let backlog = backlog.max(1);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let id = xxh3_64(format!("{}-{}", process::id(), now).as_bytes());
+        Self {
+            id: format!("{:016x}", id),
+            backlog,
+            state: Default::default(),
+            sender: broadcast::channel(backlog).0,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 最后一条记录的 offset
+    pub fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    /// 执行写命令，成功时记录下来
+    pub fn record(
+        &self,
+        cmd: CommandRequest,
+        f: impl FnOnce(CommandRequest) -> CommandResponse,
+    ) -> CommandResponse {
+        let mut state = self.state.lock().unwrap();
+        let res = f(cmd.clone());
+        if res.status == 200 {
+            self.push(&mut state, cmd);
+        }
+        res
+    }
+
+    /// 记录已经执行成功的写命令，调用者需要保证按数据修改的顺序调用
+    pub fn append(&self, cmd: CommandRequest) {
+        let mut state = self.state.lock().unwrap();
+        self.push(&mut state, cmd);
+    }
+
+    fn push(&self, state: &mut LogState, cmd: CommandRequest) {
+        state.offset += 1;
+        let entry = ReplicationEntry::new(state.offset, Entry::Command(cmd));
+        if state.entries.len() == self.backlog {
+            state.entries.pop_front();
+        }
+        state.entries.push_back(entry.clone());
+        // 没有副本时会出错，可以忽略
+        let _ = self.sender.send(entry);
+    }
+
+    /// 副本从 req.offset 开始需要的记录，不能增量复制时返回全量同步的数据
+    pub fn feed(&self, req: &Replicate, store: &impl Storage) -> Result<ReplicationFeed, KvError> {
+        let state = self.state.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        let first = state.offset + 1 - state.entries.len() as u64;
+        if req.id == self.id && (first..=state.offset + 1).contains(&req.offset) {
+            let skip = (req.offset - first) as usize;
+            let initial = state.entries.iter().skip(skip).cloned().collect();
+            return Ok(ReplicationFeed { initial, receiver });
+        }
+
+        // 之后的写命令都会通过 receiver 发给副本，快照里已经包含的写命令再执行一遍，
+        // 结果也是一样的，所以快照不需要持有锁
+        let offset = state.offset;
+        drop(state);
+
+        let snapshot = snapshot(store)?;
+        let reset = Entry::Reset(Reset {
+            id: self.id.clone(),
+            chunks: snapshot.len() as u64,
+        });
+        let mut initial = vec![ReplicationEntry::new(offset, reset)];
+        for hmset in snapshot {
+            initial.push(ReplicationEntry::new(offset, Entry::Snapshot(hmset)));
+        }
+        Ok(ReplicationFeed { initial, receiver })
+    }
+}
+
+// 把所有 table 的数据按 SNAPSHOT_CHUNK 分成多个 Hmset
+pub(crate) fn snapshot(store: &impl Storage) -> Result<Vec<Hmset>, KvError> {
+    let mut snapshot = Vec::new();
+    for table in store.tables()? {
+        let pairs: Vec<Kvpair> = store.get_iter(&table)?.collect();
+        for chunk in pairs.chunks(SNAPSHOT_CHUNK) {
+            snapshot.push(Hmset {
+                table: table.clone(),
+                pairs: chunk.to_vec(),
+            });
+        }
+    }
+    Ok(snapshot)
+}
+
+impl ReplicationEntry {
+    pub fn new(offset: u64, entry: Entry) -> Self {
+        Self {
+            offset,
+            entry: Some(entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, Value};
+
+    fn hset(log: &ReplicationLog, store: &MemTable, key: &str, value: Value) {
+        let cmd = CommandRequest::new_hset("t1", key, value);
+        log.record(cmd, |cmd| crate::dispatch(cmd, store));
+    }
+
+    #[test]
+    fn replication_log_should_keep_backlog() {
+        let log = ReplicationLog::new(2);
+        let store = MemTable::new();
+        for i in 0..3 {
+            hset(&log, &store, "k1", i.into());
+        }
+        assert_eq!(log.offset(), 3);
+
+        // 失败的命令不会记录
+        log.record(CommandRequest::default(), |cmd| {
+            crate::dispatch(cmd, &store)
+        });
+        assert_eq!(log.offset(), 3);
+
+        let req = Replicate {
+            id: log.id().into(),
+            offset: 3,
+        };
+        let feed = log.feed(&req, &store).unwrap();
+        let offsets: Vec<_> = feed.initial.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, vec![3]);
+
+        // 已经同步到最新
+        let req = Replicate {
+            id: log.id().into(),
+            offset: 4,
+        };
+        assert!(log.feed(&req, &store).unwrap().initial.is_empty());
+    }
+
+    #[test]
+    fn replication_log_should_fall_back_to_snapshot() {
+        let log = ReplicationLog::new(2);
+        let store = MemTable::new();
+        for i in 0..3 {
+            hset(&log, &store, &format!("k{}", i), i.into());
+        }
+
+        // offset 1 已经不在日志里了，id 不对也需要全量同步
+        for (id, offset) in [(log.id(), 1), ("other", 3), ("", 0)] {
+            let req = Replicate {
+                id: id.into(),
+                offset,
+            };
+            let mut feed = log.feed(&req, &store).unwrap();
+            assert_eq!(
+                feed.initial[0],
+                ReplicationEntry::new(
+                    log.offset(),
+                    Entry::Reset(Reset {
+                        id: log.id().into(),
+                        chunks: 1,
+                    })
+                )
+            );
+            match feed.initial.pop().unwrap().entry {
+                Some(Entry::Snapshot(hmset)) => {
+                    assert_eq!(hmset.pairs.len(), store.get_all("t1").unwrap().len())
+                }
+                v => panic!("Expect snapshot, got {:?}", v),
+            }
+
+            // 之后的写命令通过 receiver 收到
+            hset(&log, &store, "k9", 9.into());
+            assert_eq!(feed.receiver.try_recv().unwrap().offset, log.offset());
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use prost::Message;
+use tracing::warn;
+
+use crate::{
+    command_request::RequestData, BackupReader, BackupWriter, CommandRequest, Compression, KvError,
+    Storage,
+};
+
+// AOF 文件名是 aof.<id>，快照文件名是 snapshot.<id>；
+// snapshot.<id> 加上所有 id 不小于它的 AOF 就是完整的数据
+const AOF_PREFIX: &str = "aof.";
+const SNAPSHOT_PREFIX: &str = "snapshot.";
+const SNAPSHOT_TMP: &str = "snapshot.tmp";
+// 单条记录的最大长度，防止损坏的文件导致分配过多内存
+const MAX_RECORD: usize = 256 * 1024 * 1024;
+
+/// AOF 调用 fsync 的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// 每个写命令都 fsync，最安全也最慢
+    Always,
+    /// 每秒 fsync 一次，宕机时最多丢失一秒的数据
+    EverySec,
+    /// 不主动 fsync，由操作系统决定什么时候写到磁盘
+    Never,
+}
+
+/// MemTable 持久化的参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AofConfig {
+    /// AOF 调用 fsync 的策略
+    pub fsync: FsyncPolicy,
+    /// 生成快照并删除旧 AOF 的间隔，这期间没有写命令时不生成
+    pub snapshot_interval: Duration,
+    /// 快照使用的压缩算法
+    pub compression: Compression,
+}
+
+impl Default for AofConfig {
+    fn default() -> Self {
+        Self {
+            fsync: FsyncPolicy::EverySec,
+            snapshot_interval: Duration::from_secs(3600),
+            compression: Compression::Lz4,
+        }
+    }
+}
+
+impl AofConfig {
+    /// AOF 调用 fsync 的策略
+    pub fn with_fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// 生成快照的间隔
+    pub fn with_snapshot_interval(mut self, interval: Duration) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    /// 快照使用的压缩算法
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+}
+
+/// MemTable 的 AOF，记录每个修改数据的 HSET 和 HDEL
+///
+/// 每条记录是 4 字节大端序长度、4 字节 crc32c 加上 CommandRequest
+#[derive(Debug)]
+pub(crate) struct Aof {
+    dir: PathBuf,
+    config: AofConfig,
+    segment: Mutex<Segment>,
+    // 同一时间只生成一个快照
+    compacting: Mutex<()>,
+}
+
+// 正在写入的 AOF 文件
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    file: Arc<File>,
+    size: u64,
+    // 上次 fsync 之后有没有写入
+    dirty: bool,
+    // 上次快照之后有没有写入
+    written: bool,
+}
+
+impl Aof {
+    /// 读出 dir 中的快照和 AOF 并写入 store，返回之后用来追加记录的 Aof
+    pub(crate) fn open(
+        dir: impl Into<PathBuf>,
+        config: AofConfig,
+        store: &impl Storage,
+    ) -> Result<Self, KvError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let (snapshots, segments) = list_files(&dir)?;
+
+        // 只需要最新的快照，以及它之后的 AOF
+        let snapshot = snapshots.last().copied().unwrap_or_default();
+        if !snapshots.is_empty() {
+            let file = File::open(dir.join(format!("{}{}", SNAPSHOT_PREFIX, snapshot)))?;
+            BackupReader::new(file)?.restore_to(store)?;
+        }
+        let segments: Vec<u64> = segments.into_iter().filter(|id| *id >= snapshot).collect();
+        for id in &segments {
+            replay(&dir.join(format!("{}{}", AOF_PREFIX, id)), store)?;
+        }
+        remove_before(&dir, snapshot)?;
+
+        // 接着写最后一个 AOF
+        let id = segments.last().copied().unwrap_or(snapshot);
+        let file = open_segment(&dir, id)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            dir,
+            config,
+            segment: Mutex::new(Segment {
+                id,
+                file: Arc::new(file),
+                size,
+                dirty: false,
+                written: size > 0,
+            }),
+            compacting: Mutex::new(()),
+        })
+    }
+
+    pub(crate) fn config(&self) -> &AofConfig {
+        &self.config
+    }
+
+    /// 追加一条记录，调用者需要持有 key 的锁，保证同一个 key 的记录和内存中的修改顺序一致
+    pub(crate) fn append(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        let payload = cmd.encode_to_vec();
+        let mut buf = Vec::with_capacity(payload.len() + 8);
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&crc32c::crc32c(&payload).to_be_bytes());
+        buf.extend_from_slice(&payload);
+
+        let mut segment = self.segment.lock().unwrap();
+        if let Err(e) = (&*segment.file).write_all(&buf) {
+            // 去掉写了一半的记录，之后的记录才能正常读出
+            let _ = segment.file.set_len(segment.size);
+            return Err(e.into());
+        }
+        segment.size += buf.len() as u64;
+        segment.written = true;
+        match self.config.fsync {
+            FsyncPolicy::Always => segment.file.sync_data()?,
+            _ => segment.dirty = true,
+        }
+        Ok(())
+    }
+
+    /// 把已经写入的记录 fsync 到磁盘
+    pub(crate) fn sync(&self) -> Result<(), KvError> {
+        let file = {
+            let mut segment = self.segment.lock().unwrap();
+            if !segment.dirty {
+                return Ok(());
+            }
+            segment.dirty = false;
+            segment.file.clone()
+        };
+        // fsync 的时候不阻塞写命令
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// 生成快照并删除快照之前的 AOF；上次快照之后没有写入时返回 false
+    ///
+    /// 快照不是同一时刻的数据，但生成快照时的写命令都记录在新的 AOF 里，
+    /// 重放时同一个 key 最后一次修改会覆盖快照里的值，结果和内存中一致
+    pub(crate) fn compact(&self, store: &impl Storage) -> Result<bool, KvError> {
+        let _compacting = self.compacting.lock().unwrap();
+        let id = {
+            let mut segment = self.segment.lock().unwrap();
+            if !segment.written {
+                return Ok(false);
+            }
+            let id = segment.id + 1;
+            let file = open_segment(&self.dir, id)?;
+            segment.file.sync_data()?;
+            *segment = Segment {
+                id,
+                file: Arc::new(file),
+                size: 0,
+                dirty: false,
+                written: false,
+            };
+            id
+        };
+
+        let tmp = self.dir.join(SNAPSHOT_TMP);
+        let file = File::create(&tmp)?;
+        let mut writer = BackupWriter::new(BufWriter::new(&file), self.config.compression)?;
+        writer.write_storage(store)?;
+        writer.finish()?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(format!("{}{}", SNAPSHOT_PREFIX, id)))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        remove_before(&self.dir, id)?;
+        Ok(true)
+    }
+}
+
+impl Drop for Aof {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("Failed to sync AOF: {:?}", e);
+        }
+    }
+}
+
+fn open_segment(dir: &Path, id: u64) -> Result<File, KvError> {
+    let path = dir.join(format!("{}{}", AOF_PREFIX, id));
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+// 目录中所有快照和 AOF 的 id，从小到大排列
+fn list_files(dir: &Path) -> Result<(Vec<u64>, Vec<u64>), KvError> {
+    let mut snapshots = Vec::new();
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(id) = name.strip_prefix(SNAPSHOT_PREFIX) {
+            if let Ok(id) = id.parse() {
+                snapshots.push(id);
+            }
+        } else if let Some(id) = name.strip_prefix(AOF_PREFIX) {
+            if let Ok(id) = id.parse() {
+                segments.push(id);
+            }
+        }
+    }
+    snapshots.sort_unstable();
+    segments.sort_unstable();
+    Ok((snapshots, segments))
+}
+
+// 删除 id 之前的快照和 AOF，它们已经包含在 snapshot.<id> 里了
+fn remove_before(dir: &Path, id: u64) -> Result<(), KvError> {
+    let (snapshots, segments) = list_files(dir)?;
+    for snapshot in snapshots.into_iter().filter(|s| *s < id) {
+        fs::remove_file(dir.join(format!("{}{}", SNAPSHOT_PREFIX, snapshot)))?;
+    }
+    for segment in segments.into_iter().filter(|s| *s < id) {
+        fs::remove_file(dir.join(format!("{}{}", AOF_PREFIX, segment)))?;
+    }
+    match fs::remove_file(dir.join(SNAPSHOT_TMP)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// 把一个 AOF 中的记录写入 store；文件末尾写了一半的记录是宕机造成的，截掉之后继续
+fn replay(path: &Path, store: &impl Storage) -> Result<(), KvError> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(&file);
+    let mut offset = 0u64;
+    loop {
+        let mut header = [0u8; 8];
+        let payload = match reader.read_exact(&mut header) {
+            Ok(_) => {
+                let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+                if len > MAX_RECORD {
+                    return Err(KvError::FrameTooLarge(len, MAX_RECORD));
+                }
+                let mut payload = vec![0u8; len];
+                reader.read_exact(&mut payload).map(|_| payload)
+            }
+            Err(e) => Err(e),
+        };
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let expected = u32::from_be_bytes(header[4..].try_into().unwrap());
+        let actual = crc32c::crc32c(&payload);
+        if expected != actual {
+            return Err(KvError::ChecksumMismatch(expected as _, actual as _));
+        }
+        apply(CommandRequest::decode(payload.as_slice())?, store)?;
+        offset += payload.len() as u64 + 8;
+    }
+
+    let len = file.metadata()?.len();
+    if offset < len {
+        warn!(
+            "AOF {:?} is truncated, dropping the last {} bytes",
+            path,
+            len - offset
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+    }
+    Ok(())
+}
+
+fn apply(cmd: CommandRequest, store: &impl Storage) -> Result<(), KvError> {
+    match cmd.request_data {
+        Some(RequestData::Hset(param)) => {
+            let pair = param.pair.unwrap_or_default();
+            store.set(&param.table, pair.key, pair.value.unwrap_or_default())?;
+        }
+        Some(RequestData::Hdel(param)) => {
+            store.del(&param.table, &param.key)?;
+        }
+        _ => return Err(KvError::InvalidFrame("Unexpected command in AOF".into())),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, Value};
+    use tempfile::tempdir;
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn memtable_should_recover_from_snapshot_and_aof() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::default().with_fsync(FsyncPolicy::Always);
+        {
+            let store = MemTable::open(dir.path(), config).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+            store.compact().unwrap();
+            // 快照之后的修改只在 AOF 里
+            store.set("t1", "k1".into(), "v3".into()).unwrap();
+            store.del("t1", "k2").unwrap();
+            store.set("t2", "k1".into(), 42.into()).unwrap();
+        }
+        assert_eq!(files(dir.path()), vec!["aof.1", "snapshot.1"]);
+
+        let store = MemTable::open(dir.path(), config).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v3".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get("t2", "k1").unwrap(), Some(Value::from(42)));
+
+        // 没有新的写入时不生成快照
+        store.compact().unwrap();
+        store.compact().unwrap();
+        assert_eq!(files(dir.path()), vec!["aof.2", "snapshot.2"]);
+    }
+
+    #[test]
+    fn aof_should_drop_truncated_record() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::default().with_fsync(FsyncPolicy::Never);
+        {
+            let store = MemTable::open(dir.path(), config).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+        }
+        let path = dir.path().join("aof.0");
+        let len = fs::metadata(&path).unwrap().len();
+        // 宕机时只写了一半的记录
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 10, 1, 2]).unwrap();
+
+        let store = MemTable::open(dir.path(), config).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        drop(store);
+
+        let store = MemTable::open(dir.path(), config).unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        drop(store);
+
+        // 中间的记录损坏时拒绝启动
+        let mut data = fs::read(&path).unwrap();
+        data[10] ^= 0xff;
+        fs::write(&path, data).unwrap();
+        assert!(matches!(
+            MemTable::open(dir.path(), config),
+            Err(KvError::ChecksumMismatch(..))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder};
+use prost::Message;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    backup_record::Record, BackupEnd, BackupInfo, BackupRecord, Compression, Hmset, KvError,
+    Storage,
+};
+
+/// 备份文件的格式版本，格式不兼容时增加
+pub const BACKUP_VERSION: u32 = 1;
+// 备份文件开头的 magic
+const BACKUP_MAGIC: &[u8; 4] = b"KVBK";
+// 每条记录最多包含的 kv pair
+pub(crate) const BACKUP_CHUNK: usize = 1000;
+// 单条记录的最大长度，防止损坏的文件导致分配过多内存
+const MAX_RECORD: usize = 256 * 1024 * 1024;
+
+/// 写备份文件
+///
+/// 文件格式：magic、长度前缀的 BackupInfo，之后是压缩过的记录。
+/// 每条记录是 4 字节大端序长度加上 BackupRecord，最后一条是 BackupEnd
+pub struct BackupWriter<W: Write> {
+    encoder: Encoder<W>,
+    hasher: Xxh3,
+    info: BackupInfo,
+    last_table: Option<String>,
+}
+
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> BackupWriter<W> {
+    pub fn new(mut writer: W, compression: Compression) -> Result<Self, KvError> {
+        let info = BackupInfo {
+            version: BACKUP_VERSION,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            compression: compression as _,
+            ..Default::default()
+        };
+        writer.write_all(BACKUP_MAGIC)?;
+        write_message(&mut writer, &info)?;
+
+        let encoder = match compression {
+            Compression::None => Encoder::None(writer),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(writer)),
+            Compression::Zstd => {
+                Encoder::Zstd(zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?)
+            }
+        };
+        Ok(Self {
+            encoder,
+            hasher: Xxh3::new(),
+            info,
+            last_table: None,
+        })
+    }
+
+    /// 写入一个 table 的部分数据，同一个 table 的数据需要连续写入
+    pub fn write_chunk(&mut self, hmset: Hmset) -> Result<(), KvError> {
+        if self.last_table.as_ref() != Some(&hmset.table) {
+            self.info.tables += 1;
+            self.last_table = Some(hmset.table.clone());
+        }
+        self.info.pairs += hmset.pairs.len() as u64;
+        self.write_record(Record::Chunk(hmset))
+    }
+
+    /// 写入 storage 中所有 table 的数据
+    pub fn write_storage(&mut self, store: &impl Storage) -> Result<(), KvError> {
+        for table in store.tables()? {
+            let mut pairs = store.get_iter(&table)?.peekable();
+            while pairs.peek().is_some() {
+                let hmset = Hmset {
+                    table: table.clone(),
+                    pairs: pairs.by_ref().take(BACKUP_CHUNK).collect(),
+                };
+                self.write_chunk(hmset)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 写入结束记录并刷新，返回备份的信息
+    pub fn finish(mut self) -> Result<BackupInfo, KvError> {
+        let end = BackupEnd {
+            tables: self.info.tables,
+            pairs: self.info.pairs,
+            checksum: self.hasher.digest(),
+        };
+        self.write_record(Record::End(end))?;
+
+        let mut writer = match self.encoder {
+            Encoder::None(w) => w,
+            Encoder::Gzip(e) => e.finish()?,
+            Encoder::Lz4(e) => e.finish().map_err(|e| KvError::Internal(e.to_string()))?,
+            Encoder::Zstd(e) => e.finish()?,
+        };
+        writer.flush()?;
+        Ok(self.info)
+    }
+
+    fn write_record(&mut self, record: Record) -> Result<(), KvError> {
+        let record = BackupRecord {
+            record: Some(record),
+        };
+        let mut buf = Vec::with_capacity(record.encoded_len() + 4);
+        write_message(&mut buf, &record)?;
+        if !matches!(record.record, Some(Record::End(_))) {
+            self.hasher.update(&buf);
+        }
+        match &mut self.encoder {
+            Encoder::None(w) => w.write_all(&buf)?,
+            Encoder::Gzip(e) => e.write_all(&buf)?,
+            Encoder::Lz4(e) => e.write_all(&buf)?,
+            Encoder::Zstd(e) => e.write_all(&buf)?,
+        }
+        Ok(())
+    }
+}
+
+/// 读备份文件，按顺序返回每个 Hmset，读到结束记录时检查数量和校验和
+pub struct BackupReader {
+    decoder: Box<dyn Read>,
+    hasher: Xxh3,
+    info: BackupInfo,
+    done: bool,
+}
+
+impl BackupReader {
+    pub fn new(reader: impl Read + 'static) -> Result<Self, KvError> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != BACKUP_MAGIC {
+            return Err(KvError::InvalidBackup("Not a kv backup file".into()));
+        }
+        let mut info: BackupInfo = read_message(&mut reader)?
+            .ok_or_else(|| KvError::InvalidBackup("Missing backup header".into()))?;
+        if info.version > BACKUP_VERSION {
+            return Err(KvError::InvalidBackup(format!(
+                "Backup version {} is newer than {}",
+                info.version, BACKUP_VERSION
+            )));
+        }
+
+        let decoder: Box<dyn Read> = match Compression::from_i32(info.compression) {
+            Some(Compression::None) => Box::new(reader),
+            Some(Compression::Gzip) => Box::new(GzDecoder::new(reader)),
+            Some(Compression::Lz4) => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+            Some(Compression::Zstd) => Box::new(zstd::Decoder::with_buffer(reader)?),
+            None => {
+                return Err(KvError::InvalidBackup(format!(
+                    "Unknown compression {}",
+                    info.compression
+                )))
+            }
+        };
+        // 数量在读完之后才知道
+        info.tables = 0;
+        info.pairs = 0;
+        Ok(Self {
+            decoder,
+            hasher: Xxh3::new(),
+            info,
+            done: false,
+        })
+    }
+
+    /// 备份的信息，读完之前 tables 为 0，pairs 是目前已经读到的数量
+    pub fn info(&self) -> &BackupInfo {
+        &self.info
+    }
+
+    /// 下一个 Hmset，读完时返回 None
+    pub fn next_chunk(&mut self) -> Result<Option<Hmset>, KvError> {
+        if self.done {
+            return Ok(None);
+        }
+        let raw = match read_frame(&mut self.decoder)? {
+            Some(raw) => raw,
+            None => return Err(KvError::InvalidBackup("Backup is truncated".into())),
+        };
+        let record = BackupRecord::decode(&raw[4..])?;
+        match record.record {
+            Some(Record::Chunk(hmset)) => {
+                self.hasher.update(&raw);
+                self.info.pairs += hmset.pairs.len() as u64;
+                Ok(Some(hmset))
+            }
+            Some(Record::End(end)) => {
+                self.done = true;
+                if end.checksum != self.hasher.digest() || end.pairs != self.info.pairs {
+                    return Err(KvError::InvalidBackup("Backup checksum mismatch".into()));
+                }
+                self.info.tables = end.tables;
+                Ok(None)
+            }
+            None => Err(KvError::InvalidBackup("Empty backup record".into())),
+        }
+    }
+
+    /// 读出所有数据，检查完整之后才返回
+    pub fn read_all(mut self) -> Result<(BackupInfo, Vec<Hmset>), KvError> {
+        let mut chunks = Vec::new();
+        while let Some(hmset) = self.next_chunk()? {
+            chunks.push(hmset);
+        }
+        Ok((self.info, chunks))
+    }
+
+    /// 把数据写入 storage，返回备份的信息；备份损坏时已经写入的数据不会回滚
+    pub fn restore_to(mut self, store: &impl Storage) -> Result<BackupInfo, KvError> {
+        while let Some(hmset) = self.next_chunk()? {
+            for pair in hmset.pairs {
+                store.set(&hmset.table, pair.key, pair.value.unwrap_or_default())?;
+            }
+        }
+        Ok(self.info)
+    }
+}
+
+/// 把 storage 中所有的数据备份到 writer
+pub fn backup_storage(
+    store: &impl Storage,
+    writer: impl Write,
+    compression: Compression,
+) -> Result<BackupInfo, KvError> {
+    let mut backup = BackupWriter::new(BufWriter::new(writer), compression)?;
+    backup.write_storage(store)?;
+    backup.finish()
+}
+
+fn write_message(writer: &mut impl Write, msg: &impl Message) -> Result<(), KvError> {
+    writer.write_all(&(msg.encoded_len() as u32).to_be_bytes())?;
+    writer.write_all(&msg.encode_to_vec())?;
+    Ok(())
+}
+
+fn read_message<M: Message + Default>(reader: &mut impl Read) -> Result<Option<M>, KvError> {
+    match read_frame(reader)? {
+        Some(raw) => Ok(Some(M::decode(&raw[4..])?)),
+        None => Ok(None),
+    }
+}
+
+// 读取一条带长度前缀的记录，返回包括长度在内的原始字节，用来计算校验和
+fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>, KvError> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let size = u32::from_be_bytes(len) as usize;
+    if size > MAX_RECORD {
+        return Err(KvError::InvalidBackup(format!(
+            "Record size {} is too large",
+            size
+        )));
+    }
+    let mut raw = len.to_vec();
+    raw.resize(size + 4, 0);
+    reader.read_exact(&mut raw[4..])?;
+    Ok(Some(raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb, Value};
+    use tempfile::tempdir;
+
+    fn fill(store: &impl Storage) {
+        for i in 0..2500 {
+            store
+                .set("t1", format!("k{}", i), Value::from(i as i64))
+                .unwrap();
+        }
+        store.set("t2", "hello".into(), "world".into()).unwrap();
+    }
+
+    #[test]
+    fn backup_should_round_trip_between_backends() {
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Lz4,
+            Compression::Zstd,
+        ] {
+            let store = MemTable::new();
+            fill(&store);
+            let mut buf = Vec::new();
+            let info = backup_storage(&store, &mut buf, compression).unwrap();
+            assert_eq!((info.tables, info.pairs), (2, 2501));
+
+            // MemTable 的备份恢复到 SledDb
+            let dir = tempdir().unwrap();
+            let db = SledDb::new(dir.path());
+            let reader = BackupReader::new(io::Cursor::new(buf)).unwrap();
+            let restored = reader.restore_to(&db).unwrap();
+            assert_eq!(restored.tables, 2);
+            assert_eq!(restored.pairs, 2501);
+            assert_eq!(restored.compression, compression as i32);
+            assert_eq!(db.get("t1", "k2499").unwrap(), Some(2499.into()));
+            assert_eq!(db.get("t2", "hello").unwrap(), Some("world".into()));
+            assert_eq!(db.get_all("t1").unwrap().len(), 2500);
+        }
+    }
+
+    #[test]
+    fn corrupted_backup_should_be_rejected() {
+        let store = MemTable::new();
+        fill(&store);
+        let mut buf = Vec::new();
+        backup_storage(&store, &mut buf, Compression::None).unwrap();
+
+        // 截断的备份
+        let truncated = buf[..buf.len() - 10].to_vec();
+        let reader = BackupReader::new(io::Cursor::new(truncated)).unwrap();
+        assert!(reader.read_all().is_err());
+
+        // 修改了数据
+        let mut modified = buf.clone();
+        let pos = modified.len() / 2;
+        modified[pos] ^= 0xff;
+        let reader = BackupReader::new(io::Cursor::new(modified)).unwrap();
+        assert!(reader.read_all().is_err());
+
+        let e = BackupReader::new(io::Cursor::new(b"nope".to_vec())).err();
+        assert!(matches!(e, Some(KvError::InvalidBackup(_))));
+    }
+}
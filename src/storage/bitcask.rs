@@ -0,0 +1,1094 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+
+// 数据文件是 <id>.data，合并之后生成的数据文件还有对应的 <id>.hint
+const DATA_EXT: &str = "data";
+const HINT_EXT: &str = "hint";
+const HINT_TMP_EXT: &str = "hint.tmp";
+// 合并时正在写入的数据文件，完成之后才改名成 <id>.data
+const DATA_TMP_EXT: &str = "data.tmp";
+// 合并的输出都写好之后，先写下要删除的输入文件，再开始删除
+const MANIFEST: &str = "merge.manifest";
+const MANIFEST_TMP: &str = "merge.manifest.tmp";
+// 记录头：crc32c、seq、table 长度、key 长度、value 长度
+const HEADER: usize = 4 + 8 + 4 + 4 + 4;
+// hint 记录头：crc32c、seq、记录位置、记录长度、table 长度、key 长度
+const HINT_HEADER: usize = 4 + 8 + 8 + 4 + 4 + 4;
+// value 长度为这个值时表示删除
+const TOMBSTONE: u32 = u32::MAX;
+// 单条记录的最大长度，防止损坏的文件导致分配过多内存
+const MAX_RECORD: usize = 256 * 1024 * 1024;
+
+/// Bitcask 存储的参数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitcaskConfig {
+    /// 数据文件超过这个大小之后写到新的文件
+    pub max_file_size: u64,
+    /// 每次写入之后都 fsync
+    pub sync: bool,
+    /// 检查是否需要合并的间隔
+    pub merge_interval: Duration,
+    /// 失效数据占所有数据的比例超过这个值时合并
+    pub merge_ratio: f64,
+}
+
+impl Default for BitcaskConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 64 * 1024 * 1024,
+            sync: false,
+            merge_interval: Duration::from_secs(60),
+            merge_ratio: 0.5,
+        }
+    }
+}
+
+impl BitcaskConfig {
+    /// 数据文件的最大大小
+    pub fn with_max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = size.max(1);
+        self
+    }
+
+    /// 每次写入之后是否 fsync
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// 检查合并的间隔和触发合并的失效数据比例
+    pub fn with_merge(mut self, interval: Duration, ratio: f64) -> Self {
+        self.merge_interval = interval;
+        self.merge_ratio = ratio;
+        self
+    }
+}
+
+/// 不依赖 sled 的日志结构存储，参考了 Bitcask 的设计
+///
+/// 所有修改都追加到数据文件，内存中的 keydir 记录每个 key 最新的记录在哪儿。
+/// 每条记录都有 crc32c 和递增的 seq，重启时按 seq 重建 keydir；
+/// 后台定期把仍然有效的记录合并到新的文件，并生成 hint 文件加快启动
+pub struct Bitcask {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    config: BitcaskConfig,
+    next_id: AtomicU64,
+    writer: Mutex<Writer>,
+    state: RwLock<State>,
+    // 同一时间只有一个合并
+    merging: Mutex<()>,
+}
+
+// 正在写入的数据文件
+struct Writer {
+    id: u64,
+    file: Arc<File>,
+    offset: u64,
+    seq: u64,
+}
+
+#[derive(Default)]
+struct State {
+    keydir: HashMap<String, HashMap<String, Location>>,
+    files: HashMap<u64, Arc<File>>,
+    // 每个文件中最小的 seq（或者它的下界），合并时用来判断删除记录还需不需要保留
+    first_seq: HashMap<u64, u64>,
+    // 所有有效记录的总长度
+    live: u64,
+}
+
+// 一条记录在数据文件中的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    file: u64,
+    offset: u64,
+    size: u32,
+    seq: u64,
+}
+
+// 重启时读出的记录，location 为 None 表示删除
+struct Entry {
+    table: String,
+    key: String,
+    seq: u64,
+    location: Option<Location>,
+}
+
+impl Bitcask {
+    /// 打开 dir 目录中的数据，目录不存在时创建
+    pub fn open(dir: impl Into<PathBuf>, config: BitcaskConfig) -> Result<Self, KvError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        // 上次合并在删除输入文件时中断了，把剩下的输入文件删完
+        finish_merge(&dir)?;
+
+        // 每个 key 最新的记录，删除也要记下来，它可能比其它文件中的记录更新
+        let mut index: HashMap<String, HashMap<String, (u64, Option<Location>)>> = HashMap::new();
+        let mut state = State::default();
+        let mut max_seq = 0;
+        let ids = list_files(&dir)?;
+        // 没有 hint 的文件都是之前写入的文件，只有最后一个可能有写了一半的记录
+        let tail = ids
+            .iter()
+            .copied()
+            .filter(|id| !file_path(&dir, *id, HINT_EXT).exists())
+            .max();
+        for id in &ids {
+            let entries = read_entries(&dir, *id, Some(*id) == tail)?;
+            let first = entries.iter().map(|e| e.seq).min().unwrap_or_default();
+            state.first_seq.insert(*id, first);
+            for entry in entries {
+                max_seq = max_seq.max(entry.seq);
+                let keys = index.entry(entry.table).or_default();
+                match keys.get(&entry.key) {
+                    Some((seq, _)) if *seq >= entry.seq => {}
+                    _ => {
+                        keys.insert(entry.key, (entry.seq, entry.location));
+                    }
+                }
+            }
+            state.files.insert(*id, Arc::new(open_data(&dir, *id)?));
+        }
+        for (table, keys) in index {
+            let keys: HashMap<_, _> = keys
+                .into_iter()
+                .filter_map(|(key, (_, location))| location.map(|l| (key, l)))
+                .collect();
+            if !keys.is_empty() {
+                state.live += keys.values().map(|l| l.size as u64).sum::<u64>();
+                state.keydir.insert(table, keys);
+            }
+        }
+
+        // 总是写到新的文件，合并生成的文件有 hint，不能再追加
+        let id = ids.last().map(|id| id + 1).unwrap_or_default();
+        let file = Arc::new(open_data(&dir, id)?);
+        state.files.insert(id, file.clone());
+        state.first_seq.insert(id, max_seq + 1);
+        let inner = Arc::new(Inner {
+            dir,
+            config,
+            next_id: AtomicU64::new(id + 1),
+            writer: Mutex::new(Writer {
+                id,
+                file,
+                offset: 0,
+                seq: max_seq,
+            }),
+            state: RwLock::new(state),
+            merging: Mutex::new(()),
+        });
+
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || run_merge(weak));
+        Ok(Self { inner })
+    }
+
+    /// 立即把失效的数据合并掉，没有可以合并的文件时返回 false
+    pub fn merge(&self) -> Result<bool, KvError> {
+        self.inner.merge()
+    }
+}
+
+impl Inner {
+    // 写入一条记录，调用者需要持有 writer 的锁
+    fn append(
+        &self,
+        writer: &mut Writer,
+        table: &str,
+        key: &str,
+        value: Option<&[u8]>,
+    ) -> Result<Location, KvError> {
+        if writer.offset >= self.config.max_file_size {
+            self.rotate(writer)?;
+        }
+        let seq = writer.seq + 1;
+        let record = encode_record(seq, table, key, value)?;
+        if let Err(e) = (&*writer.file).write_all(&record) {
+            // 去掉写了一半的记录，之后的记录才能正常读出
+            let _ = writer.file.set_len(writer.offset);
+            return Err(e.into());
+        }
+        if self.config.sync {
+            writer.file.sync_data()?;
+        }
+        let location = Location {
+            file: writer.id,
+            offset: writer.offset,
+            size: record.len() as u32,
+            seq,
+        };
+        writer.offset += record.len() as u64;
+        writer.seq = seq;
+        Ok(location)
+    }
+
+    // 切换到新的数据文件，之前的文件不会再修改
+    fn rotate(&self, writer: &mut Writer) -> Result<(), KvError> {
+        writer.file.sync_data()?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let file = Arc::new(open_data(&self.dir, id)?);
+        let mut state = self.state.write().unwrap();
+        state.files.insert(id, file.clone());
+        state.first_seq.insert(id, writer.seq + 1);
+        drop(state);
+        writer.id = id;
+        writer.file = file;
+        writer.offset = 0;
+        Ok(())
+    }
+
+    // table 中所有 key 的位置；持有文件的引用，合并删除了文件之后也能读到数据
+    fn locations(&self, table: &str) -> Vec<(String, Location, Arc<File>)> {
+        let state = self.state.read().unwrap();
+        match state.keydir.get(table) {
+            Some(keys) => keys
+                .iter()
+                .map(|(key, l)| (key.clone(), *l, state.files[&l.file].clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn location(&self, table: &str, key: &str) -> Option<(Location, Arc<File>)> {
+        let state = self.state.read().unwrap();
+        let location = *state.keydir.get(table)?.get(key)?;
+        Some((location, state.files.get(&location.file)?.clone()))
+    }
+
+    // 修改 keydir，返回之前的位置
+    fn update(&self, table: &str, key: &str, location: Option<Location>) -> Option<Location> {
+        let mut state = self.state.write().unwrap();
+        let old = match location {
+            Some(location) => {
+                state.live += location.size as u64;
+                let keys = state.keydir.entry(table.into()).or_default();
+                keys.insert(key.into(), location)
+            }
+            None => {
+                let keys = state.keydir.get_mut(table)?;
+                let old = keys.remove(key);
+                if keys.is_empty() {
+                    state.keydir.remove(table);
+                }
+                old
+            }
+        };
+        if let Some(old) = old {
+            state.live -= old.size as u64;
+        }
+        old
+    }
+
+    // 失效数据的比例
+    fn garbage_ratio(&self) -> Result<f64, KvError> {
+        let state = self.state.read().unwrap();
+        let mut total = 0;
+        for file in state.files.values() {
+            total += file.metadata()?.len();
+        }
+        match total {
+            0 => Ok(0.0),
+            _ => Ok(total.saturating_sub(state.live) as f64 / total as f64),
+        }
+    }
+
+    fn merge(&self) -> Result<bool, KvError> {
+        let _merging = self.merging.lock().unwrap();
+        match self.compact()? {
+            Some(_) => {
+                finish_merge(&self.dir)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // 把之前的文件合并到新的文件，写好清单之后返回要删除的输入文件，调用者需要持有 merging 的锁
+    fn compact(&self) -> Result<Option<Vec<u64>>, KvError> {
+        // 之后的写入都在新的文件里，之前的文件都可以合并
+        let (inputs, files, oldest_other) = {
+            let mut writer = self.writer.lock().unwrap();
+            self.rotate(&mut writer)?;
+            let state = self.state.read().unwrap();
+            let mut inputs: Vec<u64> = state
+                .files
+                .keys()
+                .copied()
+                .filter(|id| *id != writer.id)
+                .collect();
+            inputs.sort_unstable();
+            // 不参与合并的文件里最旧的记录，删除记录比它新时，这些文件里可能还有更旧的值
+            let oldest_other = state
+                .first_seq
+                .iter()
+                .filter(|(id, _)| !inputs.contains(id))
+                .map(|(_, seq)| *seq)
+                .min()
+                .unwrap_or(u64::MAX);
+            (inputs, state.files.clone(), oldest_other)
+        };
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+        let input_set: HashSet<u64> = inputs.iter().copied().collect();
+        let live: Vec<(String, String, Location)> = {
+            let state = self.state.read().unwrap();
+            state
+                .keydir
+                .iter()
+                .flat_map(|(table, keys)| {
+                    keys.iter()
+                        .filter(|(_, l)| input_set.contains(&l.file))
+                        .map(move |(key, l)| (table.clone(), key.clone(), *l))
+                })
+                .collect()
+        };
+        let tombstones = self.tombstones(&inputs, oldest_other)?;
+
+        // 有效的记录原样复制到新的文件，seq 不变；还需要保留的删除记录也一起复制
+        let mut outputs: Vec<(u64, Arc<File>, u64)> = Vec::new();
+        let mut output: Option<MergeOutput> = None;
+        let mut moved = Vec::with_capacity(live.len());
+        let records = live
+            .into_iter()
+            .map(|(table, key, l)| (table, key, l.seq, Some(l)))
+            .chain(
+                tombstones
+                    .into_iter()
+                    .map(|((t, k), seq)| (t, k, seq, None)),
+            );
+        for (table, key, seq, old) in records {
+            let record = match old {
+                Some(old) => read_record(&files[&old.file], old)?,
+                None => encode_record(seq, &table, &key, None)?,
+            };
+            if output
+                .as_ref()
+                .is_none_or(|o| o.offset >= self.config.max_file_size)
+            {
+                if let Some(o) = output.take() {
+                    outputs.push(o.finish(&self.dir)?);
+                }
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                output = Some(MergeOutput::new(&self.dir, id)?);
+            }
+            let o = output.as_mut().unwrap();
+            let new = o.write(&record, &table, &key, seq, old.is_some())?;
+            if let Some(old) = old {
+                moved.push((table, key, old, new));
+            }
+        }
+        if let Some(o) = output.take() {
+            outputs.push(o.finish(&self.dir)?);
+        }
+        File::open(&self.dir)?.sync_all()?;
+        write_manifest(&self.dir, &inputs)?;
+
+        // 合并期间修改过的 key 不需要更新，新文件里的记录 seq 更小，重启时也会被覆盖
+        let mut state = self.state.write().unwrap();
+        for (id, file, first) in outputs {
+            state.files.insert(id, file);
+            state.first_seq.insert(id, first);
+        }
+        for (table, key, old, new) in moved {
+            let location = state.keydir.get_mut(&table).and_then(|k| k.get_mut(&key));
+            if let Some(location) = location.filter(|l| **l == old) {
+                *location = new;
+            }
+        }
+        for id in &inputs {
+            state.files.remove(id);
+            state.first_seq.remove(id);
+        }
+        Ok(Some(inputs))
+    }
+
+    // 输入文件中还需要保留的删除记录：key 现在仍然是删除的，
+    // 并且不参与合并的文件里可能有这个 key 更旧的记录
+    fn tombstones(
+        &self,
+        inputs: &[u64],
+        oldest_other: u64,
+    ) -> Result<HashMap<(String, String), u64>, KvError> {
+        let mut tombstones = HashMap::new();
+        for id in inputs {
+            for entry in read_entries(&self.dir, *id, false)? {
+                if entry.location.is_some() || entry.seq <= oldest_other {
+                    continue;
+                }
+                let seq = tombstones.entry((entry.table, entry.key)).or_insert(0);
+                *seq = entry.seq.max(*seq);
+            }
+        }
+        let state = self.state.read().unwrap();
+        tombstones.retain(|(table, key), seq| {
+            state
+                .keydir
+                .get(table)
+                .and_then(|k| k.get(key))
+                .is_none_or(|l| l.seq < *seq)
+        });
+        Ok(tombstones)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.lock().unwrap().file.sync_data() {
+            warn!("Failed to sync data file: {:?}", e);
+        }
+    }
+}
+
+// 定期检查失效数据的比例，需要时合并；Bitcask 释放后退出
+fn run_merge(inner: Weak<Inner>) {
+    loop {
+        let interval = match inner.upgrade() {
+            Some(inner) => inner.config.merge_interval,
+            None => return,
+        };
+        thread::sleep(interval);
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let merge = match inner.garbage_ratio() {
+            Ok(ratio) => ratio > 0.0 && ratio >= inner.config.merge_ratio,
+            Err(e) => {
+                warn!("Failed to check data files: {:?}", e);
+                false
+            }
+        };
+        if merge {
+            if let Err(e) = inner.merge() {
+                warn!("Failed to merge data files: {:?}", e);
+            }
+        }
+    }
+}
+
+// 合并时正在写入的新文件
+struct MergeOutput {
+    id: u64,
+    file: File,
+    offset: u64,
+    first_seq: u64,
+    hint: Vec<u8>,
+}
+
+impl MergeOutput {
+    fn new(dir: &Path, id: u64) -> Result<Self, KvError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(file_path(dir, id, DATA_TMP_EXT))?;
+        Ok(Self {
+            id,
+            file,
+            offset: 0,
+            first_seq: u64::MAX,
+            hint: Vec::new(),
+        })
+    }
+
+    fn write(
+        &mut self,
+        record: &[u8],
+        table: &str,
+        key: &str,
+        seq: u64,
+        live: bool,
+    ) -> Result<Location, KvError> {
+        self.file.write_all(record)?;
+        let location = Location {
+            file: self.id,
+            offset: self.offset,
+            size: record.len() as u32,
+            seq,
+        };
+        self.hint.extend(encode_hint(table, key, location, live));
+        self.offset += record.len() as u64;
+        self.first_seq = self.first_seq.min(seq);
+        Ok(location)
+    }
+
+    // 数据写到磁盘之后才生成 hint 文件，最后再把数据文件改名，
+    // 所以 <id>.data 没有 hint 时一定是之前正常写入的文件
+    fn finish(self, dir: &Path) -> Result<(u64, Arc<File>, u64), KvError> {
+        self.file.sync_all()?;
+        let tmp = file_path(dir, self.id, HINT_TMP_EXT);
+        let mut hint = File::create(&tmp)?;
+        hint.write_all(&self.hint)?;
+        hint.sync_all()?;
+        fs::rename(&tmp, file_path(dir, self.id, HINT_EXT))?;
+        fs::rename(
+            file_path(dir, self.id, DATA_TMP_EXT),
+            file_path(dir, self.id, DATA_EXT),
+        )?;
+        Ok((self.id, Arc::new(self.file), self.first_seq))
+    }
+}
+
+impl Storage for Bitcask {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        match self.inner.location(table, key) {
+            Some((location, file)) => Ok(Some(read_value(&file, location)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let data: Vec<u8> = value.try_into()?;
+        // 持有 writer 的锁，读旧值、写入和修改 keydir 不会和其它写入交错
+        let mut writer = self.inner.writer.lock().unwrap();
+        let old = match self.inner.location(table, &key) {
+            Some((location, file)) => Some(read_value(&file, location)?),
+            None => None,
+        };
+        let location = self.inner.append(&mut writer, table, &key, Some(&data))?;
+        self.inner.update(table, &key, Some(location));
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let state = self.inner.state.read().unwrap();
+        Ok(state.keydir.get(table).is_some_and(|k| k.contains_key(key)))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        let old = match self.inner.location(table, key) {
+            Some((location, file)) => read_value(&file, location)?,
+            // 删除不存在的 key 不需要写入
+            None => return Ok(None),
+        };
+        self.inner.append(&mut writer, table, key, None)?;
+        self.inner.update(table, key, None);
+        Ok(Some(old))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner
+            .locations(table)
+            .into_iter()
+            .map(|(key, location, file)| Ok(Kvpair::new(key, read_value(&file, location)?)))
+            .collect()
+    }
+
+    // 只先取出 key 的位置，value 在迭代时才从文件中读取
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let table = table.to_string();
+        let iter =
+            self.inner
+                .locations(&table)
+                .into_iter()
+                .filter_map(
+                    move |(key, location, file)| match read_value(&file, location) {
+                        Ok(value) => Some(Kvpair::new(key, value)),
+                        // iterator 无法返回错误，跳过无法读取的数据
+                        Err(e) => {
+                            warn!("Skip unreadable pair {} in {}: {:?}", key, table, e);
+                            None
+                        }
+                    },
+                );
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let state = self.inner.state.read().unwrap();
+        Ok(state.keydir.keys().cloned().collect())
+    }
+}
+
+fn file_path(dir: &Path, id: u64, ext: &str) -> PathBuf {
+    dir.join(format!("{:09}.{}", id, ext))
+}
+
+fn open_data(dir: &Path, id: u64) -> Result<File, KvError> {
+    let path = file_path(dir, id, DATA_EXT);
+    Ok(OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?)
+}
+
+fn remove_file(path: &Path) -> Result<(), KvError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// 目录中所有数据文件的 id，从小到大排列；顺便删掉空的数据文件、
+// 没写完的 hint 和合并输出，以及数据文件已经不在的 hint
+fn list_files(dir: &Path) -> Result<Vec<u64>, KvError> {
+    let mut ids = Vec::new();
+    let mut hints = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.ends_with(HINT_TMP_EXT) || name.ends_with(DATA_TMP_EXT) {
+            remove_file(&entry.path())?;
+            continue;
+        }
+        let parse =
+            |ext: &str| -> Option<u64> { name.strip_suffix(ext)?.strip_suffix('.')?.parse().ok() };
+        if let Some(id) = parse(HINT_EXT) {
+            hints.push(id);
+        }
+        if let Some(id) = parse(DATA_EXT) {
+            if entry.metadata()?.len() == 0 {
+                remove_file(&entry.path())?;
+            } else {
+                ids.push(id);
+            }
+        }
+    }
+    for id in hints {
+        if !ids.contains(&id) {
+            remove_file(&file_path(dir, id, HINT_EXT))?;
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+// 写下合并要删除的输入文件，清单写到磁盘之后合并才算完成
+fn write_manifest(dir: &Path, inputs: &[u64]) -> Result<(), KvError> {
+    let mut buf = vec![0; 4];
+    for id in inputs {
+        buf.extend_from_slice(&id.to_be_bytes());
+    }
+    let crc = crc32c::crc32c(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_be_bytes());
+    let tmp = dir.join(MANIFEST_TMP);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(MANIFEST))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// 删除清单中的输入文件，全部删掉之后再删除清单；没有清单时什么都不做
+fn finish_merge(dir: &Path) -> Result<(), KvError> {
+    remove_file(&dir.join(MANIFEST_TMP))?;
+    let data = match fs::read(dir.join(MANIFEST)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if data.len() < 4 || (data.len() - 4) % 8 != 0 {
+        return Err(KvError::InvalidFrame("Merge manifest is truncated".into()));
+    }
+    let expected = u32::from_be_bytes(data[..4].try_into().unwrap());
+    let actual = crc32c::crc32c(&data[4..]);
+    if expected != actual {
+        return Err(KvError::ChecksumMismatch(expected as _, actual as _));
+    }
+    for id in data[4..].chunks(8) {
+        let id = u64::from_be_bytes(id.try_into().unwrap());
+        remove_file(&file_path(dir, id, HINT_EXT))?;
+        remove_file(&file_path(dir, id, DATA_EXT))?;
+    }
+    File::open(dir)?.sync_all()?;
+    remove_file(&dir.join(MANIFEST))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn encode_record(
+    seq: u64,
+    table: &str,
+    key: &str,
+    value: Option<&[u8]>,
+) -> Result<Vec<u8>, KvError> {
+    let value_len = value.map_or(0, |v| v.len());
+    let size = HEADER + table.len() + key.len() + value_len;
+    if size > MAX_RECORD {
+        return Err(KvError::FrameTooLarge(size, MAX_RECORD));
+    }
+    let mut buf = Vec::with_capacity(size);
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend_from_slice(&(table.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    let len = value.map_or(TOMBSTONE, |v| v.len() as u32);
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(table.as_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(value.unwrap_or_default());
+    let crc = crc32c::crc32c(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_be_bytes());
+    Ok(buf)
+}
+
+// 记录头中的 table、key 和 value 长度
+fn record_lens(header: &[u8]) -> (usize, usize, Option<usize>) {
+    let u32_at = |i: usize| u32::from_be_bytes(header[i..i + 4].try_into().unwrap());
+    let value = match u32_at(20) {
+        TOMBSTONE => None,
+        len => Some(len as usize),
+    };
+    (u32_at(12) as usize, u32_at(16) as usize, value)
+}
+
+// 解码之后的记录，value 为 None 表示删除
+struct Record<'a> {
+    seq: u64,
+    table: String,
+    key: String,
+    value: Option<&'a [u8]>,
+}
+
+// 检查记录的 crc 并解码
+fn decode_record(record: &[u8]) -> Result<Record<'_>, KvError> {
+    if record.len() < HEADER {
+        return Err(KvError::InvalidFrame("Record is too short".into()));
+    }
+    let expected = u32::from_be_bytes(record[..4].try_into().unwrap());
+    let actual = crc32c::crc32c(&record[4..]);
+    if expected != actual {
+        return Err(KvError::ChecksumMismatch(expected as _, actual as _));
+    }
+    let seq = u64::from_be_bytes(record[4..12].try_into().unwrap());
+    let (table_len, key_len, value_len) = record_lens(record);
+    if HEADER + table_len + key_len + value_len.unwrap_or_default() != record.len() {
+        return Err(KvError::InvalidFrame("Record length mismatch".into()));
+    }
+    let utf8 = |data: &[u8]| {
+        String::from_utf8(data.to_vec()).map_err(|e| KvError::InvalidFrame(e.to_string()))
+    };
+    let table = utf8(&record[HEADER..HEADER + table_len])?;
+    let key = utf8(&record[HEADER + table_len..HEADER + table_len + key_len])?;
+    let value = value_len.map(|_| &record[HEADER + table_len + key_len..]);
+    Ok(Record {
+        seq,
+        table,
+        key,
+        value,
+    })
+}
+
+fn read_record(file: &File, location: Location) -> Result<Vec<u8>, KvError> {
+    let mut record = vec![0u8; location.size as usize];
+    file.read_exact_at(&mut record, location.offset)?;
+    decode_record(&record)?;
+    Ok(record)
+}
+
+fn read_value(file: &File, location: Location) -> Result<Value, KvError> {
+    let record = read_record(file, location)?;
+    match decode_record(&record)?.value {
+        Some(value) => value.try_into(),
+        None => Err(KvError::InvalidFrame("Unexpected tombstone".into())),
+    }
+}
+
+// 读出一个文件中的所有记录，有 hint 文件时读 hint
+fn read_entries(dir: &Path, id: u64, tail: bool) -> Result<Vec<Entry>, KvError> {
+    match read_hint(dir, id) {
+        Ok(Some(entries)) => Ok(entries),
+        Ok(None) => scan(dir, id, tail),
+        Err(e) => {
+            warn!("Ignoring invalid hint file {}: {:?}", id, e);
+            scan(dir, id, tail)
+        }
+    }
+}
+
+// 读出数据文件中的所有记录。tail 是最后写入的文件，崩溃时可能有写了一半的记录，
+// 截掉坏掉的记录和之后的内容；其它文件切换之前都 fsync 过，出现损坏时返回错误
+fn scan(dir: &Path, id: u64, tail: bool) -> Result<Vec<Entry>, KvError> {
+    let path = file_path(dir, id, DATA_EXT);
+    let file = File::open(&path)?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut entries = Vec::new();
+    let mut offset = 0u64;
+    let truncated = || KvError::InvalidFrame(format!("Data file {:?} is truncated", path));
+    let error = loop {
+        if offset >= len {
+            break None;
+        }
+        let mut record = vec![0u8; HEADER];
+        if reader.read_exact(&mut record).is_err() {
+            break Some(truncated());
+        }
+        let (table_len, key_len, value_len) = record_lens(&record);
+        let size = HEADER + table_len + key_len + value_len.unwrap_or_default();
+        if size > MAX_RECORD {
+            break Some(KvError::FrameTooLarge(size, MAX_RECORD));
+        }
+        record.resize(size, 0);
+        if reader.read_exact(&mut record[HEADER..]).is_err() {
+            break Some(truncated());
+        }
+        let record = match decode_record(&record) {
+            Ok(record) => record,
+            Err(e) => break Some(e),
+        };
+        let location = record.value.map(|_| Location {
+            file: id,
+            offset,
+            size: size as u32,
+            seq: record.seq,
+        });
+        entries.push(Entry {
+            table: record.table,
+            key: record.key,
+            seq: record.seq,
+            location,
+        });
+        offset += size as u64;
+    };
+
+    match error {
+        Some(e) if !tail => Err(e),
+        Some(e) => {
+            warn!(
+                "Data file {:?} is corrupted ({:?}), dropping the last {} bytes",
+                path,
+                e,
+                len - offset
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(offset)?;
+            Ok(entries)
+        }
+        None => Ok(entries),
+    }
+}
+
+// 删除记录在 hint 中的长度是 0，真正的记录至少有一个记录头
+fn encode_hint(table: &str, key: &str, location: Location, live: bool) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HINT_HEADER + table.len() + key.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&location.seq.to_be_bytes());
+    buf.extend_from_slice(&location.offset.to_be_bytes());
+    let size = if live { location.size } else { 0 };
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(&(table.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(table.as_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    let crc = crc32c::crc32c(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_be_bytes());
+    buf
+}
+
+// 读出 hint 文件中的所有记录，没有 hint 文件时返回 None
+fn read_hint(dir: &Path, id: u64) -> Result<Option<Vec<Entry>>, KvError> {
+    let data = match fs::read(file_path(dir, id, HINT_EXT)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    let mut rest = data.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < HINT_HEADER {
+            return Err(KvError::InvalidFrame("Hint is truncated".into()));
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(rest[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_be_bytes(rest[i..i + 8].try_into().unwrap());
+        let (table_len, key_len) = (u32_at(24) as usize, u32_at(28) as usize);
+        let size = HINT_HEADER + table_len + key_len;
+        if rest.len() < size {
+            return Err(KvError::InvalidFrame("Hint is truncated".into()));
+        }
+        let (expected, actual) = (u32_at(0), crc32c::crc32c(&rest[4..size]));
+        if expected != actual {
+            return Err(KvError::ChecksumMismatch(expected as _, actual as _));
+        }
+        let utf8 = |data: &[u8]| {
+            String::from_utf8(data.to_vec()).map_err(|e| KvError::InvalidFrame(e.to_string()))
+        };
+        let location = Location {
+            file: id,
+            offset: u64_at(12),
+            size: u32_at(20),
+            seq: u64_at(4),
+        };
+        entries.push(Entry {
+            table: utf8(&rest[HINT_HEADER..HINT_HEADER + table_len])?,
+            key: utf8(&rest[HINT_HEADER + table_len..size])?,
+            seq: location.seq,
+            location: (location.size != 0).then_some(location),
+        });
+        rest = &rest[size..];
+    }
+    Ok(Some(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn bitcask_should_recover_after_merge() {
+        let dir = tempdir().unwrap();
+        let config = BitcaskConfig::default().with_max_file_size(256);
+        {
+            let store = Bitcask::open(dir.path(), config).unwrap();
+            for i in 0..20 {
+                store.set("t1", format!("k{}", i), i.into()).unwrap();
+            }
+            for i in 0..10 {
+                store.set("t1", format!("k{}", i), (i * 10).into()).unwrap();
+                store.del("t1", &format!("k{}", i + 10)).unwrap();
+            }
+            store.set("t2", "k1".into(), "v1".into()).unwrap();
+            assert!(store.merge().unwrap());
+            assert_eq!(store.inner.garbage_ratio().unwrap(), 0.0);
+            // 合并之后的写入在新的文件里
+            store.set("t1", "k0".into(), "v0".into()).unwrap();
+            store.del("t2", "k1").unwrap();
+        }
+        assert!(names(dir.path()).iter().any(|n| n.ends_with(".hint")));
+
+        let store = Bitcask::open(dir.path(), config).unwrap();
+        assert_eq!(store.get("t1", "k0").unwrap(), Some("v0".into()));
+        assert_eq!(store.get("t1", "k9").unwrap(), Some(Value::from(90)));
+        assert_eq!(store.get("t1", "k10").unwrap(), None);
+        assert_eq!(store.get_all("t1").unwrap().len(), 10);
+        assert_eq!(store.tables().unwrap(), vec!["t1"]);
+    }
+
+    #[test]
+    fn bitcask_iter_should_read_values_lazily() {
+        let dir = tempdir().unwrap();
+        let config = BitcaskConfig::default().with_max_file_size(256);
+        let store = Bitcask::open(dir.path(), config).unwrap();
+        for i in 0..10 {
+            store.set("t1", format!("k{}", i), i.into()).unwrap();
+        }
+
+        // 创建 iterator 之后覆盖所有 key 并合并，旧的数据文件被删除了，
+        // iterator 仍然从创建时的位置读到当时的值
+        let iter = store.get_iter("t1").unwrap();
+        for i in 0..10 {
+            store.set("t1", format!("k{}", i), (i * 10).into()).unwrap();
+        }
+        assert!(store.merge().unwrap());
+        let mut pairs: Vec<_> = iter.collect();
+        pairs.sort_by_key(|pair| pair.key[1..].parse::<i64>().unwrap());
+        let values: Vec<_> = pairs.into_iter().map(|pair| pair.value.unwrap()).collect();
+        assert_eq!(values, (0..10).map(Value::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bitcask_should_drop_corrupted_tail() {
+        let dir = tempdir().unwrap();
+        let config = BitcaskConfig::default().with_sync(true);
+        {
+            let store = Bitcask::open(dir.path(), config).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+        }
+        let path = file_path(dir.path(), 0, DATA_EXT);
+        let len = fs::metadata(&path).unwrap().len();
+        // 最后一条记录损坏，之后还有写了一半的记录
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        data.extend_from_slice(&[1, 2, 3]);
+        fs::write(&path, data).unwrap();
+
+        let store = Bitcask::open(dir.path(), config).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert!(fs::metadata(&path).unwrap().len() < len);
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        drop(store);
+
+        let store = Bitcask::open(dir.path(), config).unwrap();
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn interrupted_merge_should_not_resurrect_deleted_keys() {
+        let dir = tempdir().unwrap();
+        // 每条记录一个文件
+        let config = BitcaskConfig::default().with_max_file_size(1);
+        {
+            let store = Bitcask::open(dir.path(), config).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+            store.del("t1", "k1").unwrap();
+
+            let _merging = store.inner.merging.lock().unwrap();
+            let inputs = store.inner.compact().unwrap().unwrap();
+            assert_eq!(inputs, vec![0, 1, 2]);
+            // 只删掉了有删除记录的文件就崩溃了，k1 的旧值还在文件 0 里
+            remove_file(&file_path(dir.path(), 2, DATA_EXT)).unwrap();
+        }
+        assert!(dir.path().join(MANIFEST).exists());
+
+        let store = Bitcask::open(dir.path(), config).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        let names = names(dir.path());
+        assert!(!names.iter().any(|n| n.starts_with("000000000.")));
+        assert!(!names.contains(&MANIFEST.to_string()));
+    }
+
+    #[test]
+    fn bitcask_should_reject_corrupted_sealed_file() {
+        let dir = tempdir().unwrap();
+        let config = BitcaskConfig::default().with_max_file_size(1);
+        {
+            let store = Bitcask::open(dir.path(), config).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+        }
+        // 文件 0 已经切换过，不是最后写入的文件
+        let path = file_path(dir.path(), 0, DATA_EXT);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let result = Bitcask::open(dir.path(), config);
+        assert!(matches!(result, Err(KvError::ChecksumMismatch(..))));
+        // 文件没有被截断
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+}
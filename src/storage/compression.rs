@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use flate2::{read::GzDecoder, read::GzEncoder};
+use prost::Message;
+
+use crate::{Compression, KvError, Value};
+
+// 压缩过的 value 以这个字节开头，后面一个字节是压缩算法。
+// protobuf 的 field number 不能是 0，所以没有压缩的 Value 不会以 0 开头，两种数据可以共存
+const MARKER: u8 = 0;
+// 默认只压缩超过这个大小的 value，太小的 value 压缩之后往往更大
+const DEFAULT_THRESHOLD: usize = 512;
+
+/// SledDb 的 value 压缩配置，可以给每个 table 指定不同的压缩算法
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    default: Compression,
+    tables: HashMap<String, Compression>,
+    threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self::new(Compression::None)
+    }
+}
+
+impl CompressionConfig {
+    /// 所有 table 默认使用的压缩算法
+    pub fn new(default: Compression) -> Self {
+        Self {
+            default,
+            tables: HashMap::new(),
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// 单独设置一个 table 的压缩算法，Compression::None 表示这个 table 不压缩
+    pub fn with_table(mut self, table: impl Into<String>, compression: Compression) -> Self {
+        self.tables.insert(table.into(), compression);
+        self
+    }
+
+    /// 编码之后超过 threshold 字节的 value 才会压缩
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn compression(&self, table: &str) -> Compression {
+        self.tables.get(table).copied().unwrap_or(self.default)
+    }
+}
+
+/// 写入的 value 的压缩统计
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    values: AtomicU64,
+    compressed: AtomicU64,
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    /// 写入的 value 数量
+    pub fn values(&self) -> u64 {
+        self.values.load(Ordering::Relaxed)
+    }
+
+    /// 压缩之后写入的 value 数量
+    pub fn compressed_values(&self) -> u64 {
+        self.compressed.load(Ordering::Relaxed)
+    }
+
+    /// 编码之后、压缩之前的总字节数
+    pub fn raw_bytes(&self) -> u64 {
+        self.raw_bytes.load(Ordering::Relaxed)
+    }
+
+    /// 实际写入的总字节数
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes.load(Ordering::Relaxed)
+    }
+
+    // 记录一个写入成功的 value，压缩过的 value 一定比原始数据小
+    pub(crate) fn record(&self, raw: usize, data: &[u8]) {
+        self.values.fetch_add(1, Ordering::Relaxed);
+        if data.len() < raw {
+            self.compressed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.raw_bytes.fetch_add(raw as u64, Ordering::Relaxed);
+        self.stored_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+
+    /// 压缩比，raw_bytes / stored_bytes，没有写入时是 1
+    pub fn ratio(&self) -> f64 {
+        match self.stored_bytes() {
+            0 => 1.0,
+            stored => self.raw_bytes() as f64 / stored as f64,
+        }
+    }
+}
+
+// 把 value 编码成写入 sled 的数据，压缩之后没有变小就保存原始数据，同时返回压缩之前的大小
+pub(crate) fn encode_value(
+    config: &CompressionConfig,
+    table: &str,
+    value: Value,
+) -> Result<(Vec<u8>, usize), KvError> {
+    let raw: Vec<u8> = value.try_into()?;
+    let compression = config.compression(table);
+    let data = match compression {
+        Compression::None => None,
+        _ if raw.len() <= config.threshold => None,
+        _ => Some(compress(compression, &raw)?).filter(|data| data.len() < raw.len()),
+    };
+    let size = raw.len();
+    Ok((data.unwrap_or(raw), size))
+}
+
+// 从 sled 里读出来的数据，可能压缩过也可能没有
+pub(crate) fn decode_value(data: &[u8]) -> Result<Value, KvError> {
+    match data {
+        [MARKER, compression, rest @ ..] => {
+            let data = decompress(*compression, rest)?;
+            Ok(Value::decode(data.as_ref())?)
+        }
+        _ => data.try_into(),
+    }
+}
+
+fn compress(compression: Compression, raw: &[u8]) -> Result<Vec<u8>, KvError> {
+    let mut data = vec![MARKER, compression as u8];
+    match compression {
+        Compression::None => data.extend_from_slice(raw),
+        Compression::Gzip => {
+            GzEncoder::new(raw, flate2::Compression::default()).read_to_end(&mut data)?;
+        }
+        Compression::Lz4 => data.extend(lz4_flex::compress_prepend_size(raw)),
+        Compression::Zstd => data.extend(zstd::bulk::compress(raw, 0)?),
+    }
+    Ok(data)
+}
+
+fn decompress(compression: u8, data: &[u8]) -> Result<Vec<u8>, KvError> {
+    let invalid = |e: String| KvError::Internal(format!("Cannot decompress value: {}", e));
+    match Compression::from_i32(compression as i32) {
+        Some(Compression::None) => Ok(data.to_vec()),
+        Some(Compression::Gzip) => {
+            let mut buf = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+        Some(Compression::Lz4) => {
+            lz4_flex::decompress_size_prepended(data).map_err(|e| invalid(e.to_string()))
+        }
+        Some(Compression::Zstd) => Ok(zstd::stream::decode_all(data)?),
+        None => Err(invalid(format!("unknown compression {}", compression))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tempfile::tempdir;
+
+    use crate::{Kvpair, SledDb, Storage};
+
+    use super::*;
+
+    fn json(n: usize) -> Value {
+        let data: String = (0..n)
+            .map(|i| format!(r#"{{"id":{},"name":"user","active":true}},"#, i))
+            .collect();
+        Bytes::from(data).into()
+    }
+
+    #[test]
+    fn compressed_values_should_round_trip() {
+        for compression in [Compression::Gzip, Compression::Lz4, Compression::Zstd] {
+            let config = CompressionConfig::new(compression);
+            let stats = CompressionStats::default();
+            let value = json(100);
+            let (data, raw) = encode_value(&config, "t1", value.clone()).unwrap();
+            stats.record(raw, &data);
+            assert_eq!(&data[..2], &[MARKER, compression as u8]);
+            assert_eq!(decode_value(&data).unwrap(), value);
+            assert_eq!(stats.compressed_values(), 1);
+            assert!(stats.ratio() > 5.0);
+        }
+    }
+
+    #[test]
+    fn small_and_disabled_values_should_be_stored_raw() {
+        let config = CompressionConfig::new(Compression::Zstd)
+            .with_table("raw", Compression::None)
+            .with_threshold(64);
+        let stats = CompressionStats::default();
+
+        let small: Value = "hello".into();
+        let (data, raw) = encode_value(&config, "t1", small.clone()).unwrap();
+        stats.record(raw, &data);
+        assert_eq!(data, Vec::<u8>::try_from(small).unwrap());
+        let (data, raw) = encode_value(&config, "raw", json(100)).unwrap();
+        stats.record(raw, &data);
+        assert_eq!(decode_value(&data).unwrap(), json(100));
+        assert_eq!(stats.values(), 2);
+        assert_eq!(stats.compressed_values(), 0);
+        assert_eq!(stats.raw_bytes(), stats.stored_bytes());
+    }
+
+    #[test]
+    fn sleddb_should_read_mixed_values() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        // 开启压缩之前写入的数据
+        store.set("t1", "k1".into(), json(100)).unwrap();
+        drop(store);
+
+        let config = CompressionConfig::default().with_table("t1", Compression::Lz4);
+        let store = SledDb::new(dir.path()).with_compression(config);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(json(100)));
+        let old = store.set("t1", "k2".into(), json(200)).unwrap();
+        assert!(old.is_none());
+        store.set("t2", "k1".into(), json(200)).unwrap();
+
+        let stats = store.compression_stats();
+        assert_eq!(stats.values(), 2);
+        assert_eq!(stats.compressed_values(), 1);
+        let mut pairs: Vec<_> = store.get_iter("t1").unwrap().collect();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(pairs[0].value, Some(json(100)));
+        assert_eq!(pairs[1].value, Some(json(200)));
+        assert_eq!(store.del("t1", "k2").unwrap(), Some(json(200)));
+    }
+
+    #[test]
+    fn sleddb_should_report_corrupt_values() {
+        let dir = tempdir().unwrap();
+        {
+            let db = sled::open(dir.path()).unwrap();
+            // 压缩标记后面是未知的压缩算法
+            db.insert("t1:k1", vec![MARKER, 9, 1, 2, 3]).unwrap();
+            db.flush().unwrap();
+        }
+        let store = SledDb::new(dir.path());
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        assert!(store.get("t1", "k1").is_err());
+        assert!(store.get_all("t1").is_err());
+        // iterator 跳过无法解码的数据，不会变成空的 kv pair
+        let pairs: Vec<_> = store.get_iter("t1").unwrap().collect();
+        assert_eq!(pairs, vec![Kvpair::new("k2", "v2".into())]);
+    }
+
+    #[test]
+    fn sleddb_should_decompress_all_read_paths() {
+        let dir = tempdir().unwrap();
+        let config = CompressionConfig::new(Compression::Zstd).with_threshold(0);
+        let store = SledDb::new(dir.path()).with_compression(config);
+        store.set("t1", "k1".into(), json(100)).unwrap();
+        store.set("t1", "k2".into(), json(50)).unwrap();
+        assert_eq!(store.compression_stats().compressed_values(), 2);
+
+        let mut pairs = store.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pairs,
+            vec![Kvpair::new("k1", json(100)), Kvpair::new("k2", json(50))]
+        );
+        // 覆盖和删除返回的旧值也是解压之后的
+        let old = store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(old, Some(json(100)));
+        assert_eq!(store.del("t1", "k2").unwrap(), Some(json(50)));
+    }
+}
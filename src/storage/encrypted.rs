@@ -0,0 +1,569 @@
+use std::{collections::BTreeMap, collections::BTreeSet, convert::TryInto, fmt, fs, path::Path};
+
+use bytes::Bytes;
+use prost::Message;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{value, Hmset, KvError, Kvpair, Storage, StorageIter, Value};
+
+// 密钥的长度，两种算法都是 256 位
+const KEY_LEN: usize = 32;
+// 加密之后的 value：key id + nonce + 密文 + tag
+const HEADER_LEN: usize = 4 + NONCE_LEN;
+
+/// 加密使用的 AEAD 算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+// 加密 table 和 key 名字的密钥，名字需要确定性加密，所以不参与轮换
+struct NameKey {
+    mac: hmac::Key,
+    aead: LessSafeKey,
+}
+
+/// 加密用的密钥，每个密钥有一个 id，id 最大的密钥用来加密新的数据，
+/// 其它密钥只用来解密之前写入的数据
+pub struct KeyRing {
+    cipher: Cipher,
+    keys: BTreeMap<u32, LessSafeKey>,
+    names: Option<NameKey>,
+    rng: SystemRandom,
+}
+
+impl KeyRing {
+    pub fn new(cipher: Cipher) -> Self {
+        Self {
+            cipher,
+            keys: BTreeMap::new(),
+            names: None,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// 从密钥文件中读取密钥，每行是 `<id>:<base64 编码的 32 字节密钥>`，
+    /// `name:<base64>` 是加密名字的密钥，空行和 `#` 开头的行会被忽略
+    pub fn from_file(path: impl AsRef<Path>, cipher: Cipher) -> Result<Self, KvError> {
+        let content = fs::read_to_string(path)?;
+        let mut keys = Self::new(cipher);
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, key) = line
+                .split_once(':')
+                .ok_or_else(|| KvError::Encryption(format!("Invalid key line: {}", line)))?;
+            let key = base64::decode(key.trim())
+                .map_err(|_| KvError::Encryption(format!("Invalid base64 for key {}", id)))?;
+            match id.trim() {
+                "name" => keys.set_name_key(&key)?,
+                id => {
+                    let id = id
+                        .parse()
+                        .map_err(|_| KvError::Encryption(format!("Invalid key id: {}", id)))?;
+                    keys.add_key(id, &key)?;
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// 加入一个密钥，轮换时加入一个更大的 id，新写入的数据就会用新的密钥
+    pub fn add_key(&mut self, id: u32, key: &[u8]) -> Result<(), KvError> {
+        let key = self.aead_key(key)?;
+        self.keys.insert(id, key);
+        Ok(())
+    }
+
+    /// 设置加密名字的密钥，一旦使用就不能更换，否则之前的数据都找不到了
+    pub fn set_name_key(&mut self, key: &[u8]) -> Result<(), KvError> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &check_len(key)?);
+        // 从同一个密钥派生出计算 nonce 和加密用的两个密钥
+        let mac = hmac::sign(&key, b"kv name mac");
+        let aead = hmac::sign(&key, b"kv name aead");
+        self.names = Some(NameKey {
+            mac: hmac::Key::new(hmac::HMAC_SHA256, mac.as_ref()),
+            aead: self.aead_key(aead.as_ref())?,
+        });
+        Ok(())
+    }
+
+    /// 加密新数据使用的 key id
+    pub fn active_id(&self) -> Option<u32> {
+        self.keys.keys().next_back().copied()
+    }
+
+    fn aead_key(&self, key: &[u8]) -> Result<LessSafeKey, KvError> {
+        let algorithm = match self.cipher {
+            Cipher::Aes256Gcm => &AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        };
+        let key = UnboundKey::new(algorithm, &check_len(key)?)
+            .map_err(|_| KvError::Encryption("Invalid key".into()))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    // 用当前的密钥和随机的 nonce 加密
+    fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, KvError> {
+        let (id, key) = self
+            .keys
+            .iter()
+            .next_back()
+            .ok_or_else(|| KvError::Encryption("No encryption key".into()))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| KvError::Encryption("Cannot generate nonce".into()))?;
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + data.len() + key.algorithm().tag_len());
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&nonce);
+        let mut data = data.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut data,
+        )
+        .map_err(|_| KvError::Encryption("Cannot encrypt value".into()))?;
+        buf.extend_from_slice(&data);
+        Ok(buf)
+    }
+
+    fn open(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, KvError> {
+        let id = key_id(data)?;
+        let key = self
+            .keys
+            .get(&id)
+            .ok_or_else(|| KvError::Encryption(format!("Key {} is not found", id)))?;
+        let nonce = data[4..HEADER_LEN].try_into().unwrap();
+        let mut buf = data[HEADER_LEN..].to_vec();
+        let len = key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut buf,
+            )
+            .map_err(|_| KvError::Encryption(format!("Cannot decrypt value with key {}", id)))?
+            .len();
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    // 确定性地加密名字，nonce 由名字算出来，同样的名字总是得到同样的密文，
+    // 这样 get 可以直接找到 key，sled 也可以按照 table 做前缀扫描
+    fn seal_name(&self, names: &NameKey, context: &str, name: &str) -> Result<String, KvError> {
+        let mut ctx = hmac::Context::with_key(&names.mac);
+        ctx.update(&(context.len() as u32).to_be_bytes());
+        ctx.update(context.as_bytes());
+        ctx.update(name.as_bytes());
+        let nonce: [u8; NONCE_LEN] = ctx.sign().as_ref()[..NONCE_LEN].try_into().unwrap();
+
+        let mut data = name.as_bytes().to_vec();
+        names
+            .aead
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(context.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| KvError::Encryption("Cannot encrypt name".into()))?;
+        let mut buf = nonce.to_vec();
+        buf.extend_from_slice(&data);
+        // url safe 的 base64 里没有 `:`，不会和 sled 的 table 前缀冲突
+        Ok(base64::encode_config(buf, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn open_name(&self, names: &NameKey, context: &str, name: &str) -> Result<String, KvError> {
+        let invalid = || KvError::Encryption(format!("Cannot decrypt name {}", name));
+        let mut data =
+            base64::decode_config(name, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        if data.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let nonce = data[..NONCE_LEN].try_into().unwrap();
+        let plain = names
+            .aead
+            .open_within(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(context.as_bytes()),
+                &mut data,
+                NONCE_LEN..,
+            )
+            .map_err(|_| invalid())?;
+        String::from_utf8(plain.to_vec()).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 不要把密钥打印出来
+        f.debug_struct("KeyRing")
+            .field("cipher", &self.cipher)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("names", &self.names.is_some())
+            .finish()
+    }
+}
+
+/// 加密 value 的 Storage，可以包在 SledDb 或者其它任何 Storage 外面。
+/// value 用 AEAD 加密之后以 Binary 的形式存到内部的 Storage 里，
+/// table 和 key 会作为附加数据，密文不能被挪到别的 key 下面
+#[derive(Debug)]
+pub struct EncryptedStorage<S> {
+    inner: S,
+    keys: KeyRing,
+    encrypt_names: bool,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    pub fn new(inner: S, keys: KeyRing) -> Self {
+        Self {
+            inner,
+            keys,
+            encrypt_names: false,
+        }
+    }
+
+    /// 同时确定性地加密 table 和 key 的名字，需要 KeyRing 里有名字的密钥。
+    /// 同一个 table 的数据还是有同样的前缀，但 key 的前缀扫描就做不到了
+    pub fn with_encrypted_names(mut self) -> Result<Self, KvError> {
+        if self.keys.names.is_none() {
+            return Err(KvError::Encryption("Missing name key".into()));
+        }
+        self.encrypt_names = true;
+        Ok(self)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// 把用旧密钥加密的 value 用当前的密钥重新加密，返回重新加密的 value 数量。
+    /// 完成之后就可以把旧的密钥从密钥文件中删掉了。
+    /// 读出和写回之间没有锁，最好在没有写入的时候执行
+    pub fn reencrypt(&self) -> Result<u64, KvError> {
+        let active = self.keys.active_id();
+        let mut count = 0;
+        for raw_table in self.inner.tables()? {
+            let table = self.plain_name("", &raw_table)?;
+            for pair in self.inner.get_all(&raw_table)? {
+                let value = pair.value.unwrap_or_default();
+                if key_id(&ciphertext(&value)?).ok() == active {
+                    continue;
+                }
+                let key = self.plain_name(&table, &pair.key)?;
+                let value = self.decrypt(&table, &key, value)?;
+                let value = self.encrypt(&table, &key, value)?;
+                self.inner.set(&raw_table, pair.key, value)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // 写到内部 Storage 里的 kv pair
+    fn stored_pairs(&self, table: &str, pairs: &[Kvpair]) -> Result<Vec<Kvpair>, KvError> {
+        pairs
+            .iter()
+            .map(|pair| {
+                let value = pair.value.clone().unwrap_or_default();
+                let value = self.encrypt(table, &pair.key, value)?;
+                Ok(Kvpair::new(self.stored_name(table, &pair.key)?, value))
+            })
+            .collect()
+    }
+
+    fn encrypt(&self, table: &str, key: &str, value: Value) -> Result<Value, KvError> {
+        let data = self.keys.seal(&aad(table, key), &value.encode_to_vec())?;
+        Ok(Bytes::from(data).into())
+    }
+
+    fn decrypt(&self, table: &str, key: &str, value: Value) -> Result<Value, KvError> {
+        let data = self.keys.open(&aad(table, key), &ciphertext(&value)?)?;
+        Ok(Value::decode(data.as_ref())?)
+    }
+
+    fn decrypt_opt(
+        &self,
+        table: &str,
+        key: &str,
+        value: Option<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        value.map(|v| self.decrypt(table, key, v)).transpose()
+    }
+
+    // 内部 Storage 里使用的名字，key 的名字和所在的 table 绑定在一起
+    fn stored_name(&self, context: &str, name: &str) -> Result<String, KvError> {
+        match (&self.keys.names, self.encrypt_names) {
+            (Some(names), true) => self.keys.seal_name(names, context, name),
+            _ => Ok(name.to_string()),
+        }
+    }
+
+    fn plain_name(&self, context: &str, name: &str) -> Result<String, KvError> {
+        match (&self.keys.names, self.encrypt_names) {
+            (Some(names), true) => self.keys.open_name(names, context, name),
+            _ => Ok(name.to_string()),
+        }
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let value = self.inner.get(
+            &self.stored_name("", table)?,
+            &self.stored_name(table, key)?,
+        )?;
+        self.decrypt_opt(table, key, value)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let value = self.encrypt(table, &key, value)?;
+        let old = self.inner.set(
+            &self.stored_name("", table)?,
+            self.stored_name(table, &key)?,
+            value,
+        )?;
+        self.decrypt_opt(table, &key, old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(
+            &self.stored_name("", table)?,
+            &self.stored_name(table, key)?,
+        )
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.inner.del(
+            &self.stored_name("", table)?,
+            &self.stored_name(table, key)?,
+        )?;
+        self.decrypt_opt(table, key, old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner
+            .get_all(&self.stored_name("", table)?)?
+            .into_iter()
+            .map(|pair| {
+                let key = self.plain_name(table, &pair.key)?;
+                let value = self.decrypt(table, &key, pair.value.unwrap_or_default())?;
+                Ok(Kvpair::new(key, value))
+            })
+            .collect()
+    }
+
+    // 解密失败需要返回错误，所以先全部解密出来
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let iter = StorageIter::new(self.get_all(table)?.into_iter());
+        Ok(Box::new(iter))
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = BTreeSet::new();
+        for table in self.inner.tables()? {
+            tables.insert(self.plain_name("", &table)?);
+        }
+        Ok(tables.into_iter().collect())
+    }
+
+    // 按加密之后的大小检查
+    fn reserve(&self, table: &str, pairs: &[Kvpair]) -> Result<(), KvError> {
+        let pairs = self.stored_pairs(table, pairs)?;
+        self.inner.reserve(&self.stored_name("", table)?, &pairs)
+    }
+
+    fn check_capacity(&self, data: &[Hmset]) -> Result<(), KvError> {
+        let data = data
+            .iter()
+            .map(|hmset| {
+                let pairs = self.stored_pairs(&hmset.table, &hmset.pairs)?;
+                let table = self.stored_name("", &hmset.table)?;
+                Ok(Hmset { table, pairs })
+            })
+            .collect::<Result<Vec<_>, KvError>>()?;
+        self.inner.check_capacity(&data)
+    }
+
+    fn take_evicted(&self) -> Result<Vec<(String, Kvpair)>, KvError> {
+        self.inner
+            .take_evicted()?
+            .into_iter()
+            .map(|(table, pair)| {
+                let table = self.plain_name("", &table)?;
+                let key = self.plain_name(&table, &pair.key)?;
+                let value = self.decrypt(&table, &key, pair.value.unwrap_or_default())?;
+                Ok((table, Kvpair::new(key, value)))
+            })
+            .collect()
+    }
+}
+
+fn check_len(key: &[u8]) -> Result<[u8; KEY_LEN], KvError> {
+    key.try_into().map_err(|_| {
+        KvError::Encryption(format!("Key must be {} bytes, got {}", KEY_LEN, key.len()))
+    })
+}
+
+// 附加数据，table 带上长度，避免 ("ab", "c") 和 ("a", "bc") 混淆
+fn aad(table: &str, key: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + table.len() + key.len());
+    buf.extend_from_slice(&(table.len() as u32).to_be_bytes());
+    buf.extend_from_slice(table.as_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf
+}
+
+fn ciphertext(value: &Value) -> Result<Bytes, KvError> {
+    match &value.value {
+        Some(value::Value::Binary(data)) if data.len() >= HEADER_LEN => Ok(data.clone()),
+        _ => Err(KvError::Encryption("Value is not encrypted".into())),
+    }
+}
+
+fn key_id(data: &[u8]) -> Result<u32, KvError> {
+    match data.get(..4) {
+        Some(id) if data.len() >= HEADER_LEN => Ok(u32::from_be_bytes(id.try_into().unwrap())),
+        _ => Err(KvError::Encryption("Value is not encrypted".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::{MemTable, SledDb};
+
+    use super::*;
+
+    fn key_file(dir: &Path, lines: &[(&str, u8)]) -> std::path::PathBuf {
+        let content: Vec<_> = lines
+            .iter()
+            .map(|(id, b)| format!("{}:{}", id, base64::encode([*b; KEY_LEN])))
+            .collect();
+        let path = dir.join("keys");
+        fs::write(&path, format!("# kv keys\n{}\n", content.join("\n"))).unwrap();
+        path
+    }
+
+    #[test]
+    fn encrypted_names_should_hide_plaintext() {
+        let dir = tempdir().unwrap();
+        let path = key_file(dir.path(), &[("1", 1), ("name", 9)]);
+        let keys = KeyRing::from_file(path, Cipher::Aes256Gcm).unwrap();
+        let store = EncryptedStorage::new(SledDb::new(dir.path().join("db")), keys)
+            .with_encrypted_names()
+            .unwrap();
+
+        store.set("users", "alice".into(), "secret".into()).unwrap();
+        store.set("users", "bob".into(), 42i64.into()).unwrap();
+        assert_eq!(store.get("users", "alice").unwrap(), Some("secret".into()));
+        assert!(store.contains("users", "bob").unwrap());
+        assert_eq!(store.tables().unwrap(), vec!["users"]);
+        let mut pairs = store.get_all("users").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pairs,
+            vec![
+                Kvpair::new("alice", "secret".into()),
+                Kvpair::new("bob", 42i64.into())
+            ]
+        );
+
+        // 内部的存储里看不到任何明文
+        let inner = store.into_inner();
+        let tables = inner.tables().unwrap();
+        assert_ne!(tables, vec!["users"]);
+        for pair in inner.get_all(&tables[0]).unwrap() {
+            assert!(!pair.key.contains("alice") && !pair.key.contains("bob"));
+            let data = ciphertext(&pair.value.unwrap()).unwrap();
+            assert!(!data.windows(6).any(|w| w == b"secret"));
+        }
+    }
+
+    #[test]
+    fn key_rotation_should_work() {
+        let mut keys = KeyRing::new(Cipher::ChaCha20Poly1305);
+        keys.add_key(1, &[1; KEY_LEN]).unwrap();
+        let store = EncryptedStorage::new(MemTable::new(), keys);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        // 加入新的密钥之后，旧的数据还能读出来，新的数据用新的密钥
+        let mut keys = KeyRing::new(Cipher::ChaCha20Poly1305);
+        keys.add_key(1, &[1; KEY_LEN]).unwrap();
+        keys.add_key(2, &[2; KEY_LEN]).unwrap();
+        let store = EncryptedStorage::new(store.into_inner(), keys);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        store.set("t1", "k2".into(), "v3".into()).unwrap();
+        assert_eq!(store.reencrypt().unwrap(), 1);
+        assert_eq!(store.reencrypt().unwrap(), 0);
+
+        // 重新加密之后可以删掉旧的密钥
+        let mut keys = KeyRing::new(Cipher::ChaCha20Poly1305);
+        keys.add_key(2, &[2; KEY_LEN]).unwrap();
+        let store = EncryptedStorage::new(store.into_inner(), keys);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn tampered_or_moved_value_should_be_rejected() {
+        let mut keys = KeyRing::new(Cipher::Aes256Gcm);
+        keys.add_key(1, &[1; KEY_LEN]).unwrap();
+        let store = EncryptedStorage::new(MemTable::new(), keys);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+
+        // 把密文挪到另一个 key 下面
+        let inner = store.into_inner();
+        let value = inner.get("t1", "k1").unwrap().unwrap();
+        inner.set("t1", "k2".into(), value.clone()).unwrap();
+        // 修改一个字节
+        let mut data = ciphertext(&value).unwrap().to_vec();
+        *data.last_mut().unwrap() ^= 1;
+        inner
+            .set("t1", "k3".into(), Bytes::from(data).into())
+            .unwrap();
+
+        let mut keys = KeyRing::new(Cipher::Aes256Gcm);
+        keys.add_key(1, &[1; KEY_LEN]).unwrap();
+        let store = EncryptedStorage::new(inner, keys);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(matches!(store.get("t1", "k2"), Err(KvError::Encryption(_))));
+        assert!(matches!(store.get("t1", "k3"), Err(KvError::Encryption(_))));
+        assert!(store.with_encrypted_names().is_err());
+    }
+
+    #[test]
+    fn encrypted_store_should_return_plaintext_old_values() {
+        let dir = tempdir().unwrap();
+        let mut keys = KeyRing::new(Cipher::Aes256Gcm);
+        keys.add_key(1, &[1; KEY_LEN]).unwrap();
+        keys.set_name_key(&[2; KEY_LEN]).unwrap();
+        let store = EncryptedStorage::new(SledDb::new(dir.path()), keys)
+            .with_encrypted_names()
+            .unwrap();
+
+        // set 和 del 返回的旧值是解密之后的
+        assert_eq!(store.set("t1", "k1".into(), "v1".into()).unwrap(), None);
+        let cipher1 = store.inner.get_all(&store.inner.tables().unwrap()[0]);
+        let old = store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(old, Some("v1".into()));
+        // 同样的值每次加密的结果都不一样
+        let cipher2 = store.inner.get_all(&store.inner.tables().unwrap()[0]);
+        assert_ne!(cipher1.unwrap(), cipher2.unwrap());
+
+        let pairs: Vec<_> = store.get_iter("t1").unwrap().collect();
+        assert_eq!(pairs, vec![Kvpair::new("k1", "v1".into())]);
+        assert_eq!(store.del("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.del("t1", "k1").unwrap(), None);
+        assert!(!store.contains("t1", "k1").unwrap());
+    }
+}
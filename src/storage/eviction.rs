@@ -0,0 +1,418 @@
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use prost::Message;
+
+use crate::{Kvpair, Value};
+
+// 每个 key 除了 key 和 value 本身之外大约占用的内存
+const ENTRY_OVERHEAD: u64 = 64;
+// LFU 计数器的初始值，新写入的 key 不会马上被淘汰
+const LFU_INIT: u64 = 5;
+// LFU 计数器的增长速度，越大增长越慢，和 redis 的 lfu-log-factor 一样
+const LFU_LOG_FACTOR: f64 = 10.0;
+// 候选 key 超过这个时间之后丢掉，它们的访问记录可能已经变了
+const POOL_TTL: Duration = Duration::from_secs(1);
+// 每次淘汰随机采样的 key 数量，和 redis 的 maxmemory-samples 一样
+const SAMPLES: usize = 5;
+// 保留的候选 key 数量，和 redis 的 eviction pool 一样
+const POOL_SIZE: usize = 16;
+// key 列表里已经删除的 key 超过这个数量时整理一次
+const MIN_COMPACT: usize = 1024;
+
+/// MemTable 使用的内存达到上限之后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// 不淘汰，拒绝会增加内存的写入
+    NoEviction,
+    /// 淘汰最久没有访问的 key
+    AllKeysLru,
+    /// 淘汰访问频率最低的 key，计数器每分钟衰减一次
+    AllKeysLfu,
+}
+
+/// MemTable 的内存使用统计，可以在其它地方定期读取
+#[derive(Debug, Default)]
+pub struct MemoryStats {
+    used: AtomicU64,
+    evicted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl MemoryStats {
+    /// 估算的内存使用，包括 key、value 和每个 key 的额外开销
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// 被淘汰的 key 数量
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// 因为内存不足被拒绝的写入数量
+    pub fn rejected_writes(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+// 一个候选的淘汰 key
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Candidate {
+    pub score: u64,
+    pub table: String,
+    pub key: String,
+}
+
+// 随机采样用到的 key 列表和候选 key。
+// 删除 key 时不从列表里找出来删掉，而是采样到的时候再去掉，列表太大时整理一次
+#[derive(Debug)]
+struct Sampler {
+    keys: Vec<(String, String)>,
+    live: usize,
+    // 按 score 从大到小排列，pop 得到最应该淘汰的 key
+    pool: Vec<Candidate>,
+    pool_at: Instant,
+}
+
+impl Sampler {
+    // 随机取出 SAMPLES 个 key 放进候选 key；key 不多时全部取出
+    fn sample(&mut self, score: impl Fn(&str, &str) -> Option<u64>) {
+        let indexes: Vec<usize> = match self.keys.len() {
+            n if n <= SAMPLES => (0..n).rev().collect(),
+            n => (0..SAMPLES).map(|_| random() as usize % n).collect(),
+        };
+        for i in indexes {
+            // 前面去掉的 key 可能让列表变短了
+            let Some((table, key)) = self.keys.get(i) else {
+                continue;
+            };
+            match score(table, key) {
+                Some(score) => {
+                    let candidate = Candidate {
+                        score,
+                        table: table.clone(),
+                        key: key.clone(),
+                    };
+                    self.offer(candidate);
+                }
+                None => {
+                    self.keys.swap_remove(i);
+                }
+            }
+        }
+    }
+
+    fn offer(&mut self, candidate: Candidate) {
+        self.pool
+            .retain(|c| c.table != candidate.table || c.key != candidate.key);
+        self.pool.push(candidate);
+        self.pool.sort_unstable_by(|a, b| b.cmp(a));
+        if self.pool.len() > POOL_SIZE {
+            self.pool.remove(0);
+        }
+    }
+}
+
+// 内存上限和淘汰策略
+#[derive(Debug)]
+pub(crate) struct MemoryLimit {
+    pub max: u64,
+    pub policy: EvictionPolicy,
+    pub stats: Arc<MemoryStats>,
+    start: Instant,
+    sampler: Mutex<Sampler>,
+    // 淘汰的 key，Storage::take_evicted 第一次调用之后才开始记录
+    evicted: Mutex<Option<Vec<(String, Kvpair)>>>,
+}
+
+impl MemoryLimit {
+    pub fn new(max: u64, policy: EvictionPolicy) -> Self {
+        let start = Instant::now();
+        Self {
+            max,
+            policy,
+            stats: Default::default(),
+            start,
+            sampler: Mutex::new(Sampler {
+                keys: Vec::new(),
+                live: 0,
+                pool: Vec::new(),
+                pool_at: start,
+            }),
+            evicted: Mutex::new(None),
+        }
+    }
+
+    pub fn add(&self, size: u64) {
+        self.stats.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, size: u64) {
+        self.stats.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self, table: &str, key: &str, value: Value) {
+        self.stats.evicted.fetch_add(1, Ordering::Relaxed);
+        if let Some(evicted) = self.evicted.lock().unwrap().as_mut() {
+            evicted.push((table.into(), Kvpair::new(key, value)));
+        }
+    }
+
+    // 取出记录下来的淘汰的 key，之后继续记录
+    pub fn take_evicted(&self) -> Vec<(String, Kvpair)> {
+        let mut evicted = self.evicted.lock().unwrap();
+        evicted.replace(Vec::new()).unwrap_or_default()
+    }
+
+    pub fn rejected(&self) {
+        self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 把一个 key 占用的内存从 old 改成 new，增加之后超过上限时不修改并返回 false
+    pub fn try_grow(&self, new: u64, old: u64) -> bool {
+        if new <= old {
+            self.sub(old - new);
+            return true;
+        }
+        let growth = new - old;
+        self.stats
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used + growth <= self.max).then_some(used + growth)
+            })
+            .is_ok()
+    }
+
+    // 写入 size 字节之后是否超过上限
+    pub fn exceeds(&self, size: u64) -> bool {
+        self.stats.used() + size > self.max
+    }
+
+    // 记录新写入的 key，用来随机采样；exists 用来在整理 key 列表时去掉已经删除的 key
+    pub fn track(&self, table: &str, key: &str, exists: impl Fn(&str, &str) -> bool) {
+        let mut sampler = self.sampler.lock().unwrap();
+        sampler.keys.push((table.into(), key.into()));
+        sampler.live += 1;
+        if sampler.keys.len() > sampler.live * 2 + MIN_COMPACT {
+            let mut seen = HashSet::new();
+            sampler
+                .keys
+                .retain(|(t, k)| exists(t, k) && seen.insert((t.clone(), k.clone())));
+        }
+    }
+
+    // key 被删除了
+    pub fn untrack(&self) {
+        let mut sampler = self.sampler.lock().unwrap();
+        sampler.live = sampler.live.saturating_sub(1);
+    }
+
+    // 下一个要淘汰的 key：每次随机采样几个 key 放进候选 key，返回 score 最小的一个。
+    // score 返回 None 表示 key 已经不存在了
+    pub fn next_victim(&self, score: impl Fn(&str, &str) -> Option<u64>) -> Option<Candidate> {
+        let mut sampler = self.sampler.lock().unwrap();
+        if sampler.pool_at.elapsed() >= POOL_TTL {
+            sampler.pool.clear();
+            sampler.pool_at = Instant::now();
+        }
+        sampler.sample(score);
+        sampler.pool.pop()
+    }
+
+    // 新写入的 key 的访问记录
+    pub fn new_access(&self) -> u64 {
+        match self.policy {
+            EvictionPolicy::AllKeysLfu => (self.minutes() << 32) | LFU_INIT,
+            _ => self.millis(),
+        }
+    }
+
+    // 访问 key 时更新访问记录
+    pub fn touch(&self, access: &AtomicU64) {
+        match self.policy {
+            EvictionPolicy::NoEviction => {}
+            EvictionPolicy::AllKeysLru => access.store(self.millis(), Ordering::Relaxed),
+            EvictionPolicy::AllKeysLfu => {
+                let counter = self.lfu_counter(access.load(Ordering::Relaxed));
+                let counter = lfu_increment(counter);
+                access.store((self.minutes() << 32) | counter, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // 淘汰的优先级，越小越先淘汰
+    pub fn score(&self, access: u64) -> u64 {
+        match self.policy {
+            EvictionPolicy::AllKeysLfu => self.lfu_counter(access),
+            _ => access,
+        }
+    }
+
+    // 衰减之后的 LFU 计数器，每过一分钟减一
+    fn lfu_counter(&self, access: u64) -> u64 {
+        let elapsed = self.minutes().saturating_sub(access >> 32);
+        (access & 0xffff_ffff).saturating_sub(elapsed)
+    }
+
+    fn millis(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn minutes(&self) -> u64 {
+        self.start.elapsed().as_secs() / 60
+    }
+}
+
+// 计数器越大，增长的概率越小，255 大约对应一百万次访问
+fn lfu_increment(counter: u64) -> u64 {
+    if counter >= 255 {
+        return 255;
+    }
+    let base = counter.saturating_sub(LFU_INIT) as f64;
+    let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+    let r = random() as f64 / u64::MAX as f64;
+    match r < p {
+        true => counter + 1,
+        false => counter,
+    }
+}
+
+// 不需要很好的随机数，RandomState 每次的 key 都不一样
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// 一个 key 大约占用的内存
+pub(crate) fn entry_size(key_len: usize, value: &Value) -> u64 {
+    (key_len + value.encoded_len()) as u64 + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, thread, time::Duration};
+
+    use crate::{KvError, MemTable, Storage};
+
+    use super::*;
+
+    // 每个 key 是 2 字节，value 编码之后是 4 字节，最多放下 5 个
+    const MAX: u64 = 5 * (2 + 4 + ENTRY_OVERHEAD);
+
+    fn fill(store: &MemTable) {
+        for i in 0..5 {
+            store
+                .set("t1", format!("k{}", i), format!("v{}", i).into())
+                .unwrap();
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    #[test]
+    fn lru_should_evict_least_recently_used() {
+        let store = MemTable::new().with_max_memory(MAX, EvictionPolicy::AllKeysLru);
+        fill(&store);
+        store.get("t1", "k0").unwrap();
+        store.set("t1", "k5".into(), "v5".into()).unwrap();
+        store.set("t1", "k6".into(), "v6".into()).unwrap();
+
+        assert!(store.contains("t1", "k0").unwrap());
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert!(!store.contains("t1", "k2").unwrap());
+        let stats = store.memory_stats().unwrap();
+        assert_eq!(stats.evicted_keys(), 2);
+        assert_eq!(stats.used(), MAX);
+    }
+
+    #[test]
+    fn lfu_should_evict_least_frequently_used() {
+        let store = MemTable::new().with_max_memory(MAX, EvictionPolicy::AllKeysLfu);
+        fill(&store);
+        // 第一次访问一定会增加计数器
+        for i in [0, 1, 3, 4] {
+            store.get("t1", &format!("k{}", i)).unwrap();
+        }
+        store.set("t1", "k5".into(), "v5".into()).unwrap();
+
+        assert!(!store.contains("t1", "k2").unwrap());
+        assert_eq!(store.memory_stats().unwrap().evicted_keys(), 1);
+    }
+
+    #[test]
+    fn no_eviction_should_reject_writes() {
+        let store = MemTable::new().with_max_memory(MAX, EvictionPolicy::NoEviction);
+        fill(&store);
+        let result = store.set("t1", "k5".into(), "v5".into());
+        assert!(matches!(result, Err(KvError::OutOfMemory(MAX))));
+        assert_eq!(store.memory_stats().unwrap().rejected_writes(), 1);
+
+        // 删除之后可以继续写入
+        store.del("t1", "k0").unwrap();
+        store.set("t1", "k5".into(), "v5".into()).unwrap();
+        assert_eq!(store.memory_stats().unwrap().used(), MAX);
+    }
+
+    #[test]
+    fn overwrite_should_only_reserve_growth() {
+        let store = MemTable::new().with_max_memory(MAX, EvictionPolicy::NoEviction);
+        fill(&store);
+        // 内存已经用满，同样大小的覆盖和更小的覆盖都不需要额外的内存
+        store.set("t1", "k0".into(), "v9".into()).unwrap();
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        let stats = store.memory_stats().unwrap();
+        assert_eq!(stats.used(), MAX - 1);
+        assert_eq!(stats.rejected_writes(), 0);
+
+        let result = store.set("t1", "k2".into(), "v100".into());
+        assert!(matches!(result, Err(KvError::OutOfMemory(MAX))));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn concurrent_writes_should_not_exceed_limit() {
+        let store = Arc::new(MemTable::new().with_max_memory(MAX, EvictionPolicy::NoEviction));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || store.set("t1", format!("k{}", i), "v0".into()).is_ok())
+            })
+            .collect();
+        let written = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|ok| *ok)
+            .count();
+        assert_eq!(written, 5);
+        assert_eq!(store.memory_stats().unwrap().used(), MAX);
+    }
+
+    #[test]
+    fn sampling_should_visit_bounded_keys() {
+        let limit = MemoryLimit::new(MAX, EvictionPolicy::AllKeysLru);
+        for i in 0..10_000 {
+            limit.track("t1", &format!("k{}", i), |_, _| true);
+        }
+        let calls = Cell::new(0);
+        let victim = limit.next_victim(|_, key| {
+            calls.set(calls.get() + 1);
+            key[1..].parse().ok()
+        });
+        assert!(victim.is_some());
+        assert_eq!(calls.get(), SAMPLES);
+
+        // 删除的 key 会在整理时从列表里去掉
+        for i in 0..10_000 {
+            limit.untrack();
+            limit.track("t2", &format!("k{}", i), |_, _| false);
+            limit.untrack();
+        }
+        assert!(limit.sampler.lock().unwrap().keys.len() <= MIN_COMPACT + 1);
+    }
+}
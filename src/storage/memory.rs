@@ -1,10 +1,56 @@
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use std::{
+    collections::HashMap,
+    mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    storage::{
+        aof::Aof,
+        eviction::{entry_size, MemoryLimit},
+    },
+    AofConfig, CommandRequest, EvictionPolicy, FsyncPolicy, Hmset, KvError, Kvpair, MemoryStats,
+    Storage, StorageIter, Value,
+};
+use dashmap::{mapref::entry::Entry, mapref::one::Ref, DashMap};
+use tracing::warn;
+
+type Tables = DashMap<String, DashMap<String, Item>>;
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait
-#[derive(Clone, Debug, Default)]
+#[derive(Debug, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: Arc<Tables>,
+    aof: Option<Arc<Aof>>,
+    limit: Option<Arc<MemoryLimit>>,
+}
+
+// table 中的一个 value，access 是淘汰 key 时用到的访问记录
+#[derive(Debug)]
+struct Item {
+    value: Value,
+    access: AtomicU64,
+}
+
+impl Item {
+    fn new(value: Value, access: u64) -> Self {
+        Self {
+            value,
+            access: AtomicU64::new(access),
+        }
+    }
+}
+
+impl Clone for Item {
+    fn clone(&self) -> Self {
+        Self::new(self.value.clone(), self.access.load(Ordering::Relaxed))
+    }
 }
 
 impl MemTable {
@@ -13,8 +59,108 @@ impl MemTable {
         Self::default()
     }
 
+    /// 创建持久化到 dir 目录的 MemTable：先读出目录中的快照和 AOF，
+    /// 之后每个修改数据的写命令都追加到 AOF，并在后台定期生成快照
+    pub fn open(dir: impl Into<PathBuf>, config: AofConfig) -> Result<Self, KvError> {
+        let tables = Arc::new(Tables::default());
+        let aof = Aof::open(dir, config, &Self::view(&tables))?;
+        let aof = Arc::new(aof);
+
+        let (weak_tables, weak_aof) = (Arc::downgrade(&tables), Arc::downgrade(&aof));
+        thread::spawn(move || run_background(weak_tables, weak_aof));
+        Ok(Self {
+            tables,
+            aof: Some(aof),
+            limit: None,
+        })
+    }
+
+    /// 限制估算的内存使用，写入之后超过 max 字节时按 policy 淘汰 key 或者拒绝写入。
+    /// 淘汰的 key 可以用 `Storage::take_evicted` 取出，Service 会把它们当作删除复制和通知
+    pub fn with_max_memory(mut self, max: u64, policy: EvictionPolicy) -> Self {
+        let limit = MemoryLimit::new(max, policy);
+        let access = limit.new_access();
+        for table in self.tables.iter() {
+            for item in table.iter() {
+                limit.add(entry_size(item.key().len(), &item.value));
+                limit.track(table.key(), item.key(), |_, _| true);
+                item.access.store(access, Ordering::Relaxed);
+            }
+        }
+        self.limit = Some(Arc::new(limit));
+        self
+    }
+
+    /// 内存使用和淘汰的统计，没有限制内存时返回 None
+    pub fn memory_stats(&self) -> Option<Arc<MemoryStats>> {
+        self.limit.as_ref().map(|limit| limit.stats.clone())
+    }
+
+    /// 立即生成快照并删除旧的 AOF，没有持久化时什么也不做
+    pub fn compact(&self) -> Result<(), KvError> {
+        if let Some(aof) = &self.aof {
+            aof.compact(&Self::view(&self.tables))?;
+        }
+        Ok(())
+    }
+
+    // 共享数据但不写 AOF 的 MemTable，用来重放 AOF 和生成快照
+    fn view(tables: &Arc<Tables>) -> Self {
+        Self {
+            tables: tables.clone(),
+            aof: None,
+            limit: None,
+        }
+    }
+
+    // 写入 size 字节之前按淘汰策略腾出内存，不能淘汰时返回错误
+    fn make_room(&self, limit: &MemoryLimit, size: u64) -> Result<(), KvError> {
+        while limit.exceeds(size) {
+            let victim = match limit.policy {
+                EvictionPolicy::NoEviction => None,
+                _ => limit.next_victim(|t, k| self.score(limit, t, k)),
+            };
+            let victim = match victim {
+                Some(victim) => victim,
+                None => {
+                    limit.rejected();
+                    return Err(KvError::OutOfMemory(limit.max));
+                }
+            };
+            // 采样之后可能已经被删除了
+            if let Some(value) = self.del(&victim.table, &victim.key)? {
+                limit.evicted(&victim.table, &victim.key, value);
+            }
+        }
+        Ok(())
+    }
+
+    // key 的淘汰优先级，key 不存在时返回 None
+    fn score(&self, limit: &MemoryLimit, table: &str, key: &str) -> Option<u64> {
+        let table = self.tables.get(table)?;
+        let score = table
+            .get(key)
+            .map(|item| limit.score(item.access.load(Ordering::Relaxed)));
+        score
+    }
+
+    fn exists(&self, table: &str, key: &str) -> bool {
+        self.tables
+            .get(table)
+            .is_some_and(|table| table.contains_key(key))
+    }
+
+    // key 当前占用的内存，不存在时是 0
+    fn size_of(&self, table: &str, key: &str) -> u64 {
+        let table = self.get_or_create_table(table);
+        let size = table
+            .get(key)
+            .map_or(0, |item| entry_size(key.len(), &item.value));
+        size
+    }
+
     /// 如果名为 name 的 hash table 不存在，则创建，否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Item>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -25,15 +171,119 @@ impl MemTable {
     }
 }
 
+// 克隆的 MemTable 只复制数据，不会写入原来的 AOF，也没有内存限制
+impl Clone for MemTable {
+    fn clone(&self) -> Self {
+        Self {
+            tables: Arc::new((*self.tables).clone()),
+            aof: None,
+            limit: None,
+        }
+    }
+}
+
+// 每秒 fsync 一次 AOF，到时间后生成快照；MemTable 释放后退出
+fn run_background(tables: Weak<Tables>, aof: Weak<Aof>) {
+    let mut last_snapshot = Instant::now();
+    loop {
+        let interval = match aof.upgrade() {
+            Some(aof) => aof.config().snapshot_interval,
+            None => return,
+        };
+        thread::sleep(interval.min(Duration::from_secs(1)));
+
+        let (tables, aof) = match (tables.upgrade(), aof.upgrade()) {
+            (Some(tables), Some(aof)) => (tables, aof),
+            _ => return,
+        };
+        if aof.config().fsync == FsyncPolicy::EverySec {
+            if let Err(e) = aof.sync() {
+                warn!("Failed to sync AOF: {:?}", e);
+            }
+        }
+        if last_snapshot.elapsed() >= interval {
+            last_snapshot = Instant::now();
+            if let Err(e) = aof.compact(&MemTable::view(&tables)) {
+                warn!("Failed to write snapshot: {:?}", e);
+            }
+        }
+    }
+}
+
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        Ok(table.get(key).map(|item| {
+            if let Some(limit) = &self.limit {
+                limit.touch(&item.access);
+            }
+            item.value.clone()
+        }))
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        let name = table;
+        let limit = self.limit.as_deref();
+        let size = limit.map_or(0, |_| entry_size(key.len(), &value));
+        let mut key = key;
+        loop {
+            if let Some(limit) = limit {
+                // 覆盖已有的 key 时只需要为增加的部分腾出内存
+                let old = self.size_of(name, &key);
+                self.make_room(limit, size.saturating_sub(old))?;
+            }
+
+            let (old, inserted) = {
+                let table = self.get_or_create_table(name);
+                let entry = table.entry(key);
+                // 检查上限和增加内存使用都在 key 的锁里，并发的写入不会一起超过上限；
+                // 空间被其它写入用掉了就重新腾出内存
+                let old_size = match &entry {
+                    Entry::Occupied(entry) if limit.is_some() => {
+                        entry_size(entry.key().len(), &entry.get().value)
+                    }
+                    _ => 0,
+                };
+                if let Some(limit) = limit {
+                    if !limit.try_grow(size, old_size) {
+                        key = entry.into_key();
+                        continue;
+                    }
+                }
+
+                // 持有 key 的锁写 AOF，同一个 key 的记录顺序和内存中的修改顺序一致
+                if let Some(aof) = &self.aof {
+                    let cmd = CommandRequest::new_hset(name, entry.key().clone(), value.clone());
+                    if let Err(e) = aof.append(&cmd) {
+                        if let Some(limit) = limit {
+                            limit.sub(size);
+                            limit.add(old_size);
+                        }
+                        return Err(e);
+                    }
+                }
+                match entry {
+                    Entry::Occupied(mut entry) => {
+                        let item = entry.get_mut();
+                        if let Some(limit) = limit {
+                            limit.touch(&item.access);
+                        }
+                        (Some(mem::replace(&mut item.value, value)), None)
+                    }
+                    Entry::Vacant(entry) => {
+                        let key = entry.key().clone();
+                        let access = limit.map_or(0, |limit| limit.new_access());
+                        entry.insert(Item::new(value, access));
+                        (None, Some(key))
+                    }
+                }
+            };
+            // 放开 table 的锁之后再记录新的 key，整理 key 列表时需要读 table
+            if let (Some(limit), Some(key)) = (limit, inserted) {
+                limit.track(name, &key, |t, k| self.exists(t, k));
+            }
+            return Ok(old);
+        }
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
@@ -42,22 +292,103 @@ impl Storage for MemTable {
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = table;
         let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+        let old = match &self.aof {
+            Some(aof) => match table.entry(key.into()) {
+                Entry::Occupied(entry) => {
+                    aof.append(&CommandRequest::new_hdel(name, key))?;
+                    Some(entry.remove().value)
+                }
+                // 删除不存在的 key 没有改变数据，不需要记录
+                Entry::Vacant(_) => None,
+            },
+            None => table.remove(key).map(|(_k, item)| item.value),
+        };
+        if let (Some(limit), Some(old)) = (&self.limit, &old) {
+            limit.sub(entry_size(key.len(), old));
+            limit.untrack();
+        }
+        Ok(old)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
         let table = self.get_or_create_table(table);
         Ok(table
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .map(|v| Kvpair::new(v.key(), v.value().value.clone()))
             .collect())
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
         let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
-        Ok(Box::new(iter))
+        let iter = table
+            .into_iter()
+            .map(|(key, item)| Kvpair::new(key, item.value));
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.tables.iter().map(|t| t.key().clone()).collect())
+    }
+
+    fn reserve(&self, table: &str, pairs: &[Kvpair]) -> Result<(), KvError> {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        // 按顺序写入时内存使用最多会增加多少，同一个 key 写入多次时按最后一次计算
+        let mut sizes: HashMap<&str, u64> = HashMap::new();
+        let (mut growth, mut peak) = (0i64, 0i64);
+        for pair in pairs {
+            let old = match sizes.get(pair.key.as_str()) {
+                Some(size) => *size,
+                None => self.size_of(table, &pair.key),
+            };
+            let size = entry_size(
+                pair.key.len(),
+                pair.value.as_ref().unwrap_or(&Value::default()),
+            );
+            sizes.insert(&pair.key, size);
+            growth += size as i64 - old as i64;
+            peak = peak.max(growth);
+        }
+        // 淘汰所有 key 也放不下时直接拒绝，不需要淘汰
+        if peak as u64 > limit.max {
+            limit.rejected();
+            return Err(KvError::OutOfMemory(limit.max));
+        }
+        self.make_room(limit, peak as u64)
+    }
+
+    fn check_capacity(&self, data: &[Hmset]) -> Result<(), KvError> {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let size: u64 = data
+            .iter()
+            .flat_map(|hmset| &hmset.pairs)
+            .map(|pair| {
+                entry_size(
+                    pair.key.len(),
+                    pair.value.as_ref().unwrap_or(&Value::default()),
+                )
+            })
+            .sum();
+        if size > limit.max {
+            limit.rejected();
+            return Err(KvError::OutOfMemory(limit.max));
+        }
+        Ok(())
+    }
+
+    fn take_evicted(&self) -> Result<Vec<(String, Kvpair)>, KvError> {
+        Ok(self
+            .limit
+            .as_ref()
+            .map(|limit| limit.take_evicted())
+            .unwrap_or_default())
     }
 }
 
@@ -65,7 +396,7 @@ impl Storage for MemTable {
 mod tests {
     use tempfile::tempdir;
 
-    use crate::sleddb::SledDb;
+    use crate::{sleddb::SledDb, Bitcask, BitcaskConfig};
 
     use super::*;
 
@@ -145,6 +476,28 @@ mod tests {
         )
     }
 
+    #[test]
+    fn memtable_tables_should_work() {
+        let store = MemTable::new();
+        test_tables(store);
+    }
+
+    #[test]
+    fn sleddb_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_tables(store);
+    }
+
+    fn test_tables(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        let mut tables = store.tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1", "t2"]);
+    }
+
     #[test]
     fn sleddb_basic_interface_should_work() {
         let dir = tempdir().unwrap();
@@ -165,4 +518,32 @@ mod tests {
         let store = SledDb::new(dir);
         test_get_iter(store);
     }
+
+    #[test]
+    fn bitcask_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = Bitcask::open(dir.path(), BitcaskConfig::default()).unwrap();
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn bitcask_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = Bitcask::open(dir.path(), BitcaskConfig::default()).unwrap();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn bitcask_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = Bitcask::open(dir.path(), BitcaskConfig::default()).unwrap();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn bitcask_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = Bitcask::open(dir.path(), BitcaskConfig::default()).unwrap();
+        test_tables(store);
+    }
 }
@@ -1,7 +1,19 @@
+mod aof;
+mod backup;
+pub mod bitcask;
+mod compression;
+mod encrypted;
+mod eviction;
 pub mod memory;
 pub mod sleddb;
 
-use crate::{KvError, Kvpair, Value};
+use crate::{Hmset, KvError, Kvpair, Value};
+pub use aof::{AofConfig, FsyncPolicy};
+pub use backup::*;
+pub use bitcask::{Bitcask, BitcaskConfig};
+pub use compression::{CompressionConfig, CompressionStats};
+pub use encrypted::{Cipher, EncryptedStorage, KeyRing};
+pub use eviction::{EvictionPolicy, MemoryStats};
 pub use memory::MemTable;
 pub use sleddb::SledDb;
 
@@ -19,6 +31,22 @@ pub trait Storage {
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 所有 HashTable 的名字
+    fn tables(&self) -> Result<Vec<String>, KvError>;
+    /// 按顺序写入 pairs 之前为它们腾出内存，覆盖已有的 key 时只计算增加的部分；
+    /// 放不下时什么都不修改，返回错误。没有内存限制的存储什么也不做
+    fn reserve(&self, _table: &str, _pairs: &[Kvpair]) -> Result<(), KvError> {
+        Ok(())
+    }
+    /// 清空所有数据之后能否放下 data，放不下时返回错误。没有内存限制的存储总是可以
+    fn check_capacity(&self, _data: &[Hmset]) -> Result<(), KvError> {
+        Ok(())
+    }
+    /// 取出因为内存限制被淘汰的 key 和淘汰前的 value，调用者把它们当作删除记录下来。
+    /// 第一次调用之后才开始记录，没有内存限制的存储总是返回空
+    fn take_evicted(&self) -> Result<Vec<(String, Kvpair)>, KvError> {
+        Ok(Vec::new())
+    }
 }
 
 /// 提供 Storage iterator， 这样 trait 的实现者只需要
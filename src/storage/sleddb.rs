@@ -1,14 +1,46 @@
 use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use std::{collections::BTreeSet, path::Path, str, sync::Arc};
+use tracing::warn;
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use super::compression::{decode_value, encode_value};
+use crate::{
+    CompressionConfig, CompressionStats, KvError, Kvpair, SledChangeStore, Storage, StorageIter,
+    Value,
+};
+
+// 变更日志使用单独的 tree，不会出现在 table 里
+const CHANGE_TREE: &str = "changes";
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    compression: CompressionConfig,
+    stats: Arc<CompressionStats>,
+}
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self {
+            db: sled::open(path).unwrap(),
+            compression: CompressionConfig::default(),
+            stats: Default::default(),
+        }
+    }
+
+    /// 写入时压缩 value，读取时会自动识别压缩过的和没有压缩的 value
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// value 压缩的统计
+    pub fn compression_stats(&self) -> Arc<CompressionStats> {
+        self.stats.clone()
+    }
+
+    /// 和数据保存在同一个 sled 数据库里的变更日志
+    pub fn change_store(&self) -> Result<SledChangeStore, KvError> {
+        Ok(SledChangeStore::new(self.db.open_tree(CHANGE_TREE)?))
     }
 
     // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
@@ -32,60 +64,74 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        let result = self.db.get(name.as_bytes())?.map(|v| decode_value(&v));
         flip(result)
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
-        let data: Vec<u8> = value.try_into()?;
+        let (data, raw) = encode_value(&self.compression, table, value)?;
 
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
-        flip(result)
+        let old = self.db.insert(name, data.as_slice())?;
+        // 写入成功之后才计入压缩统计
+        self.stats.record(raw, &data);
+        flip(old.map(|v| decode_value(&v)))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+        let name = SledDb::get_full_key(table, key);
 
-        Ok(self.0.contains_key(name)?)
+        Ok(self.db.contains_key(name)?)
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+        let name = SledDb::get_full_key(table, key);
 
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
+        let result = self.db.remove(name)?.map(|v| decode_value(&v));
         flip(result)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
-
-        Ok(result)
+        self.db.scan_prefix(prefix).map(decode_pair).collect()
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
-        Ok(Box::new(iter))
+        // iterator 无法返回错误，跳过无法解码的数据
+        let table = table.to_string();
+        let iter = self
+            .db
+            .scan_prefix(prefix)
+            .filter_map(move |item| match decode_pair(item) {
+                Ok(pair) => Some(pair),
+                Err(e) => {
+                    warn!("Skip undecodable pair in {}: {:?}", table, e);
+                    None
+                }
+            });
+        Ok(Box::new(StorageIter::new(iter)))
     }
-}
 
-impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
-    fn from(v: Result<(IVec, IVec), sled::Error>) -> Self {
-        match v {
-            Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
-                Err(_) => Kvpair::default(),
-            },
-            _ => Kvpair::default(),
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = BTreeSet::new();
+        for item in self.db.iter().keys() {
+            let key = item?;
+            if let Some((table, _)) = str::from_utf8(&key).ok().and_then(|s| s.split_once(':')) {
+                tables.insert(table.to_string());
+            }
         }
+        Ok(tables.into_iter().collect())
     }
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
+// 把 sled 里的一条数据解码成 kv pair，key 去掉 table 前缀
+fn decode_pair(item: Result<(IVec, IVec), sled::Error>) -> Result<Kvpair, KvError> {
+    let (k, v) = item?;
+    let key = str::from_utf8(&k)
+        .ok()
+        .and_then(|s| s.split_once(':'))
+        .map(|(_, key)| key)
+        .ok_or_else(|| KvError::Internal(format!("Invalid key in sled: {:?}", k)))?;
+    Ok(Kvpair::new(key, decode_value(&v)?))
 }